@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_songs_isrc;
+DROP INDEX IF EXISTS idx_songs_artist;
+DROP TABLE IF EXISTS songs;
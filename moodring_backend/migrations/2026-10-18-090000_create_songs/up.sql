@@ -0,0 +1,22 @@
+-- Shared track catalogue. `id` is the same track ID stored in song_tags.song_id.
+CREATE TABLE songs (
+    id VARCHAR PRIMARY KEY,
+    title VARCHAR NOT NULL,
+    artist VARCHAR NOT NULL,
+    album VARCHAR,
+    release_year INTEGER,
+    duration_ms INTEGER,
+    isrc VARCHAR,
+    energy DOUBLE PRECISION,
+    valence DOUBLE PRECISION,
+    tempo DOUBLE PRECISION,
+    danceability DOUBLE PRECISION,
+    acousticness DOUBLE PRECISION,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_songs_artist ON songs(artist);
+CREATE INDEX idx_songs_isrc ON songs(isrc);
+
+SELECT diesel_manage_updated_at('songs');
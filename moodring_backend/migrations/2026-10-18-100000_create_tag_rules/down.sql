@@ -0,0 +1,4 @@
+ALTER TABLE song_tags DROP COLUMN IF EXISTS rule_id;
+DROP INDEX IF EXISTS idx_tag_rules_user_id;
+DROP TABLE IF EXISTS tag_rules;
+DROP TABLE IF EXISTS user_songs;
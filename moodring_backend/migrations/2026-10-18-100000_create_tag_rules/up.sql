@@ -0,0 +1,25 @@
+-- Songs that belong to a user's library (tagged, synced or imported).
+CREATE TABLE user_songs (
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    song_id VARCHAR NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, song_id)
+);
+
+CREATE TABLE tag_rules (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    conditions JSONB NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_tag_rules_user_id ON tag_rules(user_id);
+
+SELECT diesel_manage_updated_at('tag_rules');
+
+-- Set when a song tag was applied by a rule rather than by hand.
+ALTER TABLE song_tags ADD COLUMN rule_id INTEGER REFERENCES tag_rules(id) ON DELETE SET NULL;
@@ -0,0 +1,7 @@
+DROP TRIGGER IF EXISTS set_updated_at ON song_tags;
+DROP INDEX IF EXISTS idx_song_tags_user_source;
+ALTER TABLE song_tags
+    DROP COLUMN IF EXISTS updated_at,
+    DROP COLUMN IF EXISTS note,
+    DROP COLUMN IF EXISTS confidence,
+    DROP COLUMN IF EXISTS source;
@@ -0,0 +1,12 @@
+ALTER TABLE song_tags
+    ADD COLUMN source VARCHAR NOT NULL DEFAULT 'manual'
+        CHECK (source IN ('manual', 'import', 'rule', 'suggestion')),
+    ADD COLUMN confidence DOUBLE PRECISION CHECK (confidence BETWEEN 0 AND 1),
+    ADD COLUMN note TEXT,
+    ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+UPDATE song_tags SET source = 'rule' WHERE rule_id IS NOT NULL;
+
+CREATE INDEX idx_song_tags_user_source ON song_tags(user_id, source);
+
+SELECT diesel_manage_updated_at('song_tags');
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_playlists_user_id;
+DROP TABLE IF EXISTS playlists;
+ALTER TABLE song_tags DROP COLUMN IF EXISTS weight;
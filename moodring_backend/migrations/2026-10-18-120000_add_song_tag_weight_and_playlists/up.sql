@@ -0,0 +1,17 @@
+-- Optional intensity of a tag on a song, 1 (slightly) to 5 (very).
+ALTER TABLE song_tags ADD COLUMN weight INTEGER CHECK (weight BETWEEN 1 AND 5);
+
+-- Saved tag queries ("smart playlists").
+CREATE TABLE playlists (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    query VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    UNIQUE(user_id, name)
+);
+
+CREATE INDEX idx_playlists_user_id ON playlists(user_id);
+
+SELECT diesel_manage_updated_at('playlists');
@@ -0,0 +1,4 @@
+-- Tags can be nested under another of the same user's tags.
+ALTER TABLE tags ADD COLUMN parent_id INTEGER REFERENCES tags(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_tags_parent_id ON tags(parent_id);
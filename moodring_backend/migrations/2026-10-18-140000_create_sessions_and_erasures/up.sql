@@ -0,0 +1,32 @@
+-- Bearer tokens handed out at login. Only a SHA-256 hash of each token is kept.
+CREATE TABLE sessions (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_sessions_user_id ON sessions(user_id);
+
+-- One row per erased account. Deliberately holds no user ID or personal data.
+CREATE TABLE account_erasures (
+    id SERIAL PRIMARY KEY,
+    receipt VARCHAR NOT NULL UNIQUE,
+    tags_deleted INTEGER NOT NULL,
+    song_tags_deleted INTEGER NOT NULL,
+    playlists_deleted INTEGER NOT NULL,
+    sessions_deleted INTEGER NOT NULL,
+    export_requested BOOLEAN NOT NULL,
+    erased_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Final exports kept for download after their account is gone.
+CREATE TABLE data_exports (
+    id SERIAL PRIMARY KEY,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    filename VARCHAR NOT NULL,
+    content BYTEA NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN IF EXISTS spotify_unlinked_at;
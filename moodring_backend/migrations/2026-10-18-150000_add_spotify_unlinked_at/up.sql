@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN spotify_unlinked_at TIMESTAMP;
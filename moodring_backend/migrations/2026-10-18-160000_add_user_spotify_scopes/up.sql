@@ -0,0 +1,3 @@
+-- Scopes of the user's current Spotify grant. NULL for grants made before
+-- scopes were recorded.
+ALTER TABLE users ADD COLUMN spotify_scopes VARCHAR;
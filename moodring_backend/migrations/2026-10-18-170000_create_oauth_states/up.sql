@@ -0,0 +1,10 @@
+-- Pending browser logins: the PKCE verifier waits here, keyed by a hash of
+-- the `state` parameter, until Spotify redirects back.
+CREATE TABLE oauth_states (
+    id SERIAL PRIMARY KEY,
+    state_hash VARCHAR NOT NULL UNIQUE,
+    code_verifier VARCHAR NOT NULL,
+    redirect_uri VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL
+);
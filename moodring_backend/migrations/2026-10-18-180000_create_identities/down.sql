@@ -0,0 +1,21 @@
+ALTER TABLE users DROP COLUMN IF EXISTS email_verified;
+UPDATE users SET email = '' WHERE email IS NULL;
+ALTER TABLE users ALTER COLUMN email SET NOT NULL;
+
+UPDATE users SET spotify_id = identities.provider_user_id
+FROM identities
+WHERE users.spotify_id IS NULL
+  AND identities.user_id = users.id
+  AND identities.provider = 'spotify';
+-- Users who only sign in another way have no Spotify account to fall back
+-- on; refuse rather than delete them.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM users WHERE spotify_id IS NULL) THEN
+        RAISE EXCEPTION 'Some users have no Spotify account; link or erase them before reverting';
+    END IF;
+END
+$$;
+ALTER TABLE users ALTER COLUMN spotify_id SET NOT NULL;
+
+DROP TABLE IF EXISTS identities;
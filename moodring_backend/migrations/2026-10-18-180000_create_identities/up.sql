@@ -0,0 +1,21 @@
+-- External accounts a user can sign in with. One user can have several.
+CREATE TABLE identities (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    provider VARCHAR NOT NULL,
+    provider_user_id VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(provider, provider_user_id)
+);
+
+CREATE INDEX idx_identities_user_id ON identities(user_id);
+
+INSERT INTO identities (user_id, provider, provider_user_id, created_at)
+SELECT id, 'spotify', spotify_id, created_at FROM users;
+
+-- users.spotify_id now only names the Spotify account whose tokens are stored.
+ALTER TABLE users ALTER COLUMN spotify_id DROP NOT NULL;
+
+ALTER TABLE users ALTER COLUMN email DROP NOT NULL;
+UPDATE users SET email = NULL WHERE email = '';
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,11 @@
+-- Audio files found under LOCAL_MUSIC_DIR. Size and modification time let
+-- rescans skip files that haven't changed since they were last hashed.
+CREATE TABLE local_files (
+    path VARCHAR PRIMARY KEY,
+    song_id VARCHAR NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+    size BIGINT NOT NULL,
+    modified_at TIMESTAMP NOT NULL,
+    scanned_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_local_files_song_id ON local_files(song_id);
@@ -0,0 +1,17 @@
+-- Pairs of catalogue entries in a user's library that are the same
+-- recording. Each pair is stored once, with song_id < matched_song_id.
+CREATE TABLE song_matches (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    song_id VARCHAR NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+    matched_song_id VARCHAR NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+    method VARCHAR NOT NULL,
+    confidence DOUBLE PRECISION NOT NULL,
+    status VARCHAR NOT NULL DEFAULT 'suggested',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(user_id, song_id, matched_song_id),
+    CHECK (song_id < matched_song_id)
+);
+
+CREATE INDEX idx_song_matches_user_status ON song_matches(user_id, status);
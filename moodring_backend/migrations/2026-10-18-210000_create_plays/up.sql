@@ -0,0 +1,14 @@
+-- Tracks a user played. A user can't finish two plays in the same second,
+-- so played_at (truncated to seconds) identifies a play across sources.
+CREATE TABLE plays (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    song_id VARCHAR NOT NULL,
+    played_at TIMESTAMP NOT NULL,
+    ms_played INTEGER,
+    source VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(user_id, played_at)
+);
+
+CREATE INDEX idx_plays_song_id ON plays(song_id);
@@ -0,0 +1,2 @@
+-- IANA name such as Europe/Berlin; NULL means UTC
+ALTER TABLE users ADD COLUMN timezone VARCHAR;
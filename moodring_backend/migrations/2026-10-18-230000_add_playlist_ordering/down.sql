@@ -0,0 +1,6 @@
+ALTER TABLE playlists
+    DROP COLUMN IF EXISTS ordering,
+    DROP COLUMN IF EXISTS seed,
+    DROP COLUMN IF EXISTS no_repeat_artist,
+    DROP COLUMN IF EXISTS max_tracks,
+    DROP COLUMN IF EXISTS max_duration_ms;
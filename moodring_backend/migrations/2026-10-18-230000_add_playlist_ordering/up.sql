@@ -0,0 +1,7 @@
+-- How a saved playlist orders and trims its query's matches.
+ALTER TABLE playlists
+    ADD COLUMN ordering VARCHAR NOT NULL DEFAULT 'weight',
+    ADD COLUMN seed BIGINT,
+    ADD COLUMN no_repeat_artist BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN max_tracks INTEGER CHECK (max_tracks > 0),
+    ADD COLUMN max_duration_ms BIGINT CHECK (max_duration_ms > 0);
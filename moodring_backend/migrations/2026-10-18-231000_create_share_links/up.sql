@@ -0,0 +1,17 @@
+-- Public, read-only links to a tag or saved playlist. Only a SHA-256 hash
+-- of each token is kept.
+CREATE TABLE share_links (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR NOT NULL UNIQUE,
+    tag_id INTEGER REFERENCES tags(id) ON DELETE CASCADE,
+    playlist_id INTEGER REFERENCES playlists(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    access_count INTEGER NOT NULL DEFAULT 0,
+    last_accessed_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CHECK ((tag_id IS NULL) <> (playlist_id IS NULL))
+);
+
+CREATE INDEX idx_share_links_user_id ON share_links(user_id);
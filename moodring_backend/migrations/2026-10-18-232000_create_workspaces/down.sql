@@ -0,0 +1,8 @@
+DELETE FROM tags WHERE workspace_id IS NOT NULL;
+DROP INDEX IF EXISTS idx_tags_workspace_id_name;
+DROP INDEX IF EXISTS idx_tags_user_id_name;
+ALTER TABLE tags ADD CONSTRAINT tags_user_id_name_key UNIQUE (user_id, name);
+ALTER TABLE tags DROP COLUMN IF EXISTS workspace_id;
+
+DROP TABLE IF EXISTS workspace_members;
+DROP TABLE IF EXISTS workspaces;
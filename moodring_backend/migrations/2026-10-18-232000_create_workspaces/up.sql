@@ -0,0 +1,29 @@
+-- Shared tag sets. A workspace owns tags that all of its members can see
+-- and, depending on their role, apply or manage.
+CREATE TABLE workspaces (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+SELECT diesel_manage_updated_at('workspaces');
+
+CREATE TABLE workspace_members (
+    id SERIAL PRIMARY KEY,
+    workspace_id INTEGER NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role VARCHAR NOT NULL CHECK (role IN ('viewer', 'tagger', 'admin')),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    UNIQUE(workspace_id, user_id)
+);
+
+CREATE INDEX idx_workspace_members_user_id ON workspace_members(user_id);
+
+-- Workspace tags keep user_id as the member who created them.
+ALTER TABLE tags ADD COLUMN workspace_id INTEGER REFERENCES workspaces(id) ON DELETE CASCADE;
+
+ALTER TABLE tags DROP CONSTRAINT tags_user_id_name_key;
+CREATE UNIQUE INDEX idx_tags_user_id_name ON tags(user_id, name) WHERE workspace_id IS NULL;
+CREATE UNIQUE INDEX idx_tags_workspace_id_name ON tags(workspace_id, name) WHERE workspace_id IS NOT NULL;
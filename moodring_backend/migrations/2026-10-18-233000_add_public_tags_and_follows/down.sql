@@ -0,0 +1,2 @@
+DROP TABLE IF EXISTS follows;
+ALTER TABLE tags DROP COLUMN IF EXISTS is_public;
@@ -0,0 +1,186 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::http::ContentType;
+use rocket::serde::Serialize;
+
+use crate::auth::{generate_token, hash_token};
+use crate::export::{write_json_export, Download};
+use crate::workspaces::{delete_empty_workspaces, hand_over_workspaces};
+use crate::{schema, DbPool, TransactionError};
+
+/// How long a final export stays downloadable after its account is erased.
+pub const FINAL_EXPORT_LIFETIME_DAYS: i64 = 7;
+
+/// Returned once an account is gone. The receipt matches the anonymous
+/// audit record, which is all that is kept.
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AccountErasure {
+    pub receipt: String,
+    pub erased_at: NaiveDateTime,
+    /// Path of the final export, when one was requested.
+    pub export_url: Option<String>,
+    pub export_expires_at: Option<NaiveDateTime>,
+}
+
+fn count(result: QueryResult<i64>, what: &str) -> Result<i32, String> {
+    result
+        .map(|n| n as i32)
+        .map_err(|e| format!("Failed to count {what}: {e}"))
+}
+
+/// Deletes the user and everything hanging off the user row: tags, song
+/// tags, rules, playlists, library and sessions all cascade. Tags they
+/// created in a shared workspace are handed to another member first.
+/// Spotify has no token revocation endpoint, so dropping our copy of the
+/// tokens is the revocation; users can also remove the app from their
+/// Spotify account.
+pub fn erase_account(
+    conn: &mut PgConnection,
+    user_id: i32,
+    with_export: bool,
+) -> Result<AccountErasure, String> {
+    use schema::{account_erasures, data_exports, playlists, sessions, song_tags, tags, users};
+
+    conn.transaction::<_, TransactionError, _>(|conn| {
+        // Lock the row so a concurrent login can't write to a half-erased account.
+        users::table
+            .find(user_id)
+            .select(users::id)
+            .for_update()
+            .first::<i32>(conn)
+            .map_err(|e| format!("Failed to find user: {e}"))?;
+
+        let final_export = if with_export {
+            let mut content = Vec::new();
+            write_json_export(conn, user_id, &mut content)?;
+            Some(content)
+        } else {
+            None
+        };
+        hand_over_workspaces(conn, user_id)?;
+
+        let tags_deleted = count(
+            tags::table
+                .filter(tags::user_id.eq(user_id))
+                .count()
+                .get_result(conn),
+            "tags",
+        )?;
+        let song_tags_deleted = count(
+            song_tags::table
+                .filter(song_tags::user_id.eq(user_id))
+                .count()
+                .get_result(conn),
+            "song tags",
+        )?;
+        let playlists_deleted = count(
+            playlists::table
+                .filter(playlists::user_id.eq(user_id))
+                .count()
+                .get_result(conn),
+            "playlists",
+        )?;
+        let sessions_deleted = count(
+            sessions::table
+                .filter(sessions::user_id.eq(user_id))
+                .count()
+                .get_result(conn),
+            "sessions",
+        )?;
+
+        let receipt = uuid::Uuid::new_v4().to_string();
+        let erased_at = chrono::Utc::now().naive_utc();
+
+        diesel::delete(users::table.find(user_id)).execute(conn)?;
+        delete_empty_workspaces(conn)?;
+        diesel::insert_into(account_erasures::table)
+            .values((
+                account_erasures::receipt.eq(&receipt),
+                account_erasures::tags_deleted.eq(tags_deleted),
+                account_erasures::song_tags_deleted.eq(song_tags_deleted),
+                account_erasures::playlists_deleted.eq(playlists_deleted),
+                account_erasures::sessions_deleted.eq(sessions_deleted),
+                account_erasures::export_requested.eq(with_export),
+                account_erasures::erased_at.eq(erased_at),
+            ))
+            .execute(conn)?;
+        diesel::delete(data_exports::table.filter(data_exports::expires_at.le(erased_at)))
+            .execute(conn)?;
+
+        let Some(content) = final_export else {
+            return Ok(AccountErasure {
+                receipt,
+                erased_at,
+                export_url: None,
+                export_expires_at: None,
+            });
+        };
+        let token = generate_token();
+        let expires_at = erased_at + chrono::Duration::days(FINAL_EXPORT_LIFETIME_DAYS);
+        diesel::insert_into(data_exports::table)
+            .values((
+                data_exports::token_hash.eq(hash_token(&token)),
+                data_exports::filename.eq(format!(
+                    "moodring-export-{}.json",
+                    erased_at.format("%Y-%m-%d")
+                )),
+                data_exports::content.eq(content),
+                data_exports::expires_at.eq(expires_at),
+            ))
+            .execute(conn)?;
+
+        Ok(AccountErasure {
+            receipt,
+            erased_at,
+            export_url: Some(format!("/exports/{token}")),
+            export_expires_at: Some(expires_at),
+        })
+    })
+    .map_err(|TransactionError(e)| e)
+}
+
+pub async fn delete_account(
+    pool: &DbPool,
+    user_id: i32,
+    with_export: bool,
+) -> Result<AccountErasure, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        erase_account(&mut conn, user_id, with_export)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Serves a final export by the token from its link.
+pub async fn get_final_export(pool: &DbPool, token: String) -> Result<Download<Vec<u8>>, String> {
+    use schema::data_exports::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::data_exports
+            .filter(dsl::token_hash.eq(hash_token(&token)))
+            .filter(dsl::expires_at.gt(chrono::Utc::now().naive_utc()))
+            .select((dsl::filename, dsl::content))
+            .first::<(String, Vec<u8>)>(&mut conn)
+            .optional()
+            .map_err(|e| format!("Failed to load export: {e}"))?
+            .map(|(filename, content)| Download {
+                filename,
+                content_type: ContentType::JSON,
+                body: content,
+            })
+            .ok_or_else(|| "Export not found or expired".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
@@ -0,0 +1,214 @@
+use base64::Engine;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rand::RngCore;
+use rocket::http::{Cookie, SameSite, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use sha2::{Digest, Sha256};
+
+use crate::{schema, DbPool};
+
+pub const SESSION_LIFETIME_DAYS: i64 = 30;
+
+/// Cookie carrying the session token for the web app.
+pub const SESSION_COOKIE: &str = "moodring_session";
+
+#[derive(Queryable, Clone, Debug)]
+#[diesel(table_name = schema::sessions)]
+pub struct Session {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}
+
+/// A random, URL-safe token with 256 bits of entropy.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// What gets stored in place of a token, so a database leak doesn't leak
+/// usable credentials.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Starts a session for the user and returns its bearer token.
+pub fn create_session(conn: &mut PgConnection, user_id: i32) -> Result<String, String> {
+    use schema::sessions::dsl;
+
+    let token = generate_token();
+    let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::days(SESSION_LIFETIME_DAYS);
+    diesel::insert_into(dsl::sessions)
+        .values((
+            dsl::user_id.eq(user_id),
+            dsl::token_hash.eq(hash_token(&token)),
+            dsl::expires_at.eq(expires_at),
+        ))
+        .execute(conn)
+        .map_err(|e| format!("Failed to create session: {e}"))?;
+    Ok(token)
+}
+
+pub fn find_session(conn: &mut PgConnection, token: &str) -> Result<Option<Session>, String> {
+    use schema::sessions::dsl;
+
+    dsl::sessions
+        .filter(dsl::token_hash.eq(hash_token(token)))
+        .filter(dsl::expires_at.gt(chrono::Utc::now().naive_utc()))
+        .first::<Session>(conn)
+        .optional()
+        .map_err(|e| format!("Failed to load session: {e}"))
+}
+
+/// The session cookie set after a browser login. `SameSite=Lax` keeps other
+/// sites from riding it on POST and DELETE requests.
+pub fn session_cookie(token: String) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(rocket::time::Duration::days(SESSION_LIFETIME_DAYS))
+        .build()
+}
+
+/// The user behind the request's session token, taken from an
+/// `Authorization: Bearer` header or else the session cookie.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser {
+    pub user_id: i32,
+    pub session_id: i32,
+    pub token: String,
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(token) = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(bearer_token)
+            .map(str::to_string)
+            .or_else(|| {
+                request
+                    .cookies()
+                    .get(SESSION_COOKIE)
+                    .map(|cookie| cookie.value().to_string())
+            })
+        else {
+            return Outcome::Error((Status::Unauthorized, "Missing session token".to_string()));
+        };
+        let Some(pool) = request.rocket().state::<DbPool>().cloned() else {
+            return Outcome::Error((
+                Status::InternalServerError,
+                "Database pool not configured".to_string(),
+            ));
+        };
+
+        let lookup_token = token.clone();
+        let session = tokio::task::spawn_blocking(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|e| format!("Failed to get connection: {e}"))?;
+            find_session(&mut conn, &lookup_token)
+        })
+        .await
+        .map_err(|e| format!("Task join error: {e}"));
+
+        match session {
+            Ok(Ok(Some(session))) => Outcome::Success(AuthenticatedUser {
+                user_id: session.user_id,
+                session_id: session.id,
+                token,
+            }),
+            Ok(Ok(None)) => Outcome::Error((
+                Status::Unauthorized,
+                "Invalid or expired session token".to_string(),
+            )),
+            Ok(Err(e)) | Err(e) => Outcome::Error((Status::InternalServerError, e)),
+        }
+    }
+}
+
+/// The user a request acts for: the `<user_id>` of a `/users/<user_id>/…`
+/// path, or else a `user_id` query parameter.
+fn acting_user_id(request: &Request<'_>) -> Option<i32> {
+    if request.routed_segment(0) == Some("users") {
+        return request.routed_segment(1)?.parse().ok();
+    }
+    request.query_value::<i32>("user_id")?.ok()
+}
+
+/// An [`AuthenticatedUser`] acting on their own data. Requests naming
+/// another user in the path or query are refused with 403.
+#[derive(Clone, Debug)]
+pub struct AuthorizedUser(pub AuthenticatedUser);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthorizedUser {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let session = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(session) => session,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+        match acting_user_id(request) {
+            Some(user_id) if user_id != session.user_id => Outcome::Error((
+                Status::Forbidden,
+                "Session does not belong to this user".to_string(),
+            )),
+            _ => Outcome::Success(AuthorizedUser(session)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_token_is_random_and_url_safe() {
+        let first = generate_token();
+        let second = generate_token();
+
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 43);
+        assert!(first
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+    }
+
+    #[test]
+    fn test_hash_token() {
+        assert_eq!(
+            hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer abc"), Some("abc"));
+        assert_eq!(bearer_token("bearer  abc "), Some("abc"));
+        assert_eq!(bearer_token("Basic abc"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("abc"), None);
+    }
+}
@@ -0,0 +1,222 @@
+use diesel::prelude::*;
+use rocket::serde::Serialize;
+use std::collections::HashSet;
+
+use crate::identities::SPOTIFY_PROVIDER;
+use crate::providers::{parse_song_id, MusicProvider, SpotifyProvider};
+use crate::rules::{apply_rules, RuleApplication};
+use crate::spotify::SpotifyClient;
+use crate::{schema, spotify_access_token_for_user, DbPool, NewSong, Song};
+
+/// Songs that entered a user's library, with the tags their rules added.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct CatalogImport {
+    pub songs: Vec<Song>,
+    pub rule_applications: Vec<RuleApplication>,
+}
+
+/// Inserts songs into the catalogue, updating rows that already exist.
+/// Fields left as `None` keep whatever the catalogue already had.
+pub fn upsert_songs(conn: &mut PgConnection, new_songs: &[NewSong]) -> QueryResult<Vec<Song>> {
+    use schema::songs::dsl::*;
+
+    new_songs
+        .iter()
+        .map(|new_song| {
+            diesel::insert_into(songs)
+                .values(new_song)
+                .on_conflict(id)
+                .do_update()
+                .set(new_song)
+                .get_result::<Song>(conn)
+        })
+        .collect()
+}
+
+/// Adds catalogue songs to the user's library; songs already there are kept.
+pub fn add_to_library(
+    conn: &mut PgConnection,
+    user_id: i32,
+    song_ids: &[String],
+) -> QueryResult<usize> {
+    use schema::user_songs;
+
+    let rows: Vec<_> = song_ids
+        .iter()
+        .map(|song_id| {
+            (
+                user_songs::user_id.eq(user_id),
+                user_songs::song_id.eq(song_id),
+            )
+        })
+        .collect();
+    diesel::insert_into(user_songs::table)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(conn)
+}
+
+pub async fn upsert_song(pool: &DbPool, new_song: NewSong) -> Result<Song, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        upsert_songs(&mut conn, std::slice::from_ref(&new_song))
+            .map_err(|e| format!("Failed to save song: {e}"))?
+            .pop()
+            .ok_or_else(|| "Failed to save song".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn get_song(pool: &DbPool, song_id: String) -> Result<Song, String> {
+    use schema::songs::dsl::*;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        songs
+            .filter(id.eq(&song_id))
+            .first::<Song>(&mut conn)
+            .map_err(|e| format!("Failed to load song: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Fetches the given tracks from a provider, stores them in the catalogue
+/// and the user's library, then runs the user's tagging rules over them.
+pub async fn import_tracks(
+    pool: &DbPool,
+    user_id: i32,
+    provider: &dyn MusicProvider,
+    access_token: &str,
+    track_ids: Vec<String>,
+) -> Result<CatalogImport, String> {
+    if track_ids.is_empty() {
+        return Ok(CatalogImport::default());
+    }
+
+    let new_songs = provider.tracks(access_token, &track_ids).await?;
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let songs = upsert_songs(&mut conn, &new_songs)
+            .map_err(|e| format!("Failed to save songs: {e}"))?;
+        let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+        add_to_library(&mut conn, user_id, &song_ids)
+            .map_err(|e| format!("Failed to update library: {e}"))?;
+        let rule_applications = apply_rules(&mut conn, user_id, Some(&song_ids), None, false)?;
+        Ok(CatalogImport {
+            songs,
+            rule_applications,
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Imports Spotify tracks, with their audio features, into the user's
+/// library.
+pub async fn import_spotify_tracks(
+    pool: &DbPool,
+    user_id: i32,
+    access_token: &str,
+    track_ids: Vec<String>,
+) -> Result<CatalogImport, String> {
+    import_tracks(
+        pool,
+        user_id,
+        &SpotifyProvider::new(),
+        access_token,
+        track_ids,
+    )
+    .await
+}
+
+/// Brings the user's Spotify Liked Songs into their library, and every
+/// Spotify song they have tagged into the catalogue, fetching anything that
+/// is missing or still lacks audio features.
+pub async fn sync_user_catalog(pool: &DbPool, user_id: i32) -> Result<CatalogImport, String> {
+    use schema::{song_tags, songs, user_songs};
+
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    let saved_ids = SpotifyClient::new()
+        .get_saved_track_ids(&access_token)
+        .await?;
+
+    let lookup_pool = pool.clone();
+    let missing_ids = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let tagged_ids = song_tags::table
+            .filter(song_tags::user_id.eq(user_id))
+            .select(song_tags::song_id)
+            .distinct()
+            .load::<String>(&mut conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))?;
+
+        let complete_ids = songs::table
+            .filter(songs::id.eq_any(&tagged_ids))
+            .load::<Song>(&mut conn)
+            .map_err(|e| format!("Failed to load songs: {e}"))?
+            .into_iter()
+            .filter(|song| song.audio_features().is_some())
+            .map(|song| song.id)
+            .collect::<HashSet<_>>();
+
+        let library_ids = user_songs::table
+            .filter(user_songs::user_id.eq(user_id))
+            .filter(user_songs::song_id.eq_any(&saved_ids))
+            .select(user_songs::song_id)
+            .load::<String>(&mut conn)
+            .map_err(|e| format!("Failed to load library: {e}"))?
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let mut seen = HashSet::new();
+        Ok::<_, String>(
+            tagged_ids
+                .into_iter()
+                .filter(|song_id| !complete_ids.contains(song_id))
+                .filter(|song_id| parse_song_id(song_id).0 == SPOTIFY_PROVIDER)
+                .chain(
+                    saved_ids
+                        .into_iter()
+                        .filter(|song_id| !library_ids.contains(song_id)),
+                )
+                .filter(|song_id| seen.insert(song_id.clone()))
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    import_spotify_tracks(pool, user_id, &access_token, missing_ids).await
+}
+
+/// Imports every track of a Spotify playlist into the user's library.
+pub async fn import_spotify_playlist(
+    pool: &DbPool,
+    user_id: i32,
+    playlist_id: &str,
+) -> Result<CatalogImport, String> {
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    let provider = SpotifyProvider::new();
+    let track_ids = provider
+        .playlist_track_ids(&access_token, playlist_id)
+        .await?;
+    import_tracks(pool, user_id, &provider, &access_token, track_ids).await
+}
@@ -0,0 +1,552 @@
+use diesel::prelude::*;
+use rocket::http::ContentType;
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::export::{
+    csv_field, file_stem, resolve_playlist_source, xml_escape, Download, PlaylistSource,
+};
+use crate::local_library::library_root;
+use crate::tag_query::TagQueryMatch;
+use crate::webhooks::{self, WebhookEvent};
+use crate::{schema, DbPool};
+
+/// DJ software an export is built for.
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DjFormat {
+    Rekordbox,
+    Serato,
+    Traktor,
+}
+
+impl DjFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DjFormat::Rekordbox => "rekordbox",
+            DjFormat::Serato => "serato",
+            DjFormat::Traktor => "traktor",
+        }
+    }
+}
+
+/// A playlist entry as DJ software sees it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CrateTrack {
+    pub song_id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub duration_ms: Option<i32>,
+    pub isrc: Option<String>,
+    /// Absolute path of a local file holding the song.
+    pub location: Option<String>,
+}
+
+impl CrateTrack {
+    fn from_match(track: &TagQueryMatch, location: Option<String>) -> Self {
+        match &track.song {
+            Some(song) => CrateTrack {
+                song_id: track.song_id.clone(),
+                title: song.title.clone(),
+                artist: song.artist.clone(),
+                album: song.album.clone(),
+                duration_ms: song.duration_ms,
+                isrc: song.isrc.clone(),
+                location,
+            },
+            None => CrateTrack {
+                song_id: track.song_id.clone(),
+                title: track.song_id.clone(),
+                artist: String::new(),
+                album: None,
+                duration_ms: None,
+                isrc: None,
+                location,
+            },
+        }
+    }
+}
+
+/// Finds a local file for each track: one of the song's own entries or a
+/// confirmed match that came from the local library, or else a local file
+/// with the same ISRC. Paths are relative to the library root.
+fn find_local_paths(
+    conn: &mut PgConnection,
+    tracks: &[TagQueryMatch],
+) -> Result<HashMap<String, String>, String> {
+    use schema::{local_files, songs};
+
+    let entry_ids: Vec<&String> = tracks
+        .iter()
+        .flat_map(|track| std::iter::once(&track.song_id).chain(&track.matched_song_ids))
+        .collect();
+    let mut by_song: HashMap<String, String> = HashMap::new();
+    for (song_id, path) in local_files::table
+        .filter(local_files::song_id.eq_any(&entry_ids))
+        .order(local_files::path.asc())
+        .select((local_files::song_id, local_files::path))
+        .load::<(String, String)>(conn)
+        .map_err(|e| format!("Failed to load local files: {e}"))?
+    {
+        by_song.entry(song_id).or_insert(path);
+    }
+
+    let isrcs: Vec<String> = tracks
+        .iter()
+        .filter_map(|track| track.song.as_ref()?.isrc.as_ref())
+        .map(|isrc| isrc.to_ascii_uppercase())
+        .collect();
+    let mut by_isrc: HashMap<String, String> = HashMap::new();
+    for (isrc, path) in local_files::table
+        .inner_join(songs::table)
+        .filter(songs::isrc.eq_any(&isrcs))
+        .order(local_files::path.asc())
+        .select((songs::isrc.assume_not_null(), local_files::path))
+        .load::<(String, String)>(conn)
+        .map_err(|e| format!("Failed to load local files: {e}"))?
+    {
+        by_isrc.entry(isrc).or_insert(path);
+    }
+
+    Ok(tracks
+        .iter()
+        .filter_map(|track| {
+            let path = std::iter::once(&track.song_id)
+                .chain(&track.matched_song_ids)
+                .find_map(|id| by_song.get(id))
+                .or_else(|| {
+                    let isrc = track.song.as_ref()?.isrc.as_ref()?;
+                    by_isrc.get(&isrc.to_ascii_uppercase())
+                })?;
+            Some((track.song_id.clone(), path.clone()))
+        })
+        .collect())
+}
+
+/// `file://localhost` URL of an absolute path, as Rekordbox writes them.
+fn file_url(path: &str) -> String {
+    let mut url = String::from("file://localhost");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    url
+}
+
+/// A Rekordbox library with the tracks in its collection and one playlist
+/// under the root node. Tracks without a local file keep their metadata,
+/// with the ISRC in the comments, so they can be found by hand.
+pub fn render_rekordbox_xml(name: &str, tracks: &[CrateTrack]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<DJ_PLAYLISTS Version=\"1.0.0\">\n");
+    out.push_str("  <PRODUCT Name=\"Moodring\" Version=\"1\" Company=\"Moodring\"/>\n");
+    out.push_str(&format!("  <COLLECTION Entries=\"{}\">\n", tracks.len()));
+    for (index, track) in tracks.iter().enumerate() {
+        let mut attributes = format!(
+            "TrackID=\"{}\" Name=\"{}\" Artist=\"{}\"",
+            index + 1,
+            xml_escape(&track.title),
+            xml_escape(&track.artist)
+        );
+        if let Some(album) = &track.album {
+            attributes.push_str(&format!(" Album=\"{}\"", xml_escape(album)));
+        }
+        if let Some(duration_ms) = track.duration_ms {
+            attributes.push_str(&format!(" TotalTime=\"{}\"", duration_ms / 1000));
+        }
+        if let Some(isrc) = &track.isrc {
+            attributes.push_str(&format!(" Comments=\"ISRC {}\"", xml_escape(isrc)));
+        }
+        if let Some(location) = &track.location {
+            attributes.push_str(&format!(
+                " Location=\"{}\"",
+                xml_escape(&file_url(location))
+            ));
+        }
+        out.push_str(&format!("    <TRACK {attributes}/>\n"));
+    }
+    out.push_str("  </COLLECTION>\n  <PLAYLISTS>\n");
+    out.push_str("    <NODE Type=\"0\" Name=\"ROOT\" Count=\"1\">\n");
+    out.push_str(&format!(
+        "      <NODE Name=\"{}\" Type=\"1\" KeyType=\"0\" Entries=\"{}\">\n",
+        xml_escape(name),
+        tracks.len()
+    ));
+    for index in 0..tracks.len() {
+        out.push_str(&format!("        <TRACK Key=\"{}\"/>\n", index + 1));
+    }
+    out.push_str("      </NODE>\n    </NODE>\n  </PLAYLISTS>\n</DJ_PLAYLISTS>\n");
+    out
+}
+
+fn serato_field(out: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+fn utf16_be(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_be_bytes).collect()
+}
+
+/// A Serato crate: a version header followed by one entry per track, each
+/// holding the file's path relative to the volume root. Serato can only
+/// list files, so tracks without one are left out.
+pub fn render_serato_crate(tracks: &[CrateTrack]) -> Vec<u8> {
+    let mut out = Vec::new();
+    serato_field(&mut out, b"vrsn", &utf16_be("1.0/Serato ScratchLive Crate"));
+    for location in tracks.iter().filter_map(|track| track.location.as_deref()) {
+        let mut entry = Vec::new();
+        serato_field(
+            &mut entry,
+            b"ptrk",
+            &utf16_be(location.trim_start_matches('/')),
+        );
+        serato_field(&mut out, b"otrk", &entry);
+    }
+    out
+}
+
+/// Traktor's location of a file: the volume, then directories written as
+/// `/:` separated segments, then the file name.
+#[derive(Clone, PartialEq, Debug)]
+struct TraktorLocation {
+    volume: String,
+    dir: String,
+    file: String,
+}
+
+impl TraktorLocation {
+    /// A Windows path's volume is its drive, such as `C:`. On a Mac it is
+    /// the name of the disk, which a path under `/` doesn't say; it is left
+    /// blank there for Traktor to fill in on import.
+    fn parse(path: &str) -> Self {
+        let path = path.replace('\\', "/");
+        let (volume, path) = match path.as_bytes() {
+            [drive, b':', ..] if drive.is_ascii_alphabetic() => path.split_at(2),
+            _ => ("", path.as_str()),
+        };
+        let (dir, file) = path.rsplit_once('/').unwrap_or(("", path));
+        let segments: String = dir
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("/:{segment}"))
+            .collect();
+        TraktorLocation {
+            volume: volume.to_string(),
+            dir: format!("{segments}/:"),
+            file: file.to_string(),
+        }
+    }
+
+    /// How playlists refer to the collection entry.
+    fn key(&self) -> String {
+        format!("{}{}{}", self.volume, self.dir, self.file)
+    }
+}
+
+/// A Traktor collection and playlist. Like Serato, Traktor refers to
+/// tracks by file, so tracks without one are left out.
+pub fn render_traktor_nml(name: &str, tracks: &[CrateTrack]) -> String {
+    let located: Vec<(&CrateTrack, TraktorLocation)> = tracks
+        .iter()
+        .filter_map(|track| Some((track, TraktorLocation::parse(track.location.as_deref()?))))
+        .collect();
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\" ?>\n");
+    out.push_str("<NML VERSION=\"19\">\n  <HEAD COMPANY=\"www.native-instruments.com\" PROGRAM=\"Traktor\"></HEAD>\n");
+    out.push_str(&format!("  <COLLECTION ENTRIES=\"{}\">\n", located.len()));
+    for (track, location) in &located {
+        out.push_str(&format!(
+            "    <ENTRY TITLE=\"{}\" ARTIST=\"{}\">\n",
+            xml_escape(&track.title),
+            xml_escape(&track.artist)
+        ));
+        out.push_str(&format!(
+            "      <LOCATION DIR=\"{}\" FILE=\"{}\" VOLUME=\"{}\" VOLUMEID=\"\"></LOCATION>\n",
+            xml_escape(&location.dir),
+            xml_escape(&location.file),
+            xml_escape(&location.volume)
+        ));
+        if let Some(album) = &track.album {
+            out.push_str(&format!(
+                "      <ALBUM TITLE=\"{}\"></ALBUM>\n",
+                xml_escape(album)
+            ));
+        }
+        if let Some(duration_ms) = track.duration_ms {
+            out.push_str(&format!(
+                "      <INFO PLAYTIME=\"{}\"></INFO>\n",
+                duration_ms / 1000
+            ));
+        }
+        out.push_str("    </ENTRY>\n");
+    }
+    out.push_str("  </COLLECTION>\n  <PLAYLISTS>\n");
+    out.push_str("    <NODE TYPE=\"FOLDER\" NAME=\"$ROOT\">\n      <SUBNODES COUNT=\"1\">\n");
+    out.push_str(&format!(
+        "        <NODE TYPE=\"PLAYLIST\" NAME=\"{}\">\n          <PLAYLIST ENTRIES=\"{}\" TYPE=\"LIST\" UUID=\"{}\">\n",
+        xml_escape(name),
+        located.len(),
+        uuid::Uuid::new_v4().simple()
+    ));
+    for (_, location) in &located {
+        out.push_str(&format!(
+            "            <ENTRY><PRIMARYKEY TYPE=\"TRACK\" KEY=\"{}\"></PRIMARYKEY></ENTRY>\n",
+            xml_escape(&location.key())
+        ));
+    }
+    out.push_str("          </PLAYLIST>\n        </NODE>\n      </SUBNODES>\n    </NODE>\n  </PLAYLISTS>\n</NML>\n");
+    out
+}
+
+/// Tracks with no local file, with what's needed to track them down.
+pub fn render_unmatched_csv(tracks: &[CrateTrack]) -> String {
+    let mut out = String::from("song_id,title,artist,album,isrc\n");
+    for track in tracks.iter().filter(|track| track.location.is_none()) {
+        out.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&track.song_id),
+            csv_field(&track.title),
+            csv_field(&track.artist),
+            csv_field(track.album.as_deref().unwrap_or_default()),
+            csv_field(track.isrc.as_deref().unwrap_or_default())
+        ));
+    }
+    out
+}
+
+/// Builds the archive for the requested formats, or all of them.
+pub fn build_archive(
+    name: &str,
+    tracks: &[CrateTrack],
+    format: Option<DjFormat>,
+) -> Result<Vec<u8>, String> {
+    let stem = file_stem(name);
+    let formats = match format {
+        Some(format) => vec![format],
+        None => vec![DjFormat::Rekordbox, DjFormat::Serato, DjFormat::Traktor],
+    };
+
+    let mut files: Vec<(String, Vec<u8>)> = formats
+        .into_iter()
+        .map(|format| match format {
+            DjFormat::Rekordbox => (
+                format!("rekordbox/{stem}.xml"),
+                render_rekordbox_xml(name, tracks).into_bytes(),
+            ),
+            DjFormat::Serato => (
+                format!("_Serato_/Subcrates/{stem}.crate"),
+                render_serato_crate(tracks),
+            ),
+            DjFormat::Traktor => (
+                format!("traktor/{stem}.nml"),
+                render_traktor_nml(name, tracks).into_bytes(),
+            ),
+        })
+        .collect();
+    if tracks.iter().any(|track| track.location.is_none()) {
+        files.push((
+            "unmatched.csv".to_string(),
+            render_unmatched_csv(tracks).into_bytes(),
+        ));
+    }
+
+    let mut archive = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (path, contents) in files {
+        archive
+            .start_file(path, options)
+            .map_err(|e| format!("Failed to write archive: {e}"))?;
+        archive
+            .write_all(&contents)
+            .map_err(|e| format!("Failed to write archive: {e}"))?;
+    }
+    Ok(archive
+        .finish()
+        .map_err(|e| format!("Failed to write archive: {e}"))?
+        .into_inner())
+}
+
+/// Exports a tag, query or saved playlist as a zip of DJ software crates.
+/// File paths point into `music_root` when given, which lets the archive
+/// be used on a machine that mounts the library elsewhere; otherwise into
+/// `LOCAL_MUSIC_DIR`.
+pub async fn export_dj_crates(
+    pool: &DbPool,
+    user_id: i32,
+    source: PlaylistSource,
+    format: Option<DjFormat>,
+    music_root: Option<String>,
+) -> Result<Download<Vec<u8>>, String> {
+    let root: Option<PathBuf> = music_root
+        .map(PathBuf::from)
+        .or_else(|| library_root().ok());
+    let pool = pool.clone();
+
+    // Zipping a large crate is CPU-bound, so it is built off the async
+    // workers along with the queries.
+    let (name, body) = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let (name, matches) = resolve_playlist_source(&mut conn, user_id, &source)?;
+        let paths = find_local_paths(&mut conn, &matches)?;
+        let tracks: Vec<CrateTrack> = matches
+            .iter()
+            .map(|track| {
+                let location = root.as_deref().zip(paths.get(&track.song_id)).map(
+                    |(root, path): (&Path, &String)| root.join(path).to_string_lossy().to_string(),
+                );
+                CrateTrack::from_match(track, location)
+            })
+            .collect();
+        let body = build_archive(&name, &tracks, format)?;
+        webhooks::enqueue_event(
+            &mut conn,
+            user_id,
+            WebhookEvent::PlaylistExported,
+            serde_json::json!({
+                "title": name,
+                "format": format.map_or("dj_crates", |format| format.as_str()),
+                "track_count": tracks.len(),
+            }),
+        )?;
+        Ok::<_, String>((name, body))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    Ok(Download {
+        filename: format!("{}-crates.zip", file_stem(&name)),
+        content_type: ContentType::ZIP,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn tracks() -> Vec<CrateTrack> {
+        vec![
+            CrateTrack {
+                song_id: "local:abc".to_string(),
+                title: "Rock & Roll".to_string(),
+                artist: "Artist".to_string(),
+                album: Some("Album".to_string()),
+                duration_ms: Some(215_000),
+                isrc: None,
+                location: Some("/music/Artist/01 Rock & Roll.mp3".to_string()),
+            },
+            CrateTrack {
+                song_id: "def".to_string(),
+                title: "Streaming Only".to_string(),
+                artist: "Other".to_string(),
+                album: None,
+                duration_ms: None,
+                isrc: Some("GBAYE0601690".to_string()),
+                location: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(
+            file_url("/music/Artist/01 Rock & Roll.mp3"),
+            "file://localhost/music/Artist/01%20Rock%20%26%20Roll.mp3"
+        );
+    }
+
+    #[test]
+    fn test_render_rekordbox_xml() {
+        let xml = render_rekordbox_xml("Peak <time>", &tracks());
+
+        assert!(xml.contains("<COLLECTION Entries=\"2\">"));
+        assert!(xml.contains(
+            "<TRACK TrackID=\"1\" Name=\"Rock &amp; Roll\" Artist=\"Artist\" Album=\"Album\" \
+             TotalTime=\"215\" Location=\"file://localhost/music/Artist/01%20Rock%20%26%20Roll.mp3\"/>"
+        ));
+        assert!(xml.contains(
+            "<TRACK TrackID=\"2\" Name=\"Streaming Only\" Artist=\"Other\" Comments=\"ISRC GBAYE0601690\"/>"
+        ));
+        assert!(xml
+            .contains("<NODE Name=\"Peak &lt;time&gt;\" Type=\"1\" KeyType=\"0\" Entries=\"2\">"));
+        assert!(xml.contains("<TRACK Key=\"2\"/>"));
+    }
+
+    #[test]
+    fn test_render_serato_crate() {
+        let crate_file = render_serato_crate(&tracks());
+
+        let version = utf16_be("1.0/Serato ScratchLive Crate");
+        assert_eq!(&crate_file[..4], b"vrsn");
+        assert_eq!(&crate_file[4..8], &(version.len() as u32).to_be_bytes());
+        let rest = &crate_file[8 + version.len()..];
+        let path = utf16_be("music/Artist/01 Rock & Roll.mp3");
+        assert_eq!(&rest[..4], b"otrk");
+        assert_eq!(&rest[4..8], &(path.len() as u32 + 8).to_be_bytes());
+        assert_eq!(&rest[8..12], b"ptrk");
+        assert_eq!(&rest[16..], path.as_slice());
+    }
+
+    #[test]
+    fn test_render_traktor_nml() {
+        assert_eq!(
+            TraktorLocation::parse("/music/Artist/a.mp3"),
+            TraktorLocation {
+                volume: String::new(),
+                dir: "/:music/:Artist/:".to_string(),
+                file: "a.mp3".to_string(),
+            }
+        );
+        let windows = TraktorLocation::parse("D:\\Music/Artist/a.mp3");
+        assert_eq!(windows.volume, "D:");
+        assert_eq!(windows.key(), "D:/:Music/:Artist/:a.mp3");
+
+        let nml = render_traktor_nml("Peak", &tracks());
+
+        assert!(nml.contains("<COLLECTION ENTRIES=\"1\">"));
+        assert!(nml.contains(
+            "<LOCATION DIR=\"/:music/:Artist/:\" FILE=\"01 Rock &amp; Roll.mp3\" VOLUME=\"\" VOLUMEID=\"\">"
+        ));
+        assert!(nml.contains("KEY=\"/:music/:Artist/:01 Rock &amp; Roll.mp3\""));
+        assert!(!nml.contains("Streaming Only"));
+    }
+
+    #[test]
+    fn test_build_archive() {
+        let archive = build_archive("Peak time", &tracks(), None).unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "_Serato_/Subcrates/Peak-time.crate",
+                "rekordbox/Peak-time.xml",
+                "traktor/Peak-time.nml",
+                "unmatched.csv",
+            ]
+        );
+        let mut unmatched = String::new();
+        zip.by_name("unmatched.csv")
+            .unwrap()
+            .read_to_string(&mut unmatched)
+            .unwrap();
+        assert_eq!(
+            unmatched,
+            "song_id,title,artist,album,isrc\ndef,Streaming Only,Other,,GBAYE0601690\n"
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+
+/// Body of errors the client is expected to handle programmatically.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    /// Stable, machine-readable error code.
+    pub error: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_scopes: Option<Vec<String>>,
+    /// Space-separated scopes to ask for when sending the user back through
+    /// Spotify authorization.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_scopes: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(error: &str, message: impl Into<String>) -> Self {
+        ErrorBody {
+            error: error.to_string(),
+            message: message.into(),
+            missing_scopes: None,
+            request_scopes: None,
+        }
+    }
+}
+
+/// Route error for endpoints that have failures beyond a plain bad request.
+#[derive(rocket::Responder, Debug)]
+pub enum ApiError {
+    #[response(status = 400)]
+    BadRequest(String),
+    #[response(status = 403)]
+    Forbidden(Json<ErrorBody>),
+    #[response(status = 404)]
+    NotFound(Json<ErrorBody>),
+    #[response(status = 409)]
+    Conflict(Json<ErrorBody>),
+    #[response(status = 500)]
+    Internal(String),
+    #[response(status = 502)]
+    BadGateway(Json<ErrorBody>),
+}
+
+impl From<String> for ApiError {
+    fn from(message: String) -> Self {
+        ApiError::BadRequest(message)
+    }
+}
@@ -0,0 +1,911 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::futures::stream::{self, BoxStream, StreamExt};
+use rocket::http::ContentType;
+use rocket::response::stream::ByteStream;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::identities::SPOTIFY_PROVIDER;
+use crate::permissions::{authorize_tag, TagAction};
+use crate::playlist_gen::PlaylistOptions;
+use crate::playlists::{find_playlist, Playlist};
+use crate::plays::{Play, PlaySource};
+use crate::providers::parse_song_id;
+use crate::tag_query::{self, TagExpr, TagQueryMatch};
+use crate::webhooks::{self, WebhookEvent};
+use crate::{schema, DbPool, Song, SongTag, Tag, TagSource};
+
+pub const EXPORT_FORMAT_NAME: &str = "moodring";
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+// Rows fetched per query and bytes buffered per chunk while streaming.
+const EXPORT_PAGE_SIZE: i64 = 1000;
+const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Ends a streamed export that failed after the download started, followed
+/// by the error. It also leaves a JSON export unparseable.
+pub const EXPORT_FAILED_MARKER: &str = "#moodring-export-failed";
+
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    Json,
+    Csv,
+}
+
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlaylistFileFormat {
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFileFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaylistFileFormat::M3u8 => "m3u8",
+            PlaylistFileFormat::Xspf => "xspf",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedTag {
+    pub id: i32,
+    pub name: String,
+    pub color: Option<String>,
+    pub parent_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedPlaylist {
+    pub name: String,
+    pub query: String,
+    pub created_at: NaiveDateTime,
+    /// Missing from exports made before playlists had ordering options.
+    #[serde(flatten, default)]
+    pub options: PlaylistOptions,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedSongTag {
+    pub song_id: String,
+    /// Refers to `ExportedTag::id` in the same document.
+    pub tag_id: i32,
+    pub source: TagSource,
+    pub confidence: Option<f64>,
+    pub note: Option<String>,
+    pub weight: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportedPlay {
+    pub song_id: String,
+    pub played_at: NaiveDateTime,
+    pub ms_played: Option<i32>,
+    pub source: PlaySource,
+}
+
+/// The versioned JSON export. Exports are written field by field rather
+/// than through this struct, but it reads them back.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExportDocument {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+    pub tags: Vec<ExportedTag>,
+    pub playlists: Vec<ExportedPlaylist>,
+    pub song_tags: Vec<ExportedSongTag>,
+    /// Missing from exports made before listening history was exported.
+    #[serde(default)]
+    pub plays: Vec<ExportedPlay>,
+}
+
+impl From<Tag> for ExportedTag {
+    fn from(tag: Tag) -> Self {
+        ExportedTag {
+            id: tag.id,
+            name: tag.name,
+            color: tag.color,
+            parent_id: tag.parent_id,
+            created_at: tag.created_at,
+        }
+    }
+}
+
+impl From<Playlist> for ExportedPlaylist {
+    fn from(playlist: Playlist) -> Self {
+        let options = playlist.options();
+        ExportedPlaylist {
+            name: playlist.name,
+            query: playlist.query,
+            created_at: playlist.created_at,
+            options,
+        }
+    }
+}
+
+impl From<Play> for ExportedPlay {
+    fn from(play: Play) -> Self {
+        ExportedPlay {
+            song_id: play.song_id,
+            played_at: play.played_at,
+            ms_played: play.ms_played,
+            source: play.source,
+        }
+    }
+}
+
+impl From<SongTag> for ExportedSongTag {
+    fn from(song_tag: SongTag) -> Self {
+        ExportedSongTag {
+            song_id: song_tag.song_id,
+            tag_id: song_tag.tag_id,
+            source: song_tag.source,
+            confidence: song_tag.confidence,
+            note: song_tag.note,
+            weight: song_tag.weight,
+            created_at: song_tag.created_at,
+        }
+    }
+}
+
+/// A response served as a file download.
+pub struct Download<R> {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub body: R,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Download<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        Response::build_from(self.body.respond_to(request)?)
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .ok()
+    }
+}
+
+pub type ExportStream = ByteStream<BoxStream<'static, Vec<u8>>>;
+
+/// `io::Write` sink that hands fixed-size chunks to the response stream.
+/// Writes fail once the client has gone away, which stops the export.
+struct ChunkWriter {
+    sender: tokio::sync::mpsc::Sender<Result<Vec<u8>, String>>,
+    buffer: Vec<u8>,
+}
+
+impl ChunkWriter {
+    fn send_buffer(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(EXPORT_CHUNK_SIZE));
+        self.sender
+            .blocking_send(Ok(chunk))
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= EXPORT_CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// The line ending an export that failed with `error` part way through.
+pub fn export_failed_line(error: &str) -> Vec<u8> {
+    format!("\n{EXPORT_FAILED_MARKER}: {error}\n").into_bytes()
+}
+
+/// Runs `produce` on a blocking thread and streams whatever it writes. The
+/// stream starts once the first chunk is ready, so an export that fails
+/// before then is an ordinary error; a later failure can only be reported
+/// in the file, with [`export_failed_line`].
+async fn stream_export<F>(pool: DbPool, produce: F) -> Result<ExportStream, String>
+where
+    F: FnOnce(&mut PgConnection, &mut dyn Write) -> Result<(), String> + Send + 'static,
+{
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<Result<Vec<u8>, String>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter {
+            sender,
+            buffer: Vec::with_capacity(EXPORT_CHUNK_SIZE),
+        };
+        let result = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))
+            .and_then(|mut conn| produce(&mut conn, &mut writer))
+            .and_then(|_| writer.flush().map_err(io_error));
+        if let Err(e) = result {
+            // Fails only when the client has gone away.
+            let _ = writer.sender.blocking_send(Err(e));
+        }
+    });
+
+    let first = match receiver.recv().await {
+        Some(first) => Some(first?),
+        None => None,
+    };
+    let rest = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = match receiver.recv().await? {
+            Ok(chunk) => chunk,
+            Err(e) => export_failed_line(&e),
+        };
+        Some((chunk, receiver))
+    });
+    Ok(ByteStream(stream::iter(first).chain(rest).boxed()))
+}
+
+fn io_error(e: std::io::Error) -> String {
+    format!("Failed to write export: {e}")
+}
+
+fn json_error(e: serde_json::Error) -> String {
+    format!("Failed to write export: {e}")
+}
+
+/// Walks the user's song tags in ID order, one page at a time.
+fn for_each_song_tag_page<F>(
+    conn: &mut PgConnection,
+    user_id: i32,
+    mut visit: F,
+) -> Result<(), String>
+where
+    F: FnMut(&mut PgConnection, Vec<SongTag>) -> Result<(), String>,
+{
+    use schema::song_tags::dsl;
+
+    let mut after_id = 0;
+    loop {
+        let page = dsl::song_tags
+            .filter(dsl::user_id.eq(user_id).and(dsl::id.gt(after_id)))
+            .order(dsl::id.asc())
+            .limit(EXPORT_PAGE_SIZE)
+            .load::<SongTag>(conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))?;
+        let Some(last) = page.last() else {
+            return Ok(());
+        };
+        after_id = last.id;
+        let full_page = page.len() as i64 == EXPORT_PAGE_SIZE;
+        visit(conn, page)?;
+        if !full_page {
+            return Ok(());
+        }
+    }
+}
+
+/// Like [`for_each_song_tag_page`], for the user's listening history.
+fn for_each_play_page<F>(conn: &mut PgConnection, user_id: i32, mut visit: F) -> Result<(), String>
+where
+    F: FnMut(&mut PgConnection, Vec<Play>) -> Result<(), String>,
+{
+    use schema::plays::dsl;
+
+    let mut after_id = 0;
+    loop {
+        let page = dsl::plays
+            .filter(dsl::user_id.eq(user_id).and(dsl::id.gt(after_id)))
+            .order(dsl::id.asc())
+            .limit(EXPORT_PAGE_SIZE)
+            .load::<Play>(conn)
+            .map_err(|e| format!("Failed to load plays: {e}"))?;
+        let Some(last) = page.last() else {
+            return Ok(());
+        };
+        after_id = last.id;
+        let full_page = page.len() as i64 == EXPORT_PAGE_SIZE;
+        visit(conn, page)?;
+        if !full_page {
+            return Ok(());
+        }
+    }
+}
+
+/// The tags the user created, plus workspace tags they applied, so every
+/// exported song tag refers to a tag in the same document.
+fn load_tags(conn: &mut PgConnection, user_id: i32) -> Result<Vec<Tag>, String> {
+    use schema::song_tags;
+    use schema::tags::dsl;
+
+    let applied = song_tags::table
+        .filter(song_tags::user_id.eq(user_id))
+        .select(song_tags::tag_id);
+    dsl::tags
+        .filter(dsl::user_id.eq(user_id).or(dsl::id.eq_any(applied)))
+        .order(dsl::id.asc())
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load tags: {e}"))
+}
+
+pub(crate) fn write_json_export(
+    conn: &mut PgConnection,
+    user_id: i32,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    use schema::playlists::dsl as playlists_dsl;
+
+    let tags: Vec<ExportedTag> = load_tags(conn, user_id)?
+        .into_iter()
+        .map(ExportedTag::from)
+        .collect();
+    let playlists: Vec<ExportedPlaylist> = playlists_dsl::playlists
+        .filter(playlists_dsl::user_id.eq(user_id))
+        .order(playlists_dsl::id.asc())
+        .load::<Playlist>(conn)
+        .map_err(|e| format!("Failed to load playlists: {e}"))?
+        .into_iter()
+        .map(ExportedPlaylist::from)
+        .collect();
+
+    write!(
+        out,
+        "{{\"format\":{},\"version\":{EXPORT_FORMAT_VERSION},\"exported_at\":{},\"tags\":",
+        serde_json::to_string(EXPORT_FORMAT_NAME).map_err(json_error)?,
+        serde_json::to_string(&chrono::Utc::now().naive_utc()).map_err(json_error)?,
+    )
+    .map_err(io_error)?;
+    serde_json::to_writer(&mut *out, &tags).map_err(json_error)?;
+    out.write_all(b",\"playlists\":").map_err(io_error)?;
+    serde_json::to_writer(&mut *out, &playlists).map_err(json_error)?;
+    out.write_all(b",\"song_tags\":[").map_err(io_error)?;
+
+    let mut first = true;
+    for_each_song_tag_page(conn, user_id, |_, page| {
+        for song_tag in page {
+            if !first {
+                out.write_all(b",").map_err(io_error)?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *out, &ExportedSongTag::from(song_tag))
+                .map_err(json_error)?;
+        }
+        Ok(())
+    })?;
+    out.write_all(b"],\"plays\":[").map_err(io_error)?;
+
+    let mut first = true;
+    for_each_play_page(conn, user_id, |_, page| {
+        for play in page {
+            if !first {
+                out.write_all(b",").map_err(io_error)?;
+            }
+            first = false;
+            serde_json::to_writer(&mut *out, &ExportedPlay::from(play)).map_err(json_error)?;
+        }
+        Ok(())
+    })?;
+
+    out.write_all(b"]}").map_err(io_error)
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote or line break.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub const CSV_HEADER: &str =
+    "spotify_track_id,tag,parent_tag,weight,source,confidence,note,title,artist,album,tagged_at";
+
+fn write_csv_export(
+    conn: &mut PgConnection,
+    user_id: i32,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    use schema::songs::dsl as songs_dsl;
+
+    let tags: HashMap<i32, Tag> = load_tags(conn, user_id)?
+        .into_iter()
+        .map(|tag| (tag.id, tag))
+        .collect();
+
+    writeln!(out, "{CSV_HEADER}").map_err(io_error)?;
+
+    for_each_song_tag_page(conn, user_id, |conn, page| {
+        let song_ids: Vec<&String> = page.iter().map(|song_tag| &song_tag.song_id).collect();
+        let songs: HashMap<String, Song> = songs_dsl::songs
+            .filter(songs_dsl::id.eq_any(song_ids))
+            .load::<Song>(conn)
+            .map_err(|e| format!("Failed to load songs: {e}"))?
+            .into_iter()
+            .map(|song| (song.id.clone(), song))
+            .collect();
+
+        for song_tag in page {
+            let Some(tag) = tags.get(&song_tag.tag_id) else {
+                continue;
+            };
+            let parent = tag
+                .parent_id
+                .and_then(|parent_id| tags.get(&parent_id))
+                .map(|parent| parent.name.as_str())
+                .unwrap_or_default();
+            let song = songs.get(&song_tag.song_id);
+            let fields = [
+                song_tag.song_id.clone(),
+                tag.name.clone(),
+                parent.to_string(),
+                song_tag.weight.map(|w| w.to_string()).unwrap_or_default(),
+                song_tag.source.as_str().to_string(),
+                song_tag
+                    .confidence
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+                song_tag.note.clone().unwrap_or_default(),
+                song.map(|s| s.title.clone()).unwrap_or_default(),
+                song.map(|s| s.artist.clone()).unwrap_or_default(),
+                song.and_then(|s| s.album.clone()).unwrap_or_default(),
+                song_tag.created_at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            ];
+            let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(out, "{}", line.join(",")).map_err(io_error)?;
+        }
+        Ok(())
+    })
+}
+
+pub const PLAYS_CSV_HEADER: &str = "spotify_track_id,title,artist,album,played_at,ms_played,source";
+
+fn write_plays_csv_export(
+    conn: &mut PgConnection,
+    user_id: i32,
+    out: &mut dyn Write,
+) -> Result<(), String> {
+    use schema::songs::dsl as songs_dsl;
+
+    writeln!(out, "{PLAYS_CSV_HEADER}").map_err(io_error)?;
+
+    for_each_play_page(conn, user_id, |conn, page| {
+        let song_ids: Vec<&String> = page.iter().map(|play| &play.song_id).collect();
+        let songs: HashMap<String, Song> = songs_dsl::songs
+            .filter(songs_dsl::id.eq_any(song_ids))
+            .load::<Song>(conn)
+            .map_err(|e| format!("Failed to load songs: {e}"))?
+            .into_iter()
+            .map(|song| (song.id.clone(), song))
+            .collect();
+
+        for play in page {
+            let song = songs.get(&play.song_id);
+            let fields = [
+                play.song_id.clone(),
+                song.map(|s| s.title.clone()).unwrap_or_default(),
+                song.map(|s| s.artist.clone()).unwrap_or_default(),
+                song.and_then(|s| s.album.clone()).unwrap_or_default(),
+                play.played_at.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                play.ms_played.map(|ms| ms.to_string()).unwrap_or_default(),
+                play.source.as_str().to_string(),
+            ];
+            let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(out, "{}", line.join(",")).map_err(io_error)?;
+        }
+        Ok(())
+    })
+}
+
+fn check_user_exists(conn: &mut PgConnection, user_id: i32) -> Result<(), String> {
+    use schema::users::dsl;
+
+    dsl::users
+        .filter(dsl::id.eq(user_id))
+        .select(dsl::id)
+        .first::<i32>(conn)
+        .map(|_| ())
+        .map_err(|e| format!("Failed to find user: {e}"))
+}
+
+/// Streams a full export of the user's tags, playlists and song tags, plus
+/// listening history in the JSON format. The CSV format has one row per
+/// song tag; plays come from [`export_plays`].
+/// Exports that fail part way through end with [`EXPORT_FAILED_MARKER`].
+pub async fn export_user_data(
+    pool: &DbPool,
+    user_id: i32,
+    format: ExportFormat,
+) -> Result<Download<ExportStream>, String> {
+    let check_pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = check_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        check_user_exists(&mut conn, user_id)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    let date = chrono::Utc::now().format("%Y-%m-%d");
+    let pool = pool.clone();
+    Ok(match format {
+        ExportFormat::Json => Download {
+            filename: format!("moodring-export-{date}.json"),
+            content_type: ContentType::JSON,
+            body: stream_export(pool, move |conn, out| write_json_export(conn, user_id, out))
+                .await?,
+        },
+        ExportFormat::Csv => Download {
+            filename: format!("moodring-export-{date}.csv"),
+            content_type: ContentType::CSV,
+            body: stream_export(pool, move |conn, out| write_csv_export(conn, user_id, out))
+                .await?,
+        },
+    })
+}
+
+/// Streams the user's listening history as CSV.
+pub async fn export_plays(pool: &DbPool, user_id: i32) -> Result<Download<ExportStream>, String> {
+    let check_pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = check_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        check_user_exists(&mut conn, user_id)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    let date = chrono::Utc::now().format("%Y-%m-%d");
+    Ok(Download {
+        filename: format!("moodring-plays-{date}.csv"),
+        content_type: ContentType::CSV,
+        body: stream_export(pool.clone(), move |conn, out| {
+            write_plays_csv_export(conn, user_id, out)
+        })
+        .await?,
+    })
+}
+
+/// What a playlist file is built from.
+#[derive(Clone, Debug)]
+pub enum PlaylistSource {
+    Tag(i32),
+    Query(String),
+    SavedPlaylist(i32),
+}
+
+impl PlaylistSource {
+    /// Picks the source from route parameters, exactly one of which must be
+    /// given.
+    pub fn from_params(
+        tag_id: Option<i32>,
+        query: Option<String>,
+        playlist_id: Option<i32>,
+    ) -> Result<Self, String> {
+        match (tag_id, query, playlist_id) {
+            (Some(tag_id), None, None) => Ok(PlaylistSource::Tag(tag_id)),
+            (None, Some(query), None) => Ok(PlaylistSource::Query(query)),
+            (None, None, Some(playlist_id)) => Ok(PlaylistSource::SavedPlaylist(playlist_id)),
+            _ => Err("Provide exactly one of tag_id, q or playlist_id".to_string()),
+        }
+    }
+}
+
+/// Where players should look for a track. Songs from other providers have
+/// no public link, so their catalogue ID stands in.
+pub fn song_location(song_id: &str) -> String {
+    match parse_song_id(song_id) {
+        (SPOTIFY_PROVIDER, track_id) => format!("https://open.spotify.com/track/{track_id}"),
+        _ => song_id.to_string(),
+    }
+}
+
+pub(crate) fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub fn render_m3u8(title: &str, tracks: &[TagQueryMatch]) -> String {
+    let mut out = format!("#EXTM3U\n#PLAYLIST:{}\n", title.replace('\n', " "));
+    for track in tracks {
+        let (seconds, label) = match &track.song {
+            Some(song) => (
+                song.duration_ms.map(|ms| ms / 1000).unwrap_or(-1),
+                format!("{} - {}", song.artist, song.title),
+            ),
+            None => (-1, track.song_id.clone()),
+        };
+        out.push_str(&format!(
+            "#EXTINF:{seconds},{}\n{}\n",
+            label.replace('\n', " "),
+            song_location(&track.song_id)
+        ));
+    }
+    out
+}
+
+pub fn render_xspf(title: &str, tracks: &[TagQueryMatch]) -> String {
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    out.push_str(&format!(
+        "  <title>{}</title>\n  <trackList>\n",
+        xml_escape(title)
+    ));
+    for track in tracks {
+        out.push_str("    <track>\n");
+        out.push_str(&format!(
+            "      <location>{}</location>\n",
+            xml_escape(&song_location(&track.song_id))
+        ));
+        if let Some(song) = &track.song {
+            out.push_str(&format!(
+                "      <title>{}</title>\n",
+                xml_escape(&song.title)
+            ));
+            out.push_str(&format!(
+                "      <creator>{}</creator>\n",
+                xml_escape(&song.artist)
+            ));
+            if let Some(album) = &song.album {
+                out.push_str(&format!("      <album>{}</album>\n", xml_escape(album)));
+            }
+            if let Some(duration_ms) = song.duration_ms {
+                out.push_str(&format!("      <duration>{duration_ms}</duration>\n"));
+            }
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+/// Resolves a tag, query or saved playlist to its title and ordered tracks.
+pub fn resolve_playlist_source(
+    conn: &mut PgConnection,
+    user_id: i32,
+    source: &PlaylistSource,
+) -> Result<(String, Vec<TagQueryMatch>), String> {
+    match source {
+        PlaylistSource::Tag(tag_id) => {
+            let tag = authorize_tag(conn, user_id, *tag_id, TagAction::View)?;
+            let expr = TagExpr::Tag {
+                tag: tag.id,
+                threshold: None,
+            };
+            Ok((
+                tag.name,
+                tag_query::evaluate_expr(conn, user_id, &expr, None)?,
+            ))
+        }
+        PlaylistSource::Query(query) => Ok((
+            query.clone(),
+            tag_query::evaluate(conn, user_id, query, None)?,
+        )),
+        PlaylistSource::SavedPlaylist(playlist_id) => {
+            let playlist = find_playlist(conn, user_id, *playlist_id)?;
+            let tracks = playlist.tracks(conn)?;
+            Ok((playlist.name, tracks))
+        }
+    }
+}
+
+/// Turns a playlist title into a safe download file name.
+pub fn file_stem(title: &str) -> String {
+    let stem: String = title
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let stem = stem.trim_matches('-');
+    if stem.is_empty() {
+        "playlist".to_string()
+    } else {
+        stem.to_string()
+    }
+}
+
+pub async fn export_playlist_file(
+    pool: &DbPool,
+    user_id: i32,
+    source: PlaylistSource,
+    format: PlaylistFileFormat,
+) -> Result<Download<String>, String> {
+    let pool = pool.clone();
+
+    let (title, tracks) = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let (title, tracks) = resolve_playlist_source(&mut conn, user_id, &source)?;
+        webhooks::enqueue_event(
+            &mut conn,
+            user_id,
+            WebhookEvent::PlaylistExported,
+            serde_json::json!({
+                "title": title,
+                "format": format.as_str(),
+                "track_count": tracks.len(),
+            }),
+        )?;
+        Ok::<_, String>((title, tracks))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    Ok(match format {
+        PlaylistFileFormat::M3u8 => Download {
+            filename: format!("{}.m3u8", file_stem(&title)),
+            content_type: ContentType::new("audio", "x-mpegurl"),
+            body: render_m3u8(&title, &tracks),
+        },
+        PlaylistFileFormat::Xspf => Download {
+            filename: format!("{}.xspf", file_stem(&title)),
+            content_type: ContentType::new("application", "xspf+xml"),
+            body: render_xspf(&title, &tracks),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::playlist_gen::PlaylistOrder;
+
+    fn track(song_id: &str, title: &str, artist: &str) -> TagQueryMatch {
+        let now = chrono::Utc::now().naive_utc();
+        TagQueryMatch {
+            song_id: song_id.to_string(),
+            score: 3,
+            last_tagged_at: now,
+            song: Some(Song {
+                id: song_id.to_string(),
+                title: title.to_string(),
+                artist: artist.to_string(),
+                album: Some("Album & Friends".to_string()),
+                release_year: None,
+                duration_ms: Some(215_000),
+                isrc: None,
+                energy: None,
+                valence: None,
+                tempo: None,
+                danceability: None,
+                acousticness: None,
+                created_at: now,
+                updated_at: now,
+            }),
+            matched_song_ids: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_exported_playlist_keeps_options() {
+        let playlist = ExportedPlaylist {
+            name: "Wind down".to_string(),
+            query: "chill AND NOT loud".to_string(),
+            created_at: chrono::Utc::now().naive_utc(),
+            options: PlaylistOptions {
+                ordering: PlaylistOrder::Shuffle,
+                seed: Some(42),
+                no_repeat_artist: true,
+                max_tracks: Some(20),
+                max_duration_ms: Some(3_600_000),
+            },
+        };
+        let json = serde_json::to_string(&playlist).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ExportedPlaylist>(&json).unwrap(),
+            playlist
+        );
+
+        let legacy: ExportedPlaylist = serde_json::from_str(
+            r#"{"name":"Old","query":"chill","created_at":"2025-08-06T01:13:23"}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.options, PlaylistOptions::default());
+    }
+
+    #[test]
+    fn test_csv_field_quoting() {
+        assert_eq!(csv_field("chill"), "chill");
+        assert_eq!(csv_field("rock, indie"), "\"rock, indie\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_render_m3u8() {
+        let tracks = vec![
+            track("abc", "Song", "Artist"),
+            TagQueryMatch {
+                song: None,
+                ..track("def", "", "")
+            },
+        ];
+
+        let m3u8 = render_m3u8("rainy-day", &tracks);
+
+        assert_eq!(
+            m3u8,
+            "#EXTM3U\n#PLAYLIST:rainy-day\n\
+             #EXTINF:215,Artist - Song\nhttps://open.spotify.com/track/abc\n\
+             #EXTINF:-1,def\nhttps://open.spotify.com/track/def\n"
+        );
+    }
+
+    #[test]
+    fn test_render_xspf_escapes_text() {
+        let xspf = render_xspf("Rock <& Roll>", &[track("abc", "Tom's Song", "Artist")]);
+
+        assert!(xspf.contains("<title>Rock &lt;&amp; Roll&gt;</title>"));
+        assert!(xspf.contains("<title>Tom&apos;s Song</title>"));
+        assert!(xspf.contains("<album>Album &amp; Friends</album>"));
+        assert!(xspf.contains("<duration>215000</duration>"));
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(file_stem("rainy-day"), "rainy-day");
+        assert_eq!(file_stem("chill & !sad"), "chill----sad");
+        assert_eq!(file_stem("\"?\""), "playlist");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_writer_streams_in_order() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+        let writer_task = tokio::task::spawn_blocking(move || {
+            let mut writer = ChunkWriter {
+                sender,
+                buffer: Vec::new(),
+            };
+            writer.write_all(&vec![b'a'; EXPORT_CHUNK_SIZE]).unwrap();
+            writer.write_all(b"tail").unwrap();
+            writer.flush().unwrap();
+        });
+
+        let mut received = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            received.push(chunk.unwrap());
+        }
+        writer_task.await.unwrap();
+
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].len(), EXPORT_CHUNK_SIZE);
+        assert_eq!(received[1], b"tail");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_export_fails_before_streaming() {
+        let manager =
+            diesel::r2d2::ConnectionManager::<PgConnection>::new("postgres://127.0.0.1:1/none");
+        let pool = diesel::r2d2::Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(manager);
+
+        let result = stream_export(pool, |_, _| Ok(())).await;
+
+        assert!(matches!(result, Err(e) if e.starts_with("Failed to get connection")));
+    }
+}
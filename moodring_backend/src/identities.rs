@@ -0,0 +1,184 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::providers::{MusicProvider, SpotifyProvider};
+use crate::{schema, AuthRequest, DbPool, TransactionError};
+
+pub const SPOTIFY_PROVIDER: &str = "spotify";
+
+/// An external account a user can sign in with.
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::identities)]
+pub struct Identity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub provider_user_id: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// The Moodring user an external account belongs to, if any.
+pub fn find_user_id(
+    conn: &mut PgConnection,
+    provider: &str,
+    provider_user_id: &str,
+) -> Result<Option<i32>, String> {
+    use schema::identities::dsl;
+
+    dsl::identities
+        .filter(dsl::provider.eq(provider))
+        .filter(dsl::provider_user_id.eq(provider_user_id))
+        .select(dsl::user_id)
+        .first::<i32>(conn)
+        .optional()
+        .map_err(|e| format!("Failed to look up identity: {e}"))
+}
+
+pub fn add_identity(
+    conn: &mut PgConnection,
+    user_id: i32,
+    provider: &str,
+    provider_user_id: &str,
+) -> Result<Identity, String> {
+    use schema::identities::dsl;
+
+    diesel::insert_into(dsl::identities)
+        .values((
+            dsl::user_id.eq(user_id),
+            dsl::provider.eq(provider),
+            dsl::provider_user_id.eq(provider_user_id),
+        ))
+        .get_result::<Identity>(conn)
+        .map_err(|e| match e {
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                format!("This {provider} account is already linked to another Moodring account")
+            }
+            e => format!("Failed to link identity: {e}"),
+        })
+}
+
+pub async fn list_identities(pool: &DbPool, user_id: i32) -> Result<Vec<Identity>, String> {
+    use schema::identities::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::identities
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.asc())
+            .load::<Identity>(&mut conn)
+            .map_err(|e| format!("Failed to load identities: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Links another Spotify account to the signed-in user so either can be
+/// used to sign in. The account's tokens are not kept; signing in with it
+/// makes it the active Spotify connection.
+pub async fn link_spotify_identity(
+    pool: &DbPool,
+    user_id: i32,
+    auth_request: AuthRequest,
+) -> Result<Identity, String> {
+    link_identity(pool, &SpotifyProvider::new(), user_id, auth_request).await
+}
+
+/// Links an account of `provider` to the signed-in user.
+pub async fn link_identity(
+    pool: &DbPool,
+    provider: &dyn MusicProvider,
+    user_id: i32,
+    auth_request: AuthRequest,
+) -> Result<Identity, String> {
+    use schema::identities::dsl;
+
+    let tokens = provider.exchange_code(&auth_request).await?;
+    let profile = provider.profile(&tokens.access_token).await?;
+    let provider_name = provider.name();
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        match find_user_id(&mut conn, provider_name, &profile.id)? {
+            Some(owner) if owner == user_id => dsl::identities
+                .filter(dsl::provider.eq(provider_name))
+                .filter(dsl::provider_user_id.eq(&profile.id))
+                .first::<Identity>(&mut conn)
+                .map_err(|e| format!("Failed to load identity: {e}")),
+            Some(_) => Err(format!(
+                "This {provider_name} account is already linked to another Moodring account"
+            )),
+            None => add_identity(&mut conn, user_id, provider_name, &profile.id),
+        }
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Removes a linked account. The last one can't be removed since the user
+/// would have no way to sign in. Removing the active Spotify connection
+/// also drops its stored tokens.
+pub async fn remove_identity(
+    pool: &DbPool,
+    user_id: i32,
+    identity_id: i32,
+) -> Result<usize, String> {
+    use schema::{identities, users};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let owned: Vec<Identity> = identities::table
+                .filter(identities::user_id.eq(user_id))
+                .for_update()
+                .load::<Identity>(conn)?;
+            let Some(identity) = owned.iter().find(|identity| identity.id == identity_id) else {
+                return Ok(0);
+            };
+            if owned.len() == 1 {
+                return Err("Cannot remove the only account this user can sign in with"
+                    .to_string()
+                    .into());
+            }
+
+            if identity.provider == SPOTIFY_PROVIDER {
+                let now = chrono::Utc::now().naive_utc();
+                diesel::update(
+                    users::table
+                        .filter(users::id.eq(user_id))
+                        .filter(users::spotify_id.eq(&identity.provider_user_id)),
+                )
+                .set((
+                    users::spotify_id.eq(None::<String>),
+                    users::spotify_access_token.eq(None::<String>),
+                    users::spotify_refresh_token.eq(None::<String>),
+                    users::token_expires_at.eq(None::<NaiveDateTime>),
+                    users::spotify_scopes.eq(None::<String>),
+                    users::spotify_unlinked_at.eq(Some(now)),
+                    users::updated_at.eq(now),
+                ))
+                .execute(conn)?;
+            }
+
+            Ok(diesel::delete(identities::table.find(identity_id)).execute(conn)?)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
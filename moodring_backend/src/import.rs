@@ -0,0 +1,718 @@
+use diesel::prelude::*;
+use rocket::serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::export::{ExportDocument, EXPORT_FORMAT_NAME, EXPORT_FORMAT_VERSION};
+use crate::identities::SPOTIFY_PROVIDER;
+use crate::webhooks::{enqueue_links_added, enqueue_tags_created};
+use crate::{schema, DbPool, NewSongTag, NewTag, SongTag, Tag, TagSource, TransactionError};
+
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImportFormat {
+    Json,
+    Csv,
+}
+
+/// What to do when an imported tag has the same name as one the user
+/// already has.
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CollisionStrategy {
+    /// Link songs to the existing tag.
+    #[default]
+    Merge,
+    /// Create the tag under a free name such as `chill (2)`.
+    Rename,
+    /// Leave the existing tag alone and skip the songs imported for it.
+    Skip,
+}
+
+/// A tag as read from an import file. `key` identifies it within the file:
+/// the exported tag ID for JSON, the name for CSV.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ImportTag {
+    pub key: String,
+    pub name: String,
+    pub color: Option<String>,
+    pub parent_key: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ImportLink {
+    pub song_id: String,
+    pub tag_key: String,
+    pub source: TagSource,
+    pub confidence: Option<f64>,
+    pub note: Option<String>,
+    pub weight: Option<i32>,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ImportSet {
+    pub tags: Vec<ImportTag>,
+    pub links: Vec<ImportLink>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum TagOutcome {
+    Created,
+    Renamed,
+    Existing,
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum LinkOutcome {
+    Linked,
+    AlreadyLinked,
+    Skipped,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportedTag {
+    pub name: String,
+    /// The name the tag ends up with; differs from `name` when renamed.
+    pub imported_as: Option<String>,
+    pub tag_id: Option<i32>,
+    pub outcome: TagOutcome,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportedLink {
+    pub song_id: String,
+    pub tag: String,
+    pub outcome: LinkOutcome,
+    pub reason: Option<String>,
+}
+
+/// What an import did, or would do when `dry_run` is set. Tag IDs are only
+/// known for existing tags until the import is committed.
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub tags_created: usize,
+    pub links_created: usize,
+    pub skipped: usize,
+    pub tags: Vec<ImportedTag>,
+    pub links: Vec<ImportedLink>,
+}
+
+/// Accepts bare IDs as well as `spotify:track:` URIs and track URLs, which
+/// is what spreadsheets usually end up holding. IDs of songs from other
+/// providers, such as `local:<hash>`, are kept as they are.
+pub fn normalize_track_id(value: &str) -> Option<String> {
+    let value = value.trim();
+    if let Some((provider, id)) = value.split_once(':') {
+        if provider != SPOTIFY_PROVIDER
+            && !provider.is_empty()
+            && provider.chars().all(|c| c.is_ascii_lowercase())
+            && !id.is_empty()
+            && id.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return Some(value.to_string());
+        }
+    }
+    let id = if let Some(id) = value.strip_prefix("spotify:track:") {
+        id
+    } else if let Some(rest) = value
+        .strip_prefix("https://open.spotify.com/track/")
+        .or_else(|| value.strip_prefix("http://open.spotify.com/track/"))
+    {
+        rest.split(['?', '#', '/']).next().unwrap_or_default()
+    } else {
+        value
+    };
+    (!id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())).then(|| id.to_string())
+}
+
+/// Parses `spotify_track_id,tag[,tag...]` rows. A header row is optional.
+pub fn parse_csv(input: &str) -> Result<ImportSet, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input.as_bytes());
+
+    let mut set = ImportSet::default();
+    let mut seen_tags = HashSet::new();
+
+    for (index, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("Invalid CSV on line {}: {e}", index + 1))?;
+        let Some(first) = record.get(0) else {
+            continue;
+        };
+        if index == 0 && first.eq_ignore_ascii_case("spotify_track_id") {
+            continue;
+        }
+        if first.is_empty() && record.iter().all(str::is_empty) {
+            continue;
+        }
+        let song_id = normalize_track_id(first)
+            .ok_or_else(|| format!("Invalid Spotify track ID on line {}: {first}", index + 1))?;
+
+        for name in record.iter().skip(1).filter(|name| !name.is_empty()) {
+            if seen_tags.insert(name.to_string()) {
+                set.tags.push(ImportTag {
+                    key: name.to_string(),
+                    name: name.to_string(),
+                    color: None,
+                    parent_key: None,
+                });
+            }
+            set.links.push(ImportLink {
+                song_id: song_id.clone(),
+                tag_key: name.to_string(),
+                source: TagSource::Import,
+                confidence: None,
+                note: None,
+                weight: None,
+            });
+        }
+    }
+
+    Ok(set)
+}
+
+/// Reads a Moodring JSON export. Saved playlists are not imported since
+/// their queries name tags that may be renamed on the way in.
+pub fn parse_json(input: &str) -> Result<ImportSet, String> {
+    let document: ExportDocument =
+        serde_json::from_str(input).map_err(|e| format!("Invalid export file: {e}"))?;
+    if document.format != EXPORT_FORMAT_NAME {
+        return Err(format!("Unknown export format: {}", document.format));
+    }
+    if document.version > EXPORT_FORMAT_VERSION {
+        return Err(format!(
+            "Export version {} is newer than this server supports",
+            document.version
+        ));
+    }
+
+    let tags = document
+        .tags
+        .into_iter()
+        .map(|tag| ImportTag {
+            key: tag.id.to_string(),
+            name: tag.name,
+            color: tag.color,
+            parent_key: tag.parent_id.map(|id| id.to_string()),
+        })
+        .collect();
+    let links = document
+        .song_tags
+        .into_iter()
+        .map(|song_tag| ImportLink {
+            song_id: song_tag.song_id,
+            tag_key: song_tag.tag_id.to_string(),
+            // The rules that made these tags don't come along.
+            source: match song_tag.source {
+                TagSource::Rule => TagSource::Import,
+                source => source,
+            },
+            confidence: song_tag.confidence,
+            note: song_tag.note,
+            weight: song_tag.weight,
+        })
+        .collect();
+
+    Ok(ImportSet { tags, links })
+}
+
+pub fn parse_import(format: ImportFormat, input: &str) -> Result<ImportSet, String> {
+    match format {
+        ImportFormat::Json => parse_json(input),
+        ImportFormat::Csv => parse_csv(input),
+    }
+}
+
+/// Where an imported tag's songs end up.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum TagTarget {
+    Existing(i32),
+    /// Index into `ImportPlan::new_tags`.
+    New(usize),
+    Skipped,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct NewTagPlan {
+    name: String,
+    color: Option<String>,
+    parent_key: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct LinkPlan {
+    link: ImportLink,
+    target: TagTarget,
+}
+
+#[derive(Clone, PartialEq, Debug, Default)]
+struct ImportPlan {
+    new_tags: Vec<NewTagPlan>,
+    targets: HashMap<String, TagTarget>,
+    links: Vec<LinkPlan>,
+    report: ImportReport,
+}
+
+fn free_name(name: &str, taken: &HashSet<String>) -> String {
+    (2..)
+        .map(|n| format!("{name} ({n})"))
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap_or_default()
+}
+
+/// Decides what happens to every tag and link without touching the
+/// database, so dry runs and real imports report the same thing.
+fn plan_import(
+    set: ImportSet,
+    existing_tags: &[Tag],
+    existing_links: &HashSet<(String, i32)>,
+    strategy: CollisionStrategy,
+) -> ImportPlan {
+    let existing_by_name: HashMap<&str, i32> = existing_tags
+        .iter()
+        .map(|tag| (tag.name.as_str(), tag.id))
+        .collect();
+    // File names are reserved too so a renamed tag can't take the name of
+    // another tag further down the file.
+    let mut taken: HashSet<String> = existing_tags
+        .iter()
+        .map(|tag| tag.name.clone())
+        .chain(set.tags.iter().map(|tag| tag.name.clone()))
+        .collect();
+    let mut plan = ImportPlan::default();
+    let mut names = HashMap::new();
+    let mut targets_by_name: HashMap<String, TagTarget> = HashMap::new();
+
+    for tag in set.tags {
+        if plan.targets.contains_key(&tag.key) {
+            continue;
+        }
+        names.insert(tag.key.clone(), tag.name.clone());
+        // Two imported tags sharing a name collapse into one.
+        if let Some(target) = targets_by_name.get(&tag.name) {
+            plan.targets.insert(tag.key, target.clone());
+            continue;
+        }
+
+        let next_new = TagTarget::New(plan.new_tags.len());
+        let (target, imported_as, outcome) =
+            match (existing_by_name.get(tag.name.as_str()), strategy) {
+                (Some(tag_id), CollisionStrategy::Merge) => (
+                    TagTarget::Existing(*tag_id),
+                    Some(tag.name.clone()),
+                    TagOutcome::Existing,
+                ),
+                (Some(_), CollisionStrategy::Skip) => {
+                    (TagTarget::Skipped, None, TagOutcome::Skipped)
+                }
+                (Some(_), CollisionStrategy::Rename) => {
+                    let name = free_name(&tag.name, &taken);
+                    taken.insert(name.clone());
+                    (next_new, Some(name), TagOutcome::Renamed)
+                }
+                (None, _) => (next_new, Some(tag.name.clone()), TagOutcome::Created),
+            };
+
+        match target {
+            TagTarget::New(_) => {
+                plan.new_tags.push(NewTagPlan {
+                    name: imported_as.clone().unwrap_or_default(),
+                    color: tag.color.clone(),
+                    parent_key: tag.parent_key.clone(),
+                });
+                plan.report.tags_created += 1;
+            }
+            TagTarget::Skipped => plan.report.skipped += 1,
+            TagTarget::Existing(_) => {}
+        }
+        plan.report.tags.push(ImportedTag {
+            name: tag.name.clone(),
+            imported_as,
+            tag_id: match target {
+                TagTarget::Existing(tag_id) => Some(tag_id),
+                _ => None,
+            },
+            outcome,
+        });
+        targets_by_name.insert(tag.name, target.clone());
+        plan.targets.insert(tag.key, target);
+    }
+
+    let mut planned_links: HashSet<(String, TagTarget)> = HashSet::new();
+    for link in set.links {
+        let tag_name = names
+            .get(&link.tag_key)
+            .cloned()
+            .unwrap_or_else(|| link.tag_key.clone());
+        let target = plan
+            .targets
+            .get(&link.tag_key)
+            .cloned()
+            .unwrap_or(TagTarget::Skipped);
+
+        let skip_reason = match &target {
+            TagTarget::Skipped if !names.contains_key(&link.tag_key) => {
+                Some("Unknown tag".to_string())
+            }
+            TagTarget::Skipped => Some("Tag skipped".to_string()),
+            TagTarget::Existing(tag_id)
+                if existing_links.contains(&(link.song_id.clone(), *tag_id)) =>
+            {
+                plan.report.links.push(ImportedLink {
+                    song_id: link.song_id,
+                    tag: tag_name,
+                    outcome: LinkOutcome::AlreadyLinked,
+                    reason: None,
+                });
+                continue;
+            }
+            _ => NewSongTag {
+                user_id: 0,
+                song_id: link.song_id.clone(),
+                tag_id: 0,
+                rule_id: None,
+                source: link.source,
+                confidence: link.confidence,
+                note: link.note.clone(),
+                weight: link.weight,
+            }
+            .validate()
+            .err(),
+        };
+
+        let target_key = (link.song_id.clone(), target.clone());
+        let skip_reason = skip_reason
+            .or_else(|| (!planned_links.insert(target_key)).then(|| "Duplicate row".to_string()));
+
+        if let Some(reason) = skip_reason {
+            plan.report.skipped += 1;
+            plan.report.links.push(ImportedLink {
+                song_id: link.song_id,
+                tag: tag_name,
+                outcome: LinkOutcome::Skipped,
+                reason: Some(reason),
+            });
+            continue;
+        }
+
+        plan.report.links_created += 1;
+        plan.report.links.push(ImportedLink {
+            song_id: link.song_id.clone(),
+            tag: tag_name,
+            outcome: LinkOutcome::Linked,
+            reason: None,
+        });
+        plan.links.push(LinkPlan { link, target });
+    }
+
+    plan
+}
+
+fn load_plan(
+    conn: &mut PgConnection,
+    user_id: i32,
+    set: ImportSet,
+    strategy: CollisionStrategy,
+) -> Result<ImportPlan, String> {
+    use schema::{song_tags, tags};
+
+    let existing_tags = tags::table
+        .filter(tags::user_id.eq(user_id))
+        .filter(tags::workspace_id.is_null())
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load tags: {e}"))?;
+    let song_ids: Vec<&String> = set.links.iter().map(|link| &link.song_id).collect();
+    let existing_links: HashSet<(String, i32)> = song_tags::table
+        .filter(song_tags::user_id.eq(user_id))
+        .filter(song_tags::song_id.eq_any(song_ids))
+        .select((song_tags::song_id, song_tags::tag_id))
+        .load::<(String, i32)>(conn)
+        .map_err(|e| format!("Failed to load song tags: {e}"))?
+        .into_iter()
+        .collect();
+
+    Ok(plan_import(set, &existing_tags, &existing_links, strategy))
+}
+
+fn apply_plan(
+    conn: &mut PgConnection,
+    user_id: i32,
+    mut plan: ImportPlan,
+) -> Result<ImportReport, String> {
+    use schema::{song_tags, tags};
+
+    let mut created = Vec::with_capacity(plan.new_tags.len());
+    for new_tag in &plan.new_tags {
+        let tag = diesel::insert_into(tags::table)
+            .values(&NewTag {
+                user_id,
+                name: new_tag.name.clone(),
+                color: new_tag.color.clone(),
+                parent_id: None,
+                workspace_id: None,
+                is_public: false,
+            })
+            .get_result::<Tag>(conn)
+            .map_err(|e| format!("Failed to create tag {}: {e}", new_tag.name))?;
+        created.push(tag);
+    }
+    let created_ids: Vec<i32> = created.iter().map(|tag| tag.id).collect();
+    let resolve = |target: &TagTarget| match target {
+        TagTarget::Existing(tag_id) => Some(*tag_id),
+        TagTarget::New(index) => created_ids.get(*index).copied(),
+        TagTarget::Skipped => None,
+    };
+
+    // Parents are set once every new tag exists, so file order doesn't matter.
+    for (index, new_tag) in plan.new_tags.iter().enumerate() {
+        let parent_id = new_tag
+            .parent_key
+            .as_ref()
+            .and_then(|key| plan.targets.get(key))
+            .and_then(&resolve);
+        if let Some(parent_id) = parent_id {
+            diesel::update(tags::table.find(created_ids[index]))
+                .set(tags::parent_id.eq(parent_id))
+                .execute(conn)
+                .map_err(|e| format!("Failed to set parent of {}: {e}", new_tag.name))?;
+        }
+    }
+
+    let rows: Vec<NewSongTag> = plan
+        .links
+        .iter()
+        .filter_map(|planned| {
+            resolve(&planned.target).map(|tag_id| NewSongTag {
+                user_id,
+                song_id: planned.link.song_id.clone(),
+                tag_id,
+                rule_id: None,
+                source: planned.link.source,
+                confidence: planned.link.confidence,
+                note: planned.link.note.clone(),
+                weight: planned.link.weight,
+            })
+        })
+        .collect();
+    let mut added = Vec::with_capacity(rows.len());
+    for chunk in rows.chunks(1000) {
+        added.extend(
+            diesel::insert_into(song_tags::table)
+                .values(chunk)
+                .get_results::<SongTag>(conn)
+                .map_err(|e| format!("Failed to add song tags: {e}"))?,
+        );
+    }
+    enqueue_tags_created(conn, user_id, &created)?;
+    enqueue_links_added(conn, user_id, &added)?;
+
+    for (tag, tag_id) in plan
+        .report
+        .tags
+        .iter_mut()
+        .filter(|tag| matches!(tag.outcome, TagOutcome::Created | TagOutcome::Renamed))
+        .zip(created_ids)
+    {
+        tag.tag_id = Some(tag_id);
+    }
+    Ok(plan.report)
+}
+
+/// Imports tags and song tags for a user. With `dry_run` set nothing is
+/// written and the report describes what the import would do; otherwise
+/// the whole import is committed in one transaction or not at all.
+pub async fn import_user_data(
+    pool: &DbPool,
+    user_id: i32,
+    set: ImportSet,
+    strategy: CollisionStrategy,
+    dry_run: bool,
+) -> Result<ImportReport, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        if dry_run {
+            let mut report = load_plan(&mut conn, user_id, set, strategy)?.report;
+            report.dry_run = true;
+            return Ok(report);
+        }
+
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let plan = load_plan(conn, user_id, set, strategy)?;
+            Ok(apply_plan(conn, user_id, plan)?)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn existing_tag(id: i32, name: &str) -> Tag {
+        let now = chrono::Utc::now().naive_utc();
+        Tag {
+            id,
+            user_id: 1,
+            name: name.to_string(),
+            color: None,
+            created_at: now,
+            updated_at: now,
+            parent_id: None,
+            workspace_id: None,
+            is_public: false,
+        }
+    }
+
+    #[test]
+    fn test_normalize_track_id() {
+        assert_eq!(normalize_track_id(" abc123 "), Some("abc123".to_string()));
+        assert_eq!(
+            normalize_track_id("spotify:track:abc123"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(
+            normalize_track_id("https://open.spotify.com/track/abc123?si=xyz"),
+            Some("abc123".to_string())
+        );
+        assert_eq!(
+            normalize_track_id("local:9f86d081"),
+            Some("local:9f86d081".to_string())
+        );
+        assert_eq!(normalize_track_id("spotify:album:abc123"), None);
+        assert_eq!(normalize_track_id("not an id"), None);
+        assert_eq!(normalize_track_id(""), None);
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let set = parse_csv(
+            "spotify_track_id,tag\n\
+             abc,chill,\"rainy, night\"\n\
+             \n\
+             spotify:track:def,chill\n",
+        )
+        .unwrap();
+
+        let names: Vec<&str> = set.tags.iter().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, vec!["chill", "rainy, night"]);
+        assert_eq!(set.links.len(), 3);
+        assert_eq!(set.links[2].song_id, "def");
+        assert!(set
+            .links
+            .iter()
+            .all(|link| link.source == TagSource::Import));
+    }
+
+    #[test]
+    fn test_parse_csv_rejects_bad_track_id() {
+        let err = parse_csv("abc,chill\nnot an id,chill\n").unwrap_err();
+        assert!(err.contains("line 2"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_json_rejects_other_formats() {
+        let now = serde_json::to_string(&chrono::Utc::now().naive_utc()).unwrap();
+        let input = format!(
+            r#"{{"format":"other","version":1,"exported_at":{now},"tags":[],"playlists":[],"song_tags":[]}}"#
+        );
+        assert!(parse_json(&input).is_err());
+    }
+
+    #[test]
+    fn test_plan_merge_reuses_existing_tags() {
+        let set = parse_csv("abc,chill\nabc,focus\ndef,chill\n").unwrap();
+        let existing_links = HashSet::from([("abc".to_string(), 7)]);
+
+        let plan = plan_import(
+            set,
+            &[existing_tag(7, "chill")],
+            &existing_links,
+            CollisionStrategy::Merge,
+        );
+
+        assert_eq!(plan.report.tags[0].outcome, TagOutcome::Existing);
+        assert_eq!(plan.report.tags[0].tag_id, Some(7));
+        assert_eq!(plan.report.tags[1].outcome, TagOutcome::Created);
+        assert_eq!(plan.report.links[0].outcome, LinkOutcome::AlreadyLinked);
+        assert_eq!(plan.report.tags_created, 1);
+        assert_eq!(plan.report.links_created, 2);
+        assert_eq!(plan.links.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_rename_picks_free_name() {
+        let set = parse_csv("abc,chill\n").unwrap();
+
+        let plan = plan_import(
+            set,
+            &[existing_tag(7, "chill"), existing_tag(8, "chill (2)")],
+            &HashSet::new(),
+            CollisionStrategy::Rename,
+        );
+
+        assert_eq!(plan.report.tags[0].outcome, TagOutcome::Renamed);
+        assert_eq!(
+            plan.report.tags[0].imported_as.as_deref(),
+            Some("chill (3)")
+        );
+        assert_eq!(plan.new_tags[0].name, "chill (3)");
+        assert_eq!(plan.report.links[0].outcome, LinkOutcome::Linked);
+    }
+
+    #[test]
+    fn test_plan_skip_drops_colliding_links() {
+        let set = parse_csv("abc,chill\nabc,chill\n").unwrap();
+
+        let plan = plan_import(
+            set,
+            &[existing_tag(7, "chill")],
+            &HashSet::new(),
+            CollisionStrategy::Skip,
+        );
+
+        assert_eq!(plan.report.tags[0].outcome, TagOutcome::Skipped);
+        assert!(plan.links.is_empty());
+        assert_eq!(plan.report.skipped, 3);
+    }
+
+    #[test]
+    fn test_plan_skips_invalid_and_duplicate_links() {
+        let mut set = parse_csv("abc,chill\nabc,chill\n").unwrap();
+        set.links.push(ImportLink {
+            weight: Some(9),
+            song_id: "def".to_string(),
+            ..set.links[0].clone()
+        });
+
+        let plan = plan_import(set, &[], &HashSet::new(), CollisionStrategy::Merge);
+
+        let reasons: Vec<Option<&str>> = plan
+            .report
+            .links
+            .iter()
+            .map(|link| link.reason.as_deref())
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                None,
+                Some("Duplicate row"),
+                Some("Weight must be between 1 and 5")
+            ]
+        );
+        assert_eq!(plan.links.len(), 1);
+    }
+}
@@ -1,13 +1,51 @@
 use base64::Engine;
 use chrono::NaiveDateTime;
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
 use diesel::prelude::*;
-use rocket::serde::{Deserialize, Serialize};
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Deserializer, Serialize};
 use std::env;
 
+use crate::providers::{MusicProvider, ProviderProfile, ProviderTokens, SpotifyProvider};
+
+pub mod account;
+pub mod auth;
+pub mod catalog;
+pub mod dj_export;
+pub mod email;
+pub mod errors;
+pub mod export;
+pub mod identities;
+pub mod import;
+pub mod local_library;
+pub mod matching;
+pub mod now_playing;
+pub mod oauth;
+pub mod permissions;
+pub mod playback;
+pub mod playlist_gen;
+pub mod playlists;
+pub mod plays;
+pub mod providers;
+pub mod rules;
 pub mod schema;
+pub mod sharing;
+pub mod social;
+pub mod spotify;
+pub mod suggestions;
+pub mod tag_query;
+pub mod timeline;
+pub mod webhooks;
+pub mod workspaces;
 
 pub type DbPool = diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<PgConnection>>;
 
+/// Allowed intensities for a weighted song tag.
+pub const TAG_WEIGHT_RANGE: std::ops::RangeInclusive<i32> = 1..=5;
+
 #[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(crate = "rocket::serde")]
 #[diesel(table_name = schema::tags)]
@@ -18,6 +56,11 @@ pub struct Tag {
     pub color: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub parent_id: Option<i32>,
+    /// The workspace sharing this tag; `None` for the user's own tags.
+    pub workspace_id: Option<i32>,
+    /// Visible to the owner's followers.
+    pub is_public: bool,
 }
 
 #[derive(Insertable, Deserialize, Clone, Debug)]
@@ -27,6 +70,78 @@ pub struct NewTag {
     pub user_id: i32,
     pub name: String,
     pub color: Option<String>,
+    pub parent_id: Option<i32>,
+    #[serde(default)]
+    pub workspace_id: Option<i32>,
+    #[serde(default)]
+    pub is_public: bool,
+}
+
+/// Where a song tag came from.
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    rocket::FromFormField,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Default,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum TagSource {
+    /// Applied by hand.
+    #[default]
+    Manual,
+    /// Brought in by a bulk import.
+    Import,
+    /// Applied by an auto-tagging rule.
+    Rule,
+    /// A suggestion the user accepted.
+    Suggestion,
+}
+
+impl TagSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TagSource::Manual => "manual",
+            TagSource::Import => "import",
+            TagSource::Rule => "rule",
+            TagSource::Suggestion => "suggestion",
+        }
+    }
+}
+
+impl std::str::FromStr for TagSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "manual" => Ok(TagSource::Manual),
+            "import" => Ok(TagSource::Import),
+            "rule" => Ok(TagSource::Rule),
+            "suggestion" => Ok(TagSource::Suggestion),
+            other => Err(format!("Unknown tag source: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for TagSource {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for TagSource {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
 }
 
 #[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -38,6 +153,12 @@ pub struct SongTag {
     pub song_id: String,
     pub tag_id: i32,
     pub created_at: NaiveDateTime,
+    pub rule_id: Option<i32>,
+    pub source: TagSource,
+    pub confidence: Option<f64>,
+    pub note: Option<String>,
+    pub updated_at: NaiveDateTime,
+    pub weight: Option<i32>,
 }
 
 #[derive(Insertable, Deserialize, Clone, Debug)]
@@ -47,6 +168,143 @@ pub struct NewSongTag {
     pub user_id: i32,
     pub song_id: String,
     pub tag_id: i32,
+    #[serde(skip_deserializing)]
+    pub rule_id: Option<i32>,
+    #[serde(default)]
+    pub source: TagSource,
+    pub confidence: Option<f64>,
+    pub note: Option<String>,
+    pub weight: Option<i32>,
+}
+
+impl NewSongTag {
+    /// Checks the provenance fields a client is allowed to send.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.source == TagSource::Rule && self.rule_id.is_none() {
+            return Err("Rule song tags can only be created by rules".to_string());
+        }
+        if self.confidence.is_some_and(|c| !(0.0..=1.0).contains(&c)) {
+            return Err("Confidence must be between 0 and 1".to_string());
+        }
+        validate_weight(self.weight)
+    }
+}
+
+/// Error type for diesel transactions whose steps report `String` errors.
+pub struct TransactionError(pub String);
+
+impl From<String> for TransactionError {
+    fn from(e: String) -> Self {
+        TransactionError(e)
+    }
+}
+
+impl From<diesel::result::Error> for TransactionError {
+    fn from(e: diesel::result::Error) -> Self {
+        TransactionError(format!("Database error: {e}"))
+    }
+}
+
+pub fn validate_weight(weight: Option<i32>) -> Result<(), String> {
+    match weight {
+        Some(w) if !TAG_WEIGHT_RANGE.contains(&w) => Err(format!(
+            "Weight must be between {} and {}",
+            TAG_WEIGHT_RANGE.start(),
+            TAG_WEIGHT_RANGE.end()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Partial update of a song tag's weight and provenance. Omitted fields are
+/// left alone and an explicit `null` clears the field.
+#[derive(AsChangeset, Deserialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::song_tags)]
+pub struct SongTagUpdate {
+    #[serde(default, deserialize_with = "deserialize_present")]
+    pub confidence: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    pub note: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_present")]
+    pub weight: Option<Option<i32>>,
+}
+
+impl SongTagUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.confidence.is_none() && self.note.is_none() && self.weight.is_none() {
+            return Err("Nothing to update; send confidence, note or weight".to_string());
+        }
+        if self
+            .confidence
+            .flatten()
+            .is_some_and(|c| !(0.0..=1.0).contains(&c))
+        {
+            return Err("Confidence must be between 0 and 1".to_string());
+        }
+        validate_weight(self.weight.flatten())
+    }
+}
+
+// Distinguishes a field sent as `null` (Some(None)) from a missing one (None).
+fn deserialize_present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::songs)]
+pub struct Song {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub release_year: Option<i32>,
+    pub duration_ms: Option<i32>,
+    pub isrc: Option<String>,
+    pub energy: Option<f64>,
+    pub valence: Option<f64>,
+    pub tempo: Option<f64>,
+    pub danceability: Option<f64>,
+    pub acousticness: Option<f64>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Song {
+    /// Audio features are only usable once every feature is known.
+    pub fn audio_features(&self) -> Option<suggestions::AudioFeatures> {
+        Some(suggestions::AudioFeatures {
+            energy: self.energy?,
+            valence: self.valence?,
+            tempo: self.tempo?,
+            danceability: self.danceability?,
+            acousticness: self.acousticness?,
+        })
+    }
+}
+
+#[derive(Insertable, AsChangeset, Deserialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::songs)]
+pub struct NewSong {
+    #[serde(default)]
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    pub release_year: Option<i32>,
+    pub duration_ms: Option<i32>,
+    pub isrc: Option<String>,
+    pub energy: Option<f64>,
+    pub valence: Option<f64>,
+    pub tempo: Option<f64>,
+    pub danceability: Option<f64>,
+    pub acousticness: Option<f64>,
 }
 
 #[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -54,8 +312,9 @@ pub struct NewSongTag {
 #[diesel(table_name = schema::users)]
 pub struct User {
     pub id: i32,
-    pub spotify_id: String,
-    pub email: String,
+    /// The Spotify account whose tokens are stored below.
+    pub spotify_id: Option<String>,
+    pub email: Option<String>,
     pub display_name: Option<String>,
     pub spotify_access_token: Option<String>,
     pub spotify_refresh_token: Option<String>,
@@ -63,19 +322,30 @@ pub struct User {
     pub profile_image_url: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Set while the account has no Spotify connection.
+    pub spotify_unlinked_at: Option<NaiveDateTime>,
+    /// Space-separated scopes of the current Spotify grant; `None` for
+    /// grants made before scopes were recorded.
+    pub spotify_scopes: Option<String>,
+    /// Set once the user opens the link emailed to the address; emails
+    /// that came from Spotify are not verified.
+    pub email_verified: bool,
+    /// IANA time zone that days and weeks are counted in; `None` is UTC.
+    pub timezone: Option<String>,
 }
 
 #[derive(Insertable, Deserialize, Clone, Debug)]
 #[serde(crate = "rocket::serde")]
 #[diesel(table_name = schema::users)]
 pub struct NewUser {
-    pub spotify_id: String,
-    pub email: String,
+    pub spotify_id: Option<String>,
+    pub email: Option<String>,
     pub display_name: Option<String>,
     pub spotify_access_token: Option<String>,
     pub spotify_refresh_token: Option<String>,
     pub token_expires_at: Option<NaiveDateTime>,
     pub profile_image_url: Option<String>,
+    pub spotify_scopes: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -83,6 +353,10 @@ pub struct NewUser {
 pub struct AuthRequest {
     pub code: String,
     pub code_verifier: String,
+    /// The redirect URI the authorization request used. Defaults to the
+    /// first entry of `SPOTIFY_REDIRECT_URIS`.
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -119,9 +393,90 @@ pub struct SpotifyImage {
     pub width: Option<u32>,
 }
 
-pub async fn refresh_spotify_token(pool: &DbPool, user_id: i32) -> Result<AuthResponse, String> {
+/// Swaps a Spotify refresh token for a new access token.
+pub async fn request_spotify_token_refresh(
+    refresh_token: &str,
+) -> Result<SpotifyTokenResponse, String> {
+    let client_id = env::var("SPOTIFY_CLIENT_ID").map_err(|_| "SPOTIFY_CLIENT_ID not set")?;
+    let client_secret =
+        env::var("SPOTIFY_CLIENT_SECRET").map_err(|_| "SPOTIFY_CLIENT_SECRET not set")?;
+
+    let client = reqwest::Client::new();
+    let params = [
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", &client_id),
+    ];
+
+    let response = client
+        .post("https://accounts.spotify.com/api/token")
+        .form(&params)
+        .header(
+            "Authorization",
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{client_id}:{client_secret}"))
+            ),
+        )
+        .send()
+        .await
+        .map_err(|e| format!("Token refresh failed: Failed to send refresh request: {e}"))?;
+
+    let status_code = response.status();
+    if !status_code.is_success() {
+        let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+        return Err(format!(
+            "Token refresh failed: Spotify refresh API error {status_code}: {error_text}"
+        ));
+    }
+
+    response
+        .json::<SpotifyTokenResponse>()
+        .await
+        .map_err(|json_err| {
+            format!("Token refresh failed: Failed to parse Spotify refresh response: {json_err}")
+        })
+}
+
+/// Swaps the user's Spotify refresh token for a new access token.
+pub async fn refresh_spotify_token(pool: &DbPool, user_id: i32) -> Result<User, String> {
+    refresh_token_with(pool, &SpotifyProvider::new(), user_id).await
+}
+
+/// [`refresh_spotify_token`] against any provider that stands in for
+/// Spotify.
+pub async fn refresh_token_with(
+    pool: &DbPool,
+    provider: &dyn MusicProvider,
+    user_id: i32,
+) -> Result<User, String> {
     use schema::users::dsl::*;
 
+    check_login_provider(provider)?;
+
+    let lookup_pool = pool.clone();
+    let user_record = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        users
+            .filter(id.eq(user_id))
+            .first::<User>(&mut conn)
+            .map_err(|e| format!("Failed to find user: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    if user_record.spotify_unlinked_at.is_some() {
+        return Err("Spotify is unlinked from this account; link it again to continue".to_string());
+    }
+    let refresh_token = user_record
+        .spotify_refresh_token
+        .ok_or("No refresh token available")?;
+
+    let tokens = provider.refresh_tokens(&refresh_token).await?;
+
     let pool = pool.clone();
 
     tokio::task::spawn_blocking(move || {
@@ -129,100 +484,209 @@ pub async fn refresh_spotify_token(pool: &DbPool, user_id: i32) -> Result<AuthRe
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
 
-        // Get user with refresh token
-        let user_record = users
+        let expires_at =
+            chrono::Utc::now().naive_utc() + chrono::Duration::seconds(tokens.expires_in);
+
+        // The link may have been removed while Spotify was answering.
+        diesel::update(
+            users
+                .filter(id.eq(user_id))
+                .filter(spotify_unlinked_at.is_null()),
+        )
+        .set((
+            spotify_access_token.eq(Some(tokens.access_token)),
+            spotify_refresh_token.eq(tokens.refresh_token.or(Some(refresh_token))),
+            token_expires_at.eq(Some(expires_at)),
+            spotify_scopes.eq(Some(tokens.scopes.join(" "))
+                .filter(|scope| !scope.is_empty())
+                .or(user_record.spotify_scopes)),
+            updated_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result::<User>(&mut conn)
+        .map_err(|e| format!("Failed to update user with new token: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Returns a usable Spotify access token for the user, refreshing the stored
+/// token first when it has expired (or is about to).
+pub async fn spotify_access_token_for_user(pool: &DbPool, user_id: i32) -> Result<String, String> {
+    use schema::users::dsl::*;
+
+    let lookup_pool = pool.clone();
+    let user_record = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        users
             .filter(id.eq(user_id))
             .first::<User>(&mut conn)
-            .map_err(|e| format!("Failed to find user: {e}"))?;
-
-        let refresh_token = user_record
-            .spotify_refresh_token
-            .ok_or("No refresh token available")?;
-
-        let client_id = env::var("SPOTIFY_CLIENT_ID").map_err(|_| "SPOTIFY_CLIENT_ID not set")?;
-        let client_secret =
-            env::var("SPOTIFY_CLIENT_SECRET").map_err(|_| "SPOTIFY_CLIENT_SECRET not set")?;
-
-        // Refresh the token
-        let token_response = match tokio::task::block_in_place(|| {
-            let rt = tokio::runtime::Handle::current();
-            rt.block_on(async {
-                let client = reqwest::Client::new();
-                let params = [
-                    ("grant_type", "refresh_token"),
-                    ("refresh_token", &refresh_token),
-                    ("client_id", &client_id),
-                ];
-
-                let response = match client
-                    .post("https://accounts.spotify.com/api/token")
-                    .form(&params)
-                    .header(
-                        "Authorization",
-                        format!(
-                            "Basic {}",
-                            base64::engine::general_purpose::STANDARD
-                                .encode(format!("{client_id}:{client_secret}"))
-                        ),
-                    )
-                    .send()
-                    .await
-                {
-                    Ok(resp) => resp,
-                    Err(e) => return Err(format!("Failed to send refresh request: {e}")),
-                };
-
-                let status_code = response.status();
-                if !status_code.is_success() {
-                    let error_text = response.text().await.unwrap_or("Unknown error".to_string());
-                    return Err(format!(
-                        "Spotify refresh API error {status_code}: {error_text}"
-                    ));
-                }
+            .map_err(|e| format!("Failed to find user: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    let refresh_after = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(60);
+    match (
+        user_record.spotify_access_token,
+        user_record.token_expires_at,
+    ) {
+        (Some(token), Some(expires)) if expires > refresh_after => Ok(token),
+        _ => refresh_spotify_token(pool, user_id)
+            .await?
+            .spotify_access_token
+            .ok_or_else(|| "No Spotify access token available".to_string()),
+    }
+}
 
-                let token_result = response.json::<SpotifyTokenResponse>().await;
-                match token_result {
-                    Ok(tokens) => Ok(tokens),
-                    Err(json_err) => Err(format!(
-                        "Failed to parse Spotify refresh response: {json_err}"
-                    )),
-                }
-            })
-        }) {
-            Ok(tokens) => tokens,
-            Err(e) => return Err(format!("Token refresh failed: {e}")),
-        };
+/// Exchanges a PKCE authorization code for Spotify tokens.
+pub async fn exchange_authorization_code(
+    auth_request: &AuthRequest,
+) -> Result<SpotifyTokenResponse, String> {
+    oauth::validate_authorization_code(&auth_request.code)?;
+    oauth::validate_code_verifier(&auth_request.code_verifier)?;
+    let redirect_uri = oauth::select_redirect_uri(
+        auth_request.redirect_uri.as_deref(),
+        &oauth::configured_redirect_uris(),
+    )?;
+
+    let client_id = env::var("SPOTIFY_CLIENT_ID").map_err(|_| "SPOTIFY_CLIENT_ID not set")?;
+    let client_secret =
+        env::var("SPOTIFY_CLIENT_SECRET").map_err(|_| "SPOTIFY_CLIENT_SECRET not set")?;
+
+    let client = reqwest::Client::new();
+    let params = [
+        ("grant_type", "authorization_code"),
+        ("code", &auth_request.code),
+        ("redirect_uri", &redirect_uri),
+        ("client_id", &client_id),
+        ("code_verifier", &auth_request.code_verifier),
+    ];
+
+    let response = client
+        .post("https://accounts.spotify.com/api/token")
+        .form(&params)
+        .header(
+            "Authorization",
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD
+                    .encode(format!("{client_id}:{client_secret}"))
+            ),
+        )
+        .send()
+        .await
+        .map_err(|e| format!("Token exchange failed: Failed to send token request: {e}"))?;
+
+    let status_code = response.status();
+    if !status_code.is_success() {
+        let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+        return Err(format!(
+            "Token exchange failed: {}",
+            oauth::token_error_message(status_code.as_u16(), &error_text)
+        ));
+    }
 
-        // Update user with new token
-        let expires_at =
-            chrono::Utc::now().naive_utc() + chrono::Duration::seconds(token_response.expires_in);
+    response
+        .json::<SpotifyTokenResponse>()
+        .await
+        .map_err(|json_err| {
+            format!("Token exchange failed: Failed to parse Spotify token response: {json_err}")
+        })
+}
 
-        let updated_user = diesel::update(users.filter(id.eq(user_id)))
-            .set((
-                spotify_access_token.eq(Some(token_response.access_token.clone())),
-                spotify_refresh_token.eq(token_response.refresh_token.or(Some(refresh_token))),
-                token_expires_at.eq(Some(expires_at)),
-                updated_at.eq(chrono::Utc::now().naive_utc()),
-            ))
-            .get_result::<User>(&mut conn)
-            .map_err(|e| format!("Failed to update user with new token: {e}"))?;
+/// Accounts keep one provider connection, in the `spotify_*` columns, so
+/// only Spotify (or a test provider standing in for it) can sign users in.
+fn check_login_provider(provider: &dyn MusicProvider) -> Result<(), String> {
+    if provider.name() == identities::SPOTIFY_PROVIDER {
+        Ok(())
+    } else {
+        Err(format!(
+            "Signing in with {} is not supported",
+            provider.name()
+        ))
+    }
+}
 
-        let jwt_token = format!("user_token_{}", updated_user.id);
-        Ok(AuthResponse {
-            user: updated_user,
-            access_token: jwt_token,
-        })
-    })
-    .await
-    .map_err(|e| format!("Task join error: {e}"))?
+/// The user row for a freshly authorized Spotify account.
+fn new_user_from_spotify(tokens: &ProviderTokens, profile: &ProviderProfile) -> NewUser {
+    let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(tokens.expires_in);
+
+    NewUser {
+        spotify_id: Some(profile.id.clone()),
+        email: profile
+            .email
+            .clone()
+            .filter(|profile_email| !profile_email.is_empty()),
+        display_name: profile.display_name.clone(),
+        spotify_access_token: Some(tokens.access_token.clone()),
+        spotify_refresh_token: tokens.refresh_token.clone(),
+        token_expires_at: Some(expires_at),
+        profile_image_url: profile.image_url.clone(),
+        spotify_scopes: Some(tokens.scopes.join(" ")),
+    }
 }
 
+/// Makes a Spotify account the user's active connection, storing its
+/// tokens and profile details and marking Spotify as linked again.
+fn attach_spotify(
+    conn: &mut PgConnection,
+    user_id: i32,
+    new_user: &NewUser,
+) -> Result<User, String> {
+    use schema::users::dsl::*;
+
+    // A verified email is the user's own; don't let Spotify replace it.
+    diesel::update(
+        users
+            .filter(id.eq(user_id))
+            .filter(email_verified.eq(false)),
+    )
+    .set(email.eq(&new_user.email))
+    .execute(conn)
+    .map_err(|e| format!("Failed to update user: {e}"))?;
+
+    diesel::update(users.filter(id.eq(user_id)))
+        .set((
+            spotify_id.eq(&new_user.spotify_id),
+            display_name.eq(&new_user.display_name),
+            spotify_access_token.eq(&new_user.spotify_access_token),
+            spotify_refresh_token.eq(&new_user.spotify_refresh_token),
+            token_expires_at.eq(&new_user.token_expires_at),
+            profile_image_url.eq(&new_user.profile_image_url),
+            spotify_unlinked_at.eq(None::<NaiveDateTime>),
+            spotify_scopes.eq(&new_user.spotify_scopes),
+            updated_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result::<User>(conn)
+        .map_err(|e| format!("Failed to update user: {e}"))
+}
+
+/// Logs a user in with any Spotify account linked to their Moodring
+/// account, creating the Moodring account on first login. An account whose
+/// Spotify link was removed is reattached.
 pub async fn authenticate_user_with_spotify(
     pool: &DbPool,
     auth_request: AuthRequest,
+) -> Result<AuthResponse, String> {
+    authenticate_user_with(pool, &SpotifyProvider::new(), auth_request).await
+}
+
+/// [`authenticate_user_with_spotify`] against any provider that stands in
+/// for Spotify.
+pub async fn authenticate_user_with(
+    pool: &DbPool,
+    provider: &dyn MusicProvider,
+    auth_request: AuthRequest,
 ) -> Result<AuthResponse, String> {
     use schema::users::dsl::*;
 
+    check_login_provider(provider)?;
+    let tokens = provider.exchange_code(&auth_request).await?;
+    let user_profile = provider.profile(&tokens.access_token).await?;
+    let new_user = new_user_from_spotify(&tokens, &user_profile);
+
     let pool = pool.clone();
 
     tokio::task::spawn_blocking(move || {
@@ -230,150 +694,137 @@ pub async fn authenticate_user_with_spotify(
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
 
-        // Step 1: Exchange authorization code for access token
-        let client_id = env::var("SPOTIFY_CLIENT_ID").map_err(|_| "SPOTIFY_CLIENT_ID not set")?;
-        let client_secret =
-            env::var("SPOTIFY_CLIENT_SECRET").map_err(|_| "SPOTIFY_CLIENT_SECRET not set")?;
-
-        let token_response = match tokio::task::block_in_place(|| {
-            let rt = tokio::runtime::Handle::current();
-            rt.block_on(async {
-                let client = reqwest::Client::new();
-                let params = [
-                    ("grant_type", "authorization_code"),
-                    ("code", &auth_request.code),
-                    ("redirect_uri", "moodring://auth"),
-                    ("client_id", &client_id),
-                    ("code_verifier", &auth_request.code_verifier),
-                ];
-
-                let response = match client
-                    .post("https://accounts.spotify.com/api/token")
-                    .form(&params)
-                    .header(
-                        "Authorization",
-                        format!(
-                            "Basic {}",
-                            base64::engine::general_purpose::STANDARD
-                                .encode(format!("{client_id}:{client_secret}"))
-                        ),
-                    )
-                    .send()
-                    .await
-                {
-                    Ok(resp) => resp,
-                    Err(e) => return Err(format!("Failed to send token request: {e}")),
-                };
-
-                let status_code = response.status();
-                if !status_code.is_success() {
-                    let error_text = response.text().await.unwrap_or("Unknown error".to_string());
-                    return Err(format!("Spotify API error {status_code}: {error_text}"));
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let existing_user_id =
+                identities::find_user_id(conn, identities::SPOTIFY_PROVIDER, &user_profile.id)?;
+            let result_user = match existing_user_id {
+                Some(existing_user_id) => attach_spotify(conn, existing_user_id, &new_user)?,
+                None => {
+                    let created_user = diesel::insert_into(users)
+                        .values(&new_user)
+                        .get_result::<User>(conn)
+                        .map_err(|e| format!("Failed to create user: {e}"))?;
+                    identities::add_identity(
+                        conn,
+                        created_user.id,
+                        identities::SPOTIFY_PROVIDER,
+                        &user_profile.id,
+                    )?;
+                    created_user
                 }
+            };
 
-                let token_result = response.json::<SpotifyTokenResponse>().await;
-                match token_result {
-                    Ok(tokens) => Ok(tokens),
-                    Err(json_err) => Err(format!(
-                        "Failed to parse Spotify token response: {json_err}"
-                    )),
-                }
+            let session_token = auth::create_session(conn, result_user.id)?;
+            Ok(AuthResponse {
+                user: result_user,
+                access_token: session_token,
             })
-        }) {
-            Ok(tokens) => tokens,
-            Err(e) => return Err(format!("Token exchange failed: {e}")),
-        };
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
 
-        // Step 2: Get user profile from Spotify
-        let user_profile = match tokio::task::block_in_place(|| {
-            let rt = tokio::runtime::Handle::current();
-            rt.block_on(async {
-                let client = reqwest::Client::new();
-                let response = match client
-                    .get("https://api.spotify.com/v1/me")
-                    .header(
-                        "Authorization",
-                        format!("Bearer {}", token_response.access_token),
-                    )
-                    .send()
-                    .await
-                {
-                    Ok(resp) => resp,
-                    Err(e) => return Err(format!("Failed to send profile request: {e}")),
-                };
-
-                let status_code = response.status();
-                if !status_code.is_success() {
-                    let error_text = response.text().await.unwrap_or("Unknown error".to_string());
-                    return Err(format!(
-                        "Spotify profile API error {status_code}: {error_text}"
-                    ));
-                }
+/// Disconnects Spotify from a Moodring account. Tags, playlists and the
+/// library are kept; only the stored Spotify tokens are dropped.
+pub async fn unlink_spotify(pool: &DbPool, user_id: i32) -> Result<User, String> {
+    use schema::users::dsl::*;
 
-                let profile_result = response.json::<SpotifyUserProfile>().await;
-                match profile_result {
-                    Ok(profile) => Ok(profile),
-                    Err(json_err) => Err(format!(
-                        "Failed to parse Spotify profile response: {json_err}"
-                    )),
-                }
-            })
-        }) {
-            Ok(profile) => profile,
-            Err(e) => return Err(format!("Profile fetch failed: {e}")),
-        };
+    let pool = pool.clone();
 
-        // Step 3: Create or update user in database
-        let expires_at =
-            chrono::Utc::now().naive_utc() + chrono::Duration::seconds(token_response.expires_in);
-        let profile_image = user_profile.images.first().map(|img| img.url.clone());
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let now = chrono::Utc::now().naive_utc();
+        diesel::update(users.filter(id.eq(user_id)))
+            .set((
+                spotify_access_token.eq(None::<String>),
+                spotify_refresh_token.eq(None::<String>),
+                token_expires_at.eq(None::<NaiveDateTime>),
+                spotify_unlinked_at.eq(Some(now)),
+                spotify_scopes.eq(None::<String>),
+                updated_at.eq(now),
+            ))
+            .get_result::<User>(&mut conn)
+            .map_err(|e| format!("Failed to unlink Spotify: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
 
-        let new_user = NewUser {
-            spotify_id: user_profile.id.clone(),
-            email: user_profile.email.clone().unwrap_or_default(),
-            display_name: user_profile.display_name.clone(),
-            spotify_access_token: Some(token_response.access_token.clone()),
-            spotify_refresh_token: token_response.refresh_token.clone(),
-            token_expires_at: Some(expires_at),
-            profile_image_url: profile_image,
-        };
+/// Sets the time zone the user's days and weeks are counted in. `None`
+/// goes back to UTC.
+pub async fn set_user_timezone(
+    pool: &DbPool,
+    user_id: i32,
+    new_timezone: Option<String>,
+) -> Result<User, String> {
+    use schema::users::dsl::*;
 
-        // Try to find existing user first
-        let result_user = match users
-            .filter(spotify_id.eq(&user_profile.id))
-            .first::<User>(&mut conn)
-        {
-            Ok(_existing_user) => {
-                // Update existing user
-                diesel::update(users.filter(spotify_id.eq(&user_profile.id)))
-                    .set((
-                        email.eq(&new_user.email),
-                        display_name.eq(&new_user.display_name),
-                        spotify_access_token.eq(&new_user.spotify_access_token),
-                        spotify_refresh_token.eq(&new_user.spotify_refresh_token),
-                        token_expires_at.eq(&new_user.token_expires_at),
-                        profile_image_url.eq(&new_user.profile_image_url),
-                        updated_at.eq(chrono::Utc::now().naive_utc()),
-                    ))
-                    .get_result::<User>(&mut conn)
-                    .map_err(|e| format!("Failed to update user: {e}"))
-            }
-            Err(diesel::NotFound) => {
-                // Create new user
-                diesel::insert_into(users)
-                    .values(&new_user)
-                    .get_result::<User>(&mut conn)
-                    .map_err(|e| format!("Failed to create user: {e}"))
-            }
-            Err(e) => Err(format!("Database error: {e}")),
-        }?;
-
-        // Generate a simple JWT token (in production, use proper JWT implementation)
-        let jwt_token = format!("user_token_{}", result_user.id);
-        Ok(AuthResponse {
-            user: result_user,
-            access_token: jwt_token,
-        })
+    if let Some(name) = &new_timezone {
+        name.parse::<chrono_tz::Tz>()
+            .map_err(|_| format!("Unknown time zone: {name}"))?;
+    }
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::update(users.filter(id.eq(user_id)))
+            .set((
+                timezone.eq(new_timezone),
+                updated_at.eq(chrono::Utc::now().naive_utc()),
+            ))
+            .get_result::<User>(&mut conn)
+            .map_err(|e| format!("Failed to update time zone: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Reconnects Spotify to the signed-in account. The authorizing Spotify
+/// account has to be one already linked to the Moodring account.
+pub async fn relink_spotify(
+    pool: &DbPool,
+    user_id: i32,
+    auth_request: AuthRequest,
+) -> Result<User, String> {
+    relink_with(pool, &SpotifyProvider::new(), user_id, auth_request).await
+}
+
+/// [`relink_spotify`] against any provider that stands in for Spotify.
+pub async fn relink_with(
+    pool: &DbPool,
+    provider: &dyn MusicProvider,
+    user_id: i32,
+    auth_request: AuthRequest,
+) -> Result<User, String> {
+    check_login_provider(provider)?;
+    let tokens = provider.exchange_code(&auth_request).await?;
+    let user_profile = provider.profile(&tokens.access_token).await?;
+    let new_user = new_user_from_spotify(&tokens, &user_profile);
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let owner =
+            identities::find_user_id(&mut conn, identities::SPOTIFY_PROVIDER, &user_profile.id)?;
+
+        if owner != Some(user_id) {
+            return Err(format!(
+                "Spotify account {} does not match the accounts linked to this Moodring account; \
+                 sign in to Spotify as a linked account and try again",
+                user_profile.id
+            ));
+        }
+
+        attach_spotify(&mut conn, user_id, &new_user)
     })
     .await
     .map_err(|e| format!("Task join error: {e}"))?
@@ -444,20 +895,47 @@ mod tests {
     use serde_json::json;
     use std::env;
 
+    #[test]
+    fn test_new_user_from_spotify() {
+        let before = chrono::Utc::now().naive_utc();
+        let tokens = ProviderTokens::from(test_helpers::create_mock_token_response());
+        let new_user = new_user_from_spotify(
+            &tokens,
+            &ProviderProfile::from(test_helpers::create_mock_spotify_user()),
+        );
+
+        assert_eq!(new_user.spotify_id.as_deref(), Some("test_spotify_id"));
+        assert_eq!(
+            new_user.spotify_access_token.as_deref(),
+            Some("mock_access_token")
+        );
+        assert_eq!(
+            new_user.profile_image_url.as_deref(),
+            Some("https://example.com/avatar.jpg")
+        );
+        assert!(new_user.token_expires_at.unwrap() >= before + chrono::Duration::seconds(3600));
+
+        let mut without_email = test_helpers::create_mock_spotify_user();
+        without_email.email = Some(String::new());
+        let new_user = new_user_from_spotify(&tokens, &ProviderProfile::from(without_email));
+        assert_eq!(new_user.email, None);
+    }
+
     #[tokio::test]
     async fn test_new_user_struct_creation() {
         let new_user = NewUser {
-            spotify_id: "test_spotify_123".to_string(),
-            email: "test@example.com".to_string(),
+            spotify_id: Some("test_spotify_123".to_string()),
+            email: Some("test@example.com".to_string()),
             display_name: Some("Test User".to_string()),
             spotify_access_token: Some("access_token_123".to_string()),
             spotify_refresh_token: Some("refresh_token_123".to_string()),
             token_expires_at: Some(chrono::Utc::now().naive_utc() + chrono::Duration::hours(1)),
             profile_image_url: Some("https://example.com/avatar.jpg".to_string()),
+            spotify_scopes: None,
         };
 
-        assert_eq!(new_user.spotify_id, "test_spotify_123");
-        assert_eq!(new_user.email, "test@example.com");
+        assert_eq!(new_user.spotify_id.as_deref(), Some("test_spotify_123"));
+        assert_eq!(new_user.email.as_deref(), Some("test@example.com"));
         assert_eq!(new_user.display_name, Some("Test User".to_string()));
         assert_eq!(
             new_user.spotify_access_token,
@@ -477,8 +955,8 @@ mod tests {
     async fn test_user_model_serialization() {
         let user = User {
             id: 1,
-            spotify_id: "test_spotify_id".to_string(),
-            email: "test@example.com".to_string(),
+            spotify_id: Some("test_spotify_id".to_string()),
+            email: Some("test@example.com".to_string()),
             display_name: Some("Test User".to_string()),
             spotify_access_token: Some("access_token".to_string()),
             spotify_refresh_token: Some("refresh_token".to_string()),
@@ -486,6 +964,10 @@ mod tests {
             profile_image_url: Some("https://example.com/avatar.jpg".to_string()),
             created_at: chrono::Utc::now().naive_utc(),
             updated_at: chrono::Utc::now().naive_utc(),
+            spotify_unlinked_at: None,
+            spotify_scopes: None,
+            email_verified: false,
+            timezone: None,
         };
 
         let serialized = serde_json::to_string(&user).expect("Failed to serialize user");
@@ -515,8 +997,8 @@ mod tests {
     async fn test_auth_response_serialization() {
         let user = User {
             id: 1,
-            spotify_id: "test_spotify_id".to_string(),
-            email: "test@example.com".to_string(),
+            spotify_id: Some("test_spotify_id".to_string()),
+            email: Some("test@example.com".to_string()),
             display_name: Some("Test User".to_string()),
             spotify_access_token: Some("access_token".to_string()),
             spotify_refresh_token: Some("refresh_token".to_string()),
@@ -524,6 +1006,10 @@ mod tests {
             profile_image_url: Some("https://example.com/avatar.jpg".to_string()),
             created_at: chrono::Utc::now().naive_utc(),
             updated_at: chrono::Utc::now().naive_utc(),
+            spotify_unlinked_at: None,
+            spotify_scopes: None,
+            email_verified: false,
+            timezone: None,
         };
 
         let auth_response = AuthResponse {
@@ -605,16 +1091,121 @@ mod tests {
         assert_eq!(user_profile.images[0].width, Some(300));
     }
 
+    #[test]
+    fn test_new_song_tag_provenance_defaults() {
+        let new_song_tag: NewSongTag = serde_json::from_value(json!({
+            "user_id": 1,
+            "song_id": "track_1",
+            "tag_id": 2,
+            "rule_id": 9
+        }))
+        .expect("Failed to deserialize NewSongTag");
+
+        assert_eq!(new_song_tag.source, TagSource::Manual);
+        assert_eq!(new_song_tag.rule_id, None);
+        assert!(new_song_tag.validate().is_ok());
+    }
+
+    #[test]
+    fn test_new_song_tag_validation() {
+        let mut new_song_tag: NewSongTag = serde_json::from_value(json!({
+            "user_id": 1,
+            "song_id": "track_1",
+            "tag_id": 2,
+            "source": "suggestion",
+            "confidence": 0.8
+        }))
+        .expect("Failed to deserialize NewSongTag");
+        assert!(new_song_tag.validate().is_ok());
+
+        new_song_tag.confidence = Some(1.5);
+        assert!(new_song_tag.validate().is_err());
+
+        new_song_tag.confidence = None;
+        new_song_tag.weight = Some(6);
+        assert!(new_song_tag.validate().is_err());
+
+        new_song_tag.weight = Some(5);
+        assert!(new_song_tag.validate().is_ok());
+
+        new_song_tag.source = TagSource::Rule;
+        assert!(new_song_tag.validate().is_err());
+    }
+
+    #[test]
+    fn test_song_tag_update_distinguishes_null_from_missing() {
+        let update: SongTagUpdate =
+            serde_json::from_value(json!({ "note": null })).expect("Failed to deserialize");
+        assert_eq!(update.note, Some(None));
+        assert_eq!(update.confidence, None);
+
+        let update: SongTagUpdate = serde_json::from_value(json!({ "note": "live version" }))
+            .expect("Failed to deserialize");
+        assert_eq!(update.note, Some(Some("live version".to_string())));
+    }
+
+    #[test]
+    fn test_empty_song_tag_update_is_rejected() {
+        let update: SongTagUpdate = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(
+            update.validate(),
+            Err("Nothing to update; send confidence, note or weight".to_string())
+        );
+        assert!(SongTagUpdate {
+            weight: Some(None),
+            ..SongTagUpdate::default()
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_tag_source_round_trip() {
+        for source in [
+            TagSource::Manual,
+            TagSource::Import,
+            TagSource::Rule,
+            TagSource::Suggestion,
+        ] {
+            assert_eq!(source.as_str().parse::<TagSource>(), Ok(source));
+            assert_eq!(
+                serde_json::to_value(source).unwrap(),
+                json!(source.as_str())
+            );
+        }
+        assert!("bulk".parse::<TagSource>().is_err());
+    }
+
     #[tokio::test]
     async fn test_auth_request_validation() {
         let valid_request = AuthRequest {
             code: "valid_auth_code_12345".to_string(),
-            code_verifier: "valid_code_verifier_12345".to_string(),
+            code_verifier: "valid_code_verifier_12345_abcdefghijklmnopqrstu".to_string(),
+            redirect_uri: None,
         };
 
         assert!(!valid_request.code.is_empty());
         assert!(!valid_request.code_verifier.is_empty());
         assert!(valid_request.code.len() > 10);
         assert!(valid_request.code_verifier.len() > 10);
+        assert!(oauth::validate_code_verifier(&valid_request.code_verifier).is_ok());
+    }
+
+    #[test]
+    fn test_login_requires_spotify_provider() {
+        use crate::providers::InMemoryProvider;
+
+        assert!(check_login_provider(&InMemoryProvider::new(identities::SPOTIFY_PROVIDER)).is_ok());
+        assert_eq!(
+            check_login_provider(&InMemoryProvider::new("fake")),
+            Err("Signing in with fake is not supported".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_request_redirect_uri_is_optional() {
+        let without_redirect: AuthRequest =
+            serde_json::from_value(json!({ "code": "abc", "code_verifier": "def" })).unwrap();
+        assert_eq!(without_redirect.redirect_uri, None);
     }
 }
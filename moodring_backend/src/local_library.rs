@@ -0,0 +1,611 @@
+use chrono::{NaiveDateTime, Timelike};
+use diesel::prelude::*;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::{Hint, ProbeResult};
+
+use crate::catalog::{add_to_library, upsert_songs, CatalogImport};
+use crate::errors::{ApiError, ErrorBody};
+use crate::providers::{song_id, MusicProvider, ProviderProfile, ProviderTokens};
+use crate::rules::apply_rules;
+use crate::{schema, AuthRequest, DbPool, NewSong, Song, TransactionError};
+
+pub const LOCAL_PROVIDER: &str = "local";
+
+/// Files with these extensions are read; everything else is ignored.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "flac", "mp3", "m4a", "mp4", "aac", "ogg", "oga", "opus", "wav",
+];
+
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::local_files)]
+pub struct LocalFile {
+    /// Relative to the library root, with `/` separators.
+    pub path: String,
+    pub song_id: String,
+    pub size: i64,
+    pub modified_at: NaiveDateTime,
+    pub scanned_at: NaiveDateTime,
+}
+
+/// An audio file as found on disk.
+#[derive(Clone, PartialEq, Debug)]
+pub struct FoundFile {
+    pub path: String,
+    pub size: i64,
+    pub modified_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ScanFailure {
+    pub path: String,
+    pub error: String,
+}
+
+/// What a scan of the local library changed.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct LocalScan {
+    /// New or changed files, as catalogue songs.
+    #[serde(flatten)]
+    pub import: CatalogImport,
+    pub unchanged: usize,
+    pub removed: usize,
+    pub failed: Vec<ScanFailure>,
+}
+
+/// Users who may scan `LOCAL_MUSIC_DIR`, from the comma-separated
+/// `LOCAL_MUSIC_USER_IDS`. The directory belongs to whoever runs the
+/// server, so nobody may scan it unless listed.
+pub fn library_user_ids() -> Vec<i32> {
+    env::var("LOCAL_MUSIC_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+/// The directory named by `LOCAL_MUSIC_DIR`.
+pub fn library_root() -> Result<PathBuf, String> {
+    let root = env::var("LOCAL_MUSIC_DIR").map_err(|_| "LOCAL_MUSIC_DIR not set")?;
+    let root = PathBuf::from(root);
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+    Ok(root)
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Postgres keeps microseconds; anything finer would make every file look
+/// modified on the next scan.
+fn to_timestamp(time: std::time::SystemTime) -> NaiveDateTime {
+    let time = chrono::DateTime::<chrono::Utc>::from(time).naive_utc();
+    time.with_nanosecond(time.nanosecond() / 1_000 * 1_000)
+        .unwrap_or(time)
+}
+
+/// Every audio file under `root`. Symlinks are not followed.
+pub fn find_audio_files(root: &Path) -> Vec<FoundFile> {
+    walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file() && is_audio_file(entry.path()))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let relative = entry.path().strip_prefix(root).ok()?;
+            Some(FoundFile {
+                path: relative
+                    .components()
+                    .map(|part| part.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/"),
+                size: metadata.len() as i64,
+                modified_at: to_timestamp(metadata.modified().ok()?),
+            })
+        })
+        .collect()
+}
+
+/// Splits found files into those that need reading and the known paths that
+/// no longer exist. A file is reread when its size or modification time
+/// changed since the last scan.
+pub fn plan_rescan<'a>(
+    known: &[LocalFile],
+    found: &'a [FoundFile],
+) -> (Vec<&'a FoundFile>, Vec<String>) {
+    let known_by_path: HashMap<&str, &LocalFile> = known
+        .iter()
+        .map(|file| (file.path.as_str(), file))
+        .collect();
+    let changed = found
+        .iter()
+        .filter(|file| {
+            known_by_path.get(file.path.as_str()).is_none_or(|known| {
+                known.size != file.size || known.modified_at != file.modified_at
+            })
+        })
+        .collect();
+    let found_paths: HashSet<&str> = found.iter().map(|file| file.path.as_str()).collect();
+    let removed = known
+        .iter()
+        .filter(|file| !found_paths.contains(&file.path.as_str()))
+        .map(|file| file.path.clone())
+        .collect();
+    (changed, removed)
+}
+
+fn probe(path: &Path) -> Result<ProbeResult, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open file: {e}"))?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(ext);
+    }
+
+    symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| format!("Unsupported audio file: {e}"))
+}
+
+/// SHA-256 of the file's audio packets, which identifies the recording
+/// wherever the file is moved or renamed and however it is retagged.
+pub fn content_hash(path: &Path) -> Result<String, String> {
+    let mut reader = probe(path)?.format;
+    let track_id = reader
+        .default_track()
+        .map(|track| track.id)
+        .ok_or_else(|| "No audio track".to_string())?;
+    let mut hasher = Sha256::new();
+    loop {
+        match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => hasher.update(packet.buf()),
+            Ok(_) => {}
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(format!("Failed to read audio: {e}")),
+        }
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Builds a catalogue row from embedded tags, falling back to the file name
+/// for the title.
+pub fn song_from_tags(tags: &[Tag], fallback_title: &str, duration_ms: Option<i32>) -> NewSong {
+    let find = |key: StandardTagKey| {
+        tags.iter()
+            .find(|tag| tag.std_key == Some(key))
+            .map(|tag| tag.value.to_string().trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    NewSong {
+        id: String::new(),
+        title: find(StandardTagKey::TrackTitle).unwrap_or_else(|| fallback_title.to_string()),
+        artist: find(StandardTagKey::Artist)
+            .or_else(|| find(StandardTagKey::AlbumArtist))
+            .unwrap_or_else(|| "Unknown Artist".to_string()),
+        album: find(StandardTagKey::Album),
+        release_year: find(StandardTagKey::Date)
+            .or_else(|| find(StandardTagKey::OriginalDate))
+            .and_then(|date| date.get(..4).and_then(|year| year.parse().ok())),
+        duration_ms,
+        isrc: find(StandardTagKey::IdentIsrc).map(|isrc| isrc.to_ascii_uppercase()),
+        ..NewSong::default()
+    }
+}
+
+/// Reads the ID3, Vorbis comment or MP4 tags and the duration of an audio
+/// file.
+pub fn read_audio_metadata(path: &Path) -> Result<NewSong, String> {
+    let mut probed = probe(path)?;
+
+    // The container's own tags win over any found while probing, such as
+    // an ID3v2 block ahead of MP3 frames.
+    let mut tags: Vec<Tag> = probed
+        .format
+        .metadata()
+        .current()
+        .map(|revision| revision.tags().to_vec())
+        .unwrap_or_default();
+    if let Some(revision) = probed
+        .metadata
+        .get()
+        .as_ref()
+        .and_then(|metadata| metadata.current())
+    {
+        tags.extend(revision.tags().iter().cloned());
+    }
+
+    let duration_ms = probed.format.default_track().and_then(|track| {
+        let params = &track.codec_params;
+        let frames = params.n_frames?;
+        let time = params
+            .time_base
+            .or_else(|| {
+                params
+                    .sample_rate
+                    .map(|rate| symphonia::core::units::TimeBase::new(1, rate))
+            })?
+            .calc_time(frames);
+        i32::try_from(time.seconds * 1_000 + (time.frac * 1_000.0).round() as u64).ok()
+    });
+
+    let fallback_title = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(song_from_tags(&tags, &fallback_title, duration_ms))
+}
+
+/// Scans `LOCAL_MUSIC_DIR`, registering new and changed files as songs and
+/// adding every file in it to the user's library. Only files whose size or
+/// modification time changed are read again. Songs of removed files stay
+/// in the catalogue so their tags survive the file coming back. Limited to
+/// the users in [`library_user_ids`].
+pub async fn scan_local_library(pool: &DbPool, user_id: i32) -> Result<LocalScan, ApiError> {
+    use schema::local_files;
+
+    if !library_user_ids().contains(&user_id) {
+        return Err(ApiError::Forbidden(Json(ErrorBody::new(
+            "local_library_forbidden",
+            "Only the users in LOCAL_MUSIC_USER_IDS may scan the local library",
+        ))));
+    }
+    let root = library_root()?;
+    let pool = pool.clone();
+
+    Ok(tokio::task::spawn_blocking(move || {
+        let known = local_files::table
+            .load::<LocalFile>(
+                &mut pool
+                    .get()
+                    .map_err(|e| format!("Failed to get connection: {e}"))?,
+            )
+            .map_err(|e| format!("Failed to load local files: {e}"))?;
+        let found = find_audio_files(&root);
+        let (changed, removed) = plan_rescan(&known, &found);
+
+        let mut failed = Vec::new();
+        let mut read = Vec::new();
+        for file in &changed {
+            let full_path = root.join(&file.path);
+            match content_hash(&full_path)
+                .and_then(|hash| Ok((hash, read_audio_metadata(&full_path)?)))
+            {
+                Ok((hash, song)) => read.push((
+                    *file,
+                    NewSong {
+                        id: song_id(LOCAL_PROVIDER, &hash),
+                        ..song
+                    },
+                )),
+                Err(error) => failed.push(ScanFailure {
+                    path: file.path.clone(),
+                    error,
+                }),
+            }
+        }
+
+        // Hashing can take a while; only hold a connection for the writes.
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let new_songs: Vec<NewSong> = read.iter().map(|(_, song)| song.clone()).collect();
+            let songs = upsert_songs(conn, &new_songs)?;
+
+            let now = chrono::Utc::now().naive_utc();
+            for (file, song) in &read {
+                diesel::insert_into(local_files::table)
+                    .values((
+                        local_files::path.eq(&file.path),
+                        local_files::song_id.eq(&song.id),
+                        local_files::size.eq(file.size),
+                        local_files::modified_at.eq(file.modified_at),
+                        local_files::scanned_at.eq(now),
+                    ))
+                    .on_conflict(local_files::path)
+                    .do_update()
+                    .set((
+                        local_files::song_id.eq(&song.id),
+                        local_files::size.eq(file.size),
+                        local_files::modified_at.eq(file.modified_at),
+                        local_files::scanned_at.eq(now),
+                    ))
+                    .execute(conn)?;
+            }
+            diesel::delete(local_files::table.filter(local_files::path.eq_any(&removed)))
+                .execute(conn)?;
+
+            let library_ids = local_files::table
+                .select(local_files::song_id)
+                .distinct()
+                .load::<String>(conn)?;
+            add_to_library(conn, user_id, &library_ids)?;
+
+            let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+            let rule_applications = apply_rules(conn, user_id, Some(&song_ids), None, false)?;
+
+            Ok(LocalScan {
+                import: CatalogImport {
+                    songs,
+                    rule_applications,
+                },
+                unchanged: found.len() - changed.len(),
+                removed: removed.len(),
+                failed,
+            })
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??)
+}
+
+/// Local files as a [`MusicProvider`]. Track IDs are content hashes of
+/// files already registered by a scan; there are no accounts or playlists.
+#[derive(Clone)]
+pub struct LocalProvider {
+    pool: DbPool,
+}
+
+impl LocalProvider {
+    pub fn new(pool: DbPool) -> Self {
+        LocalProvider { pool }
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for LocalProvider {
+    fn name(&self) -> &'static str {
+        LOCAL_PROVIDER
+    }
+
+    async fn exchange_code(&self, _auth_request: &AuthRequest) -> Result<ProviderTokens, String> {
+        Err("The local library has no accounts to sign in with".to_string())
+    }
+
+    async fn refresh_tokens(&self, _refresh_token: &str) -> Result<ProviderTokens, String> {
+        Err("The local library has no accounts to sign in with".to_string())
+    }
+
+    async fn profile(&self, _access_token: &str) -> Result<ProviderProfile, String> {
+        Err("The local library has no accounts to sign in with".to_string())
+    }
+
+    async fn tracks(
+        &self,
+        _access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<NewSong>, String> {
+        use schema::songs;
+
+        let song_ids: Vec<String> = track_ids
+            .iter()
+            .map(|hash| song_id(LOCAL_PROVIDER, hash))
+            .collect();
+        let pool = self.pool.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|e| format!("Failed to get connection: {e}"))?;
+            let found = songs::table
+                .filter(songs::id.eq_any(&song_ids))
+                .load::<Song>(&mut conn)
+                .map_err(|e| format!("Failed to load songs: {e}"))?;
+            Ok(found
+                .into_iter()
+                .map(|song| NewSong {
+                    id: song.id,
+                    title: song.title,
+                    artist: song.artist,
+                    album: song.album,
+                    release_year: song.release_year,
+                    duration_ms: song.duration_ms,
+                    isrc: song.isrc,
+                    ..NewSong::default()
+                })
+                .collect())
+        })
+        .await
+        .map_err(|e| format!("Task join error: {e}"))?
+    }
+
+    async fn playlist_track_ids(
+        &self,
+        _access_token: &str,
+        _playlist_id: &str,
+    ) -> Result<Vec<String>, String> {
+        Err("The local library has no playlists".to_string())
+    }
+
+    async fn create_playlist(
+        &self,
+        _access_token: &str,
+        _name: &str,
+        _track_ids: &[String],
+    ) -> Result<String, String> {
+        Err("The local library is read-only".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+
+    fn tag(key: StandardTagKey, value: &str) -> Tag {
+        Tag::new(Some(key), "", Value::String(value.to_string()))
+    }
+
+    fn known(path: &str, size: i64, modified_at: NaiveDateTime) -> LocalFile {
+        LocalFile {
+            path: path.to_string(),
+            song_id: format!("local:{path}"),
+            size,
+            modified_at,
+            scanned_at: modified_at,
+        }
+    }
+
+    /// A second of 8 kHz mono silence, titled in a `LIST` chunk if `title`
+    /// is given. The title must have an even length.
+    fn write_wav(path: &Path, title: Option<&str>) {
+        let samples = 8_000u32;
+        let mut info = Vec::new();
+        if let Some(title) = title {
+            info.extend(b"LIST");
+            info.extend((12 + title.len() as u32).to_le_bytes());
+            info.extend(b"INFOINAM");
+            info.extend((title.len() as u32).to_le_bytes());
+            info.extend(title.as_bytes());
+        }
+        let mut bytes = Vec::new();
+        bytes.extend(b"RIFF");
+        bytes.extend((36 + info.len() as u32 + samples * 2).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(8_000u32.to_le_bytes());
+        bytes.extend(16_000u32.to_le_bytes());
+        bytes.extend(2u16.to_le_bytes());
+        bytes.extend(16u16.to_le_bytes());
+        bytes.extend(info);
+        bytes.extend(b"data");
+        bytes.extend((samples * 2).to_le_bytes());
+        bytes.extend(vec![0u8; samples as usize * 2]);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_song_from_tags() {
+        let tags = vec![
+            tag(StandardTagKey::TrackTitle, "Windowlicker"),
+            tag(StandardTagKey::AlbumArtist, "Aphex Twin"),
+            tag(StandardTagKey::Album, "Windowlicker"),
+            tag(StandardTagKey::Date, "1999-03-22"),
+            tag(StandardTagKey::IdentIsrc, "gbbpw9900001"),
+        ];
+
+        let song = song_from_tags(&tags, "01 track", Some(366_000));
+
+        assert_eq!(song.title, "Windowlicker");
+        assert_eq!(song.artist, "Aphex Twin");
+        assert_eq!(song.release_year, Some(1999));
+        assert_eq!(song.isrc.as_deref(), Some("GBBPW9900001"));
+        assert_eq!(song.duration_ms, Some(366_000));
+    }
+
+    #[test]
+    fn test_song_from_tags_falls_back_to_file_name() {
+        let song = song_from_tags(&[tag(StandardTagKey::TrackTitle, " ")], "01 track", None);
+
+        assert_eq!(song.title, "01 track");
+        assert_eq!(song.artist, "Unknown Artist");
+        assert_eq!(song.album, None);
+    }
+
+    #[test]
+    fn test_plan_rescan() {
+        let time = chrono::NaiveDate::from_ymd_opt(2026, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        let later = time + chrono::Duration::seconds(1);
+        let known = vec![
+            known("same.flac", 10, time),
+            known("touched.flac", 10, time),
+            known("gone.flac", 10, time),
+        ];
+        let found = vec![
+            FoundFile {
+                path: "same.flac".to_string(),
+                size: 10,
+                modified_at: time,
+            },
+            FoundFile {
+                path: "touched.flac".to_string(),
+                size: 10,
+                modified_at: later,
+            },
+            FoundFile {
+                path: "new.flac".to_string(),
+                size: 5,
+                modified_at: time,
+            },
+        ];
+
+        let (changed, removed) = plan_rescan(&known, &found);
+
+        let changed: Vec<&str> = changed.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(changed, vec!["touched.flac", "new.flac"]);
+        assert_eq!(removed, vec!["gone.flac".to_string()]);
+    }
+
+    #[test]
+    fn test_scan_reads_audio_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("Album")).unwrap();
+        write_wav(&root.path().join("Album/01 Silence.WAV"), None);
+        std::fs::write(root.path().join("Album/cover.jpg"), b"not audio").unwrap();
+
+        let found = find_audio_files(root.path());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "Album/01 Silence.WAV");
+        assert_eq!(found[0].size, 44 + 16_000);
+
+        let full_path = root.path().join(&found[0].path);
+        let song = read_audio_metadata(&full_path).unwrap();
+        assert_eq!(song.title, "01 Silence");
+        assert_eq!(song.duration_ms, Some(1_000));
+
+        // Moving a file keeps its identity.
+        let hash = content_hash(&full_path).unwrap();
+        std::fs::rename(&full_path, root.path().join("moved.wav")).unwrap();
+        assert_eq!(content_hash(&root.path().join("moved.wav")).unwrap(), hash);
+        assert_eq!(hash.len(), 64);
+    }
+
+    #[test]
+    fn test_content_hash_ignores_tags() {
+        let root = tempfile::tempdir().unwrap();
+        let plain = root.path().join("plain.wav");
+        let tagged = root.path().join("tagged.wav");
+        write_wav(&plain, None);
+        write_wav(&tagged, Some("Hush"));
+
+        assert_eq!(read_audio_metadata(&tagged).unwrap().title, "Hush");
+        assert_eq!(
+            content_hash(&tagged).unwrap(),
+            content_hash(&plain).unwrap()
+        );
+    }
+}
@@ -218,14 +218,351 @@ async fn spotify_auth(
     }
 }
 
+// Browser login: Spotify redirects back to the callback, which sets the
+// session cookie and sends the browser on to the web app.
+#[get("/auth/spotify/start?<scope>")]
+async fn start_spotify_login(
+    pool: &State<DbPool>,
+    cookies: &rocket::http::CookieJar<'_>,
+    scope: Option<&str>,
+) -> Result<rocket::response::Redirect, rocket::response::status::BadRequest<String>> {
+    match oauth::start_web_login(pool.inner(), scope).await {
+        Ok((url, state)) => {
+            cookies.add_private(oauth::login_state_cookie(&state));
+            Ok(rocket::response::Redirect::to(url))
+        }
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/auth/spotify/callback?<code>&<state>&<error>")]
+async fn spotify_login_callback(
+    pool: &State<DbPool>,
+    cookies: &rocket::http::CookieJar<'_>,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+) -> Result<rocket::response::Redirect, rocket::response::status::BadRequest<String>> {
+    let (code, state) = match (code, state, error) {
+        (_, _, Some(error)) => {
+            return Err(rocket::response::status::BadRequest(format!(
+                "Spotify authorization failed: {error}"
+            )))
+        }
+        (Some(code), Some(state), None) => (code, state),
+        _ => {
+            return Err(rocket::response::status::BadRequest(
+                "Missing code or state".to_string(),
+            ))
+        }
+    };
+
+    let state_cookie = cookies
+        .get_private(oauth::LOGIN_STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove_private(oauth::login_state_cookie(""));
+
+    match oauth::finish_web_login(pool.inner(), state, state_cookie.as_deref(), code).await {
+        Ok(auth_response) => {
+            cookies.add(auth::session_cookie(auth_response.access_token));
+            let web_app_url = env::var("WEB_APP_URL").unwrap_or_else(|_| "/".to_string());
+            Ok(rocket::response::Redirect::to(web_app_url))
+        }
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Spotify scopes each feature needs
+#[get("/auth/spotify/scopes")]
+fn get_spotify_scopes() -> Json<&'static [oauth::FeatureScopes]> {
+    Json(oauth::FEATURE_SCOPES)
+}
+
 // Token refresh endpoint
 #[post("/auth/refresh/<user_id>")]
 async fn refresh_token(
     pool: &State<DbPool>,
     user_id: i32,
+    session: auth::AuthenticatedUser,
 ) -> Result<Json<AuthResponse>, rocket::response::status::BadRequest<String>> {
+    if session.user_id != user_id {
+        return Err(rocket::response::status::BadRequest(
+            "Session does not belong to this user".to_string(),
+        ));
+    }
+
     match refresh_spotify_token(pool.inner(), user_id).await {
-        Ok(auth_response) => Ok(Json(auth_response)),
+        Ok(user) => Ok(Json(AuthResponse {
+            user,
+            access_token: session.token,
+        })),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Account endpoints
+
+#[delete("/me?<export>")]
+async fn delete_me(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    export: Option<bool>,
+) -> Result<Json<account::AccountErasure>, rocket::response::status::BadRequest<String>> {
+    match account::delete_account(pool.inner(), session.user_id, export.unwrap_or(false)).await {
+        Ok(erasure) => Ok(Json(erasure)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/me/spotify/unlink")]
+async fn unlink_spotify_account(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+) -> Result<Json<User>, rocket::response::status::BadRequest<String>> {
+    match unlink_spotify(pool.inner(), session.user_id).await {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/me/spotify/link", data = "<auth_request>")]
+async fn link_spotify_account(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    auth_request: Json<AuthRequest>,
+) -> Result<Json<User>, rocket::response::status::BadRequest<String>> {
+    match relink_spotify(pool.inner(), session.user_id, auth_request.into_inner()).await {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct TimezoneSetting {
+    timezone: Option<String>,
+}
+
+#[put("/me/timezone", data = "<setting>")]
+async fn set_my_timezone(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    setting: Json<TimezoneSetting>,
+) -> Result<Json<User>, rocket::response::status::BadRequest<String>> {
+    match set_user_timezone(pool.inner(), session.user_id, setting.into_inner().timezone).await {
+        Ok(user) => Ok(Json(user)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[put("/me/email", data = "<change>")]
+async fn change_my_email(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    change: Json<email::EmailChange>,
+) -> Result<rocket::response::status::Accepted<()>, rocket::response::status::BadRequest<String>> {
+    match email::request_email_change(pool.inner(), session.user_id, change.into_inner()).await {
+        Ok(()) => Ok(rocket::response::status::Accepted(())),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Target of the link in verification emails
+#[get("/auth/email/verify?<token>")]
+async fn verify_email(
+    pool: &State<DbPool>,
+    token: String,
+) -> Result<rocket::response::Redirect, rocket::response::status::BadRequest<String>> {
+    match email::verify_email(pool.inner(), token).await {
+        Ok(_) => {
+            let web_app_url = env::var("WEB_APP_URL").unwrap_or_else(|_| "/".to_string());
+            Ok(rocket::response::Redirect::to(web_app_url))
+        }
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/me/identities")]
+async fn get_my_identities(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+) -> Result<Json<Vec<identities::Identity>>, rocket::response::status::BadRequest<String>> {
+    match identities::list_identities(pool.inner(), session.user_id).await {
+        Ok(linked) => Ok(Json(linked)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/me/identities/spotify", data = "<auth_request>")]
+async fn link_spotify_identity(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    auth_request: Json<AuthRequest>,
+) -> Result<Json<identities::Identity>, rocket::response::status::BadRequest<String>> {
+    match identities::link_spotify_identity(
+        pool.inner(),
+        session.user_id,
+        auth_request.into_inner(),
+    )
+    .await
+    {
+        Ok(identity) => Ok(Json(identity)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/me/identities/<identity_id>")]
+async fn remove_identity(
+    pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
+    identity_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match identities::remove_identity(pool.inner(), session.user_id, identity_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Identity not found or not owned by user".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/exports/<token>")]
+async fn get_final_export(
+    pool: &State<DbPool>,
+    token: String,
+) -> Result<export::Download<Vec<u8>>, rocket::response::status::BadRequest<String>> {
+    match account::get_final_export(pool.inner(), token).await {
+        Ok(download) => Ok(download),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Share link endpoints
+
+#[post("/users/<user_id>/share-links", data = "<new_link>")]
+async fn create_share_link(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_link: Json<sharing::NewShareLink>,
+) -> Result<Json<sharing::CreatedShareLink>, rocket::response::status::BadRequest<String>> {
+    match sharing::create_share_link(pool.inner(), user_id, new_link.into_inner()).await {
+        Ok(created) => Ok(Json(created)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/share-links")]
+async fn get_share_links(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<sharing::ShareLink>>, rocket::response::status::BadRequest<String>> {
+    match sharing::list_share_links(pool.inner(), user_id).await {
+        Ok(links) => Ok(Json(links)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/share-links/<link_id>")]
+async fn revoke_share_link(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    link_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match sharing::revoke_share_link(pool.inner(), user_id, link_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Share link not found, not owned by user or already revoked".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/shared/<token>")]
+async fn get_shared_collection(
+    pool: &State<DbPool>,
+    token: String,
+) -> Result<Json<sharing::SharedCollection>, rocket::response::status::NotFound<String>> {
+    match sharing::open_share_link(pool.inner(), token).await {
+        Ok(collection) => Ok(Json(collection)),
+        Err(e) => Err(rocket::response::status::NotFound(e)),
+    }
+}
+
+// Workspace endpoints
+
+#[post("/users/<user_id>/workspaces", data = "<new_workspace>")]
+async fn create_workspace(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_workspace: Json<workspaces::NewWorkspace>,
+) -> Result<Json<workspaces::MemberWorkspace>, rocket::response::status::BadRequest<String>> {
+    match workspaces::create_workspace(pool.inner(), user_id, new_workspace.into_inner()).await {
+        Ok(workspace) => Ok(Json(workspace)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/workspaces")]
+async fn get_workspaces(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<workspaces::MemberWorkspace>>, rocket::response::status::BadRequest<String>> {
+    match workspaces::list_workspaces(pool.inner(), user_id).await {
+        Ok(workspaces) => Ok(Json(workspaces)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/workspaces/<workspace_id>/members")]
+async fn get_workspace_members(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    workspace_id: i32,
+) -> Result<Json<Vec<workspaces::WorkspaceMember>>, rocket::response::status::BadRequest<String>> {
+    match workspaces::list_members(pool.inner(), user_id, workspace_id).await {
+        Ok(members) => Ok(Json(members)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post(
+    "/users/<user_id>/workspaces/<workspace_id>/members",
+    data = "<member>"
+)]
+async fn set_workspace_member(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    workspace_id: i32,
+    member: Json<workspaces::MemberRole>,
+) -> Result<Json<workspaces::WorkspaceMember>, rocket::response::status::BadRequest<String>> {
+    match workspaces::set_member_role(pool.inner(), user_id, workspace_id, member.into_inner())
+        .await
+    {
+        Ok(member) => Ok(Json(member)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/workspaces/<workspace_id>/members/<member_id>")]
+async fn remove_workspace_member(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    workspace_id: i32,
+    member_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match workspaces::remove_member(pool.inner(), user_id, workspace_id, member_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "User is not a member of this workspace".to_string(),
+        )),
         Err(e) => Err(rocket::response::status::BadRequest(e)),
     }
 }
@@ -234,10 +571,9 @@ async fn refresh_token(
 #[get("/users/<user_id>/tags")]
 async fn get_user_tags(
     pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
     user_id: i32,
 ) -> Result<Json<Vec<Tag>>, rocket::response::status::BadRequest<String>> {
-    use schema::tags::dsl;
-
     let pool = pool.inner().clone();
     let query_user_id = user_id;
 
@@ -245,11 +581,7 @@ async fn get_user_tags(
         let mut conn = pool
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
-        dsl::tags
-            .filter(dsl::user_id.eq(query_user_id))
-            .order(dsl::name.asc())
-            .load::<Tag>(&mut conn)
-            .map_err(|e| format!("Failed to load tags: {e}"))
+        permissions::visible_tags(&mut conn, query_user_id)
     })
     .await
     {
@@ -264,6 +596,7 @@ async fn get_user_tags(
 #[post("/users/<user_id>/tags", data = "<new_tag>")]
 async fn create_tag(
     pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
     user_id: i32,
     new_tag: Json<NewTag>,
 ) -> Result<Json<Tag>, rocket::response::status::BadRequest<String>> {
@@ -277,10 +610,45 @@ async fn create_tag(
         let mut conn = pool
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
-        diesel::insert_into(dsl::tags)
-            .values(&new_tag_data)
-            .get_result::<Tag>(&mut conn)
-            .map_err(|e| format!("Failed to create tag: {e}"))
+
+        if let Some(workspace_id) = new_tag_data.workspace_id {
+            if new_tag_data.is_public {
+                return Err("Workspace tags can't be made public".to_string());
+            }
+            permissions::authorize_workspace(
+                &mut conn,
+                new_tag_data.user_id,
+                workspace_id,
+                permissions::WorkspaceRole::Admin,
+            )?;
+        }
+        if let Some(parent_id) = new_tag_data.parent_id {
+            let parent = permissions::authorize_tag(
+                &mut conn,
+                new_tag_data.user_id,
+                parent_id,
+                permissions::TagAction::Manage,
+            )
+            .map_err(|_| "Parent tag not found or not owned by user".to_string())?;
+            if parent.workspace_id != new_tag_data.workspace_id {
+                return Err("Parent tag belongs to a different workspace".to_string());
+            }
+        }
+
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let tag = diesel::insert_into(dsl::tags)
+                .values(&new_tag_data)
+                .get_result::<Tag>(conn)
+                .map_err(|e| format!("Failed to create tag: {e}"))?;
+            webhooks::enqueue_event(
+                conn,
+                tag.user_id,
+                webhooks::WebhookEvent::TagCreated,
+                serde_json::json!({ "tag": tag }),
+            )?;
+            Ok(tag)
+        })
+        .map_err(|TransactionError(e)| e)
     })
     .await
     {
@@ -295,6 +663,7 @@ async fn create_tag(
 #[delete("/users/<user_id>/tags/<tag_id>")]
 async fn delete_tag(
     pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
     user_id: i32,
     tag_id: i32,
 ) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
@@ -308,11 +677,27 @@ async fn delete_tag(
         let mut conn = pool
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
-        diesel::delete(
-            dsl::tags.filter(dsl::id.eq(query_tag_id).and(dsl::user_id.eq(query_user_id))),
-        )
-        .execute(&mut conn)
-        .map_err(|e| format!("Failed to delete tag: {e}"))
+        let tag = permissions::authorize_tag(
+            &mut conn,
+            query_user_id,
+            query_tag_id,
+            permissions::TagAction::Manage,
+        )?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let rows_affected = diesel::delete(dsl::tags.find(query_tag_id))
+                .execute(conn)
+                .map_err(|e| format!("Failed to delete tag: {e}"))?;
+            if rows_affected > 0 {
+                webhooks::enqueue_event(
+                    conn,
+                    query_user_id,
+                    webhooks::WebhookEvent::TagDeleted,
+                    serde_json::json!({ "tag": tag }),
+                )?;
+            }
+            Ok(rows_affected)
+        })
+        .map_err(|TransactionError(e)| e)
     })
     .await
     {
@@ -332,12 +717,128 @@ async fn delete_tag(
     }
 }
 
+// Follow and public tag endpoints
+
+#[put("/users/<user_id>/tags/<tag_id>/visibility", data = "<visibility>")]
+async fn set_tag_visibility(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    tag_id: i32,
+    visibility: Json<social::TagVisibility>,
+) -> Result<Json<Tag>, rocket::response::status::BadRequest<String>> {
+    match social::set_tag_visibility(pool.inner(), user_id, tag_id, visibility.into_inner()).await {
+        Ok(tag) => Ok(Json(tag)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/following", data = "<new_follow>")]
+async fn follow_user(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_follow: Json<social::NewFollow>,
+) -> Result<Json<social::Follow>, rocket::response::status::BadRequest<String>> {
+    match social::follow_user(pool.inner(), user_id, new_follow.user_id).await {
+        Ok(follow) => Ok(Json(follow)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/following")]
+async fn get_following(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<social::FollowedUser>>, rocket::response::status::BadRequest<String>> {
+    match social::list_following(pool.inner(), user_id).await {
+        Ok(users) => Ok(Json(users)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/followers")]
+async fn get_followers(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<social::FollowedUser>>, rocket::response::status::BadRequest<String>> {
+    match social::list_followers(pool.inner(), user_id).await {
+        Ok(users) => Ok(Json(users)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/following/<followee_id>")]
+async fn unfollow_user(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    followee_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match social::unfollow_user(pool.inner(), user_id, followee_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Not following this user".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/following/<owner_id>/tags")]
+async fn get_public_tags(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    owner_id: i32,
+) -> Result<Json<Vec<social::PublicTagNode>>, rocket::response::status::BadRequest<String>> {
+    match social::browse_public_tags(pool.inner(), user_id, owner_id).await {
+        Ok(tree) => Ok(Json(tree)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/following/<owner_id>/tags/<tag_id>/songs")]
+async fn get_public_tag_songs(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    owner_id: i32,
+    tag_id: i32,
+) -> Result<Json<sharing::SharedCollection>, rocket::response::status::BadRequest<String>> {
+    match social::public_tag_songs(pool.inner(), user_id, owner_id, tag_id).await {
+        Ok(collection) => Ok(Json(collection)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post(
+    "/users/<user_id>/following/<owner_id>/tags/<tag_id>/fork",
+    data = "<fork>"
+)]
+async fn fork_public_tag(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    owner_id: i32,
+    tag_id: i32,
+    fork: Json<social::ForkTag>,
+) -> Result<Json<social::ForkedTag>, rocket::response::status::BadRequest<String>> {
+    match social::fork_tag(pool.inner(), user_id, owner_id, tag_id, fork.into_inner()).await {
+        Ok(forked) => Ok(Json(forked)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
 // Song tagging endpoints
-#[get("/songs/<song_id>/tags?<user_id>")]
+#[get("/songs/<song_id>/tags?<user_id>&<source>")]
 async fn get_song_tags(
     pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
     song_id: &str,
     user_id: i32,
+    source: Option<TagSource>,
 ) -> Result<Json<Vec<Tag>>, rocket::response::status::BadRequest<String>> {
     use schema::song_tags::dsl;
     use schema::tags;
@@ -351,7 +852,7 @@ async fn get_song_tags(
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
 
-        dsl::song_tags
+        let mut query = dsl::song_tags
             .inner_join(tags::table)
             .filter(
                 dsl::song_id
@@ -359,6 +860,12 @@ async fn get_song_tags(
                     .and(dsl::user_id.eq(query_user_id)),
             )
             .select(tags::all_columns)
+            .into_boxed();
+        if let Some(source) = source {
+            query = query.filter(dsl::source.eq(source));
+        }
+
+        query
             .load::<Tag>(&mut conn)
             .map_err(|e| format!("Failed to load song tags: {e}"))
     })
@@ -375,6 +882,7 @@ async fn get_song_tags(
 #[post("/songs/<song_id>/tags", data = "<new_song_tag>")]
 async fn add_tag_to_song(
     pool: &State<DbPool>,
+    session: auth::AuthenticatedUser,
     song_id: &str,
     new_song_tag: Json<NewSongTag>,
 ) -> Result<Json<SongTag>, rocket::response::status::BadRequest<String>> {
@@ -382,18 +890,34 @@ async fn add_tag_to_song(
 
     let pool = pool.inner().clone();
     let mut new_song_tag_data = new_song_tag.into_inner();
-    // Use the song_id from the URL path, not from the POST body
+    // Use the song_id from the URL path and the user from the session, not
+    // from the POST body
     new_song_tag_data.song_id = song_id.to_string();
+    new_song_tag_data.user_id = session.user_id;
+    if let Err(e) = new_song_tag_data.validate() {
+        return Err(rocket::response::status::BadRequest(e));
+    }
 
     match tokio::task::spawn_blocking(move || {
         let mut conn = pool
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
-        
-        diesel::insert_into(dsl::song_tags)
-            .values(&new_song_tag_data)
-            .get_result::<SongTag>(&mut conn)
-            .map_err(|e| format!("Failed to add tag to song: {e}"))
+        permissions::authorize_tag(
+            &mut conn,
+            new_song_tag_data.user_id,
+            new_song_tag_data.tag_id,
+            permissions::TagAction::Apply,
+        )?;
+
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let song_tag = diesel::insert_into(dsl::song_tags)
+                .values(&new_song_tag_data)
+                .get_result::<SongTag>(conn)
+                .map_err(|e| format!("Failed to add tag to song: {e}"))?;
+            webhooks::enqueue_links_added(conn, song_tag.user_id, std::slice::from_ref(&song_tag))?;
+            Ok(song_tag)
+        })
+        .map_err(|TransactionError(e)| e)
     })
     .await
     {
@@ -405,47 +929,90 @@ async fn add_tag_to_song(
     }
 }
 
-#[delete("/songs/<song_id>/tags/<tag_id>?<user_id>")]
-async fn remove_tag_from_song(
+#[patch("/songs/<song_id>/tags/<tag_id>?<user_id>", data = "<update>")]
+async fn update_song_tag(
     pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
     song_id: &str,
     tag_id: i32,
     user_id: i32,
-) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    update: Json<SongTagUpdate>,
+) -> Result<Json<SongTag>, rocket::response::status::BadRequest<String>> {
     use schema::song_tags::dsl;
 
     let pool = pool.inner().clone();
-    let query_user_id = user_id;
-    let query_tag_id = tag_id;
+    let update_data = update.into_inner();
     let song_id = song_id.to_string();
 
+    if let Err(e) = update_data.validate() {
+        return Err(rocket::response::status::BadRequest(e));
+    }
+
     match tokio::task::spawn_blocking(move || {
         let mut conn = pool
             .get()
             .map_err(|e| format!("Failed to get connection: {e}"))?;
-        
-        diesel::delete(
+
+        diesel::update(
             dsl::song_tags.filter(
                 dsl::song_id
                     .eq(&song_id)
-                    .and(dsl::tag_id.eq(query_tag_id))
-                    .and(dsl::user_id.eq(query_user_id)),
+                    .and(dsl::tag_id.eq(tag_id))
+                    .and(dsl::user_id.eq(user_id)),
             ),
         )
-        .execute(&mut conn)
-        .map_err(|e| format!("Failed to remove tag from song: {e}"))
+        .set(&update_data)
+        .get_result::<SongTag>(&mut conn)
+        .map_err(|e| format!("Failed to update song tag: {e}"))
     })
     .await
     {
-        Ok(Ok(rows_affected)) => {
-            if rows_affected > 0 {
-                Ok(rocket::response::status::NoContent)
-            } else {
-                Err(rocket::response::status::BadRequest(
-                    "Song tag not found or not owned by user".to_string(),
-                ))
-            }
+        Ok(Ok(song_tag)) => Ok(Json(song_tag)),
+        Ok(Err(e)) => Err(rocket::response::status::BadRequest(e)),
+        Err(e) => Err(rocket::response::status::BadRequest(format!(
+            "Task join error: {e}"
+        ))),
+    }
+}
+
+#[get("/users/<user_id>/song-tags?<source>&<tag_id>&<song_id>")]
+async fn get_user_song_tags(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    source: Option<TagSource>,
+    tag_id: Option<i32>,
+    song_id: Option<&str>,
+) -> Result<Json<Vec<SongTag>>, rocket::response::status::BadRequest<String>> {
+    use schema::song_tags::dsl;
+
+    let pool = pool.inner().clone();
+    let song_id = song_id.map(str::to_string);
+
+    match tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let mut query = dsl::song_tags.filter(dsl::user_id.eq(user_id)).into_boxed();
+        if let Some(source) = source {
+            query = query.filter(dsl::source.eq(source));
+        }
+        if let Some(tag_id) = tag_id {
+            query = query.filter(dsl::tag_id.eq(tag_id));
         }
+        if let Some(song_id) = song_id {
+            query = query.filter(dsl::song_id.eq(song_id));
+        }
+
+        query
+            .order(dsl::created_at.desc())
+            .load::<SongTag>(&mut conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))
+    })
+    .await
+    {
+        Ok(Ok(song_tags)) => Ok(Json(song_tags)),
         Ok(Err(e)) => Err(rocket::response::status::BadRequest(e)),
         Err(e) => Err(rocket::response::status::BadRequest(format!(
             "Task join error: {e}"
@@ -453,6 +1020,753 @@ async fn remove_tag_from_song(
     }
 }
 
+#[delete("/songs/<song_id>/tags/<tag_id>?<user_id>")]
+async fn remove_tag_from_song(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    song_id: &str,
+    tag_id: i32,
+    user_id: i32,
+) -> Result<rocket::response::status::NoContent, errors::ApiError> {
+    use schema::song_tags::dsl;
+
+    let pool = pool.inner().clone();
+    let query_user_id = user_id;
+    let query_tag_id = tag_id;
+    let song_id = song_id.to_string();
+
+    let rows_affected = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| errors::ApiError::Internal(format!("Failed to get connection: {e}")))?;
+
+        // Admins of a shared tag remove everyone's links; taggers only their own.
+        let tag = permissions::authorize_tag(
+            &mut conn,
+            query_user_id,
+            query_tag_id,
+            permissions::TagAction::Apply,
+        )?;
+        let role = match tag.workspace_id {
+            Some(workspace_id) => {
+                permissions::workspace_role(&mut conn, query_user_id, workspace_id)
+                    .map_err(errors::ApiError::Internal)?
+            }
+            None => None,
+        };
+        let manage =
+            permissions::is_allowed(query_user_id, &tag, role, permissions::TagAction::Manage);
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let links =
+                dsl::song_tags.filter(dsl::song_id.eq(&song_id).and(dsl::tag_id.eq(query_tag_id)));
+            let rows_affected = if manage {
+                diesel::delete(links).execute(conn)
+            } else {
+                diesel::delete(links.filter(dsl::user_id.eq(query_user_id))).execute(conn)
+            }
+            .map_err(|e| format!("Failed to remove tag from song: {e}"))?;
+            webhooks::enqueue_links_removed(
+                conn,
+                query_user_id,
+                &song_id,
+                query_tag_id,
+                rows_affected,
+            )?;
+            Ok(rows_affected)
+        })
+        .map_err(|TransactionError(e)| errors::ApiError::Internal(e))
+    })
+    .await
+    .map_err(|e| errors::ApiError::Internal(format!("Task join error: {e}")))??;
+
+    if rows_affected > 0 {
+        Ok(rocket::response::status::NoContent)
+    } else {
+        Err(errors::ApiError::BadRequest(
+            "Song tag not found or not owned by user".to_string(),
+        ))
+    }
+}
+
+// Song catalogue endpoints
+#[get("/catalog/songs/<song_id>")]
+async fn get_catalog_song(
+    pool: &State<DbPool>,
+    _session: auth::AuthenticatedUser,
+    song_id: &str,
+) -> Result<Json<Song>, rocket::response::status::BadRequest<String>> {
+    match catalog::get_song(pool.inner(), song_id.to_string()).await {
+        Ok(song) => Ok(Json(song)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[put("/catalog/songs/<song_id>", data = "<song>")]
+async fn put_catalog_song(
+    pool: &State<DbPool>,
+    _session: auth::AuthenticatedUser,
+    song_id: &str,
+    song: Json<NewSong>,
+) -> Result<Json<Song>, rocket::response::status::BadRequest<String>> {
+    let mut song_data = song.into_inner();
+    // Use the song_id from the URL path, not from the PUT body
+    song_data.id = song_id.to_string();
+
+    match catalog::upsert_song(pool.inner(), song_data).await {
+        Ok(song) => Ok(Json(song)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/catalog/sync")]
+async fn sync_catalog(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<catalog::CatalogImport>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::LIBRARY_SYNC_SCOPES).await?;
+
+    match catalog::sync_user_catalog(pool.inner(), user_id).await {
+        Ok(import) => Ok(Json(import)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/catalog/local/scan")]
+async fn scan_local_library(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<local_library::LocalScan>, errors::ApiError> {
+    local_library::scan_local_library(pool.inner(), user_id)
+        .await
+        .map(Json)
+}
+
+#[post("/users/<user_id>/matches/scan")]
+async fn scan_song_matches(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<matching::SongMatch>>, rocket::response::status::BadRequest<String>> {
+    match matching::scan_matches(pool.inner(), user_id).await {
+        Ok(matches) => Ok(Json(matches)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/matches?<status>")]
+async fn get_song_matches(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    status: Option<matching::MatchStatus>,
+) -> Result<Json<Vec<matching::SongMatch>>, rocket::response::status::BadRequest<String>> {
+    match matching::list_matches(pool.inner(), user_id, status).await {
+        Ok(matches) => Ok(Json(matches)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/matches/<match_id>/confirm")]
+async fn confirm_song_match(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    match_id: i32,
+) -> Result<Json<matching::SongMatch>, rocket::response::status::BadRequest<String>> {
+    match matching::set_match_status(
+        pool.inner(),
+        user_id,
+        match_id,
+        matching::MatchStatus::Confirmed,
+    )
+    .await
+    {
+        Ok(song_match) => Ok(Json(song_match)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/matches/<match_id>/reject")]
+async fn reject_song_match(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    match_id: i32,
+) -> Result<Json<matching::SongMatch>, rocket::response::status::BadRequest<String>> {
+    match matching::set_match_status(
+        pool.inner(),
+        user_id,
+        match_id,
+        matching::MatchStatus::Rejected,
+    )
+    .await
+    {
+        Ok(song_match) => Ok(Json(song_match)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/spotify/playlists/<playlist_id>/import")]
+async fn import_spotify_playlist(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    playlist_id: &str,
+) -> Result<Json<catalog::CatalogImport>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::PLAYLIST_IMPORT_SCOPES).await?;
+
+    match catalog::import_spotify_playlist(pool.inner(), user_id, playlist_id).await {
+        Ok(import) => Ok(Json(import)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+// Tag suggestion endpoint
+#[get("/users/<user_id>/suggestions?<song_id>&<limit>&<min_confidence>")]
+async fn get_tag_suggestions(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    song_id: Option<&str>,
+    limit: Option<usize>,
+    min_confidence: Option<f64>,
+) -> Result<Json<Vec<suggestions::SongSuggestions>>, rocket::response::status::BadRequest<String>> {
+    match suggestions::suggest_tags_for_user(
+        pool.inner(),
+        user_id,
+        song_id.map(str::to_string),
+        limit.unwrap_or(suggestions::DEFAULT_SUGGESTION_LIMIT),
+        min_confidence.unwrap_or(suggestions::DEFAULT_MIN_CONFIDENCE),
+    )
+    .await
+    {
+        Ok(results) => Ok(Json(results)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Auto-tagging rule endpoints
+#[get("/users/<user_id>/rules")]
+async fn get_user_rules(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<rules::TagRule>>, rocket::response::status::BadRequest<String>> {
+    match rules::list_rules(pool.inner(), user_id).await {
+        Ok(user_rules) => Ok(Json(user_rules)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/rules", data = "<new_rule>")]
+async fn create_rule(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_rule: Json<rules::RuleRequest>,
+) -> Result<Json<rules::TagRule>, rocket::response::status::BadRequest<String>> {
+    match rules::create_rule(pool.inner(), user_id, new_rule.into_inner()).await {
+        Ok(rule) => Ok(Json(rule)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/rules/<rule_id>")]
+async fn delete_rule(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    rule_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match rules::delete_rule(pool.inner(), user_id, rule_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Rule not found or not owned by user".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/rules/run?<rule_id>&<dry_run>")]
+async fn run_rules(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    rule_id: Option<i32>,
+    dry_run: Option<bool>,
+) -> Result<Json<Vec<rules::RuleApplication>>, rocket::response::status::BadRequest<String>> {
+    match rules::run_rules(pool.inner(), user_id, rule_id, dry_run.unwrap_or(false)).await {
+        Ok(applications) => Ok(Json(applications)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Tag query and smart playlist endpoints
+#[get("/users/<user_id>/songs/query?<q>&<source>")]
+async fn query_songs(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    q: &str,
+    source: Option<TagSource>,
+) -> Result<Json<Vec<tag_query::TagQueryMatch>>, rocket::response::status::BadRequest<String>> {
+    match tag_query::query_songs(pool.inner(), user_id, q.to_string(), source).await {
+        Ok(matches) => Ok(Json(matches)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/playlists")]
+async fn get_user_playlists(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<playlists::Playlist>>, rocket::response::status::BadRequest<String>> {
+    match playlists::list_playlists(pool.inner(), user_id).await {
+        Ok(user_playlists) => Ok(Json(user_playlists)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/playlists", data = "<new_playlist>")]
+async fn create_playlist(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_playlist: Json<playlists::NewPlaylist>,
+) -> Result<Json<playlists::Playlist>, rocket::response::status::BadRequest<String>> {
+    let mut new_playlist_data = new_playlist.into_inner();
+    new_playlist_data.user_id = user_id;
+
+    match playlists::create_playlist(pool.inner(), new_playlist_data).await {
+        Ok(playlist) => Ok(Json(playlist)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/playlists/<playlist_id>")]
+async fn delete_playlist(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match playlists::delete_playlist(pool.inner(), user_id, playlist_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Playlist not found or not owned by user".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/playlists/<playlist_id>/tracks")]
+async fn get_playlist_tracks(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<Json<Vec<tag_query::TagQueryMatch>>, rocket::response::status::BadRequest<String>> {
+    match playlists::playlist_tracks(pool.inner(), user_id, playlist_id).await {
+        Ok(tracks) => Ok(Json(tracks)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/playlists/<playlist_id>/spotify")]
+async fn export_playlist_to_spotify(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<Json<playlists::ProviderPlaylist>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::PLAYLIST_EXPORT_SCOPES).await?;
+
+    match playlists::export_to_spotify(pool.inner(), user_id, playlist_id).await {
+        Ok(exported) => Ok(Json(exported)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+// Import endpoints
+
+// Exports of heavily tagged libraries run to several megabytes.
+const IMPORT_SIZE_LIMIT_MIB: u64 = 20;
+
+#[post(
+    "/users/<user_id>/import?<format>&<strategy>&<dry_run>",
+    data = "<file>"
+)]
+async fn import_user_data(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    format: import::ImportFormat,
+    strategy: Option<import::CollisionStrategy>,
+    dry_run: Option<bool>,
+    file: rocket::Data<'_>,
+) -> Result<Json<import::ImportReport>, rocket::response::status::BadRequest<String>> {
+    use rocket::data::ToByteUnit;
+
+    let body = match file
+        .open(IMPORT_SIZE_LIMIT_MIB.mebibytes())
+        .into_string()
+        .await
+    {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            return Err(rocket::response::status::BadRequest(format!(
+                "Import files are limited to {IMPORT_SIZE_LIMIT_MIB} MiB"
+            )))
+        }
+        Err(e) => {
+            return Err(rocket::response::status::BadRequest(format!(
+                "Failed to read import file: {e}"
+            )))
+        }
+    };
+    let set = match import::parse_import(format, &body) {
+        Ok(set) => set,
+        Err(e) => return Err(rocket::response::status::BadRequest(e)),
+    };
+
+    match import::import_user_data(
+        pool.inner(),
+        user_id,
+        set,
+        strategy.unwrap_or_default(),
+        dry_run.unwrap_or(true),
+    )
+    .await
+    {
+        Ok(report) => Ok(Json(report)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Export endpoints
+
+#[get("/users/<user_id>/export?<format>")]
+async fn export_user_data(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    format: Option<export::ExportFormat>,
+) -> Result<export::Download<export::ExportStream>, rocket::response::status::BadRequest<String>> {
+    let format = format.unwrap_or(export::ExportFormat::Json);
+    match export::export_user_data(pool.inner(), user_id, format).await {
+        Ok(download) => Ok(download),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/export/plays")]
+async fn export_plays(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<export::Download<export::ExportStream>, rocket::response::status::BadRequest<String>> {
+    match export::export_plays(pool.inner(), user_id).await {
+        Ok(download) => Ok(download),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/export/playlist?<format>&<tag_id>&<q>&<playlist_id>")]
+async fn export_playlist_file(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    format: Option<export::PlaylistFileFormat>,
+    tag_id: Option<i32>,
+    q: Option<String>,
+    playlist_id: Option<i32>,
+) -> Result<export::Download<String>, rocket::response::status::BadRequest<String>> {
+    let source = export::PlaylistSource::from_params(tag_id, q, playlist_id)
+        .map_err(rocket::response::status::BadRequest)?;
+    let format = format.unwrap_or(export::PlaylistFileFormat::M3u8);
+
+    match export::export_playlist_file(pool.inner(), user_id, source, format).await {
+        Ok(download) => Ok(download),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/export/dj?<format>&<tag_id>&<q>&<playlist_id>&<music_root>")]
+#[allow(clippy::too_many_arguments)]
+async fn export_dj_crates(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    format: Option<dj_export::DjFormat>,
+    tag_id: Option<i32>,
+    q: Option<String>,
+    playlist_id: Option<i32>,
+    music_root: Option<String>,
+) -> Result<export::Download<Vec<u8>>, rocket::response::status::BadRequest<String>> {
+    let source = export::PlaylistSource::from_params(tag_id, q, playlist_id)
+        .map_err(rocket::response::status::BadRequest)?;
+
+    match dj_export::export_dj_crates(pool.inner(), user_id, source, format, music_root).await {
+        Ok(download) => Ok(download),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Webhook endpoints
+
+#[post("/users/<user_id>/webhooks", data = "<new_webhook>")]
+async fn create_webhook(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    new_webhook: Json<webhooks::NewWebhook>,
+) -> Result<Json<webhooks::CreatedWebhook>, rocket::response::status::BadRequest<String>> {
+    match webhooks::create_webhook(pool.inner(), user_id, new_webhook.into_inner()).await {
+        Ok(created) => Ok(Json(created)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/webhooks")]
+async fn get_webhooks(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<webhooks::Webhook>>, rocket::response::status::BadRequest<String>> {
+    match webhooks::list_webhooks(pool.inner(), user_id).await {
+        Ok(hooks) => Ok(Json(hooks)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/webhooks/<webhook_id>")]
+async fn delete_webhook(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    webhook_id: i32,
+) -> Result<rocket::response::status::NoContent, rocket::response::status::BadRequest<String>> {
+    match webhooks::delete_webhook(pool.inner(), user_id, webhook_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(rocket::response::status::BadRequest(
+            "Webhook not found or not owned by user".to_string(),
+        )),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/webhooks/<webhook_id>/deliveries?<status>")]
+async fn get_webhook_deliveries(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    webhook_id: i32,
+    status: Option<webhooks::DeliveryStatus>,
+) -> Result<Json<Vec<webhooks::WebhookDelivery>>, rocket::response::status::BadRequest<String>> {
+    match webhooks::list_deliveries(pool.inner(), user_id, webhook_id, status).await {
+        Ok(deliveries) => Ok(Json(deliveries)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/webhooks/deliveries/<delivery_id>/redeliver")]
+async fn redeliver_webhook(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    delivery_id: i32,
+) -> Result<Json<webhooks::WebhookDelivery>, rocket::response::status::BadRequest<String>> {
+    match webhooks::redeliver(pool.inner(), user_id, delivery_id).await {
+        Ok(delivery) => Ok(Json(delivery)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+// Now playing endpoints
+
+#[get("/users/<user_id>/now-playing")]
+async fn get_now_playing(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Option<now_playing::NowPlaying>>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::NOW_PLAYING_SCOPES).await?;
+
+    match now_playing::current_track(pool.inner(), user_id).await {
+        Ok(playing) => Ok(Json(playing)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/now-playing/tags", data = "<tag>")]
+async fn tag_now_playing(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    tag: Json<now_playing::CurrentTrackTag>,
+) -> Result<Json<SongTag>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::NOW_PLAYING_SCOPES).await?;
+
+    match now_playing::tag_current_track(pool.inner(), user_id, tag.into_inner()).await {
+        Ok(song_tag) => Ok(Json(song_tag)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+#[delete("/users/<user_id>/now-playing/tags/<tag_id>")]
+async fn untag_now_playing(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    tag_id: i32,
+) -> Result<rocket::response::status::NoContent, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::NOW_PLAYING_SCOPES).await?;
+
+    match now_playing::untag_current_track(pool.inner(), user_id, tag_id).await {
+        Ok(rows_affected) if rows_affected > 0 => Ok(rocket::response::status::NoContent),
+        Ok(_) => Err(errors::ApiError::BadRequest(
+            "Tag not found on the playing track".to_string(),
+        )),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+// Playback endpoints
+
+#[get("/users/<user_id>/playback/devices")]
+async fn get_playback_devices(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<Vec<spotify::SpotifyDevice>>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::PLAYBACK_SCOPES).await?;
+
+    match playback::list_devices(pool.inner(), user_id).await {
+        Ok(devices) => Ok(Json(devices)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/playback", data = "<request>")]
+async fn play_tag_query(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    request: Json<playback::PlaybackRequest>,
+) -> Result<Json<playback::PlaybackStarted>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::PLAYBACK_SCOPES).await?;
+
+    playback::play_tag_query(pool.inner(), user_id, request.into_inner())
+        .await
+        .map(Json)
+}
+
+// Listening history endpoints
+
+#[post("/users/<user_id>/plays/sync")]
+async fn sync_recent_plays(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+) -> Result<Json<usize>, errors::ApiError> {
+    oauth::require_scopes(pool.inner(), user_id, oauth::LISTENING_HISTORY_SCOPES).await?;
+
+    match plays::sync_recent_plays(pool.inner(), user_id).await {
+        Ok(inserted) => Ok(Json(inserted)),
+        Err(e) => Err(errors::ApiError::BadRequest(e)),
+    }
+}
+
+#[post("/users/<user_id>/plays/history", data = "<file>")]
+async fn import_streaming_history(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    file: rocket::Data<'_>,
+) -> Result<Json<plays::HistoryImport>, rocket::response::status::BadRequest<String>> {
+    use rocket::data::ToByteUnit;
+
+    let body = match file
+        .open(IMPORT_SIZE_LIMIT_MIB.mebibytes())
+        .into_string()
+        .await
+    {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => {
+            return Err(rocket::response::status::BadRequest(format!(
+                "History files are limited to {IMPORT_SIZE_LIMIT_MIB} MiB"
+            )))
+        }
+        Err(e) => {
+            return Err(rocket::response::status::BadRequest(format!(
+                "Failed to read history file: {e}"
+            )))
+        }
+    };
+
+    match plays::import_streaming_history(pool.inner(), user_id, &body).await {
+        Ok(import) => Ok(Json(import)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/stats/tags?<from>&<to>&<period>&<tag_id>")]
+async fn get_tag_play_stats(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    from: Option<&str>,
+    to: Option<&str>,
+    period: Option<plays::StatsPeriod>,
+    tag_id: Option<i32>,
+) -> Result<Json<Vec<plays::TagPlayStats>>, rocket::response::status::BadRequest<String>> {
+    let (from, to) = plays::stats_range(from, to).map_err(rocket::response::status::BadRequest)?;
+
+    match plays::tag_play_stats(
+        pool.inner(),
+        user_id,
+        from,
+        to,
+        period.unwrap_or_default(),
+        tag_id,
+    )
+    .await
+    {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
+#[get("/users/<user_id>/timeline?<from>&<to>&<period>&<tz>")]
+async fn get_mood_timeline(
+    pool: &State<DbPool>,
+    _session: auth::AuthorizedUser,
+    user_id: i32,
+    from: Option<&str>,
+    to: Option<&str>,
+    period: Option<plays::StatsPeriod>,
+    tz: Option<String>,
+) -> Result<Json<timeline::MoodTimeline>, rocket::response::status::BadRequest<String>> {
+    let (from, to) = plays::stats_range(from, to).map_err(rocket::response::status::BadRequest)?;
+
+    match timeline::mood_timeline(
+        pool.inner(),
+        user_id,
+        from,
+        to,
+        period.unwrap_or(plays::StatsPeriod::Day),
+        tz,
+    )
+    .await
+    {
+        Ok(timeline) => Ok(Json(timeline)),
+        Err(e) => Err(rocket::response::status::BadRequest(e)),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<rocket::Error>> {
     dotenvy::dotenv().ok();
@@ -465,8 +1779,40 @@ async fn main() -> Result<(), Box<rocket::Error>> {
         .build(manager)
         .expect("Failed to create pool");
 
+    let sync_pool = pool.clone();
+    let webhook_pool = pool.clone();
     let _rocket = rocket::build()
         .manage(pool)
+        .attach(rocket::fairing::AdHoc::on_liftoff("Play sync", |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(plays::play_sync_interval());
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = plays::sync_all_recent_plays(&sync_pool).await {
+                            log::error!("Play sync failed: {e}");
+                        }
+                    }
+                });
+            })
+        }))
+        .attach(rocket::fairing::AdHoc::on_liftoff(
+            "Webhook delivery",
+            |_| {
+                Box::pin(async move {
+                    tokio::spawn(async move {
+                        let mut interval =
+                            tokio::time::interval(webhooks::webhook_delivery_interval());
+                        loop {
+                            interval.tick().await;
+                            if let Err(e) = webhooks::deliver_due(&webhook_pool).await {
+                                log::error!("Webhook delivery failed: {e}");
+                            }
+                        }
+                    });
+                })
+            },
+        ))
         .mount(
             "/",
             routes![
@@ -474,7 +1820,29 @@ async fn main() -> Result<(), Box<rocket::Error>> {
                 health,
                 test_data,
                 spotify_auth,
+                start_spotify_login,
+                spotify_login_callback,
+                get_spotify_scopes,
                 refresh_token,
+                delete_me,
+                unlink_spotify_account,
+                link_spotify_account,
+                set_my_timezone,
+                change_my_email,
+                verify_email,
+                get_my_identities,
+                link_spotify_identity,
+                remove_identity,
+                get_final_export,
+                create_share_link,
+                get_share_links,
+                revoke_share_link,
+                get_shared_collection,
+                create_workspace,
+                get_workspaces,
+                get_workspace_members,
+                set_workspace_member,
+                remove_workspace_member,
                 get_songs,
                 create_song,
                 update_song,
@@ -482,9 +1850,58 @@ async fn main() -> Result<(), Box<rocket::Error>> {
                 get_user_tags,
                 create_tag,
                 delete_tag,
+                set_tag_visibility,
+                follow_user,
+                get_following,
+                get_followers,
+                unfollow_user,
+                get_public_tags,
+                get_public_tag_songs,
+                fork_public_tag,
                 get_song_tags,
                 add_tag_to_song,
-                remove_tag_from_song
+                update_song_tag,
+                get_user_song_tags,
+                remove_tag_from_song,
+                get_catalog_song,
+                put_catalog_song,
+                sync_catalog,
+                scan_local_library,
+                scan_song_matches,
+                get_song_matches,
+                confirm_song_match,
+                reject_song_match,
+                import_spotify_playlist,
+                get_tag_suggestions,
+                get_user_rules,
+                create_rule,
+                delete_rule,
+                run_rules,
+                query_songs,
+                get_user_playlists,
+                create_playlist,
+                delete_playlist,
+                get_playlist_tracks,
+                export_playlist_to_spotify,
+                export_user_data,
+                export_plays,
+                export_playlist_file,
+                export_dj_crates,
+                import_user_data,
+                create_webhook,
+                get_webhooks,
+                delete_webhook,
+                get_webhook_deliveries,
+                redeliver_webhook,
+                get_now_playing,
+                tag_now_playing,
+                untag_now_playing,
+                get_playback_devices,
+                play_tag_query,
+                sync_recent_plays,
+                import_streaming_history,
+                get_tag_play_stats,
+                get_mood_timeline
             ],
         )
         .launch()
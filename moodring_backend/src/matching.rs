@@ -0,0 +1,603 @@
+use chrono::NaiveDateTime;
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{schema, DbPool, Song};
+
+/// Fuzzy matches scoring below this aren't suggested.
+pub const FUZZY_MATCH_THRESHOLD: f64 = 0.8;
+
+// Durations this close count as equal; past the cutoff two entries are
+// treated as different recordings no matter how alike their names are.
+const DURATION_TOLERANCE_MS: i32 = 2_000;
+const DURATION_CUTOFF_MS: i32 = 10_000;
+
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    rocket::FromFormField,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum MatchMethod {
+    /// Both entries carry the same ISRC.
+    Isrc,
+    /// Title, artist and duration are close enough.
+    Fuzzy,
+}
+
+impl MatchMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchMethod::Isrc => "isrc",
+            MatchMethod::Fuzzy => "fuzzy",
+        }
+    }
+}
+
+impl std::str::FromStr for MatchMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "isrc" => Ok(MatchMethod::Isrc),
+            "fuzzy" => Ok(MatchMethod::Fuzzy),
+            other => Err(format!("Unknown match method: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for MatchMethod {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for MatchMethod {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    rocket::FromFormField,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Default,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum MatchStatus {
+    /// Waiting for the user to confirm or reject it.
+    #[default]
+    Suggested,
+    /// The entries are treated as one song.
+    Confirmed,
+    /// Not the same song; never suggested again.
+    Rejected,
+}
+
+impl MatchStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatchStatus::Suggested => "suggested",
+            MatchStatus::Confirmed => "confirmed",
+            MatchStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl std::str::FromStr for MatchStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "suggested" => Ok(MatchStatus::Suggested),
+            "confirmed" => Ok(MatchStatus::Confirmed),
+            "rejected" => Ok(MatchStatus::Rejected),
+            other => Err(format!("Unknown match status: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for MatchStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for MatchStatus {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::song_matches)]
+pub struct SongMatch {
+    pub id: i32,
+    pub user_id: i32,
+    pub song_id: String,
+    pub matched_song_id: String,
+    pub method: MatchMethod,
+    pub confidence: f64,
+    pub status: MatchStatus,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A pair of catalogue entries that look like the same recording, with
+/// `song_id` sorting before `matched_song_id`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MatchCandidate {
+    pub song_id: String,
+    pub matched_song_id: String,
+    pub method: MatchMethod,
+    pub confidence: f64,
+}
+
+/// Lowercased title without bracketed or dash-separated qualifiers, so
+/// "Song (2011 Remaster)" and "Song - Remastered" both become "song".
+pub fn normalize_title(title: &str) -> String {
+    let mut depth = 0usize;
+    let mut plain = String::new();
+    for c in title.to_lowercase().chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => plain.push(c),
+            _ => {}
+        }
+    }
+    let plain = plain.split(" - ").next().unwrap_or_default();
+    words(plain)
+}
+
+/// The lowercased primary artist, dropping featured artists and a leading
+/// "the".
+pub fn normalize_artist(artist: &str) -> String {
+    let lower = artist.to_lowercase();
+    let primary = [
+        ",",
+        "&",
+        ";",
+        " feat.",
+        " feat ",
+        " ft.",
+        " featuring ",
+        " with ",
+    ]
+    .iter()
+    .fold(lower.as_str(), |rest, separator| {
+        rest.split(separator).next().unwrap_or_default()
+    });
+    let primary = words(primary);
+    primary
+        .strip_prefix("the ")
+        .map(str::to_string)
+        .unwrap_or(primary)
+}
+
+fn words(value: &str) -> String {
+    value
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn duration_score(a: Option<i32>, b: Option<i32>) -> Option<f64> {
+    let (Some(a), Some(b)) = (a, b) else {
+        return Some(0.5);
+    };
+    let difference = (a - b).abs();
+    if difference <= DURATION_TOLERANCE_MS {
+        Some(1.0)
+    } else if difference >= DURATION_CUTOFF_MS {
+        None
+    } else {
+        Some(
+            1.0 - f64::from(difference - DURATION_TOLERANCE_MS)
+                / f64::from(DURATION_CUTOFF_MS - DURATION_TOLERANCE_MS),
+        )
+    }
+}
+
+/// How sure we are that two entries are the same recording. Identical
+/// ISRCs settle it; different ISRCs rule it out. Otherwise title, artist
+/// and duration are compared, weighted 50/30/20.
+pub fn match_confidence(a: &Song, b: &Song) -> Option<(MatchMethod, f64)> {
+    match (a.isrc.as_deref(), b.isrc.as_deref()) {
+        (Some(first), Some(second)) if first.eq_ignore_ascii_case(second) => {
+            return Some((MatchMethod::Isrc, 1.0))
+        }
+        (Some(_), Some(_)) => return None,
+        _ => {}
+    }
+
+    let title =
+        strsim::normalized_levenshtein(&normalize_title(&a.title), &normalize_title(&b.title));
+    let artist =
+        strsim::normalized_levenshtein(&normalize_artist(&a.artist), &normalize_artist(&b.artist));
+    if title < 0.8 || artist < 0.7 {
+        return None;
+    }
+    let duration = duration_score(a.duration_ms, b.duration_ms)?;
+
+    let confidence = ((0.5 * title + 0.3 * artist + 0.2 * duration) * 1000.0).round() / 1000.0;
+    (confidence >= FUZZY_MATCH_THRESHOLD).then_some((MatchMethod::Fuzzy, confidence))
+}
+
+/// Every likely pair among `songs`. Pairs in `known` are skipped. Fuzzy
+/// comparison only happens between entries whose primary artist starts
+/// with the same word, to keep big libraries from comparing every pair.
+pub fn find_candidates(songs: &[Song], known: &HashSet<(String, String)>) -> Vec<MatchCandidate> {
+    let mut buckets: BTreeMap<String, Vec<&Song>> = BTreeMap::new();
+    for song in songs {
+        if let Some(isrc) = &song.isrc {
+            buckets
+                .entry(format!("isrc:{}", isrc.to_ascii_uppercase()))
+                .or_default()
+                .push(song);
+        }
+        let artist = normalize_artist(&song.artist);
+        let first_word = artist.split(' ').next().unwrap_or_default();
+        buckets
+            .entry(format!("artist:{first_word}"))
+            .or_default()
+            .push(song);
+    }
+
+    let mut seen = known.clone();
+    let mut candidates = Vec::new();
+    for bucket in buckets.values() {
+        for (index, first) in bucket.iter().enumerate() {
+            for second in &bucket[index + 1..] {
+                let pair = if first.id < second.id {
+                    (first.id.clone(), second.id.clone())
+                } else {
+                    (second.id.clone(), first.id.clone())
+                };
+                if pair.0 == pair.1 || seen.contains(&pair) {
+                    continue;
+                }
+                if let Some((method, confidence)) = match_confidence(first, second) {
+                    seen.insert(pair.clone());
+                    candidates.push(MatchCandidate {
+                        song_id: pair.0,
+                        matched_song_id: pair.1,
+                        method,
+                        confidence,
+                    });
+                }
+            }
+        }
+    }
+    candidates
+}
+
+/// Maps every song in a confirmed match to one representative of its
+/// group, the smallest ID. Matches chain: if A matches B and B matches C,
+/// all three are one song.
+pub fn group_matches(pairs: &[(String, String)]) -> HashMap<String, String> {
+    let mut parent: HashMap<String, String> = HashMap::new();
+
+    fn root(parent: &HashMap<String, String>, id: &str) -> String {
+        let mut current = id.to_string();
+        while let Some(next) = parent.get(&current).filter(|next| **next != current) {
+            current = next.clone();
+        }
+        current
+    }
+
+    for (a, b) in pairs {
+        parent.entry(a.clone()).or_insert_with(|| a.clone());
+        parent.entry(b.clone()).or_insert_with(|| b.clone());
+        let (root_a, root_b) = (root(&parent, a), root(&parent, b));
+        if root_a != root_b {
+            let (keep, merge) = if root_a < root_b {
+                (root_a, root_b)
+            } else {
+                (root_b, root_a)
+            };
+            parent.insert(merge, keep);
+        }
+    }
+
+    let ids: Vec<String> = parent.keys().cloned().collect();
+    ids.into_iter()
+        .map(|id| {
+            let representative = root(&parent, &id);
+            (id, representative)
+        })
+        .collect()
+}
+
+/// The user's confirmed matches as a map from song ID to the ID its group
+/// is known by. Songs without matches aren't in the map.
+pub fn canonical_song_ids(
+    conn: &mut PgConnection,
+    user_id: i32,
+) -> Result<HashMap<String, String>, String> {
+    use schema::song_matches::dsl;
+
+    let pairs = dsl::song_matches
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::status.eq(MatchStatus::Confirmed))
+        .select((dsl::song_id, dsl::matched_song_id))
+        .load::<(String, String)>(conn)
+        .map_err(|e| format!("Failed to load song matches: {e}"))?;
+    Ok(group_matches(&pairs))
+}
+
+/// Looks for duplicates among the songs in the user's library or tags.
+/// ISRC matches are confirmed straight away; fuzzy ones are suggested.
+/// Pairs the user already confirmed or rejected are left alone.
+pub async fn scan_matches(pool: &DbPool, user_id: i32) -> Result<Vec<SongMatch>, String> {
+    use schema::{song_matches, song_tags, songs, user_songs};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let library_ids = user_songs::table
+            .filter(user_songs::user_id.eq(user_id))
+            .select(user_songs::song_id);
+        let tagged_ids = song_tags::table
+            .filter(song_tags::user_id.eq(user_id))
+            .select(song_tags::song_id);
+        let library = songs::table
+            .filter(
+                songs::id
+                    .eq_any(library_ids)
+                    .or(songs::id.eq_any(tagged_ids)),
+            )
+            .load::<Song>(&mut conn)
+            .map_err(|e| format!("Failed to load songs: {e}"))?;
+
+        let known: HashSet<(String, String)> = song_matches::table
+            .filter(song_matches::user_id.eq(user_id))
+            .select((song_matches::song_id, song_matches::matched_song_id))
+            .load::<(String, String)>(&mut conn)
+            .map_err(|e| format!("Failed to load song matches: {e}"))?
+            .into_iter()
+            .collect();
+
+        let rows: Vec<_> = find_candidates(&library, &known)
+            .into_iter()
+            .map(|candidate| {
+                let status = match candidate.method {
+                    MatchMethod::Isrc => MatchStatus::Confirmed,
+                    MatchMethod::Fuzzy => MatchStatus::Suggested,
+                };
+                (
+                    song_matches::user_id.eq(user_id),
+                    song_matches::song_id.eq(candidate.song_id),
+                    song_matches::matched_song_id.eq(candidate.matched_song_id),
+                    song_matches::method.eq(candidate.method),
+                    song_matches::confidence.eq(candidate.confidence),
+                    song_matches::status.eq(status),
+                )
+            })
+            .collect();
+        if rows.is_empty() {
+            return Ok(Vec::new());
+        }
+        diesel::insert_into(song_matches::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .get_results::<SongMatch>(&mut conn)
+            .map_err(|e| format!("Failed to save song matches: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn list_matches(
+    pool: &DbPool,
+    user_id: i32,
+    status: Option<MatchStatus>,
+) -> Result<Vec<SongMatch>, String> {
+    use schema::song_matches::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let mut query = dsl::song_matches
+            .filter(dsl::user_id.eq(user_id))
+            .into_boxed();
+        if let Some(status) = status {
+            query = query.filter(dsl::status.eq(status));
+        }
+        query
+            .order((dsl::confidence.desc(), dsl::id.asc()))
+            .load::<SongMatch>(&mut conn)
+            .map_err(|e| format!("Failed to load song matches: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Confirms or rejects a match. Either decision can be changed later.
+pub async fn set_match_status(
+    pool: &DbPool,
+    user_id: i32,
+    match_id: i32,
+    status: MatchStatus,
+) -> Result<SongMatch, String> {
+    use schema::song_matches::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::update(
+            dsl::song_matches
+                .filter(dsl::id.eq(match_id))
+                .filter(dsl::user_id.eq(user_id)),
+        )
+        .set((
+            dsl::status.eq(status),
+            dsl::updated_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result::<SongMatch>(&mut conn)
+        .optional()
+        .map_err(|e| format!("Failed to update song match: {e}"))?
+        .ok_or_else(|| "Match not found or not owned by user".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, title: &str, artist: &str, duration_ms: Option<i32>) -> Song {
+        let now = chrono::Utc::now().naive_utc();
+        Song {
+            id: id.to_string(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: None,
+            release_year: None,
+            duration_ms,
+            isrc: None,
+            energy: None,
+            valence: None,
+            tempo: None,
+            danceability: None,
+            acousticness: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn with_isrc(mut song: Song, isrc: &str) -> Song {
+        song.isrc = Some(isrc.to_string());
+        song
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize_title("Hey Jude (Remastered 2015)"), "hey jude");
+        assert_eq!(normalize_title("Hey Jude - Remastered 2015"), "hey jude");
+        assert_eq!(normalize_title("Don't Stop Me Now"), "don t stop me now");
+        assert_eq!(normalize_artist("The Beatles"), "beatles");
+        assert_eq!(
+            normalize_artist("Daft Punk feat. Pharrell Williams"),
+            "daft punk"
+        );
+        assert_eq!(normalize_artist("Simon & Garfunkel"), "simon");
+    }
+
+    #[test]
+    fn test_isrc_decides_when_both_have_one() {
+        let a = with_isrc(song("a", "Hey Jude", "The Beatles", None), "GBAYE0601690");
+        let b = with_isrc(song("local:b", "Hey Jude", "Beatles", None), "gbaye0601690");
+        let c = with_isrc(song("c", "Hey Jude", "The Beatles", None), "GBAYE1500001");
+
+        assert_eq!(match_confidence(&a, &b), Some((MatchMethod::Isrc, 1.0)));
+        assert_eq!(match_confidence(&a, &c), None);
+    }
+
+    #[test]
+    fn test_fuzzy_confidence() {
+        let spotify = song(
+            "a",
+            "Hey Jude - Remastered 2015",
+            "The Beatles",
+            Some(431_000),
+        );
+        let local = song("local:b", "Hey Jude", "Beatles", Some(430_500));
+        let live = song("local:c", "Hey Jude", "The Beatles", Some(470_000));
+        let other = song("local:d", "Let It Be", "The Beatles", Some(243_000));
+
+        assert_eq!(
+            match_confidence(&spotify, &local),
+            Some((MatchMethod::Fuzzy, 1.0))
+        );
+        assert_eq!(match_confidence(&spotify, &live), None);
+        assert_eq!(match_confidence(&spotify, &other), None);
+
+        let unknown_duration = song("local:e", "Hey Jude", "The Beatles", None);
+        assert_eq!(
+            match_confidence(&spotify, &unknown_duration),
+            Some((MatchMethod::Fuzzy, 0.9))
+        );
+    }
+
+    #[test]
+    fn test_find_candidates_skips_known_pairs() {
+        let songs = vec![
+            with_isrc(song("b", "Hey Jude", "The Beatles", None), "GBAYE0601690"),
+            with_isrc(song("a", "Hey Jude", "The Beatles", None), "GBAYE0601690"),
+            song("local:c", "Hey Jude", "Beatles", None),
+            song("local:d", "Something", "Someone Else", None),
+        ];
+        let known = HashSet::from([("a".to_string(), "local:c".to_string())]);
+
+        let candidates = find_candidates(&songs, &known);
+
+        let pairs: Vec<(&str, &str, MatchMethod)> = candidates
+            .iter()
+            .map(|c| (c.song_id.as_str(), c.matched_song_id.as_str(), c.method))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("a", "b", MatchMethod::Isrc),
+                ("b", "local:c", MatchMethod::Fuzzy),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_group_matches_is_transitive() {
+        let groups = group_matches(&[
+            ("b".to_string(), "c".to_string()),
+            ("local:x".to_string(), "c".to_string()),
+            ("a".to_string(), "local:x".to_string()),
+            ("d".to_string(), "e".to_string()),
+        ]);
+
+        for id in ["a", "b", "c", "local:x"] {
+            assert_eq!(groups[id], "a");
+        }
+        assert_eq!(groups["d"], "d");
+        assert_eq!(groups["e"], "d");
+    }
+}
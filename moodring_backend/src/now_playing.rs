@@ -0,0 +1,192 @@
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::catalog::upsert_songs;
+use crate::permissions::{authorize_tag, TagAction};
+use crate::spotify::SpotifyClient;
+use crate::webhooks::{enqueue_links_added, enqueue_links_removed};
+use crate::{
+    schema, spotify_access_token_for_user, DbPool, NewSongTag, Song, SongTag, Tag, TransactionError,
+};
+
+/// The track the user is listening to, with the tags they gave it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NowPlaying {
+    pub song: Song,
+    pub is_playing: bool,
+    pub progress_ms: Option<i64>,
+    pub tags: Vec<Tag>,
+}
+
+/// A tag to put on the playing track.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CurrentTrackTag {
+    pub tag_id: i32,
+    pub weight: Option<i32>,
+    pub note: Option<String>,
+}
+
+/// Fetches the user's player state and adds the playing track to the
+/// catalogue. `None` when nothing, or something other than a Spotify
+/// track, is playing.
+async fn playing_song(
+    pool: &DbPool,
+    user_id: i32,
+) -> Result<Option<(Song, bool, Option<i64>)>, String> {
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    let Some(playing) = SpotifyClient::new()
+        .get_currently_playing(&access_token)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let Some(track) = playing.track() else {
+        return Ok(None);
+    };
+
+    let new_song = track.to_new_song(None);
+    let pool = pool.clone();
+    let song = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        upsert_songs(&mut conn, &[new_song])
+            .map_err(|e| format!("Failed to save song: {e}"))?
+            .pop()
+            .ok_or_else(|| "Failed to save song".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    Ok(Some((song, playing.is_playing, playing.progress_ms)))
+}
+
+fn load_song_tags(
+    conn: &mut PgConnection,
+    user_id: i32,
+    song_id: &str,
+) -> Result<Vec<Tag>, String> {
+    use schema::{song_tags, tags};
+
+    song_tags::table
+        .inner_join(tags::table)
+        .filter(song_tags::song_id.eq(song_id))
+        .filter(song_tags::user_id.eq(user_id))
+        .select(tags::all_columns)
+        .order(tags::name.asc())
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load song tags: {e}"))
+}
+
+/// The user's currently playing track joined with its Moodring tags.
+pub async fn current_track(pool: &DbPool, user_id: i32) -> Result<Option<NowPlaying>, String> {
+    let Some((song, is_playing, progress_ms)) = playing_song(pool, user_id).await? else {
+        return Ok(None);
+    };
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let tags = load_song_tags(&mut conn, user_id, &song.id)?;
+        Ok(Some(NowPlaying {
+            song,
+            is_playing,
+            progress_ms,
+            tags,
+        }))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Tags the playing track. Tapping a tag the track already has returns the
+/// existing link unchanged.
+pub async fn tag_current_track(
+    pool: &DbPool,
+    user_id: i32,
+    tag: CurrentTrackTag,
+) -> Result<SongTag, String> {
+    use schema::song_tags;
+
+    let Some((song, _, _)) = playing_song(pool, user_id).await? else {
+        return Err("Nothing is playing".to_string());
+    };
+    let new_song_tag = NewSongTag {
+        user_id,
+        song_id: song.id,
+        tag_id: tag.tag_id,
+        rule_id: None,
+        source: Default::default(),
+        confidence: None,
+        note: tag.note,
+        weight: tag.weight,
+    };
+    new_song_tag.validate()?;
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        authorize_tag(&mut conn, user_id, new_song_tag.tag_id, TagAction::Apply)?;
+
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let added = diesel::insert_into(song_tags::table)
+                .values(&new_song_tag)
+                .on_conflict((song_tags::user_id, song_tags::song_id, song_tags::tag_id))
+                .do_nothing()
+                .get_results::<SongTag>(conn)
+                .map_err(|e| format!("Failed to add tag to song: {e}"))?;
+            enqueue_links_added(conn, user_id, &added)?;
+            Ok(song_tags::table
+                .filter(song_tags::user_id.eq(user_id))
+                .filter(song_tags::song_id.eq(&new_song_tag.song_id))
+                .filter(song_tags::tag_id.eq(new_song_tag.tag_id))
+                .first::<SongTag>(conn)
+                .map_err(|e| format!("Failed to load song tag: {e}"))?)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Removes a tag from the playing track, returning how many links went.
+pub async fn untag_current_track(
+    pool: &DbPool,
+    user_id: i32,
+    tag_id: i32,
+) -> Result<usize, String> {
+    use schema::song_tags::dsl;
+
+    let Some((song, _, _)) = playing_song(pool, user_id).await? else {
+        return Err("Nothing is playing".to_string());
+    };
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        authorize_tag(&mut conn, user_id, tag_id, TagAction::Apply)?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let removed = diesel::delete(
+                dsl::song_tags
+                    .filter(dsl::user_id.eq(user_id))
+                    .filter(dsl::song_id.eq(&song.id))
+                    .filter(dsl::tag_id.eq(tag_id)),
+            )
+            .execute(conn)
+            .map_err(|e| format!("Failed to remove tag from song: {e}"))?;
+            enqueue_links_removed(conn, user_id, &song.id, tag_id, removed)?;
+            Ok(removed)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
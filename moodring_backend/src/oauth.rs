@@ -0,0 +1,509 @@
+use base64::Engine;
+use diesel::prelude::*;
+use rocket::http::{Cookie, SameSite};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::env;
+use std::ops::RangeInclusive;
+
+use crate::auth::{generate_token, hash_token};
+use crate::errors::{ApiError, ErrorBody};
+use crate::{authenticate_user_with_spotify, schema, AuthRequest, AuthResponse, DbPool};
+
+/// Used when `SPOTIFY_REDIRECT_URIS` is not set: the mobile app's deep link.
+pub const DEFAULT_REDIRECT_URI: &str = "moodring://auth";
+
+/// RFC 7636 section 4.1.
+pub const CODE_VERIFIER_LENGTH: RangeInclusive<usize> = 43..=128;
+
+pub const SPOTIFY_AUTHORIZE_URL: &str = "https://accounts.spotify.com/authorize";
+
+/// How long a browser has to come back from Spotify after starting a login.
+pub const LOGIN_STATE_LIFETIME_MINUTES: i64 = 10;
+
+/// Private cookie tying a browser login to the browser that started it.
+pub const LOGIN_STATE_COOKIE: &str = "moodring_login_state";
+
+/// Holds the hash of the login's `state`, so a callback carrying someone
+/// else's state is refused.
+pub fn login_state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build((LOGIN_STATE_COOKIE, hash_token(state)))
+        .path("/auth/spotify")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(rocket::time::Duration::minutes(
+            LOGIN_STATE_LIFETIME_MINUTES,
+        ))
+        .build()
+}
+
+/// Redirect URIs this deployment accepts, from the comma-separated
+/// `SPOTIFY_REDIRECT_URIS` (for example the app deep link, the web callback
+/// and a localhost URI for development). The first entry is the default.
+/// Each must also be registered with the Spotify app.
+pub fn configured_redirect_uris() -> Vec<String> {
+    let uris: Vec<String> = env::var("SPOTIFY_REDIRECT_URIS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|uri| !uri.is_empty())
+        .map(str::to_string)
+        .collect();
+    if uris.is_empty() {
+        vec![DEFAULT_REDIRECT_URI.to_string()]
+    } else {
+        uris
+    }
+}
+
+/// Picks the redirect URI for a token exchange. Spotify requires the one
+/// the authorization request used, so the client names it and it has to be
+/// on the allow-list.
+pub fn select_redirect_uri(requested: Option<&str>, allowed: &[String]) -> Result<String, String> {
+    match requested {
+        Some(uri) if allowed.iter().any(|allowed_uri| allowed_uri == uri) => Ok(uri.to_string()),
+        Some(uri) => Err(format!("Redirect URI {uri} is not allowed")),
+        None => allowed
+            .first()
+            .cloned()
+            .ok_or_else(|| "No redirect URIs configured".to_string()),
+    }
+}
+
+pub fn validate_code_verifier(code_verifier: &str) -> Result<(), String> {
+    if !CODE_VERIFIER_LENGTH.contains(&code_verifier.len()) {
+        return Err(format!(
+            "code_verifier must be between {} and {} characters",
+            CODE_VERIFIER_LENGTH.start(),
+            CODE_VERIFIER_LENGTH.end()
+        ));
+    }
+    if !code_verifier
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '~'))
+    {
+        return Err(
+            "code_verifier may only contain A-Z, a-z, 0-9, '-', '.', '_' and '~'".to_string(),
+        );
+    }
+    Ok(())
+}
+
+pub fn validate_authorization_code(code: &str) -> Result<(), String> {
+    if code.trim().is_empty() {
+        return Err("Authorization code is missing".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyOAuthError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Turns a failed token request into a message the client can act on.
+pub fn token_error_message(status_code: u16, body: &str) -> String {
+    match serde_json::from_str::<SpotifyOAuthError>(body) {
+        Ok(error) if error.error == "invalid_grant" => {
+            "Authorization code expired or was already used; start the Spotify login again"
+                .to_string()
+        }
+        Ok(error) => format!(
+            "Spotify rejected the token request ({}): {}",
+            error.error,
+            error.error_description.unwrap_or_default()
+        ),
+        Err(_) => format!("Spotify API error {status_code}: {body}"),
+    }
+}
+
+/// Spotify scopes the backend relies on.
+pub mod scopes {
+    pub const USER_READ_PRIVATE: &str = "user-read-private";
+    pub const USER_READ_EMAIL: &str = "user-read-email";
+    pub const USER_LIBRARY_READ: &str = "user-library-read";
+    pub const PLAYLIST_READ_PRIVATE: &str = "playlist-read-private";
+    pub const PLAYLIST_MODIFY_PUBLIC: &str = "playlist-modify-public";
+    pub const PLAYLIST_MODIFY_PRIVATE: &str = "playlist-modify-private";
+    pub const USER_READ_RECENTLY_PLAYED: &str = "user-read-recently-played";
+    pub const USER_READ_CURRENTLY_PLAYING: &str = "user-read-currently-playing";
+    pub const USER_READ_PLAYBACK_STATE: &str = "user-read-playback-state";
+    pub const USER_MODIFY_PLAYBACK_STATE: &str = "user-modify-playback-state";
+}
+
+pub const LOGIN_SCOPES: &[&str] = &[scopes::USER_READ_PRIVATE, scopes::USER_READ_EMAIL];
+pub const LIBRARY_SYNC_SCOPES: &[&str] = &[scopes::USER_LIBRARY_READ];
+pub const PLAYLIST_IMPORT_SCOPES: &[&str] = &[scopes::PLAYLIST_READ_PRIVATE];
+pub const PLAYLIST_EXPORT_SCOPES: &[&str] = &[
+    scopes::PLAYLIST_MODIFY_PUBLIC,
+    scopes::PLAYLIST_MODIFY_PRIVATE,
+];
+pub const LISTENING_HISTORY_SCOPES: &[&str] = &[scopes::USER_READ_RECENTLY_PLAYED];
+pub const NOW_PLAYING_SCOPES: &[&str] = &[scopes::USER_READ_CURRENTLY_PLAYING];
+pub const PLAYBACK_SCOPES: &[&str] = &[
+    scopes::USER_READ_PLAYBACK_STATE,
+    scopes::USER_MODIFY_PLAYBACK_STATE,
+];
+
+/// A feature and the Spotify scopes its endpoints need.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FeatureScopes {
+    pub feature: &'static str,
+    pub scopes: &'static [&'static str],
+}
+
+/// Lets clients ask for everything a feature needs up front.
+pub const FEATURE_SCOPES: &[FeatureScopes] = &[
+    FeatureScopes {
+        feature: "login",
+        scopes: LOGIN_SCOPES,
+    },
+    FeatureScopes {
+        feature: "library_sync",
+        scopes: LIBRARY_SYNC_SCOPES,
+    },
+    FeatureScopes {
+        feature: "playlist_import",
+        scopes: PLAYLIST_IMPORT_SCOPES,
+    },
+    FeatureScopes {
+        feature: "playlist_export",
+        scopes: PLAYLIST_EXPORT_SCOPES,
+    },
+    FeatureScopes {
+        feature: "listening_history",
+        scopes: LISTENING_HISTORY_SCOPES,
+    },
+    FeatureScopes {
+        feature: "now_playing",
+        scopes: NOW_PLAYING_SCOPES,
+    },
+    FeatureScopes {
+        feature: "playback",
+        scopes: PLAYBACK_SCOPES,
+    },
+];
+
+pub fn parse_scopes(scope: &str) -> BTreeSet<String> {
+    scope.split_whitespace().map(str::to_string).collect()
+}
+
+/// Builds the error for a user whose grant lacks some of `required`, or
+/// returns `None` when everything is granted. Spotify grants replace each
+/// other, so the scopes to request include those already granted.
+pub fn missing_scopes_error(granted: &str, required: &[&str]) -> Option<ErrorBody> {
+    let granted = parse_scopes(granted);
+    let missing: Vec<String> = required
+        .iter()
+        .filter(|scope| !granted.contains(**scope))
+        .map(|scope| scope.to_string())
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+
+    let request_scopes: BTreeSet<String> = granted.into_iter().chain(missing.clone()).collect();
+    Some(ErrorBody {
+        error: "missing_scopes".to_string(),
+        message: format!(
+            "Spotify access is missing the {} scope(s); authorize Moodring again",
+            missing.join(", ")
+        ),
+        missing_scopes: Some(missing),
+        request_scopes: Some(request_scopes.into_iter().collect::<Vec<_>>().join(" ")),
+    })
+}
+
+/// Fails with a `missing_scopes` error when the user's Spotify grant
+/// doesn't cover `required`. Grants recorded before scopes were stored are
+/// let through and left for Spotify to judge.
+pub async fn require_scopes(
+    pool: &DbPool,
+    user_id: i32,
+    required: &[&str],
+) -> Result<(), ApiError> {
+    use schema::users::dsl;
+
+    let pool = pool.clone();
+
+    let granted = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::users
+            .filter(dsl::id.eq(user_id))
+            .select(dsl::spotify_scopes)
+            .first::<Option<String>>(&mut conn)
+            .map_err(|e| format!("Failed to find user: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    match granted.and_then(|granted| missing_scopes_error(&granted, required)) {
+        Some(body) => Err(ApiError::Forbidden(Json(body))),
+        None => Ok(()),
+    }
+}
+
+/// The S256 code challenge for a PKCE verifier.
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+pub fn authorize_url(
+    client_id: &str,
+    redirect_uri: &str,
+    scope: &str,
+    state: &str,
+    code_challenge: &str,
+) -> Result<String, String> {
+    reqwest::Url::parse_with_params(
+        SPOTIFY_AUTHORIZE_URL,
+        &[
+            ("response_type", "code"),
+            ("client_id", client_id),
+            ("redirect_uri", redirect_uri),
+            ("scope", scope),
+            ("state", state),
+            ("code_challenge_method", "S256"),
+            ("code_challenge", code_challenge),
+        ],
+    )
+    .map(String::from)
+    .map_err(|e| format!("Failed to build authorize URL: {e}"))
+}
+
+/// The browser flow's callback, from `SPOTIFY_WEB_REDIRECT_URI`. It has to
+/// be on the redirect URI allow-list as well.
+pub fn web_redirect_uri() -> Result<String, String> {
+    let uri = env::var("SPOTIFY_WEB_REDIRECT_URI")
+        .map_err(|_| "SPOTIFY_WEB_REDIRECT_URI not set".to_string())?;
+    select_redirect_uri(Some(&uri), &configured_redirect_uris())
+}
+
+/// Starts a browser login: keeps a fresh `state` and PKCE verifier on the
+/// server and returns the Spotify URL to send the browser to, along with the
+/// `state` for [`login_state_cookie`].
+pub async fn start_web_login(
+    pool: &DbPool,
+    extra_scopes: Option<&str>,
+) -> Result<(String, String), String> {
+    use schema::oauth_states::dsl;
+
+    let client_id = env::var("SPOTIFY_CLIENT_ID").map_err(|_| "SPOTIFY_CLIENT_ID not set")?;
+    let redirect_uri = web_redirect_uri()?;
+    let scope: BTreeSet<String> = LOGIN_SCOPES
+        .iter()
+        .map(|scope| scope.to_string())
+        .chain(parse_scopes(extra_scopes.unwrap_or_default()))
+        .collect();
+    let scope = scope.into_iter().collect::<Vec<_>>().join(" ");
+
+    let state = generate_token();
+    let code_verifier = generate_token();
+    let url = authorize_url(
+        &client_id,
+        &redirect_uri,
+        &scope,
+        &state,
+        &pkce_challenge(&code_verifier),
+    )?;
+
+    let state_hash = hash_token(&state);
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let now = chrono::Utc::now().naive_utc();
+        diesel::delete(dsl::oauth_states.filter(dsl::expires_at.le(now)))
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to clear expired logins: {e}"))?;
+        diesel::insert_into(dsl::oauth_states)
+            .values((
+                dsl::state_hash.eq(&state_hash),
+                dsl::code_verifier.eq(&code_verifier),
+                dsl::redirect_uri.eq(&redirect_uri),
+                dsl::expires_at.eq(now + chrono::Duration::minutes(LOGIN_STATE_LIFETIME_MINUTES)),
+            ))
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to start login: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    Ok((url, state))
+}
+
+/// Finishes a browser login. The `state` must match the hash in the
+/// browser's login cookie, so an attacker can't log a victim into the
+/// attacker's account with their own callback URL. It is single-use: it is
+/// consumed before the code exchange, so a replayed callback fails.
+pub async fn finish_web_login(
+    pool: &DbPool,
+    state: String,
+    state_cookie: Option<&str>,
+    code: String,
+) -> Result<AuthResponse, String> {
+    use schema::oauth_states::dsl;
+
+    if state_cookie != Some(hash_token(&state).as_str()) {
+        return Err("Login was started in another browser; start the login again".to_string());
+    }
+
+    let lookup_pool = pool.clone();
+    let (code_verifier, redirect_uri) = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let pending =
+            diesel::delete(dsl::oauth_states.filter(dsl::state_hash.eq(hash_token(&state))))
+                .returning((dsl::code_verifier, dsl::redirect_uri, dsl::expires_at))
+                .get_result::<(String, String, chrono::NaiveDateTime)>(&mut conn)
+                .optional()
+                .map_err(|e| format!("Failed to load login state: {e}"))?;
+        match pending {
+            Some((code_verifier, redirect_uri, expires_at))
+                if expires_at > chrono::Utc::now().naive_utc() =>
+            {
+                Ok((code_verifier, redirect_uri))
+            }
+            _ => Err("Login state is invalid or expired; start the login again".to_string()),
+        }
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    authenticate_user_with_spotify(
+        pool,
+        AuthRequest {
+            code,
+            code_verifier,
+            redirect_uri: Some(redirect_uri),
+        },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_redirect_uri() {
+        let allowed = vec![
+            "moodring://auth".to_string(),
+            "https://app.moodring.example/callback".to_string(),
+        ];
+
+        assert_eq!(
+            select_redirect_uri(None, &allowed).unwrap(),
+            "moodring://auth"
+        );
+        assert_eq!(
+            select_redirect_uri(Some("https://app.moodring.example/callback"), &allowed).unwrap(),
+            "https://app.moodring.example/callback"
+        );
+        assert!(select_redirect_uri(Some("https://evil.example/callback"), &allowed).is_err());
+    }
+
+    #[test]
+    fn test_validate_code_verifier() {
+        assert!(validate_code_verifier(&"a".repeat(43)).is_ok());
+        assert!(validate_code_verifier(&"Az09-._~".repeat(16)).is_ok());
+        assert!(validate_code_verifier(&"a".repeat(42)).is_err());
+        assert!(validate_code_verifier(&"a".repeat(129)).is_err());
+        assert!(validate_code_verifier(&format!("{}+", "a".repeat(43))).is_err());
+    }
+
+    #[test]
+    fn test_missing_scopes_error() {
+        assert_eq!(
+            missing_scopes_error(
+                "user-read-email playlist-read-private",
+                PLAYLIST_IMPORT_SCOPES
+            ),
+            None
+        );
+
+        let error = missing_scopes_error(
+            "user-read-private user-read-email",
+            &[scopes::PLAYLIST_READ_PRIVATE, scopes::USER_LIBRARY_READ],
+        )
+        .unwrap();
+
+        assert_eq!(error.error, "missing_scopes");
+        assert_eq!(
+            error.missing_scopes,
+            Some(vec![
+                "playlist-read-private".to_string(),
+                "user-library-read".to_string()
+            ])
+        );
+        assert_eq!(
+            error.request_scopes.as_deref(),
+            Some("playlist-read-private user-library-read user-read-email user-read-private")
+        );
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Checked against Python's hashlib and base64.urlsafe_b64encode.
+        assert_eq!(
+            pkce_challenge("moodring-test-verifier-0123456789abcdefghijk"),
+            "jfT7ibTlttao4bofWlgI1MTU4XjnQBs-0PKdW9FkUr8"
+        );
+        assert!(validate_code_verifier(&generate_token()).is_ok());
+    }
+
+    #[test]
+    fn test_authorize_url() {
+        let url = authorize_url(
+            "client",
+            "https://api.moodring.example/auth/spotify/callback",
+            "user-read-email user-read-private",
+            "state123",
+            "challenge",
+        )
+        .unwrap();
+
+        assert!(url.starts_with("https://accounts.spotify.com/authorize?response_type=code"));
+        assert!(url.contains(
+            "redirect_uri=https%3A%2F%2Fapi.moodring.example%2Fauth%2Fspotify%2Fcallback"
+        ));
+        assert!(url.contains("scope=user-read-email+user-read-private"));
+        assert!(url.contains("state=state123"));
+        assert!(url.contains("code_challenge_method=S256&code_challenge=challenge"));
+    }
+
+    #[test]
+    fn test_token_error_message() {
+        let expired = token_error_message(
+            400,
+            r#"{"error":"invalid_grant","error_description":"Invalid authorization code"}"#,
+        );
+        assert!(expired.starts_with("Authorization code expired or was already used"));
+
+        let other = token_error_message(
+            400,
+            r#"{"error":"invalid_client","error_description":"Invalid client"}"#,
+        );
+        assert_eq!(
+            other,
+            "Spotify rejected the token request (invalid_client): Invalid client"
+        );
+
+        assert_eq!(
+            token_error_message(502, "Bad Gateway"),
+            "Spotify API error 502: Bad Gateway"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::{schema, Tag};
+
+/// What a workspace member may do, each role including the ones before it.
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum WorkspaceRole {
+    /// Sees the workspace's tags and what they are applied to.
+    Viewer,
+    /// Also applies the tags to songs and removes their own links.
+    Tagger,
+    /// Also creates and deletes tags, removes anyone's links and manages
+    /// members.
+    Admin,
+}
+
+impl WorkspaceRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkspaceRole::Viewer => "viewer",
+            WorkspaceRole::Tagger => "tagger",
+            WorkspaceRole::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for WorkspaceRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(WorkspaceRole::Viewer),
+            "tagger" => Ok(WorkspaceRole::Tagger),
+            "admin" => Ok(WorkspaceRole::Admin),
+            other => Err(format!("Unknown workspace role: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for WorkspaceRole {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for WorkspaceRole {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+/// Something a user wants to do with a tag.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TagAction {
+    View,
+    /// Add or remove the user's own links to songs.
+    Apply,
+    /// Delete the tag, nest tags under it, or remove other users' links.
+    Manage,
+}
+
+impl TagAction {
+    pub fn required_role(&self) -> WorkspaceRole {
+        match self {
+            TagAction::View => WorkspaceRole::Viewer,
+            TagAction::Apply => WorkspaceRole::Tagger,
+            TagAction::Manage => WorkspaceRole::Admin,
+        }
+    }
+}
+
+/// Whether `user_id` may act on `tag`, given their role in the tag's
+/// workspace. Personal tags are only open to their owner, who may do
+/// anything with them.
+pub fn is_allowed(user_id: i32, tag: &Tag, role: Option<WorkspaceRole>, action: TagAction) -> bool {
+    match tag.workspace_id {
+        None => tag.user_id == user_id,
+        Some(_) => role.is_some_and(|role| role >= action.required_role()),
+    }
+}
+
+pub fn workspace_role(
+    conn: &mut PgConnection,
+    user_id: i32,
+    workspace_id: i32,
+) -> Result<Option<WorkspaceRole>, String> {
+    use schema::workspace_members::dsl;
+
+    dsl::workspace_members
+        .filter(dsl::workspace_id.eq(workspace_id))
+        .filter(dsl::user_id.eq(user_id))
+        .select(dsl::role)
+        .first::<WorkspaceRole>(conn)
+        .optional()
+        .map_err(|e| format!("Failed to load workspace membership: {e}"))
+}
+
+/// Fails unless the user has at least `required` in the workspace. Users
+/// outside it are told it doesn't exist.
+pub fn authorize_workspace(
+    conn: &mut PgConnection,
+    user_id: i32,
+    workspace_id: i32,
+    required: WorkspaceRole,
+) -> Result<WorkspaceRole, String> {
+    match workspace_role(conn, user_id, workspace_id)? {
+        None => Err("Workspace not found or user is not a member".to_string()),
+        Some(role) if role < required => Err(format!(
+            "Requires the {} role in this workspace",
+            required.as_str()
+        )),
+        Some(role) => Ok(role),
+    }
+}
+
+/// Loads a tag the user may act on. Tags the user can't see at all get the
+/// same error as tags that don't exist.
+pub fn authorize_tag(
+    conn: &mut PgConnection,
+    user_id: i32,
+    tag_id: i32,
+    action: TagAction,
+) -> Result<Tag, String> {
+    use schema::tags::dsl;
+
+    let not_found = || "Tag not found or not owned by user".to_string();
+    let tag = dsl::tags
+        .find(tag_id)
+        .first::<Tag>(conn)
+        .optional()
+        .map_err(|e| format!("Failed to load tag: {e}"))?
+        .ok_or_else(not_found)?;
+    let role = match tag.workspace_id {
+        Some(workspace_id) => workspace_role(conn, user_id, workspace_id)?,
+        None => None,
+    };
+
+    if is_allowed(user_id, &tag, role, action) {
+        Ok(tag)
+    } else if is_allowed(user_id, &tag, role, TagAction::View) {
+        Err(format!(
+            "Requires the {} role in this tag's workspace",
+            action.required_role().as_str()
+        ))
+    } else {
+        Err(not_found())
+    }
+}
+
+/// IDs of the workspace tags the user can see.
+pub fn workspace_tag_ids(conn: &mut PgConnection, user_id: i32) -> Result<Vec<i32>, String> {
+    use schema::{tags, workspace_members};
+
+    tags::table
+        .inner_join(
+            workspace_members::table.on(workspace_members::workspace_id
+                .nullable()
+                .eq(tags::workspace_id)),
+        )
+        .filter(workspace_members::user_id.eq(user_id))
+        .select(tags::id)
+        .load::<i32>(conn)
+        .map_err(|e| format!("Failed to load workspace tags: {e}"))
+}
+
+/// The user's own tags followed by the workspace tags they can see.
+pub fn visible_tags(conn: &mut PgConnection, user_id: i32) -> Result<Vec<Tag>, String> {
+    use schema::tags::dsl;
+
+    let shared = workspace_tag_ids(conn, user_id)?;
+    dsl::tags
+        .filter(
+            dsl::user_id
+                .eq(user_id)
+                .and(dsl::workspace_id.is_null())
+                .or(dsl::id.eq_any(shared)),
+        )
+        .order((dsl::workspace_id.asc().nulls_first(), dsl::name.asc()))
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load tags: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(user_id: i32, workspace_id: Option<i32>) -> Tag {
+        let now = chrono::Utc::now().naive_utc();
+        Tag {
+            id: 1,
+            user_id,
+            name: "rainy day".to_string(),
+            color: None,
+            created_at: now,
+            updated_at: now,
+            parent_id: None,
+            workspace_id,
+            is_public: false,
+        }
+    }
+
+    #[test]
+    fn test_personal_tags_are_owner_only() {
+        let personal = tag(1, None);
+
+        assert!(is_allowed(1, &personal, None, TagAction::Manage));
+        assert!(!is_allowed(2, &personal, None, TagAction::View));
+        // A role elsewhere doesn't open up someone's personal tags.
+        assert!(!is_allowed(
+            2,
+            &personal,
+            Some(WorkspaceRole::Admin),
+            TagAction::View
+        ));
+    }
+
+    #[test]
+    fn test_workspace_tags_follow_roles() {
+        let shared = tag(1, Some(10));
+
+        assert!(!is_allowed(2, &shared, None, TagAction::View));
+        assert!(is_allowed(
+            2,
+            &shared,
+            Some(WorkspaceRole::Viewer),
+            TagAction::View
+        ));
+        assert!(!is_allowed(
+            2,
+            &shared,
+            Some(WorkspaceRole::Viewer),
+            TagAction::Apply
+        ));
+        assert!(is_allowed(
+            2,
+            &shared,
+            Some(WorkspaceRole::Tagger),
+            TagAction::Apply
+        ));
+        assert!(!is_allowed(
+            2,
+            &shared,
+            Some(WorkspaceRole::Tagger),
+            TagAction::Manage
+        ));
+        assert!(is_allowed(
+            2,
+            &shared,
+            Some(WorkspaceRole::Admin),
+            TagAction::Manage
+        ));
+        // Creating a workspace tag doesn't keep rights to it after leaving.
+        assert!(!is_allowed(1, &shared, None, TagAction::View));
+    }
+
+    #[test]
+    fn test_role_order_and_names() {
+        assert!(WorkspaceRole::Viewer < WorkspaceRole::Tagger);
+        assert!(WorkspaceRole::Tagger < WorkspaceRole::Admin);
+        for role in [
+            WorkspaceRole::Viewer,
+            WorkspaceRole::Tagger,
+            WorkspaceRole::Admin,
+        ] {
+            assert_eq!(role.as_str().parse::<WorkspaceRole>(), Ok(role));
+        }
+    }
+}
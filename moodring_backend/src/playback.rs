@@ -0,0 +1,214 @@
+use rand::Rng;
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::errors::{ApiError, ErrorBody};
+use crate::identities::SPOTIFY_PROVIDER;
+use crate::playlist_gen::seeded_shuffle;
+use crate::providers::parse_song_id;
+use crate::spotify::{SpotifyClient, SpotifyDevice, SpotifyPlayerError};
+use crate::tag_query::{self, TagQueryMatch};
+use crate::{spotify_access_token_for_user, DbPool};
+
+const DEFAULT_PLAYBACK_LIMIT: usize = 50;
+// Queueing takes one request per track, so mixes are kept short.
+const MAX_PLAYBACK_LIMIT: usize = 100;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum PlaybackMode {
+    /// Replace what is playing with the mix.
+    #[default]
+    Play,
+    /// Add the mix after what is already queued.
+    Queue,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PlaybackRequest {
+    /// Tag expression, as accepted by the query endpoint.
+    pub q: String,
+    #[serde(default)]
+    pub mode: PlaybackMode,
+    /// Plays on this device instead of the active one.
+    pub device_id: Option<String>,
+    #[serde(default)]
+    pub shuffle: bool,
+    /// Makes a shuffle repeatable. Implies `shuffle`.
+    pub seed: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PlaybackStarted {
+    pub mode: PlaybackMode,
+    pub track_ids: Vec<String>,
+    /// Matches with no Spotify entry to play.
+    pub skipped: usize,
+    /// The seed the mix was shuffled with, to play the same order again.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// Spotify track IDs for the matches, in order. A match known by another
+/// provider's ID is played through a Spotify entry confirmed to be the same
+/// song, if there is one. Returns the IDs and how many matches had none.
+pub fn playable_track_ids(matches: &[TagQueryMatch]) -> (Vec<String>, usize) {
+    let mut track_ids = Vec::new();
+    let mut skipped = 0;
+    for song_match in matches {
+        let spotify_id = std::iter::once(&song_match.song_id)
+            .chain(&song_match.matched_song_ids)
+            .find(|song_id| parse_song_id(song_id).0 == SPOTIFY_PROVIDER);
+        match spotify_id {
+            Some(song_id) => track_ids.push(song_id.clone()),
+            None => skipped += 1,
+        }
+    }
+    (track_ids, skipped)
+}
+
+/// Maps a failed player command to an error with a stable code the client
+/// can act on.
+pub fn playback_error(error: SpotifyPlayerError) -> ApiError {
+    let body = |code: &str| Json(ErrorBody::new(code, error.message.clone()));
+    match (error.status, error.reason.as_deref()) {
+        (_, Some("NO_ACTIVE_DEVICE")) => ApiError::Conflict(body("no_active_device")),
+        (_, Some("PREMIUM_REQUIRED")) => ApiError::Forbidden(body("premium_required")),
+        (_, Some("DEVICE_NOT_CONTROLLABLE" | "REMOTE_CONTROL_DISALLOW")) => {
+            ApiError::Conflict(body("device_not_controllable"))
+        }
+        (404, _) => ApiError::NotFound(body("device_not_found")),
+        (429, _) | (_, Some("RATE_LIMITED")) => ApiError::BadGateway(body("rate_limited")),
+        _ => ApiError::BadGateway(body("playback_failed")),
+    }
+}
+
+pub async fn list_devices(pool: &DbPool, user_id: i32) -> Result<Vec<SpotifyDevice>, String> {
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    SpotifyClient::new().get_devices(&access_token).await
+}
+
+/// Evaluates a tag query and plays or queues the matching tracks on the
+/// user's Spotify device, best matches first unless shuffled.
+pub async fn play_tag_query(
+    pool: &DbPool,
+    user_id: i32,
+    request: PlaybackRequest,
+) -> Result<PlaybackStarted, ApiError> {
+    let matches = tag_query::query_songs(pool, user_id, request.q, None).await?;
+    let (mut track_ids, skipped) = playable_track_ids(&matches);
+    if track_ids.is_empty() {
+        return Err(ApiError::BadRequest(
+            "No Spotify tracks match the query".to_string(),
+        ));
+    }
+
+    let seed = request
+        .seed
+        .or_else(|| request.shuffle.then(|| rand::thread_rng().gen()));
+    if let Some(seed) = seed {
+        seeded_shuffle(&mut track_ids, seed, String::as_str);
+    }
+    track_ids.truncate(
+        request
+            .limit
+            .unwrap_or(DEFAULT_PLAYBACK_LIMIT)
+            .clamp(1, MAX_PLAYBACK_LIMIT),
+    );
+
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    let client = SpotifyClient::new();
+    let device_id = request.device_id.as_deref();
+    match request.mode {
+        PlaybackMode::Play => client
+            .start_playback(&access_token, device_id, &track_ids)
+            .await
+            .map_err(playback_error)?,
+        PlaybackMode::Queue => {
+            for track_id in &track_ids {
+                client
+                    .add_to_queue(&access_token, device_id, track_id)
+                    .await
+                    .map_err(playback_error)?;
+            }
+        }
+    }
+
+    Ok(PlaybackStarted {
+        mode: request.mode,
+        track_ids,
+        skipped,
+        seed,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song_match(song_id: &str, matched_song_ids: &[&str]) -> TagQueryMatch {
+        TagQueryMatch {
+            song_id: song_id.to_string(),
+            score: 0,
+            last_tagged_at: chrono::Utc::now().naive_utc(),
+            song: None,
+            matched_song_ids: matched_song_ids.iter().map(|id| id.to_string()).collect(),
+        }
+    }
+
+    fn error_code(error: ApiError) -> (u16, String) {
+        match error {
+            ApiError::Forbidden(body) => (403, body.into_inner().error),
+            ApiError::NotFound(body) => (404, body.into_inner().error),
+            ApiError::Conflict(body) => (409, body.into_inner().error),
+            ApiError::BadGateway(body) => (502, body.into_inner().error),
+            ApiError::BadRequest(message) => (400, message),
+            ApiError::Internal(message) => (500, message),
+        }
+    }
+
+    fn player_error(status: u16, reason: Option<&str>) -> SpotifyPlayerError {
+        SpotifyPlayerError {
+            status,
+            reason: reason.map(str::to_string),
+            message: "Spotify API error".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_playable_track_ids_prefers_spotify_entries() {
+        let matches = vec![
+            song_match("t1", &[]),
+            song_match("local:abc", &["t2"]),
+            song_match("local:def", &[]),
+        ];
+
+        assert_eq!(
+            playable_track_ids(&matches),
+            (vec!["t1".to_string(), "t2".to_string()], 1)
+        );
+    }
+
+    #[test]
+    fn test_playback_error_codes() {
+        assert_eq!(
+            error_code(playback_error(player_error(404, Some("NO_ACTIVE_DEVICE")))),
+            (409, "no_active_device".to_string())
+        );
+        assert_eq!(
+            error_code(playback_error(player_error(403, Some("PREMIUM_REQUIRED")))),
+            (403, "premium_required".to_string())
+        );
+        assert_eq!(
+            error_code(playback_error(player_error(404, None))),
+            (404, "device_not_found".to_string())
+        );
+        assert_eq!(
+            error_code(playback_error(player_error(500, None))),
+            (502, "playback_failed".to_string())
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Ordering and trimming of tag query results into playlists. Everything
+//! here works on already evaluated matches, without the database.
+
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+
+use crate::tag_query::TagQueryMatch;
+
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Default,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum PlaylistOrder {
+    /// Highest tag weights first, then most recently tagged.
+    #[default]
+    Weight,
+    /// Most recently tagged first.
+    Added,
+    /// Seeded shuffle; see [`seeded_shuffle`].
+    Shuffle,
+    /// Calmest first. Songs without an energy value go last.
+    EnergyUp,
+    /// Most energetic first. Songs without an energy value go last.
+    EnergyDown,
+}
+
+impl PlaylistOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaylistOrder::Weight => "weight",
+            PlaylistOrder::Added => "added",
+            PlaylistOrder::Shuffle => "shuffle",
+            PlaylistOrder::EnergyUp => "energy_up",
+            PlaylistOrder::EnergyDown => "energy_down",
+        }
+    }
+}
+
+impl std::str::FromStr for PlaylistOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weight" => Ok(PlaylistOrder::Weight),
+            "added" => Ok(PlaylistOrder::Added),
+            "shuffle" => Ok(PlaylistOrder::Shuffle),
+            "energy_up" => Ok(PlaylistOrder::EnergyUp),
+            "energy_down" => Ok(PlaylistOrder::EnergyDown),
+            other => Err(format!("Unknown playlist order: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for PlaylistOrder {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for PlaylistOrder {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+/// How to turn query matches into a playlist.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct PlaylistOptions {
+    #[serde(default)]
+    pub ordering: PlaylistOrder,
+    /// Seed for [`PlaylistOrder::Shuffle`]; no seed shuffles as seed 0.
+    pub seed: Option<i64>,
+    /// Moves tracks forward so the same artist doesn't play twice in a
+    /// row, as far as later tracks allow.
+    #[serde(default)]
+    pub no_repeat_artist: bool,
+    pub max_tracks: Option<i32>,
+    /// Tracks are added in order while they fit. Tracks of unknown length
+    /// are left out when this is set.
+    pub max_duration_ms: Option<i64>,
+}
+
+impl PlaylistOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_tracks.is_some_and(|max| max < 1) {
+            return Err("max_tracks must be at least 1".to_string());
+        }
+        if self.max_duration_ms.is_some_and(|max| max < 1) {
+            return Err("max_duration_ms must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Shuffles by hashing each song ID with the seed. The same seed always
+/// gives the same order, and adding or removing a song leaves the others
+/// in the same relative order.
+pub fn seeded_shuffle<T>(items: &mut [T], seed: u64, song_id: impl Fn(&T) -> &str) {
+    items.sort_by_cached_key(|item| {
+        let mut hasher = Sha256::new();
+        hasher.update(seed.to_be_bytes());
+        hasher.update(song_id(item).as_bytes());
+        hasher.finalize()
+    });
+}
+
+fn energy(track: &TagQueryMatch) -> Option<f64> {
+    track.song.as_ref().and_then(|song| song.energy)
+}
+
+fn artist(track: &TagQueryMatch) -> Option<String> {
+    track
+        .song
+        .as_ref()
+        .map(|song| song.artist.trim().to_lowercase())
+}
+
+/// Orders tracks by energy, keeping those without one at the end in their
+/// current order.
+fn sort_by_energy(tracks: &mut [TagQueryMatch], ascending: bool) {
+    tracks.sort_by(|a, b| match (energy(a), energy(b)) {
+        (Some(a), Some(b)) if ascending => a.total_cmp(&b),
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+}
+
+/// Pulls forward the nearest later track by another artist whenever two
+/// neighbours share one. Tracks without metadata never clash.
+pub fn spread_artists(tracks: &mut [TagQueryMatch]) {
+    for i in 1..tracks.len() {
+        let previous = artist(&tracks[i - 1]);
+        if previous.is_none() || artist(&tracks[i]) != previous {
+            continue;
+        }
+        if let Some(offset) = tracks[i + 1..]
+            .iter()
+            .position(|track| artist(track) != previous)
+        {
+            tracks[i..=i + 1 + offset].rotate_right(1);
+        }
+    }
+}
+
+/// Keeps tracks in order while they fit within the limits.
+pub fn apply_limits(
+    tracks: Vec<TagQueryMatch>,
+    max_tracks: Option<i32>,
+    max_duration_ms: Option<i64>,
+) -> Vec<TagQueryMatch> {
+    let max_tracks = max_tracks.map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(0));
+    let mut total_ms = 0i64;
+    let mut kept = Vec::new();
+    for track in tracks {
+        if kept.len() >= max_tracks {
+            break;
+        }
+        if let Some(max_duration_ms) = max_duration_ms {
+            let Some(duration_ms) = track.song.as_ref().and_then(|song| song.duration_ms) else {
+                continue;
+            };
+            if total_ms + i64::from(duration_ms) > max_duration_ms {
+                continue;
+            }
+            total_ms += i64::from(duration_ms);
+        }
+        kept.push(track);
+    }
+    kept
+}
+
+/// Orders query matches and trims them to the option's limits. Matches are
+/// expected in query order, i.e. by weight.
+pub fn arrange(mut tracks: Vec<TagQueryMatch>, options: &PlaylistOptions) -> Vec<TagQueryMatch> {
+    match options.ordering {
+        PlaylistOrder::Weight => {}
+        PlaylistOrder::Added => tracks.sort_by_key(|track| std::cmp::Reverse(track.last_tagged_at)),
+        PlaylistOrder::Shuffle => {
+            let seed = options.seed.unwrap_or(0) as u64;
+            seeded_shuffle(&mut tracks, seed, |track| track.song_id.as_str());
+        }
+        PlaylistOrder::EnergyUp => sort_by_energy(&mut tracks, true),
+        PlaylistOrder::EnergyDown => sort_by_energy(&mut tracks, false),
+    }
+    if options.no_repeat_artist {
+        spread_artists(&mut tracks);
+    }
+    apply_limits(tracks, options.max_tracks, options.max_duration_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Song;
+    use chrono::NaiveDateTime;
+
+    fn at(minute: u32) -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 1)
+            .unwrap()
+            .and_hms_opt(12, minute, 0)
+            .unwrap()
+    }
+
+    fn track(
+        song_id: &str,
+        artist: &str,
+        energy: Option<f64>,
+        duration_ms: Option<i32>,
+    ) -> TagQueryMatch {
+        TagQueryMatch {
+            song_id: song_id.to_string(),
+            score: 0,
+            last_tagged_at: at(0),
+            song: Some(Song {
+                id: song_id.to_string(),
+                title: song_id.to_string(),
+                artist: artist.to_string(),
+                album: None,
+                release_year: None,
+                duration_ms,
+                isrc: None,
+                energy,
+                valence: None,
+                tempo: None,
+                danceability: None,
+                acousticness: None,
+                created_at: at(0),
+                updated_at: at(0),
+            }),
+            matched_song_ids: Vec::new(),
+        }
+    }
+
+    fn ids(tracks: &[TagQueryMatch]) -> Vec<&str> {
+        tracks.iter().map(|track| track.song_id.as_str()).collect()
+    }
+
+    fn options(ordering: PlaylistOrder) -> PlaylistOptions {
+        PlaylistOptions {
+            ordering,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_order_by_added() {
+        let mut older = track("a", "A", None, None);
+        older.last_tagged_at = at(1);
+        let mut newer = track("b", "B", None, None);
+        newer.last_tagged_at = at(2);
+
+        let arranged = arrange(vec![older, newer], &options(PlaylistOrder::Added));
+
+        assert_eq!(ids(&arranged), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_shuffle_is_stable() {
+        let tracks: Vec<TagQueryMatch> = (0..12)
+            .map(|i| track(&format!("t{i}"), "A", None, None))
+            .collect();
+        let mut shuffle = options(PlaylistOrder::Shuffle);
+        shuffle.seed = Some(42);
+
+        let first = arrange(tracks.clone(), &shuffle);
+        let mut reversed = tracks.clone();
+        reversed.reverse();
+        let second = arrange(reversed, &shuffle);
+        shuffle.seed = Some(43);
+        let other_seed = arrange(tracks.clone(), &shuffle);
+
+        assert_eq!(ids(&first), ids(&second));
+        assert_ne!(ids(&first), ids(&other_seed));
+        assert_ne!(ids(&first), ids(&tracks));
+    }
+
+    #[test]
+    fn test_shuffle_keeps_relative_order_when_songs_change() {
+        let tracks: Vec<TagQueryMatch> = (0..12)
+            .map(|i| track(&format!("t{i}"), "A", None, None))
+            .collect();
+        let shuffle = options(PlaylistOrder::Shuffle);
+
+        let full = arrange(tracks.clone(), &shuffle);
+        let fewer = arrange(tracks[1..].to_vec(), &shuffle);
+
+        let without_first: Vec<&str> = ids(&full).into_iter().filter(|id| *id != "t0").collect();
+        assert_eq!(without_first, ids(&fewer));
+    }
+
+    #[test]
+    fn test_energy_ramps() {
+        let tracks = vec![
+            track("mid", "A", Some(0.5), None),
+            track("unknown", "B", None, None),
+            track("high", "C", Some(0.9), None),
+            track("low", "D", Some(0.1), None),
+        ];
+
+        assert_eq!(
+            ids(&arrange(tracks.clone(), &options(PlaylistOrder::EnergyUp))),
+            vec!["low", "mid", "high", "unknown"]
+        );
+        assert_eq!(
+            ids(&arrange(tracks, &options(PlaylistOrder::EnergyDown))),
+            vec!["high", "mid", "low", "unknown"]
+        );
+    }
+
+    #[test]
+    fn test_spread_artists() {
+        let mut tracks = vec![
+            track("a1", "Alpha", None, None),
+            track("a2", "alpha ", None, None),
+            track("a3", "Alpha", None, None),
+            track("b1", "Beta", None, None),
+            track("c1", "Gamma", None, None),
+        ];
+
+        spread_artists(&mut tracks);
+
+        assert_eq!(ids(&tracks), vec!["a1", "b1", "a2", "c1", "a3"]);
+    }
+
+    #[test]
+    fn test_spread_artists_leaves_unavoidable_repeats() {
+        let mut tracks = vec![
+            track("b1", "Beta", None, None),
+            track("a1", "Alpha", None, None),
+            track("a2", "Alpha", None, None),
+        ];
+
+        spread_artists(&mut tracks);
+
+        assert_eq!(ids(&tracks), vec!["b1", "a1", "a2"]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let tracks = vec![
+            track("long", "A", None, Some(300_000)),
+            track("unknown", "B", None, None),
+            track("medium", "C", None, Some(200_000)),
+            track("short", "D", None, Some(100_000)),
+        ];
+
+        assert_eq!(
+            ids(&apply_limits(tracks.clone(), None, Some(400_000))),
+            vec!["long", "short"]
+        );
+        assert_eq!(
+            ids(&apply_limits(tracks, Some(2), None)),
+            vec!["long", "unknown"]
+        );
+    }
+
+    #[test]
+    fn test_validate_options() {
+        assert!(PlaylistOptions::default().validate().is_ok());
+        assert!(PlaylistOptions {
+            max_tracks: Some(0),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
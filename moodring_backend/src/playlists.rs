@@ -0,0 +1,243 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::playlist_gen::{self, PlaylistOptions, PlaylistOrder};
+use crate::providers::{parse_song_id, MusicProvider, SpotifyProvider};
+use crate::tag_query::{self, TagQueryMatch};
+use crate::{schema, spotify_access_token_for_user, DbPool};
+
+/// A saved tag query whose tracks are re-evaluated on every read.
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::playlists)]
+pub struct Playlist {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    pub query: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub ordering: PlaylistOrder,
+    pub seed: Option<i64>,
+    pub no_repeat_artist: bool,
+    pub max_tracks: Option<i32>,
+    pub max_duration_ms: Option<i64>,
+}
+
+impl Playlist {
+    pub fn options(&self) -> PlaylistOptions {
+        PlaylistOptions {
+            ordering: self.ordering,
+            seed: self.seed,
+            no_repeat_artist: self.no_repeat_artist,
+            max_tracks: self.max_tracks,
+            max_duration_ms: self.max_duration_ms,
+        }
+    }
+
+    /// Evaluates the playlist's query and arranges the matches.
+    pub fn tracks(&self, conn: &mut PgConnection) -> Result<Vec<TagQueryMatch>, String> {
+        let matches = tag_query::evaluate(conn, self.user_id, &self.query, None)?;
+        Ok(playlist_gen::arrange(matches, &self.options()))
+    }
+
+    /// Like [`Playlist::tracks`], leaving workspace tags out; see
+    /// [`tag_query::evaluate_personal`].
+    pub fn personal_tracks(&self, conn: &mut PgConnection) -> Result<Vec<TagQueryMatch>, String> {
+        let matches = tag_query::evaluate_personal(conn, self.user_id, &self.query)?;
+        Ok(playlist_gen::arrange(matches, &self.options()))
+    }
+}
+
+#[derive(Insertable, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::playlists)]
+pub struct NewPlaylist {
+    #[serde(default)]
+    pub user_id: i32,
+    pub name: String,
+    pub query: String,
+    #[serde(default)]
+    pub ordering: PlaylistOrder,
+    pub seed: Option<i64>,
+    #[serde(default)]
+    pub no_repeat_artist: bool,
+    pub max_tracks: Option<i32>,
+    pub max_duration_ms: Option<i64>,
+}
+
+impl NewPlaylist {
+    pub fn options(&self) -> PlaylistOptions {
+        PlaylistOptions {
+            ordering: self.ordering,
+            seed: self.seed,
+            no_repeat_artist: self.no_repeat_artist,
+            max_tracks: self.max_tracks,
+            max_duration_ms: self.max_duration_ms,
+        }
+    }
+}
+
+pub async fn list_playlists(pool: &DbPool, user_id: i32) -> Result<Vec<Playlist>, String> {
+    use schema::playlists::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::playlists
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::name.asc())
+            .load::<Playlist>(&mut conn)
+            .map_err(|e| format!("Failed to load playlists: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Saves a playlist after checking its query parses and only names the
+/// user's own tags.
+pub async fn create_playlist(pool: &DbPool, new_playlist: NewPlaylist) -> Result<Playlist, String> {
+    use schema::playlists::dsl;
+
+    new_playlist.options().validate()?;
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        tag_query::evaluate(&mut conn, new_playlist.user_id, &new_playlist.query, None)?;
+        diesel::insert_into(dsl::playlists)
+            .values(&new_playlist)
+            .get_result::<Playlist>(&mut conn)
+            .map_err(|e| format!("Failed to create playlist: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn delete_playlist(
+    pool: &DbPool,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<usize, String> {
+    use schema::playlists::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::delete(dsl::playlists.filter(dsl::id.eq(playlist_id).and(dsl::user_id.eq(user_id))))
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to delete playlist: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub fn find_playlist(
+    conn: &mut PgConnection,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<Playlist, String> {
+    use schema::playlists::dsl;
+
+    dsl::playlists
+        .filter(dsl::id.eq(playlist_id).and(dsl::user_id.eq(user_id)))
+        .first::<Playlist>(conn)
+        .map_err(|e| format!("Failed to find playlist: {e}"))
+}
+
+/// Current tracks of a playlist, in the playlist's order.
+pub async fn playlist_tracks(
+    pool: &DbPool,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<Vec<TagQueryMatch>, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        find_playlist(&mut conn, user_id, playlist_id)?.tracks(&mut conn)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// A playlist written to a music service.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ProviderPlaylist {
+    pub provider: String,
+    /// The provider's own playlist ID.
+    pub playlist_id: String,
+    pub track_count: usize,
+}
+
+/// Writes a saved playlist's current tracks to `provider` as a new
+/// playlist. Tracks from other providers are left out.
+pub async fn export_to_provider(
+    pool: &DbPool,
+    user_id: i32,
+    playlist_id: i32,
+    provider: &dyn MusicProvider,
+    access_token: &str,
+) -> Result<ProviderPlaylist, String> {
+    let pool = pool.clone();
+    let (name, tracks) = tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let playlist = find_playlist(&mut conn, user_id, playlist_id)?;
+        let tracks = playlist.tracks(&mut conn)?;
+        Ok::<_, String>((playlist.name, tracks))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    let track_ids: Vec<String> = tracks
+        .iter()
+        .filter_map(|track| match parse_song_id(&track.song_id) {
+            (song_provider, track_id) if song_provider == provider.name() => {
+                Some(track_id.to_string())
+            }
+            _ => None,
+        })
+        .collect();
+    if track_ids.is_empty() {
+        return Err(format!("Playlist has no {} tracks", provider.name()));
+    }
+
+    let created = provider
+        .create_playlist(access_token, &name, &track_ids)
+        .await?;
+    Ok(ProviderPlaylist {
+        provider: provider.name().to_string(),
+        playlist_id: created,
+        track_count: track_ids.len(),
+    })
+}
+
+pub async fn export_to_spotify(
+    pool: &DbPool,
+    user_id: i32,
+    playlist_id: i32,
+) -> Result<ProviderPlaylist, String> {
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    export_to_provider(
+        pool,
+        user_id,
+        playlist_id,
+        &SpotifyProvider::new(),
+        &access_token,
+    )
+    .await
+}
@@ -0,0 +1,556 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::catalog::upsert_songs;
+use crate::import::normalize_track_id;
+use crate::spotify::SpotifyClient;
+use crate::{matching, oauth, permissions, schema, spotify_access_token_for_user, DbPool, NewSong};
+
+/// Spotify only counts a stream once this much of it was played; shorter
+/// entries in streaming history are skips.
+pub const MIN_MS_PLAYED: i64 = 30_000;
+
+const DEFAULT_PLAY_SYNC_MINUTES: u64 = 30;
+
+// Postgres caps a statement at 65535 bind parameters.
+const INSERT_BATCH_SIZE: usize = 1000;
+
+#[derive(
+    Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, PartialEq, Eq, Hash, Debug,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum PlaySource {
+    /// Pulled from Spotify's recently played tracks.
+    RecentlyPlayed,
+    /// From an uploaded streaming history file.
+    HistoryUpload,
+}
+
+impl PlaySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaySource::RecentlyPlayed => "recently_played",
+            PlaySource::HistoryUpload => "history_upload",
+        }
+    }
+}
+
+impl std::str::FromStr for PlaySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recently_played" => Ok(PlaySource::RecentlyPlayed),
+            "history_upload" => Ok(PlaySource::HistoryUpload),
+            other => Err(format!("Unknown play source: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for PlaySource {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for PlaySource {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::plays)]
+pub struct Play {
+    pub id: i32,
+    pub user_id: i32,
+    pub song_id: String,
+    /// When the play finished, to the second.
+    pub played_at: NaiveDateTime,
+    pub ms_played: Option<i32>,
+    pub source: PlaySource,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Clone, PartialEq, Debug)]
+#[diesel(table_name = schema::plays)]
+pub struct NewPlay {
+    pub user_id: i32,
+    pub song_id: String,
+    pub played_at: NaiveDateTime,
+    pub ms_played: Option<i32>,
+    pub source: PlaySource,
+}
+
+/// Parses an RFC 3339 play time, dropping fractions of a second so the same
+/// play reported by different sources lands on the same timestamp.
+pub fn parse_played_at(value: &str) -> Result<NaiveDateTime, String> {
+    let played_at = chrono::DateTime::parse_from_rfc3339(value)
+        .map_err(|e| format!("Invalid play time {value}: {e}"))?
+        .naive_utc();
+    Ok(played_at.with_nanosecond(0).unwrap_or(played_at))
+}
+
+/// Stores plays, skipping any the user already has at the same time.
+/// Returns how many were new.
+pub fn insert_plays(conn: &mut PgConnection, new_plays: &[NewPlay]) -> QueryResult<usize> {
+    let mut inserted = 0;
+    for batch in new_plays.chunks(INSERT_BATCH_SIZE) {
+        inserted += diesel::insert_into(schema::plays::table)
+            .values(batch)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+    }
+    Ok(inserted)
+}
+
+/// Pulls the user's recently played Spotify tracks that came after the last
+/// pull and stores them as plays, adding the tracks to the catalogue.
+/// Spotify doesn't say how much of a recent play was heard, so the track's
+/// length is recorded.
+pub async fn sync_recent_plays(pool: &DbPool, user_id: i32) -> Result<usize, String> {
+    use schema::plays::dsl;
+
+    let lookup_pool = pool.clone();
+    let last_played_at = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::plays
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::source.eq(PlaySource::RecentlyPlayed))
+            .select(diesel::dsl::max(dsl::played_at))
+            .first::<Option<NaiveDateTime>>(&mut conn)
+            .map_err(|e| format!("Failed to load plays: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    let access_token = spotify_access_token_for_user(pool, user_id).await?;
+    let history = SpotifyClient::new()
+        .get_recently_played(
+            &access_token,
+            last_played_at.map(|played_at| played_at.and_utc().timestamp_millis()),
+        )
+        .await?;
+
+    let new_songs: Vec<NewSong> = history
+        .iter()
+        .map(|item| item.track.to_new_song(None))
+        .collect();
+    let new_plays = history
+        .iter()
+        .map(|item| {
+            Ok(NewPlay {
+                user_id,
+                song_id: item.track.id.clone(),
+                played_at: parse_played_at(&item.played_at)?,
+                ms_played: Some(item.track.duration_ms),
+                source: PlaySource::RecentlyPlayed,
+            })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        upsert_songs(&mut conn, &new_songs).map_err(|e| format!("Failed to save songs: {e}"))?;
+        insert_plays(&mut conn, &new_plays).map_err(|e| format!("Failed to save plays: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// How often recent plays are pulled, from `PLAY_SYNC_INTERVAL_MINUTES`.
+/// Spotify only keeps the last 50 plays, so this should stay well under the
+/// time it takes to listen to 50 songs.
+pub fn play_sync_interval() -> std::time::Duration {
+    let minutes = std::env::var("PLAY_SYNC_INTERVAL_MINUTES")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|minutes| *minutes > 0)
+        .unwrap_or(DEFAULT_PLAY_SYNC_MINUTES);
+    std::time::Duration::from_secs(minutes * 60)
+}
+
+/// Pulls recent plays for every user with a usable Spotify connection.
+/// One user's failure doesn't stop the others.
+pub async fn sync_all_recent_plays(pool: &DbPool) -> Result<(), String> {
+    use schema::users::dsl;
+
+    let lookup_pool = pool.clone();
+    let candidates = tokio::task::spawn_blocking(move || {
+        let mut conn = lookup_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::users
+            .filter(dsl::spotify_refresh_token.is_not_null())
+            .filter(dsl::spotify_unlinked_at.is_null())
+            .select((dsl::id, dsl::spotify_scopes))
+            .load::<(i32, Option<String>)>(&mut conn)
+            .map_err(|e| format!("Failed to load users: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+
+    for (user_id, granted) in candidates {
+        // Grants from before scopes were recorded are tried anyway.
+        if granted.is_some_and(|granted| {
+            oauth::missing_scopes_error(&granted, oauth::LISTENING_HISTORY_SCOPES).is_some()
+        }) {
+            continue;
+        }
+        if let Err(e) = sync_recent_plays(pool, user_id).await {
+            log::error!("Play sync failed for user {user_id}: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// One entry of Spotify's extended streaming history
+/// (`Streaming_History_Audio_*.json`). Other fields are ignored.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct StreamingHistoryEntry {
+    pub ts: String,
+    pub ms_played: i64,
+    pub spotify_track_uri: Option<String>,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct HistoryImport {
+    pub imported: usize,
+    /// Plays already known, from an earlier upload or the recent plays sync.
+    pub duplicates: usize,
+    /// Podcast episodes, skips and entries without a track.
+    pub skipped: usize,
+}
+
+/// Turns streaming history entries into plays, returning them with the
+/// number of entries skipped.
+pub fn plays_from_history(
+    user_id: i32,
+    entries: &[StreamingHistoryEntry],
+) -> Result<(Vec<NewPlay>, usize), String> {
+    let mut new_plays = Vec::new();
+    let mut skipped = 0;
+    for entry in entries {
+        let Some(song_id) = entry
+            .spotify_track_uri
+            .as_deref()
+            .and_then(normalize_track_id)
+            .filter(|_| entry.ms_played >= MIN_MS_PLAYED)
+        else {
+            skipped += 1;
+            continue;
+        };
+        new_plays.push(NewPlay {
+            user_id,
+            song_id,
+            played_at: parse_played_at(&entry.ts)?,
+            ms_played: Some(i32::try_from(entry.ms_played).unwrap_or(i32::MAX)),
+            source: PlaySource::HistoryUpload,
+        });
+    }
+    Ok((new_plays, skipped))
+}
+
+/// Backfills plays from an extended streaming history file.
+pub async fn import_streaming_history(
+    pool: &DbPool,
+    user_id: i32,
+    body: &str,
+) -> Result<HistoryImport, String> {
+    let entries: Vec<StreamingHistoryEntry> =
+        serde_json::from_str(body).map_err(|e| format!("Invalid streaming history file: {e}"))?;
+    let (new_plays, skipped) = plays_from_history(user_id, &entries)?;
+
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let imported = insert_plays(&mut conn, &new_plays)
+            .map_err(|e| format!("Failed to save plays: {e}"))?;
+        Ok(HistoryImport {
+            imported,
+            duplicates: new_plays.len() - imported,
+            skipped,
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Length of the periods plays are counted over.
+#[derive(rocket::FromFormField, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum StatsPeriod {
+    Day,
+    /// Weeks start on Monday.
+    Week,
+    #[default]
+    Month,
+}
+
+impl StatsPeriod {
+    pub fn start_of(&self, date: NaiveDate) -> NaiveDate {
+        match self {
+            StatsPeriod::Day => date,
+            StatsPeriod::Week => {
+                date - chrono::Duration::days(i64::from(date.weekday().num_days_from_monday()))
+            }
+            StatsPeriod::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+}
+
+/// Plays of songs carrying a tag during one period.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TagPlayStats {
+    pub tag_id: i32,
+    pub tag_name: String,
+    pub period_start: NaiveDate,
+    pub plays: i64,
+    pub ms_played: i64,
+}
+
+/// Counts plays per tag and period. A play counts toward every tag its
+/// song carries.
+pub fn tally_tag_plays(
+    plays: &[(String, NaiveDateTime, Option<i32>)],
+    tags_by_song: &HashMap<String, Vec<i32>>,
+    period: StatsPeriod,
+) -> BTreeMap<(i32, NaiveDate), (i64, i64)> {
+    let mut totals: BTreeMap<(i32, NaiveDate), (i64, i64)> = BTreeMap::new();
+    for (song_id, played_at, ms_played) in plays {
+        let period_start = period.start_of(played_at.date());
+        for tag_id in tags_by_song.get(song_id).into_iter().flatten() {
+            let total = totals.entry((*tag_id, period_start)).or_default();
+            total.0 += 1;
+            total.1 += i64::from(ms_played.unwrap_or(0));
+        }
+    }
+    totals
+}
+
+/// Reads the `YYYY-MM-DD` bounds of a stats request. `to` is exclusive and
+/// defaults to tomorrow, so today is included; `from` defaults to 30 days
+/// before `to`. Dates are UTC.
+pub fn stats_range(from: Option<&str>, to: Option<&str>) -> Result<(NaiveDate, NaiveDate), String> {
+    let parse = |value: &str| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|e| format!("Invalid date {value}: {e}"))
+    };
+    let to = match to {
+        Some(to) => parse(to)?,
+        None => chrono::Utc::now().date_naive() + chrono::Duration::days(1),
+    };
+    let from = match from {
+        Some(from) => parse(from)?,
+        None => to - chrono::Duration::days(30),
+    };
+    Ok((from, to))
+}
+
+/// Plays per tag and period between `from` (inclusive) and `to`
+/// (exclusive), optionally for one tag. Songs confirmed to be the same are
+/// counted as one.
+pub async fn tag_play_stats(
+    pool: &DbPool,
+    user_id: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+    period: StatsPeriod,
+    tag_id: Option<i32>,
+) -> Result<Vec<TagPlayStats>, String> {
+    use schema::{plays, song_tags};
+
+    if from >= to {
+        return Err("from must be before to".to_string());
+    }
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let canonical = matching::canonical_song_ids(&mut conn, user_id)?;
+        let canonical_id = |song_id: String| canonical.get(&song_id).cloned().unwrap_or(song_id);
+
+        let tag_names: HashMap<i32, String> = permissions::visible_tags(&mut conn, user_id)?
+            .into_iter()
+            .filter(|tag| tag_id.is_none_or(|tag_id| tag.id == tag_id))
+            .map(|tag| (tag.id, tag.name))
+            .collect();
+        if let Some(tag_id) = tag_id.filter(|tag_id| !tag_names.contains_key(tag_id)) {
+            return Err(format!("Tag {tag_id} not found or not owned by user"));
+        }
+
+        // Workspace tags count every member's links, as in tag queries.
+        let shared_tag_ids = permissions::workspace_tag_ids(&mut conn, user_id)?;
+        let mut tags_by_song: HashMap<String, Vec<i32>> = HashMap::new();
+        for (song_id, tag_id) in song_tags::table
+            .filter(
+                song_tags::user_id
+                    .eq(user_id)
+                    .or(song_tags::tag_id.eq_any(shared_tag_ids)),
+            )
+            .filter(song_tags::tag_id.eq_any(tag_names.keys()))
+            .select((song_tags::song_id, song_tags::tag_id))
+            .load::<(String, i32)>(&mut conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))?
+        {
+            let song_tags = tags_by_song.entry(canonical_id(song_id)).or_default();
+            if !song_tags.contains(&tag_id) {
+                song_tags.push(tag_id);
+            }
+        }
+
+        let start = from.and_hms_opt(0, 0, 0).unwrap_or_default();
+        let end = to.and_hms_opt(0, 0, 0).unwrap_or_default();
+        let user_plays: Vec<(String, NaiveDateTime, Option<i32>)> = plays::table
+            .filter(plays::user_id.eq(user_id))
+            .filter(plays::played_at.ge(start))
+            .filter(plays::played_at.lt(end))
+            .select((plays::song_id, plays::played_at, plays::ms_played))
+            .load::<(String, NaiveDateTime, Option<i32>)>(&mut conn)
+            .map_err(|e| format!("Failed to load plays: {e}"))?
+            .into_iter()
+            .map(|(song_id, played_at, ms_played)| (canonical_id(song_id), played_at, ms_played))
+            .collect();
+
+        Ok(tally_tag_plays(&user_plays, &tags_by_song, period)
+            .into_iter()
+            .map(
+                |((tag_id, period_start), (plays, ms_played))| TagPlayStats {
+                    tag_name: tag_names.get(&tag_id).cloned().unwrap_or_default(),
+                    tag_id,
+                    period_start,
+                    plays,
+                    ms_played,
+                },
+            )
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        parse_played_at(value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_played_at_drops_fractions() {
+        assert_eq!(
+            parse_played_at("2026-10-01T12:30:15.987Z").unwrap(),
+            parse_played_at("2026-10-01T12:30:15Z").unwrap()
+        );
+        assert_eq!(
+            parse_played_at("2026-10-01T14:30:15+02:00").unwrap(),
+            time("2026-10-01T12:30:15Z")
+        );
+        assert!(parse_played_at("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_plays_from_history() {
+        let entries: Vec<StreamingHistoryEntry> = serde_json::from_str(
+            r#"[
+                {"ts": "2026-10-01T12:00:00Z", "ms_played": 200000,
+                 "spotify_track_uri": "spotify:track:abc", "master_metadata_track_name": "Song"},
+                {"ts": "2026-10-01T12:05:00Z", "ms_played": 4000,
+                 "spotify_track_uri": "spotify:track:def"},
+                {"ts": "2026-10-01T12:10:00Z", "ms_played": 900000,
+                 "spotify_track_uri": null, "spotify_episode_uri": "spotify:episode:xyz"}
+            ]"#,
+        )
+        .unwrap();
+
+        let (new_plays, skipped) = plays_from_history(7, &entries).unwrap();
+
+        assert_eq!(skipped, 2);
+        assert_eq!(
+            new_plays,
+            vec![NewPlay {
+                user_id: 7,
+                song_id: "abc".to_string(),
+                played_at: time("2026-10-01T12:00:00Z"),
+                ms_played: Some(200_000),
+                source: PlaySource::HistoryUpload,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stats_range() {
+        let (from, to) = stats_range(None, Some("2026-10-18")).unwrap();
+
+        assert_eq!(from, NaiveDate::from_ymd_opt(2026, 9, 18).unwrap());
+        assert_eq!(to, NaiveDate::from_ymd_opt(2026, 10, 18).unwrap());
+        assert!(stats_range(Some("18/10/2026"), None).is_err());
+    }
+
+    #[test]
+    fn test_period_start() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 15).unwrap();
+
+        assert_eq!(StatsPeriod::Day.start_of(date), date);
+        assert_eq!(
+            StatsPeriod::Week.start_of(date),
+            NaiveDate::from_ymd_opt(2026, 10, 12).unwrap()
+        );
+        assert_eq!(
+            StatsPeriod::Month.start_of(date),
+            NaiveDate::from_ymd_opt(2026, 10, 1).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tally_tag_plays() {
+        let plays = vec![
+            ("a".to_string(), time("2026-10-01T09:00:00Z"), Some(1_000)),
+            ("a".to_string(), time("2026-10-20T09:00:00Z"), Some(2_000)),
+            ("b".to_string(), time("2026-11-02T09:00:00Z"), None),
+            (
+                "untagged".to_string(),
+                time("2026-10-03T09:00:00Z"),
+                Some(5_000),
+            ),
+        ];
+        let tags_by_song =
+            HashMap::from([("a".to_string(), vec![1, 2]), ("b".to_string(), vec![1])]);
+
+        let totals = tally_tag_plays(&plays, &tags_by_song, StatsPeriod::Month);
+
+        let october = NaiveDate::from_ymd_opt(2026, 10, 1).unwrap();
+        let november = NaiveDate::from_ymd_opt(2026, 11, 1).unwrap();
+        assert_eq!(
+            totals.into_iter().collect::<Vec<_>>(),
+            vec![
+                ((1, october), (2, 3_000)),
+                ((1, november), (1, 0)),
+                ((2, october), (2, 3_000)),
+            ]
+        );
+    }
+}
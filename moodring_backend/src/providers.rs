@@ -0,0 +1,481 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::identities::SPOTIFY_PROVIDER;
+use crate::spotify::SpotifyClient;
+use crate::{
+    exchange_authorization_code, request_spotify_token_refresh, AuthRequest, NewSong,
+    SpotifyTokenResponse, SpotifyUserProfile,
+};
+
+/// Tokens issued by a provider's authorization server.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ProviderTokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: i64,
+    pub scopes: Vec<String>,
+}
+
+impl From<SpotifyTokenResponse> for ProviderTokens {
+    fn from(response: SpotifyTokenResponse) -> Self {
+        ProviderTokens {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            expires_in: response.expires_in,
+            scopes: response
+                .scope
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+}
+
+/// The account a provider's access token belongs to.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ProviderProfile {
+    pub id: String,
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub image_url: Option<String>,
+}
+
+impl From<SpotifyUserProfile> for ProviderProfile {
+    fn from(profile: SpotifyUserProfile) -> Self {
+        ProviderProfile {
+            image_url: profile.images.first().map(|image| image.url.clone()),
+            id: profile.id,
+            email: profile.email,
+            display_name: profile.display_name,
+        }
+    }
+}
+
+/// The catalogue ID of a provider's track. Spotify track IDs were stored
+/// bare before other providers existed and stay that way, so existing tags
+/// and clients keep working; every other provider's tracks are stored as
+/// `<provider>:<track id>`. Spotify IDs are base-62 and never contain `:`.
+pub fn song_id(provider: &str, track_id: &str) -> String {
+    if provider == SPOTIFY_PROVIDER {
+        track_id.to_string()
+    } else {
+        format!("{provider}:{track_id}")
+    }
+}
+
+/// Splits a catalogue song ID into its provider and the provider's track ID.
+pub fn parse_song_id(song_id: &str) -> (&str, &str) {
+    song_id
+        .split_once(':')
+        .unwrap_or((SPOTIFY_PROVIDER, song_id))
+}
+
+/// A music service Moodring can sign in with, read tracks and playlists
+/// from, and write playlists to. Track and playlist IDs passed in and out
+/// are the provider's own; catalogue rows come back with [`song_id`]s.
+#[rocket::async_trait]
+pub trait MusicProvider: Send + Sync {
+    /// Name used for identities and song IDs.
+    fn name(&self) -> &'static str;
+
+    async fn exchange_code(&self, auth_request: &AuthRequest) -> Result<ProviderTokens, String>;
+
+    async fn refresh_tokens(&self, refresh_token: &str) -> Result<ProviderTokens, String>;
+
+    async fn profile(&self, access_token: &str) -> Result<ProviderProfile, String>;
+
+    /// Catalogue rows for the given tracks. Unknown tracks are skipped.
+    async fn tracks(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<NewSong>, String>;
+
+    /// Track IDs of a playlist, in playlist order.
+    async fn playlist_track_ids(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+    ) -> Result<Vec<String>, String>;
+
+    /// Creates a playlist holding the given tracks and returns its ID.
+    async fn create_playlist(
+        &self,
+        access_token: &str,
+        name: &str,
+        track_ids: &[String],
+    ) -> Result<String, String>;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SpotifyProvider {
+    client: SpotifyClient,
+}
+
+impl SpotifyProvider {
+    pub fn new() -> Self {
+        Self::with_client(SpotifyClient::new())
+    }
+
+    pub fn with_client(client: SpotifyClient) -> Self {
+        SpotifyProvider { client }
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for SpotifyProvider {
+    fn name(&self) -> &'static str {
+        SPOTIFY_PROVIDER
+    }
+
+    async fn exchange_code(&self, auth_request: &AuthRequest) -> Result<ProviderTokens, String> {
+        exchange_authorization_code(auth_request)
+            .await
+            .map(ProviderTokens::from)
+    }
+
+    async fn refresh_tokens(&self, refresh_token: &str) -> Result<ProviderTokens, String> {
+        request_spotify_token_refresh(refresh_token)
+            .await
+            .map(ProviderTokens::from)
+    }
+
+    async fn profile(&self, access_token: &str) -> Result<ProviderProfile, String> {
+        self.client
+            .get_current_user(access_token)
+            .await
+            .map(ProviderProfile::from)
+    }
+
+    async fn tracks(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<NewSong>, String> {
+        let tracks = self.client.get_tracks(access_token, track_ids).await?;
+        // Audio features are unavailable to some Spotify apps; keep the
+        // metadata rather than failing the whole lookup.
+        let features: HashMap<String, _> = self
+            .client
+            .get_audio_features(access_token, track_ids)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .map(|f| (f.id.clone(), f))
+            .collect();
+
+        Ok(tracks
+            .iter()
+            .map(|track| track.to_new_song(features.get(&track.id)))
+            .collect())
+    }
+
+    async fn playlist_track_ids(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+    ) -> Result<Vec<String>, String> {
+        self.client
+            .get_playlist_track_ids(access_token, playlist_id)
+            .await
+    }
+
+    async fn create_playlist(
+        &self,
+        access_token: &str,
+        name: &str,
+        track_ids: &[String],
+    ) -> Result<String, String> {
+        let owner = self.client.get_current_user(access_token).await?;
+        let playlist = self
+            .client
+            .create_playlist(access_token, &owner.id, name)
+            .await?;
+        self.client
+            .add_playlist_tracks(access_token, &playlist.id, track_ids)
+            .await?;
+        Ok(playlist.id)
+    }
+}
+
+/// A playlist held by [`InMemoryProvider`].
+#[derive(Clone, PartialEq, Debug)]
+pub struct InMemoryPlaylist {
+    pub name: String,
+    pub track_ids: Vec<String>,
+}
+
+#[derive(Default)]
+struct InMemoryState {
+    /// Authorization code to the access token it grants.
+    codes: HashMap<String, String>,
+    /// Access token to the account it belongs to.
+    accounts: HashMap<String, ProviderProfile>,
+    tracks: HashMap<String, NewSong>,
+    playlists: HashMap<String, InMemoryPlaylist>,
+}
+
+/// A provider that serves canned accounts, tracks and playlists from
+/// memory, for exercising provider-generic code without a network.
+pub struct InMemoryProvider {
+    name: &'static str,
+    state: Mutex<InMemoryState>,
+}
+
+impl InMemoryProvider {
+    pub fn new(name: &'static str) -> Self {
+        InMemoryProvider {
+            name,
+            state: Mutex::new(InMemoryState::default()),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, InMemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers an account reachable with `access_token`, and an
+    /// authorization code that grants it.
+    pub fn with_account(self, code: &str, access_token: &str, profile: ProviderProfile) -> Self {
+        {
+            let mut state = self.state();
+            state
+                .codes
+                .insert(code.to_string(), access_token.to_string());
+            state.accounts.insert(access_token.to_string(), profile);
+        }
+        self
+    }
+
+    /// Registers a track under `track.id`, the provider's own ID.
+    pub fn with_track(self, track: NewSong) -> Self {
+        self.state().tracks.insert(track.id.clone(), track);
+        self
+    }
+
+    pub fn with_playlist(self, playlist_id: &str, name: &str, track_ids: &[&str]) -> Self {
+        self.state().playlists.insert(
+            playlist_id.to_string(),
+            InMemoryPlaylist {
+                name: name.to_string(),
+                track_ids: track_ids.iter().map(|id| id.to_string()).collect(),
+            },
+        );
+        self
+    }
+
+    pub fn playlist(&self, playlist_id: &str) -> Option<InMemoryPlaylist> {
+        self.state().playlists.get(playlist_id).cloned()
+    }
+
+    fn check_token(&self, access_token: &str) -> Result<ProviderProfile, String> {
+        self.state()
+            .accounts
+            .get(access_token)
+            .cloned()
+            .ok_or_else(|| format!("{} rejected the access token", self.name))
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for InMemoryProvider {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn exchange_code(&self, auth_request: &AuthRequest) -> Result<ProviderTokens, String> {
+        let access_token = self
+            .state()
+            .codes
+            .remove(&auth_request.code)
+            .ok_or_else(|| "Token exchange failed: unknown authorization code".to_string())?;
+        Ok(ProviderTokens {
+            refresh_token: Some(format!("refresh-{access_token}")),
+            access_token,
+            expires_in: 3600,
+            scopes: Vec::new(),
+        })
+    }
+
+    async fn refresh_tokens(&self, refresh_token: &str) -> Result<ProviderTokens, String> {
+        let access_token = refresh_token
+            .strip_prefix("refresh-")
+            .filter(|token| self.state().accounts.contains_key(*token))
+            .ok_or_else(|| "Token refresh failed: unknown refresh token".to_string())?;
+        Ok(ProviderTokens {
+            access_token: access_token.to_string(),
+            refresh_token: None,
+            expires_in: 3600,
+            scopes: Vec::new(),
+        })
+    }
+
+    async fn profile(&self, access_token: &str) -> Result<ProviderProfile, String> {
+        self.check_token(access_token)
+    }
+
+    async fn tracks(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<NewSong>, String> {
+        self.check_token(access_token)?;
+        let state = self.state();
+        Ok(track_ids
+            .iter()
+            .filter_map(|track_id| state.tracks.get(track_id))
+            .map(|track| NewSong {
+                id: song_id(self.name, &track.id),
+                ..track.clone()
+            })
+            .collect())
+    }
+
+    async fn playlist_track_ids(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+    ) -> Result<Vec<String>, String> {
+        self.check_token(access_token)?;
+        self.playlist(playlist_id)
+            .map(|playlist| playlist.track_ids)
+            .ok_or_else(|| format!("Playlist {playlist_id} not found"))
+    }
+
+    async fn create_playlist(
+        &self,
+        access_token: &str,
+        name: &str,
+        track_ids: &[String],
+    ) -> Result<String, String> {
+        self.check_token(access_token)?;
+        let mut state = self.state();
+        let playlist_id = format!("playlist-{}", state.playlists.len() + 1);
+        state.playlists.insert(
+            playlist_id.clone(),
+            InMemoryPlaylist {
+                name: name.to_string(),
+                track_ids: track_ids.to_vec(),
+            },
+        );
+        Ok(playlist_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> ProviderProfile {
+        ProviderProfile {
+            id: "listener".to_string(),
+            email: None,
+            display_name: Some("Listener".to_string()),
+            image_url: None,
+        }
+    }
+
+    fn provider() -> InMemoryProvider {
+        InMemoryProvider::new("fake")
+            .with_account("code", "token", profile())
+            .with_track(NewSong {
+                id: "t1".to_string(),
+                title: "First".to_string(),
+                artist: "Artist".to_string(),
+                ..NewSong::default()
+            })
+            .with_playlist("p1", "Mix", &["t1", "missing"])
+    }
+
+    fn auth_request(code: &str) -> AuthRequest {
+        AuthRequest {
+            code: code.to_string(),
+            code_verifier: "v".repeat(43),
+            redirect_uri: None,
+        }
+    }
+
+    #[test]
+    fn test_song_id_round_trip() {
+        assert_eq!(
+            song_id("spotify", "4uLU6hMCjMI75M1A2tKUQC"),
+            "4uLU6hMCjMI75M1A2tKUQC"
+        );
+        assert_eq!(song_id("fake", "t1"), "fake:t1");
+        assert_eq!(
+            parse_song_id("4uLU6hMCjMI75M1A2tKUQC"),
+            ("spotify", "4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(parse_song_id("fake:t1"), ("fake", "t1"));
+    }
+
+    #[test]
+    fn test_spotify_tokens_split_scopes() {
+        let tokens = ProviderTokens::from(SpotifyTokenResponse {
+            access_token: "a".to_string(),
+            token_type: "Bearer".to_string(),
+            scope: "user-read-email playlist-read-private".to_string(),
+            expires_in: 3600,
+            refresh_token: None,
+        });
+
+        assert_eq!(
+            tokens.scopes,
+            vec!["user-read-email", "playlist-read-private"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_sign_in() {
+        let provider = provider();
+
+        let tokens = provider.exchange_code(&auth_request("code")).await.unwrap();
+        assert_eq!(
+            provider.profile(&tokens.access_token).await.unwrap(),
+            profile()
+        );
+        let refreshed = provider
+            .refresh_tokens(tokens.refresh_token.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(refreshed.access_token, tokens.access_token);
+
+        // Codes are single use.
+        assert!(provider.exchange_code(&auth_request("code")).await.is_err());
+        assert!(provider.profile("other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_tracks_are_qualified() {
+        let provider = provider();
+
+        let track_ids = provider.playlist_track_ids("token", "p1").await.unwrap();
+        let songs = provider.tracks("token", &track_ids).await.unwrap();
+
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].id, "fake:t1");
+        assert_eq!(parse_song_id(&songs[0].id), ("fake", "t1"));
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_create_playlist() {
+        let provider = provider();
+
+        let playlist_id = provider
+            .create_playlist("token", "Copy", &["t1".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            provider.playlist(&playlist_id),
+            Some(InMemoryPlaylist {
+                name: "Copy".to_string(),
+                track_ids: vec!["t1".to_string()],
+            })
+        );
+    }
+}
@@ -0,0 +1,635 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::permissions::{authorize_tag, is_allowed, workspace_role, TagAction};
+use crate::webhooks::enqueue_links_added;
+use crate::{schema, DbPool, NewSongTag, Song, SongTag, Tag, TagSource, TransactionError};
+
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::tag_rules)]
+pub struct TagRule {
+    pub id: i32,
+    pub user_id: i32,
+    pub tag_id: i32,
+    pub name: String,
+    pub conditions: serde_json::Value,
+    pub enabled: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Insertable, Clone, Debug)]
+#[diesel(table_name = schema::tag_rules)]
+pub struct NewTagRule {
+    pub user_id: i32,
+    pub tag_id: i32,
+    pub name: String,
+    pub conditions: serde_json::Value,
+    pub enabled: bool,
+}
+
+/// Request body for creating a rule.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RuleRequest {
+    pub tag_id: i32,
+    pub name: String,
+    pub conditions: Vec<RuleCondition>,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Feature {
+    Energy,
+    Valence,
+    Tempo,
+    Danceability,
+    Acousticness,
+}
+
+impl Feature {
+    fn value(self, song: &Song) -> Option<f64> {
+        match self {
+            Feature::Energy => song.energy,
+            Feature::Valence => song.valence,
+            Feature::Tempo => song.tempo,
+            Feature::Danceability => song.danceability,
+            Feature::Acousticness => song.acousticness,
+        }
+    }
+}
+
+/// One clause of a rule. Text matches ignore case, and ranges include both
+/// bounds; a rule applies only when all of its conditions match.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", tag = "field", rename_all = "snake_case")]
+pub enum RuleCondition {
+    /// Matches when any credited artist has this name.
+    Artist {
+        name: String,
+    },
+    Album {
+        name: String,
+    },
+    ReleaseYear {
+        min: Option<i32>,
+        max: Option<i32>,
+    },
+    AudioFeature {
+        feature: Feature,
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    /// Matches songs the user has already put under this tag.
+    HasTag {
+        tag_id: i32,
+    },
+}
+
+fn in_range<T: PartialOrd>(value: T, min: Option<T>, max: Option<T>) -> bool {
+    min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+}
+
+impl RuleCondition {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            RuleCondition::Artist { name } | RuleCondition::Album { name }
+                if name.trim().is_empty() =>
+            {
+                Err("Text conditions need a non-empty name".to_string())
+            }
+            RuleCondition::ReleaseYear { min, max } => validate_range(*min, *max),
+            RuleCondition::AudioFeature { min, max, .. } => validate_range(*min, *max),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn matches(&self, song: &Song, song_tag_ids: &HashSet<i32>) -> bool {
+        match self {
+            RuleCondition::Artist { name } => song
+                .artist
+                .split(", ")
+                .any(|artist| artist.trim().eq_ignore_ascii_case(name.trim())),
+            RuleCondition::Album { name } => song
+                .album
+                .as_deref()
+                .is_some_and(|album| album.trim().eq_ignore_ascii_case(name.trim())),
+            RuleCondition::ReleaseYear { min, max } => song
+                .release_year
+                .is_some_and(|year| in_range(year, *min, *max)),
+            RuleCondition::AudioFeature { feature, min, max } => feature
+                .value(song)
+                .is_some_and(|value| in_range(value, *min, *max)),
+            RuleCondition::HasTag { tag_id } => song_tag_ids.contains(tag_id),
+        }
+    }
+}
+
+fn validate_range<T: PartialOrd>(min: Option<T>, max: Option<T>) -> Result<(), String> {
+    match (min, max) {
+        (None, None) => Err("Range conditions need a min or a max".to_string()),
+        (Some(min), Some(max)) if min > max => Err("Range min is greater than max".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// A rule with its conditions parsed, ready to evaluate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledRule {
+    pub id: i32,
+    pub tag_id: i32,
+    pub conditions: Vec<RuleCondition>,
+}
+
+impl CompiledRule {
+    pub fn matches(&self, song: &Song, song_tag_ids: &HashSet<i32>) -> bool {
+        !self.conditions.is_empty()
+            && self
+                .conditions
+                .iter()
+                .all(|c| c.matches(song, song_tag_ids))
+    }
+}
+
+impl TagRule {
+    pub fn compile(&self) -> Result<CompiledRule, String> {
+        let conditions = serde_json::from_value(self.conditions.clone())
+            .map_err(|e| format!("Rule {} has invalid conditions: {e}", self.id))?;
+        Ok(CompiledRule {
+            id: self.id,
+            tag_id: self.tag_id,
+            conditions,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct RuleApplication {
+    pub rule_id: i32,
+    pub song_id: String,
+    pub tag_id: i32,
+}
+
+/// Works out which tags the rules would add to `songs`, given the tags each
+/// song already has. Rules are re-evaluated until nothing changes, so a rule
+/// conditioned on a tag sees tags added by other rules.
+pub fn plan_rule_applications(
+    rules: &[CompiledRule],
+    songs: &[Song],
+    existing: &HashMap<String, HashSet<i32>>,
+) -> Vec<RuleApplication> {
+    let mut current = existing.clone();
+    let mut planned = Vec::new();
+
+    // Each pass adds at least one tag or stops, and a rule can only add its
+    // one tag to each song, so this always terminates.
+    loop {
+        let mut added = false;
+        for song in songs {
+            for rule in rules {
+                let song_tags = current.entry(song.id.clone()).or_default();
+                if song_tags.contains(&rule.tag_id) || !rule.matches(song, song_tags) {
+                    continue;
+                }
+                song_tags.insert(rule.tag_id);
+                planned.push(RuleApplication {
+                    rule_id: rule.id,
+                    song_id: song.id.clone(),
+                    tag_id: rule.tag_id,
+                });
+                added = true;
+            }
+        }
+        if !added {
+            return planned;
+        }
+    }
+}
+
+pub async fn list_rules(pool: &DbPool, user_id: i32) -> Result<Vec<TagRule>, String> {
+    use schema::tag_rules::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::tag_rules
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::name.asc())
+            .load::<TagRule>(&mut conn)
+            .map_err(|e| format!("Failed to load rules: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn create_rule(
+    pool: &DbPool,
+    user_id: i32,
+    request: RuleRequest,
+) -> Result<TagRule, String> {
+    use schema::tag_rules;
+
+    if request.conditions.is_empty() {
+        return Err("A rule needs at least one condition".to_string());
+    }
+    for condition in &request.conditions {
+        condition.validate()?;
+    }
+
+    let condition_tags: HashSet<i32> = request
+        .conditions
+        .iter()
+        .filter_map(|c| match c {
+            RuleCondition::HasTag { tag_id } => Some(*tag_id),
+            _ => None,
+        })
+        .collect();
+
+    let new_rule = NewTagRule {
+        user_id,
+        tag_id: request.tag_id,
+        name: request.name,
+        conditions: serde_json::to_value(&request.conditions)
+            .map_err(|e| format!("Failed to encode conditions: {e}"))?,
+        enabled: request.enabled.unwrap_or(true),
+    };
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        authorize_tag(&mut conn, user_id, new_rule.tag_id, TagAction::Apply)?;
+        for tag_id in condition_tags {
+            authorize_tag(&mut conn, user_id, tag_id, TagAction::View)?;
+        }
+
+        diesel::insert_into(tag_rules::table)
+            .values(&new_rule)
+            .get_result::<TagRule>(&mut conn)
+            .map_err(|e| format!("Failed to create rule: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn delete_rule(pool: &DbPool, user_id: i32, rule_id: i32) -> Result<usize, String> {
+    use schema::tag_rules::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::delete(dsl::tag_rules.filter(dsl::id.eq(rule_id).and(dsl::user_id.eq(user_id))))
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to delete rule: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Runs the user's rules over `song_ids` (or their whole library when
+/// `None`) and, unless `dry_run` is set, stores the resulting song tags.
+///
+/// Only enabled rules run, unless `only_rule_id` names a specific rule.
+/// Rules whose tag the user may no longer apply, such as a workspace tag
+/// after they were demoted or left, are skipped.
+pub fn apply_rules(
+    conn: &mut PgConnection,
+    user_id: i32,
+    song_ids: Option<&[String]>,
+    only_rule_id: Option<i32>,
+    dry_run: bool,
+) -> Result<Vec<RuleApplication>, String> {
+    use schema::{song_tags, songs, tag_rules, tags, user_songs};
+
+    let mut rules_query = tag_rules::table
+        .filter(tag_rules::user_id.eq(user_id))
+        .into_boxed();
+    rules_query = match only_rule_id {
+        Some(rule_id) => rules_query.filter(tag_rules::id.eq(rule_id)),
+        None => rules_query.filter(tag_rules::enabled.eq(true)),
+    };
+    let rules = rules_query
+        .order(tag_rules::id.asc())
+        .load::<TagRule>(conn)
+        .map_err(|e| format!("Failed to load rules: {e}"))?;
+
+    let rule_tags = tags::table
+        .filter(tags::id.eq_any(rules.iter().map(|rule| rule.tag_id).collect::<Vec<_>>()))
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load tags: {e}"))?;
+    let mut applicable: HashSet<i32> = HashSet::new();
+    for tag in rule_tags {
+        let role = match tag.workspace_id {
+            Some(workspace_id) => workspace_role(conn, user_id, workspace_id)?,
+            None => None,
+        };
+        if is_allowed(user_id, &tag, role, TagAction::Apply) {
+            applicable.insert(tag.id);
+        }
+    }
+
+    let rules = rules
+        .iter()
+        .filter(|rule| applicable.contains(&rule.tag_id))
+        .map(TagRule::compile)
+        .collect::<Result<Vec<_>, _>>()?;
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let target_ids: Vec<String> = match song_ids {
+        Some(ids) => ids.to_vec(),
+        None => {
+            let mut ids = user_songs::table
+                .filter(user_songs::user_id.eq(user_id))
+                .select(user_songs::song_id)
+                .load::<String>(conn)
+                .map_err(|e| format!("Failed to load library: {e}"))?;
+            ids.extend(
+                song_tags::table
+                    .filter(song_tags::user_id.eq(user_id))
+                    .select(song_tags::song_id)
+                    .distinct()
+                    .load::<String>(conn)
+                    .map_err(|e| format!("Failed to load song tags: {e}"))?,
+            );
+            ids
+        }
+    };
+
+    let target_songs = songs::table
+        .filter(songs::id.eq_any(&target_ids))
+        .order(songs::id.asc())
+        .load::<Song>(conn)
+        .map_err(|e| format!("Failed to load songs: {e}"))?;
+
+    let mut existing: HashMap<String, HashSet<i32>> = HashMap::new();
+    for (song_id, tag_id) in song_tags::table
+        .filter(song_tags::user_id.eq(user_id))
+        .filter(song_tags::song_id.eq_any(&target_ids))
+        .select((song_tags::song_id, song_tags::tag_id))
+        .load::<(String, i32)>(conn)
+        .map_err(|e| format!("Failed to load song tags: {e}"))?
+    {
+        existing.entry(song_id).or_default().insert(tag_id);
+    }
+
+    let applications = plan_rule_applications(&rules, &target_songs, &existing);
+    if dry_run || applications.is_empty() {
+        return Ok(applications);
+    }
+
+    let new_song_tags: Vec<NewSongTag> = applications
+        .iter()
+        .map(|application| NewSongTag {
+            user_id,
+            song_id: application.song_id.clone(),
+            tag_id: application.tag_id,
+            rule_id: Some(application.rule_id),
+            source: TagSource::Rule,
+            confidence: None,
+            note: None,
+            weight: None,
+        })
+        .collect();
+
+    // Links a concurrent run or a manual tag got to first are skipped by the
+    // insert, so only what was actually stored is reported.
+    let added = conn
+        .transaction::<_, TransactionError, _>(|conn| {
+            let added = diesel::insert_into(song_tags::table)
+                .values(&new_song_tags)
+                .on_conflict_do_nothing()
+                .get_results::<SongTag>(conn)
+                .map_err(|e| format!("Failed to apply rules: {e}"))?;
+            enqueue_links_added(conn, user_id, &added)?;
+            Ok(added)
+        })
+        .map_err(|TransactionError(e)| e)?;
+
+    Ok(added
+        .into_iter()
+        .filter_map(|song_tag| {
+            song_tag.rule_id.map(|rule_id| RuleApplication {
+                rule_id,
+                song_id: song_tag.song_id,
+                tag_id: song_tag.tag_id,
+            })
+        })
+        .collect())
+}
+
+pub async fn run_rules(
+    pool: &DbPool,
+    user_id: i32,
+    only_rule_id: Option<i32>,
+    dry_run: bool,
+) -> Result<Vec<RuleApplication>, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        apply_rules(&mut conn, user_id, None, only_rule_id, dry_run)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const SHOEGAZE: i32 = 10;
+    const SLOW: i32 = 11;
+    const DREAMY: i32 = 12;
+
+    fn song(id: &str, artist: &str, tempo: Option<f64>) -> Song {
+        let now = chrono::Utc::now().naive_utc();
+        Song {
+            id: id.to_string(),
+            title: format!("Song {id}"),
+            artist: artist.to_string(),
+            album: Some("Loveless".to_string()),
+            release_year: Some(1991),
+            duration_ms: Some(240_000),
+            isrc: None,
+            energy: Some(0.6),
+            valence: Some(0.3),
+            tempo,
+            danceability: Some(0.4),
+            acousticness: Some(0.1),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn rule(id: i32, tag_id: i32, conditions: Vec<RuleCondition>) -> CompiledRule {
+        CompiledRule {
+            id,
+            tag_id,
+            conditions,
+        }
+    }
+
+    #[test]
+    fn test_condition_deserialization() {
+        let conditions: Vec<RuleCondition> = serde_json::from_value(json!([
+            { "field": "artist", "name": "My Bloody Valentine" },
+            { "field": "audio_feature", "feature": "tempo", "max": 80.0 },
+            { "field": "release_year", "min": 1990, "max": 1999 },
+            { "field": "has_tag", "tag_id": 3 }
+        ]))
+        .expect("Failed to deserialize conditions");
+
+        assert_eq!(
+            conditions[1],
+            RuleCondition::AudioFeature {
+                feature: Feature::Tempo,
+                min: None,
+                max: Some(80.0)
+            }
+        );
+        assert_eq!(conditions[3], RuleCondition::HasTag { tag_id: 3 });
+    }
+
+    #[test]
+    fn test_condition_validation() {
+        assert!(RuleCondition::Artist {
+            name: " ".to_string()
+        }
+        .validate()
+        .is_err());
+        assert!(RuleCondition::ReleaseYear {
+            min: None,
+            max: None
+        }
+        .validate()
+        .is_err());
+        assert!(RuleCondition::ReleaseYear {
+            min: Some(2000),
+            max: Some(1990)
+        }
+        .validate()
+        .is_err());
+        assert!(RuleCondition::AudioFeature {
+            feature: Feature::Tempo,
+            min: None,
+            max: Some(80.0)
+        }
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn test_conditions_match_songs() {
+        let mbv = song("a", "Slowdive, My Bloody Valentine", Some(72.0));
+        let no_tempo = song("b", "Ride", None);
+        let tags = HashSet::new();
+
+        assert!(RuleCondition::Artist {
+            name: "my bloody valentine".to_string()
+        }
+        .matches(&mbv, &tags));
+        let slow = RuleCondition::AudioFeature {
+            feature: Feature::Tempo,
+            min: None,
+            max: Some(80.0),
+        };
+        assert!(slow.matches(&mbv, &tags));
+        assert!(!slow.matches(&no_tempo, &tags));
+        assert!(RuleCondition::ReleaseYear {
+            min: Some(1991),
+            max: Some(1991)
+        }
+        .matches(&mbv, &tags));
+    }
+
+    #[test]
+    fn test_plan_skips_existing_tags() {
+        let songs = vec![
+            song("a", "My Bloody Valentine", Some(120.0)),
+            song("b", "My Bloody Valentine", Some(70.0)),
+        ];
+        let rules = vec![rule(
+            1,
+            SHOEGAZE,
+            vec![RuleCondition::Artist {
+                name: "My Bloody Valentine".to_string(),
+            }],
+        )];
+        let existing = HashMap::from([("a".to_string(), HashSet::from([SHOEGAZE]))]);
+
+        let planned = plan_rule_applications(&rules, &songs, &existing);
+
+        assert_eq!(
+            planned,
+            vec![RuleApplication {
+                rule_id: 1,
+                song_id: "b".to_string(),
+                tag_id: SHOEGAZE
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_chains_rules_on_applied_tags() {
+        let songs = vec![song("a", "My Bloody Valentine", Some(70.0))];
+        // Declared before the rules it depends on, so it only matches on a
+        // later pass.
+        let dreamy = rule(
+            3,
+            DREAMY,
+            vec![
+                RuleCondition::HasTag { tag_id: SHOEGAZE },
+                RuleCondition::HasTag { tag_id: SLOW },
+            ],
+        );
+        let shoegaze = rule(
+            1,
+            SHOEGAZE,
+            vec![RuleCondition::Artist {
+                name: "My Bloody Valentine".to_string(),
+            }],
+        );
+        let slow = rule(
+            2,
+            SLOW,
+            vec![RuleCondition::AudioFeature {
+                feature: Feature::Tempo,
+                min: None,
+                max: Some(80.0),
+            }],
+        );
+
+        let planned = plan_rule_applications(&[dreamy, shoegaze, slow], &songs, &HashMap::new());
+
+        let tags: Vec<i32> = planned.iter().map(|a| a.tag_id).collect();
+        assert_eq!(tags, vec![SHOEGAZE, SLOW, DREAMY]);
+    }
+
+    #[test]
+    fn test_rule_without_conditions_never_matches() {
+        let songs = vec![song("a", "Ride", Some(90.0))];
+
+        let planned = plan_rule_applications(&[rule(1, SLOW, vec![])], &songs, &HashMap::new());
+
+        assert!(planned.is_empty());
+    }
+}
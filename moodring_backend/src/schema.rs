@@ -1,5 +1,147 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    account_erasures (id) {
+        id -> Int4,
+        receipt -> Varchar,
+        tags_deleted -> Int4,
+        song_tags_deleted -> Int4,
+        playlists_deleted -> Int4,
+        sessions_deleted -> Int4,
+        export_requested -> Bool,
+        erased_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    data_exports (id) {
+        id -> Int4,
+        token_hash -> Varchar,
+        filename -> Varchar,
+        content -> Bytea,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    email_verifications (id) {
+        id -> Int4,
+        user_id -> Int4,
+        email -> Varchar,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    follows (id) {
+        id -> Int4,
+        follower_id -> Int4,
+        followee_id -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        provider_user_id -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    local_files (path) {
+        path -> Varchar,
+        song_id -> Varchar,
+        size -> Int8,
+        modified_at -> Timestamp,
+        scanned_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    oauth_states (id) {
+        id -> Int4,
+        state_hash -> Varchar,
+        code_verifier -> Varchar,
+        redirect_uri -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    plays (id) {
+        id -> Int4,
+        user_id -> Int4,
+        song_id -> Varchar,
+        played_at -> Timestamp,
+        ms_played -> Nullable<Int4>,
+        source -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    playlists (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        query -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        ordering -> Varchar,
+        seed -> Nullable<Int8>,
+        no_repeat_artist -> Bool,
+        max_tracks -> Nullable<Int4>,
+        max_duration_ms -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    share_links (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        tag_id -> Nullable<Int4>,
+        playlist_id -> Nullable<Int4>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        access_count -> Int4,
+        last_accessed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    song_matches (id) {
+        id -> Int4,
+        user_id -> Int4,
+        song_id -> Varchar,
+        matched_song_id -> Varchar,
+        method -> Varchar,
+        confidence -> Float8,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     song_tags (id) {
         id -> Int4,
@@ -7,6 +149,44 @@ diesel::table! {
         song_id -> Varchar,
         tag_id -> Int4,
         created_at -> Timestamp,
+        rule_id -> Nullable<Int4>,
+        source -> Varchar,
+        confidence -> Nullable<Float8>,
+        note -> Nullable<Text>,
+        updated_at -> Timestamp,
+        weight -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    songs (id) {
+        id -> Varchar,
+        title -> Varchar,
+        artist -> Varchar,
+        album -> Nullable<Varchar>,
+        release_year -> Nullable<Int4>,
+        duration_ms -> Nullable<Int4>,
+        isrc -> Nullable<Varchar>,
+        energy -> Nullable<Float8>,
+        valence -> Nullable<Float8>,
+        tempo -> Nullable<Float8>,
+        danceability -> Nullable<Float8>,
+        acousticness -> Nullable<Float8>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    tag_rules (id) {
+        id -> Int4,
+        user_id -> Int4,
+        tag_id -> Int4,
+        name -> Varchar,
+        conditions -> Jsonb,
+        enabled -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -18,6 +198,9 @@ diesel::table! {
         color -> Nullable<Varchar>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        parent_id -> Nullable<Int4>,
+        workspace_id -> Nullable<Int4>,
+        is_public -> Bool,
     }
 }
 
@@ -32,11 +215,19 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_songs (user_id, song_id) {
+        user_id -> Int4,
+        song_id -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int4,
-        spotify_id -> Varchar,
-        email -> Varchar,
+        spotify_id -> Nullable<Varchar>,
+        email -> Nullable<Varchar>,
         display_name -> Nullable<Varchar>,
         spotify_access_token -> Nullable<Varchar>,
         spotify_refresh_token -> Nullable<Varchar>,
@@ -44,11 +235,107 @@ diesel::table! {
         profile_image_url -> Nullable<Varchar>,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        spotify_unlinked_at -> Nullable<Timestamp>,
+        spotify_scopes -> Nullable<Varchar>,
+        email_verified -> Bool,
+        timezone -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamp,
+        last_status_code -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+        delivered_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        user_id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    workspace_members (id) {
+        id -> Int4,
+        workspace_id -> Int4,
+        user_id -> Int4,
+        role -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    workspaces (id) {
+        id -> Int4,
+        name -> Varchar,
+        created_by -> Nullable<Int4>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
+diesel::joinable!(email_verifications -> users (user_id));
+diesel::joinable!(identities -> users (user_id));
+diesel::joinable!(local_files -> songs (song_id));
+diesel::joinable!(playlists -> users (user_id));
+diesel::joinable!(plays -> users (user_id));
+diesel::joinable!(sessions -> users (user_id));
+diesel::joinable!(share_links -> playlists (playlist_id));
+diesel::joinable!(share_links -> tags (tag_id));
+diesel::joinable!(share_links -> users (user_id));
+diesel::joinable!(song_matches -> users (user_id));
+diesel::joinable!(song_tags -> tag_rules (rule_id));
 diesel::joinable!(song_tags -> tags (tag_id));
 diesel::joinable!(song_tags -> users (user_id));
+diesel::joinable!(tag_rules -> tags (tag_id));
+diesel::joinable!(tag_rules -> users (user_id));
 diesel::joinable!(tags -> users (user_id));
+diesel::joinable!(tags -> workspaces (workspace_id));
+diesel::joinable!(user_songs -> songs (song_id));
+diesel::joinable!(user_songs -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> users (user_id));
+diesel::joinable!(workspace_members -> users (user_id));
+diesel::joinable!(workspace_members -> workspaces (workspace_id));
 
-diesel::allow_tables_to_appear_in_same_query!(song_tags, tags, temp_songs, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    account_erasures,
+    data_exports,
+    email_verifications,
+    follows,
+    identities,
+    local_files,
+    oauth_states,
+    plays,
+    playlists,
+    sessions,
+    share_links,
+    song_matches,
+    song_tags,
+    songs,
+    tag_rules,
+    tags,
+    temp_songs,
+    user_songs,
+    users,
+    webhook_deliveries,
+    webhooks,
+    workspace_members,
+    workspaces,
+);
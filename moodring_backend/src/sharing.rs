@@ -0,0 +1,334 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::auth::{generate_token, hash_token};
+use crate::export::{resolve_playlist_source, song_location, PlaylistSource};
+use crate::permissions::{authorize_tag, TagAction};
+use crate::playlists::find_playlist;
+use crate::tag_query::TagQueryMatch;
+use crate::{schema, DbPool, TransactionError};
+
+/// A public, read-only link to a tag or saved playlist. The token itself is
+/// only shown once, when the link is created.
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::share_links)]
+pub struct ShareLink {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub tag_id: Option<i32>,
+    pub playlist_id: Option<i32>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub access_count: i32,
+    pub last_accessed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NewShareLink {
+    pub tag_id: Option<i32>,
+    pub playlist_id: Option<i32>,
+    /// Links without an expiry work until revoked.
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl NewShareLink {
+    pub fn validate(&self, now: NaiveDateTime) -> Result<(), String> {
+        if self.tag_id.is_some() == self.playlist_id.is_some() {
+            return Err("Provide exactly one of tag_id or playlist_id".to_string());
+        }
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err("expires_at must be in the future".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CreatedShareLink {
+    pub link: ShareLink,
+    pub token: String,
+}
+
+/// What a shared track shows: catalogue metadata only, without the owner's
+/// weights, notes or tagging history.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SharedTrack {
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub release_year: Option<i32>,
+    pub duration_ms: Option<i32>,
+}
+
+impl From<&TagQueryMatch> for SharedTrack {
+    fn from(track: &TagQueryMatch) -> Self {
+        let song = track.song.as_ref();
+        SharedTrack {
+            location: song_location(&track.song_id),
+            title: song.map(|song| song.title.clone()),
+            artist: song.map(|song| song.artist.clone()),
+            album: song.and_then(|song| song.album.clone()),
+            release_year: song.and_then(|song| song.release_year),
+            duration_ms: song.and_then(|song| song.duration_ms),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SharedCollection {
+    pub name: String,
+    pub tracks: Vec<SharedTrack>,
+}
+
+pub async fn create_share_link(
+    pool: &DbPool,
+    user_id: i32,
+    new_link: NewShareLink,
+) -> Result<CreatedShareLink, String> {
+    use schema::share_links;
+
+    new_link.validate(chrono::Utc::now().naive_utc())?;
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        // Workspace tags stay within the workspace; only personal tags can
+        // be shared publicly, directly or through a playlist's query.
+        match (new_link.tag_id, new_link.playlist_id) {
+            (Some(tag_id), _) => {
+                let tag = authorize_tag(&mut conn, user_id, tag_id, TagAction::Manage)?;
+                if tag.workspace_id.is_some() {
+                    return Err("Workspace tags can't be shared by link".to_string());
+                }
+            }
+            (_, Some(playlist_id)) => {
+                let playlist = find_playlist(&mut conn, user_id, playlist_id)?;
+                playlist.personal_tracks(&mut conn).map_err(|e| {
+                    format!("Only playlists over personal tags can be shared by link: {e}")
+                })?;
+            }
+            (None, None) => {
+                return Err("Tag or playlist not found or not owned by user".to_string())
+            }
+        }
+
+        let token = generate_token();
+        let link = diesel::insert_into(share_links::table)
+            .values((
+                share_links::user_id.eq(user_id),
+                share_links::token_hash.eq(hash_token(&token)),
+                share_links::tag_id.eq(new_link.tag_id),
+                share_links::playlist_id.eq(new_link.playlist_id),
+                share_links::expires_at.eq(new_link.expires_at),
+            ))
+            .get_result::<ShareLink>(&mut conn)
+            .map_err(|e| format!("Failed to create share link: {e}"))?;
+        Ok(CreatedShareLink { link, token })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn list_share_links(pool: &DbPool, user_id: i32) -> Result<Vec<ShareLink>, String> {
+    use schema::share_links::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::share_links
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.desc())
+            .load::<ShareLink>(&mut conn)
+            .map_err(|e| format!("Failed to load share links: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Stops a link from working. Revoked links stay listed with their counts.
+pub async fn revoke_share_link(pool: &DbPool, user_id: i32, link_id: i32) -> Result<usize, String> {
+    use schema::share_links::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::update(
+            dsl::share_links
+                .filter(dsl::id.eq(link_id))
+                .filter(dsl::user_id.eq(user_id))
+                .filter(dsl::revoked_at.is_null()),
+        )
+        .set(dsl::revoked_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(&mut conn)
+        .map_err(|e| format!("Failed to revoke share link: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Resolves a link to the current songs of what it shares and counts the
+/// visit. Unknown, expired and revoked links all look the same.
+pub async fn open_share_link(pool: &DbPool, token: String) -> Result<SharedCollection, String> {
+    use schema::share_links::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let now = chrono::Utc::now().naive_utc();
+            let link = diesel::update(
+                dsl::share_links
+                    .filter(dsl::token_hash.eq(hash_token(&token)))
+                    .filter(dsl::revoked_at.is_null())
+                    .filter(dsl::expires_at.is_null().or(dsl::expires_at.gt(now))),
+            )
+            .set((
+                dsl::access_count.eq(dsl::access_count + 1),
+                dsl::last_accessed_at.eq(now),
+            ))
+            .get_result::<ShareLink>(conn)
+            .optional()?
+            .ok_or_else(|| "Share link not found or expired".to_string())?;
+
+            // A saved playlist is resolved against personal tags only, so
+            // a query that came to name a workspace tag doesn't publish
+            // other members' links.
+            let (name, tracks) = match (link.tag_id, link.playlist_id) {
+                (Some(tag_id), _) => {
+                    resolve_playlist_source(conn, link.user_id, &PlaylistSource::Tag(tag_id))?
+                }
+                (_, Some(playlist_id)) => {
+                    let playlist = find_playlist(conn, link.user_id, playlist_id)?;
+                    let tracks = playlist.personal_tracks(conn)?;
+                    (playlist.name, tracks)
+                }
+                (None, None) => return Err("Share link has nothing to share".to_string().into()),
+            };
+            Ok(SharedCollection {
+                name,
+                tracks: tracks.iter().map(SharedTrack::from).collect(),
+            })
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Song;
+
+    fn now() -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_new_share_link_validation() {
+        let tag_link = NewShareLink {
+            tag_id: Some(1),
+            playlist_id: None,
+            expires_at: Some(now() + chrono::Duration::days(7)),
+        };
+        assert!(tag_link.validate(now()).is_ok());
+
+        let both = NewShareLink {
+            playlist_id: Some(2),
+            ..tag_link.clone()
+        };
+        assert!(both.validate(now()).is_err());
+
+        let expired = NewShareLink {
+            expires_at: Some(now()),
+            ..tag_link
+        };
+        assert!(expired.validate(now()).is_err());
+    }
+
+    #[test]
+    fn test_shared_track_leaves_out_private_fields() {
+        let track = TagQueryMatch {
+            song_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            score: 9,
+            last_tagged_at: now(),
+            song: Some(Song {
+                id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+                title: "Never Gonna Give You Up".to_string(),
+                artist: "Rick Astley".to_string(),
+                album: None,
+                release_year: Some(1987),
+                duration_ms: Some(213573),
+                isrc: None,
+                energy: Some(0.9),
+                valence: None,
+                tempo: None,
+                danceability: None,
+                acousticness: None,
+                created_at: now(),
+                updated_at: now(),
+            }),
+            matched_song_ids: vec!["local:abc".to_string()],
+        };
+
+        let shared = serde_json::to_value(SharedTrack::from(&track)).unwrap();
+
+        assert_eq!(
+            shared,
+            serde_json::json!({
+                "location": "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC",
+                "title": "Never Gonna Give You Up",
+                "artist": "Rick Astley",
+                "album": null,
+                "release_year": 1987,
+                "duration_ms": 213573
+            })
+        );
+    }
+
+    #[test]
+    fn test_share_link_hides_token_hash() {
+        let link = ShareLink {
+            id: 1,
+            user_id: 2,
+            token_hash: "secret".to_string(),
+            tag_id: Some(3),
+            playlist_id: None,
+            expires_at: None,
+            revoked_at: None,
+            access_count: 4,
+            last_accessed_at: None,
+            created_at: now(),
+        };
+
+        let serialized = serde_json::to_value(&link).unwrap();
+
+        assert!(serialized.get("token_hash").is_none());
+        assert_eq!(serialized["access_count"], 4);
+    }
+}
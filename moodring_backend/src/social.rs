@@ -0,0 +1,484 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::export::{resolve_playlist_source, PlaylistSource};
+use crate::sharing::{SharedCollection, SharedTrack};
+use crate::webhooks::{enqueue_links_added, enqueue_tags_created};
+use crate::{schema, DbPool, NewSongTag, NewTag, SongTag, Tag, TagSource, TransactionError};
+
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::follows)]
+pub struct Follow {
+    pub id: i32,
+    pub follower_id: i32,
+    pub followee_id: i32,
+    pub created_at: NaiveDateTime,
+}
+
+/// The other side of a follow, as shown in following and follower lists.
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FollowedUser {
+    pub user_id: i32,
+    pub display_name: Option<String>,
+    pub profile_image_url: Option<String>,
+    pub followed_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NewFollow {
+    pub user_id: i32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TagVisibility {
+    pub is_public: bool,
+}
+
+/// A public tag and the public tags nested under it.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicTagNode {
+    #[serde(flatten)]
+    pub tag: Tag,
+    pub children: Vec<PublicTagNode>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ForkTag {
+    /// Defaults to the original tag's name.
+    pub name: Option<String>,
+    /// Also copy the songs the owner tagged with it.
+    #[serde(default)]
+    pub include_songs: bool,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ForkedTag {
+    pub tag: Tag,
+    pub song_tags_copied: usize,
+}
+
+/// Nests public tags under their parents. A tag whose parent is private is
+/// shown at the top level, so nothing about the private tag leaks.
+pub fn public_tag_tree(tags: Vec<Tag>) -> Vec<PublicTagNode> {
+    fn build(tag: Tag, children: &mut HashMap<i32, Vec<Tag>>) -> PublicTagNode {
+        let nested = children.remove(&tag.id).unwrap_or_default();
+        PublicTagNode {
+            tag,
+            children: nested.into_iter().map(|c| build(c, children)).collect(),
+        }
+    }
+
+    let ids: Vec<i32> = tags.iter().map(|tag| tag.id).collect();
+    let mut roots = Vec::new();
+    let mut children: HashMap<i32, Vec<Tag>> = HashMap::new();
+    for tag in tags {
+        match tag.parent_id.filter(|parent_id| ids.contains(parent_id)) {
+            Some(parent_id) => children.entry(parent_id).or_default().push(tag),
+            None => roots.push(tag),
+        }
+    }
+    roots
+        .into_iter()
+        .map(|tag| build(tag, &mut children))
+        .collect()
+}
+
+/// Copies of the owner's links to a forked tag, as the forking user's own.
+/// Notes stay private to the owner.
+pub fn forked_song_tags(
+    user_id: i32,
+    tag_id: i32,
+    links: Vec<(String, Option<i32>)>,
+) -> Vec<NewSongTag> {
+    links
+        .into_iter()
+        .map(|(song_id, weight)| NewSongTag {
+            user_id,
+            song_id,
+            tag_id,
+            rule_id: None,
+            source: TagSource::Import,
+            confidence: None,
+            note: None,
+            weight,
+        })
+        .collect()
+}
+
+/// Fails unless `viewer_id` may see `owner_id`'s public tags: users see
+/// their own, and those of the users they follow.
+fn require_follow(conn: &mut PgConnection, viewer_id: i32, owner_id: i32) -> Result<(), String> {
+    use schema::follows::dsl;
+
+    if viewer_id == owner_id {
+        return Ok(());
+    }
+    let following = dsl::follows
+        .filter(dsl::follower_id.eq(viewer_id))
+        .filter(dsl::followee_id.eq(owner_id))
+        .count()
+        .get_result::<i64>(conn)
+        .map_err(|e| format!("Failed to check follow: {e}"))?;
+    if following == 0 {
+        return Err("Follow this user to see their public tags".to_string());
+    }
+    Ok(())
+}
+
+fn find_public_tag(conn: &mut PgConnection, owner_id: i32, tag_id: i32) -> Result<Tag, String> {
+    use schema::tags::dsl;
+
+    dsl::tags
+        .filter(dsl::id.eq(tag_id))
+        .filter(dsl::user_id.eq(owner_id))
+        .filter(dsl::workspace_id.is_null())
+        .filter(dsl::is_public.eq(true))
+        .first::<Tag>(conn)
+        .optional()
+        .map_err(|e| format!("Failed to load tag: {e}"))?
+        .ok_or_else(|| "Tag not found or not public".to_string())
+}
+
+/// Makes one of the user's own tags public or private again. Workspace tags
+/// can't be made public.
+pub async fn set_tag_visibility(
+    pool: &DbPool,
+    user_id: i32,
+    tag_id: i32,
+    visibility: TagVisibility,
+) -> Result<Tag, String> {
+    use schema::tags::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::update(
+            dsl::tags
+                .filter(dsl::id.eq(tag_id))
+                .filter(dsl::user_id.eq(user_id))
+                .filter(dsl::workspace_id.is_null()),
+        )
+        .set(dsl::is_public.eq(visibility.is_public))
+        .get_result::<Tag>(&mut conn)
+        .optional()
+        .map_err(|e| format!("Failed to update tag: {e}"))?
+        .ok_or_else(|| "Tag not found or not owned by user".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn follow_user(pool: &DbPool, user_id: i32, followee_id: i32) -> Result<Follow, String> {
+    use schema::{follows, users};
+
+    if user_id == followee_id {
+        return Err("Users can't follow themselves".to_string());
+    }
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        users::table
+            .find(followee_id)
+            .select(users::id)
+            .first::<i32>(&mut conn)
+            .map_err(|_| "User not found".to_string())?;
+
+        // Following someone twice keeps the original follow.
+        diesel::insert_into(follows::table)
+            .values((
+                follows::follower_id.eq(user_id),
+                follows::followee_id.eq(followee_id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .map_err(|e| format!("Failed to follow user: {e}"))?;
+        follows::table
+            .filter(follows::follower_id.eq(user_id))
+            .filter(follows::followee_id.eq(followee_id))
+            .first::<Follow>(&mut conn)
+            .map_err(|e| format!("Failed to load follow: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn unfollow_user(pool: &DbPool, user_id: i32, followee_id: i32) -> Result<usize, String> {
+    use schema::follows::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::delete(
+            dsl::follows
+                .filter(dsl::follower_id.eq(user_id))
+                .filter(dsl::followee_id.eq(followee_id)),
+        )
+        .execute(&mut conn)
+        .map_err(|e| format!("Failed to unfollow user: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// The users `user_id` follows, most recently followed first.
+pub async fn list_following(pool: &DbPool, user_id: i32) -> Result<Vec<FollowedUser>, String> {
+    use schema::{follows, users};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        follows::table
+            .inner_join(users::table.on(users::id.eq(follows::followee_id)))
+            .filter(follows::follower_id.eq(user_id))
+            .order(follows::created_at.desc())
+            .select((
+                users::id,
+                users::display_name,
+                users::profile_image_url,
+                follows::created_at,
+            ))
+            .load::<FollowedUser>(&mut conn)
+            .map_err(|e| format!("Failed to load followed users: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// The users following `user_id`, most recent first.
+pub async fn list_followers(pool: &DbPool, user_id: i32) -> Result<Vec<FollowedUser>, String> {
+    use schema::{follows, users};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        follows::table
+            .inner_join(users::table.on(users::id.eq(follows::follower_id)))
+            .filter(follows::followee_id.eq(user_id))
+            .order(follows::created_at.desc())
+            .select((
+                users::id,
+                users::display_name,
+                users::profile_image_url,
+                follows::created_at,
+            ))
+            .load::<FollowedUser>(&mut conn)
+            .map_err(|e| format!("Failed to load followers: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// The public tags of a user the viewer follows, as a tree.
+pub async fn browse_public_tags(
+    pool: &DbPool,
+    viewer_id: i32,
+    owner_id: i32,
+) -> Result<Vec<PublicTagNode>, String> {
+    use schema::tags::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        require_follow(&mut conn, viewer_id, owner_id)?;
+        let tags = dsl::tags
+            .filter(dsl::user_id.eq(owner_id))
+            .filter(dsl::workspace_id.is_null())
+            .filter(dsl::is_public.eq(true))
+            .order(dsl::name.asc())
+            .load::<Tag>(&mut conn)
+            .map_err(|e| format!("Failed to load tags: {e}"))?;
+        Ok(public_tag_tree(tags))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// The songs a followed user tagged with one of their public tags. Like a
+/// share link, this shows catalogue metadata but not weights or notes.
+pub async fn public_tag_songs(
+    pool: &DbPool,
+    viewer_id: i32,
+    owner_id: i32,
+    tag_id: i32,
+) -> Result<SharedCollection, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        require_follow(&mut conn, viewer_id, owner_id)?;
+        let tag = find_public_tag(&mut conn, owner_id, tag_id)?;
+        let (name, tracks) =
+            resolve_playlist_source(&mut conn, owner_id, &PlaylistSource::Tag(tag.id))?;
+        Ok(SharedCollection {
+            name,
+            tracks: tracks.iter().map(SharedTrack::from).collect(),
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Copies a followed user's public tag into the user's own library, as a
+/// private top-level tag, optionally with the owner's links to it.
+pub async fn fork_tag(
+    pool: &DbPool,
+    user_id: i32,
+    owner_id: i32,
+    tag_id: i32,
+    fork: ForkTag,
+) -> Result<ForkedTag, String> {
+    use schema::{song_tags, tags};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            require_follow(conn, user_id, owner_id)?;
+            let source = find_public_tag(conn, owner_id, tag_id)?;
+
+            let name = fork
+                .name
+                .as_deref()
+                .map(str::trim)
+                .unwrap_or(&source.name)
+                .to_string();
+            if name.is_empty() {
+                return Err("Tag name must not be empty".to_string().into());
+            }
+            let taken = tags::table
+                .filter(tags::user_id.eq(user_id))
+                .filter(tags::workspace_id.is_null())
+                .filter(tags::name.eq(&name))
+                .count()
+                .get_result::<i64>(conn)?;
+            if taken > 0 {
+                return Err(format!("You already have a tag named {name}").into());
+            }
+
+            let tag = diesel::insert_into(tags::table)
+                .values(&NewTag {
+                    user_id,
+                    name,
+                    color: source.color.clone(),
+                    parent_id: None,
+                    workspace_id: None,
+                    is_public: false,
+                })
+                .get_result::<Tag>(conn)?;
+            enqueue_tags_created(conn, user_id, std::slice::from_ref(&tag))?;
+
+            let mut song_tags_copied = 0;
+            if fork.include_songs {
+                let links = song_tags::table
+                    .filter(song_tags::user_id.eq(owner_id))
+                    .filter(song_tags::tag_id.eq(source.id))
+                    .select((song_tags::song_id, song_tags::weight))
+                    .load::<(String, Option<i32>)>(conn)?;
+                let copied = diesel::insert_into(song_tags::table)
+                    .values(forked_song_tags(user_id, tag.id, links))
+                    .get_results::<SongTag>(conn)?;
+                enqueue_links_added(conn, user_id, &copied)?;
+                song_tags_copied = copied.len();
+            }
+            Ok(ForkedTag {
+                tag,
+                song_tags_copied,
+            })
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(id: i32, name: &str, parent_id: Option<i32>) -> Tag {
+        let now = chrono::Utc::now().naive_utc();
+        Tag {
+            id,
+            user_id: 1,
+            name: name.to_string(),
+            color: None,
+            created_at: now,
+            updated_at: now,
+            parent_id,
+            workspace_id: None,
+            is_public: true,
+        }
+    }
+
+    fn names(nodes: &[PublicTagNode]) -> Vec<&str> {
+        nodes.iter().map(|node| node.tag.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_public_tag_tree_nests_children() {
+        let tree = public_tag_tree(vec![
+            tag(1, "calm", None),
+            tag(2, "rain", Some(1)),
+            tag(3, "drizzle", Some(2)),
+            tag(4, "upbeat", None),
+        ]);
+
+        assert_eq!(names(&tree), vec!["calm", "upbeat"]);
+        assert_eq!(names(&tree[0].children), vec!["rain"]);
+        assert_eq!(names(&tree[0].children[0].children), vec!["drizzle"]);
+        assert!(tree[1].children.is_empty());
+    }
+
+    #[test]
+    fn test_public_tag_tree_lifts_tags_under_private_parents() {
+        // Tag 7 is private, so it isn't in the list.
+        let tree = public_tag_tree(vec![tag(2, "rain", Some(7)), tag(3, "drizzle", Some(2))]);
+
+        assert_eq!(names(&tree), vec!["rain"]);
+        assert_eq!(names(&tree[0].children), vec!["drizzle"]);
+    }
+
+    #[test]
+    fn test_forked_song_tags_drop_private_fields() {
+        let links = forked_song_tags(5, 9, vec![("t1".to_string(), Some(4))]);
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].user_id, 5);
+        assert_eq!(links[0].tag_id, 9);
+        assert_eq!(links[0].weight, Some(4));
+        assert_eq!(links[0].source, TagSource::Import);
+        assert!(links[0].note.is_none());
+    }
+}
@@ -0,0 +1,879 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::{NewSong, SpotifyUserProfile};
+
+pub const DEFAULT_API_BASE_URL: &str = "https://api.spotify.com/v1";
+
+// Spotify caps the number of IDs accepted by its batch endpoints.
+const MAX_TRACK_IDS_PER_REQUEST: usize = 50;
+const MAX_AUDIO_FEATURE_IDS_PER_REQUEST: usize = 100;
+const PLAYLIST_PAGE_SIZE: usize = 100;
+const SAVED_TRACKS_PAGE_SIZE: usize = 50;
+const MAX_PLAYLIST_TRACKS_PER_REQUEST: usize = 100;
+const RECENTLY_PLAYED_LIMIT: usize = 50;
+
+/// Thin client for the Spotify Web API endpoints the backend uses.
+#[derive(Clone, Debug)]
+pub struct SpotifyClient {
+    base_url: String,
+    http: reqwest::Client,
+}
+
+impl Default for SpotifyClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpotifyClient {
+    /// Uses `SPOTIFY_API_BASE_URL` when set, otherwise the public API.
+    pub fn new() -> Self {
+        let base_url =
+            env::var("SPOTIFY_API_BASE_URL").unwrap_or_else(|_| DEFAULT_API_BASE_URL.to_string());
+        Self::with_base_url(&base_url)
+    }
+
+    pub fn with_base_url(base_url: &str) -> Self {
+        SpotifyClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        access_token: &str,
+        path: &str,
+    ) -> Result<T, String> {
+        let response = self
+            .http
+            .get(format!("{}{path}", self.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to send Spotify request: {e}"))?;
+
+        let status_code = response.status();
+        if !status_code.is_success() {
+            let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+            return Err(format!("Spotify API error {status_code}: {error_text}"));
+        }
+
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| format!("Failed to parse Spotify response: {e}"))
+    }
+
+    async fn post_json<T: for<'de> Deserialize<'de>>(
+        &self,
+        access_token: &str,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<T, String> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to send Spotify request: {e}"))?;
+
+        let status_code = response.status();
+        if !status_code.is_success() {
+            let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+            return Err(format!("Spotify API error {status_code}: {error_text}"));
+        }
+
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| format!("Failed to parse Spotify response: {e}"))
+    }
+
+    /// Sends a player command, which Spotify answers with an empty body.
+    async fn send_player_command(
+        &self,
+        method: reqwest::Method,
+        access_token: &str,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<&serde_json::Value>,
+    ) -> Result<(), SpotifyPlayerError> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .query(query);
+        request = match body {
+            Some(body) => request.json(body),
+            None => request.header("Content-Length", "0"),
+        };
+        let response = request.send().await.map_err(|e| SpotifyPlayerError {
+            status: 0,
+            reason: None,
+            message: format!("Failed to send Spotify request: {e}"),
+        })?;
+
+        let status_code = response.status();
+        if status_code.is_success() {
+            return Ok(());
+        }
+        let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+        Err(SpotifyPlayerError::from_response(
+            status_code.as_u16(),
+            &error_text,
+        ))
+    }
+
+    /// The devices the user can play on.
+    pub async fn get_devices(&self, access_token: &str) -> Result<Vec<SpotifyDevice>, String> {
+        let page: SpotifyDevices = self.get_json(access_token, "/me/player/devices").await?;
+        Ok(page.devices)
+    }
+
+    /// Replaces what the user is playing with the given tracks, on
+    /// `device_id` or the active device.
+    pub async fn start_playback(
+        &self,
+        access_token: &str,
+        device_id: Option<&str>,
+        track_ids: &[String],
+    ) -> Result<(), SpotifyPlayerError> {
+        let query: Vec<(&str, &str)> = device_id
+            .map(|device_id| ("device_id", device_id))
+            .into_iter()
+            .collect();
+        let uris: Vec<String> = track_ids
+            .iter()
+            .map(|id| format!("spotify:track:{id}"))
+            .collect();
+        self.send_player_command(
+            reqwest::Method::PUT,
+            access_token,
+            "/me/player/play",
+            &query,
+            Some(&serde_json::json!({ "uris": uris })),
+        )
+        .await
+    }
+
+    /// Adds a track to the end of the user's queue.
+    pub async fn add_to_queue(
+        &self,
+        access_token: &str,
+        device_id: Option<&str>,
+        track_id: &str,
+    ) -> Result<(), SpotifyPlayerError> {
+        let uri = format!("spotify:track:{track_id}");
+        let mut query = vec![("uri", uri.as_str())];
+        if let Some(device_id) = device_id {
+            query.push(("device_id", device_id));
+        }
+        self.send_player_command(
+            reqwest::Method::POST,
+            access_token,
+            "/me/player/queue",
+            &query,
+            None,
+        )
+        .await
+    }
+
+    /// The user's player state, or `None` when nothing is playing.
+    pub async fn get_currently_playing(
+        &self,
+        access_token: &str,
+    ) -> Result<Option<SpotifyCurrentlyPlaying>, String> {
+        let response = self
+            .http
+            .get(format!("{}/me/player/currently-playing", self.base_url))
+            .header("Authorization", format!("Bearer {access_token}"))
+            .send()
+            .await
+            .map_err(|e| format!("Failed to send Spotify request: {e}"))?;
+
+        let status_code = response.status();
+        if status_code == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !status_code.is_success() {
+            let error_text = response.text().await.unwrap_or("Unknown error".to_string());
+            return Err(format!("Spotify API error {status_code}: {error_text}"));
+        }
+
+        response
+            .json::<SpotifyCurrentlyPlaying>()
+            .await
+            .map(Some)
+            .map_err(|e| format!("Failed to parse Spotify response: {e}"))
+    }
+
+    pub async fn get_current_user(&self, access_token: &str) -> Result<SpotifyUserProfile, String> {
+        self.get_json(access_token, "/me").await
+    }
+
+    pub async fn get_tracks(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<SpotifyTrack>, String> {
+        let mut found = Vec::new();
+        for chunk in track_ids.chunks(MAX_TRACK_IDS_PER_REQUEST) {
+            let page: SpotifyTracksResponse = self
+                .get_json(access_token, &format!("/tracks?ids={}", chunk.join(",")))
+                .await?;
+            found.extend(page.tracks.into_iter().flatten());
+        }
+        Ok(found)
+    }
+
+    pub async fn get_audio_features(
+        &self,
+        access_token: &str,
+        track_ids: &[String],
+    ) -> Result<Vec<SpotifyAudioFeatures>, String> {
+        let mut found = Vec::new();
+        for chunk in track_ids.chunks(MAX_AUDIO_FEATURE_IDS_PER_REQUEST) {
+            let page: SpotifyAudioFeaturesResponse = self
+                .get_json(
+                    access_token,
+                    &format!("/audio-features?ids={}", chunk.join(",")),
+                )
+                .await?;
+            found.extend(page.audio_features.into_iter().flatten());
+        }
+        Ok(found)
+    }
+
+    /// IDs of every Spotify track in a playlist, in playlist order. Local
+    /// files and podcast episodes are skipped.
+    pub async fn get_playlist_track_ids(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+    ) -> Result<Vec<String>, String> {
+        let mut track_ids = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page: SpotifyPlaylistTracksPage = self
+                .get_json(
+                    access_token,
+                    &format!(
+                        "/playlists/{playlist_id}/tracks?fields=items(track(id,type)),next&limit={PLAYLIST_PAGE_SIZE}&offset={offset}"
+                    ),
+                )
+                .await?;
+            track_ids.extend(
+                page.items
+                    .into_iter()
+                    .filter_map(|item| item.track)
+                    .filter(|track| track.kind.as_deref().unwrap_or("track") == "track")
+                    .filter_map(|track| track.id),
+            );
+            if page.next.is_none() {
+                return Ok(track_ids);
+            }
+            offset += PLAYLIST_PAGE_SIZE;
+        }
+    }
+
+    /// IDs of the tracks in the user's Liked Songs, most recently saved
+    /// first.
+    pub async fn get_saved_track_ids(&self, access_token: &str) -> Result<Vec<String>, String> {
+        let mut track_ids = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page: SpotifyPlaylistTracksPage = self
+                .get_json(
+                    access_token,
+                    &format!("/me/tracks?limit={SAVED_TRACKS_PAGE_SIZE}&offset={offset}"),
+                )
+                .await?;
+            track_ids.extend(
+                page.items
+                    .into_iter()
+                    .filter_map(|item| item.track)
+                    .filter_map(|track| track.id),
+            );
+            if page.next.is_none() {
+                return Ok(track_ids);
+            }
+            offset += SAVED_TRACKS_PAGE_SIZE;
+        }
+    }
+
+    /// The user's most recent plays, newest first. With `after` (a Unix
+    /// time in milliseconds), only plays after it.
+    pub async fn get_recently_played(
+        &self,
+        access_token: &str,
+        after: Option<i64>,
+    ) -> Result<Vec<SpotifyPlayHistory>, String> {
+        let mut path = format!("/me/player/recently-played?limit={RECENTLY_PLAYED_LIMIT}");
+        if let Some(after) = after {
+            path.push_str(&format!("&after={after}"));
+        }
+        let page: SpotifyRecentlyPlayedPage = self.get_json(access_token, &path).await?;
+        Ok(page.items)
+    }
+
+    /// Creates a private playlist owned by the given Spotify user.
+    pub async fn create_playlist(
+        &self,
+        access_token: &str,
+        spotify_user_id: &str,
+        name: &str,
+    ) -> Result<SpotifyPlaylist, String> {
+        self.post_json(
+            access_token,
+            &format!("/users/{spotify_user_id}/playlists"),
+            &serde_json::json!({ "name": name, "public": false }),
+        )
+        .await
+    }
+
+    /// Appends tracks to a playlist, keeping their order.
+    pub async fn add_playlist_tracks(
+        &self,
+        access_token: &str,
+        playlist_id: &str,
+        track_ids: &[String],
+    ) -> Result<(), String> {
+        for chunk in track_ids.chunks(MAX_PLAYLIST_TRACKS_PER_REQUEST) {
+            let uris: Vec<String> = chunk
+                .iter()
+                .map(|id| format!("spotify:track:{id}"))
+                .collect();
+            let _: serde_json::Value = self
+                .post_json(
+                    access_token,
+                    &format!("/playlists/{playlist_id}/tracks"),
+                    &serde_json::json!({ "uris": uris }),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyArtist {
+    pub id: Option<String>,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyAlbum {
+    pub name: String,
+    pub release_date: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyExternalIds {
+    pub isrc: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyTrack {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<SpotifyArtist>,
+    pub album: SpotifyAlbum,
+    pub duration_ms: i32,
+    #[serde(default)]
+    pub external_ids: SpotifyExternalIds,
+}
+
+/// A failed player command. Spotify explains why in `reason`, e.g.
+/// `NO_ACTIVE_DEVICE` or `PREMIUM_REQUIRED`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SpotifyPlayerError {
+    /// HTTP status, or 0 when Spotify couldn't be reached.
+    pub status: u16,
+    pub reason: Option<String>,
+    pub message: String,
+}
+
+impl SpotifyPlayerError {
+    fn from_response(status: u16, body: &str) -> Self {
+        #[derive(Deserialize)]
+        #[serde(crate = "rocket::serde")]
+        struct Detail {
+            message: Option<String>,
+            reason: Option<String>,
+        }
+        #[derive(Deserialize)]
+        #[serde(crate = "rocket::serde")]
+        struct Envelope {
+            error: Detail,
+        }
+
+        match serde_json::from_str::<Envelope>(body) {
+            Ok(envelope) => SpotifyPlayerError {
+                status,
+                reason: envelope.error.reason,
+                message: format!(
+                    "Spotify API error {status}: {}",
+                    envelope.error.message.unwrap_or_default()
+                ),
+            },
+            Err(_) => SpotifyPlayerError {
+                status,
+                reason: None,
+                message: format!("Spotify API error {status}: {body}"),
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyDevice {
+    /// Missing for devices that can't be controlled through the API.
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub is_active: bool,
+    #[serde(default)]
+    pub is_restricted: bool,
+    pub volume_percent: Option<i32>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyDevices {
+    devices: Vec<SpotifyDevice>,
+}
+
+/// What the user's player is doing. The item is kept as JSON because it
+/// can be an episode, an ad or a local file, none of which are catalogue
+/// tracks.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyCurrentlyPlaying {
+    pub is_playing: bool,
+    pub progress_ms: Option<i64>,
+    pub currently_playing_type: String,
+    pub item: Option<serde_json::Value>,
+}
+
+impl SpotifyCurrentlyPlaying {
+    /// The playing item when it is a Spotify track.
+    pub fn track(&self) -> Option<SpotifyTrack> {
+        if self.currently_playing_type != "track" {
+            return None;
+        }
+        self.item
+            .clone()
+            .and_then(|item| serde_json::from_value(item).ok())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyAudioFeatures {
+    pub id: String,
+    pub energy: f64,
+    pub valence: f64,
+    pub tempo: f64,
+    pub danceability: f64,
+    pub acousticness: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyPlaylist {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct SpotifyPlayHistory {
+    pub track: SpotifyTrack,
+    /// RFC 3339 time the play finished.
+    pub played_at: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyRecentlyPlayedPage {
+    items: Vec<SpotifyPlayHistory>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyTracksResponse {
+    tracks: Vec<Option<SpotifyTrack>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyAudioFeaturesResponse {
+    audio_features: Vec<Option<SpotifyAudioFeatures>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyPlaylistTrackRef {
+    id: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyPlaylistItem {
+    track: Option<SpotifyPlaylistTrackRef>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyPlaylistTracksPage {
+    items: Vec<SpotifyPlaylistItem>,
+    next: Option<String>,
+}
+
+impl SpotifyTrack {
+    /// Converts a Spotify track into a catalogue row, merging in its audio
+    /// features when Spotify returned them.
+    pub fn to_new_song(&self, features: Option<&SpotifyAudioFeatures>) -> NewSong {
+        let release_year = self
+            .album
+            .release_date
+            .as_deref()
+            .and_then(|date| date.get(..4))
+            .and_then(|year| year.parse().ok());
+
+        NewSong {
+            id: self.id.clone(),
+            title: self.name.clone(),
+            artist: self
+                .artists
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            album: Some(self.album.name.clone()),
+            release_year,
+            duration_ms: Some(self.duration_ms),
+            isrc: self.external_ids.isrc.clone(),
+            energy: features.map(|f| f.energy),
+            valence: features.map(|f| f.valence),
+            tempo: features.map(|f| f.tempo),
+            danceability: features.map(|f| f.danceability),
+            acousticness: features.map(|f| f.acousticness),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn track_json() -> serde_json::Value {
+        json!({
+            "id": "4uLU6hMCjMI75M1A2tKUQC",
+            "name": "Never Gonna Give You Up",
+            "artists": [{ "id": "0gxyHStUsqpMadRV0Di1Qt", "name": "Rick Astley" }],
+            "album": { "name": "Whenever You Need Somebody", "release_date": "1987-11-12" },
+            "duration_ms": 213573,
+            "external_ids": { "isrc": "GBARL9300135" }
+        })
+    }
+
+    #[test]
+    fn test_track_to_new_song_with_features() {
+        let track: SpotifyTrack = serde_json::from_value(track_json()).unwrap();
+        let features = SpotifyAudioFeatures {
+            id: track.id.clone(),
+            energy: 0.94,
+            valence: 0.91,
+            tempo: 113.3,
+            danceability: 0.72,
+            acousticness: 0.14,
+        };
+
+        let song = track.to_new_song(Some(&features));
+
+        assert_eq!(song.id, "4uLU6hMCjMI75M1A2tKUQC");
+        assert_eq!(song.artist, "Rick Astley");
+        assert_eq!(song.album.as_deref(), Some("Whenever You Need Somebody"));
+        assert_eq!(song.release_year, Some(1987));
+        assert_eq!(song.isrc.as_deref(), Some("GBARL9300135"));
+        assert_eq!(song.tempo, Some(113.3));
+    }
+
+    #[test]
+    fn test_track_to_new_song_without_features() {
+        let mut value = track_json();
+        value["album"]["release_date"] = json!("1987");
+        value.as_object_mut().unwrap().remove("external_ids");
+        let track: SpotifyTrack = serde_json::from_value(value).unwrap();
+
+        let song = track.to_new_song(None);
+
+        assert_eq!(song.release_year, Some(1987));
+        assert_eq!(song.isrc, None);
+        assert_eq!(song.energy, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_audio_features_skips_missing_entries() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/audio-features?ids=a,b")
+            .match_header("authorization", "Bearer token")
+            .with_body(
+                json!({
+                    "audio_features": [
+                        null,
+                        { "id": "b", "energy": 0.2, "valence": 0.3, "tempo": 70.0,
+                          "danceability": 0.4, "acousticness": 0.9 }
+                    ]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let features = client
+            .get_audio_features("token", &["a".to_string(), "b".to_string()])
+            .await
+            .expect("request should succeed");
+
+        mock.assert_async().await;
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].id, "b");
+    }
+
+    #[tokio::test]
+    async fn test_get_playlist_track_ids_skips_local_and_episodes() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/playlists/p1/tracks")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "offset".to_string(),
+                "0".to_string(),
+            ))
+            .with_body(
+                json!({
+                    "items": [
+                        { "track": { "id": "t1", "type": "track" } },
+                        { "track": { "id": null, "type": "track" } },
+                        { "track": { "id": "e1", "type": "episode" } },
+                        { "track": null },
+                        { "track": { "id": "t2", "type": "track" } }
+                    ],
+                    "next": null
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let ids = client
+            .get_playlist_track_ids("token", "p1")
+            .await
+            .expect("request should succeed");
+
+        mock.assert_async().await;
+        assert_eq!(ids, vec!["t1".to_string(), "t2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_saved_track_ids_follows_pages() {
+        let mut server = mockito::Server::new_async().await;
+        let first = server
+            .mock("GET", "/me/tracks")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "offset".to_string(),
+                "0".to_string(),
+            ))
+            .with_body(
+                json!({
+                    "items": [{ "track": { "id": "t1", "type": "track" } }],
+                    "next": "https://api.spotify.com/v1/me/tracks?offset=50&limit=50"
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+        let second = server
+            .mock("GET", "/me/tracks")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "offset".to_string(),
+                "50".to_string(),
+            ))
+            .with_body(
+                json!({
+                    "items": [{ "track": { "id": "t2", "type": "track" } }],
+                    "next": null
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let ids = client
+            .get_saved_track_ids("token")
+            .await
+            .expect("request should succeed");
+
+        first.assert_async().await;
+        second.assert_async().await;
+        assert_eq!(ids, vec!["t1".to_string(), "t2".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_get_currently_playing() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/me/player/currently-playing")
+            .match_header("authorization", "Bearer token")
+            .with_body(
+                json!({
+                    "is_playing": true,
+                    "progress_ms": 42000,
+                    "currently_playing_type": "track",
+                    "item": track_json()
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let playing = client
+            .get_currently_playing("token")
+            .await
+            .expect("request should succeed")
+            .expect("something should be playing");
+
+        mock.assert_async().await;
+        assert!(playing.is_playing);
+        assert_eq!(playing.progress_ms, Some(42000));
+        assert_eq!(
+            playing.track().map(|track| track.id),
+            Some("4uLU6hMCjMI75M1A2tKUQC".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_currently_playing_nothing_or_episode() {
+        let mut server = mockito::Server::new_async().await;
+        let idle = server
+            .mock("GET", "/me/player/currently-playing")
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let playing = client
+            .get_currently_playing("token")
+            .await
+            .expect("request should succeed");
+
+        idle.assert_async().await;
+        assert!(playing.is_none());
+
+        let episode: SpotifyCurrentlyPlaying = serde_json::from_value(json!({
+            "is_playing": true,
+            "progress_ms": 1000,
+            "currently_playing_type": "episode",
+            "item": null
+        }))
+        .unwrap();
+        assert!(episode.track().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_start_playback_reports_spotify_reason() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("PUT", "/me/player/play")
+            .match_body(mockito::Matcher::Json(json!({
+                "uris": ["spotify:track:t1"]
+            })))
+            .with_status(404)
+            .with_body(
+                json!({
+                    "error": {
+                        "status": 404,
+                        "message": "Player command failed: No active device found",
+                        "reason": "NO_ACTIVE_DEVICE"
+                    }
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        let error = client
+            .start_playback("token", None, &["t1".to_string()])
+            .await
+            .expect_err("request should fail");
+
+        mock.assert_async().await;
+        assert_eq!(error.status, 404);
+        assert_eq!(error.reason.as_deref(), Some("NO_ACTIVE_DEVICE"));
+    }
+
+    #[tokio::test]
+    async fn test_add_to_queue_targets_device() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/me/player/queue")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("uri".to_string(), "spotify:track:t1".to_string()),
+                mockito::Matcher::UrlEncoded("device_id".to_string(), "d 1".to_string()),
+            ]))
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        client
+            .add_to_queue("token", Some("d 1"), "t1")
+            .await
+            .expect("request should succeed");
+
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_add_playlist_tracks_sends_track_uris() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/playlists/p1/tracks")
+            .match_header("authorization", "Bearer token")
+            .match_body(mockito::Matcher::Json(json!({
+                "uris": ["spotify:track:t1", "spotify:track:t2"]
+            })))
+            .with_status(201)
+            .with_body(json!({ "snapshot_id": "s1" }).to_string())
+            .create_async()
+            .await;
+
+        let client = SpotifyClient::with_base_url(&server.url());
+        client
+            .add_playlist_tracks("token", "p1", &["t1".to_string(), "t2".to_string()])
+            .await
+            .expect("request should succeed");
+
+        mock.assert_async().await;
+    }
+}
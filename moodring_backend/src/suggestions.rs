@@ -0,0 +1,326 @@
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::{permissions, schema, DbPool, Song, Tag};
+
+// Tempo is the only unbounded feature; map a typical 50-200 BPM range onto 0-1
+// so it doesn't dominate the distance.
+const TEMPO_FLOOR: f64 = 50.0;
+const TEMPO_CEILING: f64 = 200.0;
+const FEATURE_COUNT: usize = 5;
+const MAX_CANDIDATES: i64 = 1000;
+
+pub const DEFAULT_SUGGESTION_LIMIT: usize = 3;
+pub const DEFAULT_MIN_CONFIDENCE: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AudioFeatures {
+    pub energy: f64,
+    pub valence: f64,
+    pub tempo: f64,
+    pub danceability: f64,
+    pub acousticness: f64,
+}
+
+impl AudioFeatures {
+    /// Feature vector with every component scaled to 0-1.
+    pub fn to_vector(&self) -> [f64; FEATURE_COUNT] {
+        let tempo = ((self.tempo - TEMPO_FLOOR) / (TEMPO_CEILING - TEMPO_FLOOR)).clamp(0.0, 1.0);
+        [
+            self.energy.clamp(0.0, 1.0),
+            self.valence.clamp(0.0, 1.0),
+            tempo,
+            self.danceability.clamp(0.0, 1.0),
+            self.acousticness.clamp(0.0, 1.0),
+        ]
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TagSuggestion {
+    pub tag_id: i32,
+    pub confidence: f64,
+}
+
+#[derive(Clone, Debug)]
+struct Centroid {
+    sum: [f64; FEATURE_COUNT],
+    count: usize,
+}
+
+impl Centroid {
+    fn mean(&self) -> [f64; FEATURE_COUNT] {
+        let mut mean = self.sum;
+        for component in mean.iter_mut() {
+            *component /= self.count as f64;
+        }
+        mean
+    }
+}
+
+/// A user's tag profile: the centroid of the audio features of every song
+/// they have put under each tag.
+#[derive(Clone, Debug, Default)]
+pub struct TagProfile {
+    centroids: HashMap<i32, Centroid>,
+}
+
+impl TagProfile {
+    pub fn learn<I>(samples: I) -> Self
+    where
+        I: IntoIterator<Item = (i32, AudioFeatures)>,
+    {
+        let mut centroids: HashMap<i32, Centroid> = HashMap::new();
+        for (tag_id, features) in samples {
+            let centroid = centroids.entry(tag_id).or_insert(Centroid {
+                sum: [0.0; FEATURE_COUNT],
+                count: 0,
+            });
+            for (total, value) in centroid.sum.iter_mut().zip(features.to_vector()) {
+                *total += value;
+            }
+            centroid.count += 1;
+        }
+        TagProfile { centroids }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty()
+    }
+
+    /// Scores every learned tag against `features`, best first.
+    ///
+    /// Confidence is the similarity to the tag centroid (1 minus the distance
+    /// over the largest possible distance), discounted by `n / (n + 1)` so a
+    /// tag learned from a single song never reaches more than half confidence.
+    pub fn suggest(&self, features: &AudioFeatures, exclude: &HashSet<i32>) -> Vec<TagSuggestion> {
+        let vector = features.to_vector();
+        let max_distance = (FEATURE_COUNT as f64).sqrt();
+
+        let mut suggestions: Vec<TagSuggestion> = self
+            .centroids
+            .iter()
+            .filter(|(tag_id, _)| !exclude.contains(tag_id))
+            .map(|(&tag_id, centroid)| {
+                let distance = centroid
+                    .mean()
+                    .iter()
+                    .zip(vector.iter())
+                    .map(|(a, b)| (a - b).powi(2))
+                    .sum::<f64>()
+                    .sqrt();
+                let similarity = 1.0 - distance / max_distance;
+                let support = centroid.count as f64 / (centroid.count as f64 + 1.0);
+                TagSuggestion {
+                    tag_id,
+                    confidence: similarity * support,
+                }
+            })
+            .collect();
+
+        suggestions.sort_by(|a, b| {
+            b.confidence
+                .total_cmp(&a.confidence)
+                .then(a.tag_id.cmp(&b.tag_id))
+        });
+        suggestions
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SuggestedTag {
+    pub tag: Tag,
+    pub confidence: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SongSuggestions {
+    pub song: Song,
+    pub suggestions: Vec<SuggestedTag>,
+}
+
+/// Proposes tags for songs in the user's library they have not tagged yet, or
+/// for a single catalogue song when `only_song_id` is given (its existing tags
+/// are skipped).
+pub async fn suggest_tags_for_user(
+    pool: &DbPool,
+    user_id: i32,
+    only_song_id: Option<String>,
+    limit: usize,
+    min_confidence: f64,
+) -> Result<Vec<SongSuggestions>, String> {
+    use schema::{song_tags, songs, user_songs};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let links = song_tags::table
+            .filter(song_tags::user_id.eq(user_id))
+            .select((song_tags::song_id, song_tags::tag_id))
+            .load::<(String, i32)>(&mut conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))?;
+
+        let tagged_ids: Vec<String> = links
+            .iter()
+            .map(|(song_id, _)| song_id.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let tagged_features: HashMap<String, AudioFeatures> = songs::table
+            .filter(songs::id.eq_any(&tagged_ids))
+            .load::<Song>(&mut conn)
+            .map_err(|e| format!("Failed to load tagged songs: {e}"))?
+            .into_iter()
+            .filter_map(|song| song.audio_features().map(|f| (song.id, f)))
+            .collect();
+
+        let profile =
+            TagProfile::learn(links.iter().filter_map(|(song_id, tag_id)| {
+                tagged_features.get(song_id).map(|f| (*tag_id, *f))
+            }));
+        if profile.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Tags from workspaces the user has left drop out here.
+        let user_tags: HashMap<i32, Tag> = permissions::visible_tags(&mut conn, user_id)?
+            .into_iter()
+            .map(|tag| (tag.id, tag))
+            .collect();
+
+        let candidates = match &only_song_id {
+            Some(song_id) => songs::table
+                .filter(songs::id.eq(song_id))
+                .load::<Song>(&mut conn),
+            None => songs::table
+                .inner_join(user_songs::table)
+                .filter(user_songs::user_id.eq(user_id))
+                .filter(songs::id.ne_all(&tagged_ids))
+                .select(songs::all_columns)
+                .order(user_songs::created_at.desc())
+                .limit(MAX_CANDIDATES)
+                .load::<Song>(&mut conn),
+        }
+        .map_err(|e| format!("Failed to load candidate songs: {e}"))?;
+
+        let mut results: Vec<SongSuggestions> = candidates
+            .into_iter()
+            .filter_map(|song| {
+                let features = song.audio_features()?;
+                let existing: HashSet<i32> = links
+                    .iter()
+                    .filter(|(song_id, _)| *song_id == song.id)
+                    .map(|(_, tag_id)| *tag_id)
+                    .collect();
+                let suggestions: Vec<SuggestedTag> = profile
+                    .suggest(&features, &existing)
+                    .into_iter()
+                    .filter(|s| s.confidence >= min_confidence)
+                    .filter_map(|s| {
+                        user_tags.get(&s.tag_id).map(|tag| SuggestedTag {
+                            tag: tag.clone(),
+                            confidence: s.confidence,
+                        })
+                    })
+                    .take(limit)
+                    .collect();
+                (!suggestions.is_empty()).then_some(SongSuggestions { song, suggestions })
+            })
+            .collect();
+
+        results.sort_by(|a, b| {
+            b.suggestions[0]
+                .confidence
+                .total_cmp(&a.suggestions[0].confidence)
+        });
+        Ok(results)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHILL: i32 = 1;
+    const HYPE: i32 = 2;
+
+    fn features(energy: f64, valence: f64, tempo: f64, acousticness: f64) -> AudioFeatures {
+        AudioFeatures {
+            energy,
+            valence,
+            tempo,
+            danceability: energy,
+            acousticness,
+        }
+    }
+
+    fn fixture_profile() -> TagProfile {
+        TagProfile::learn(vec![
+            (CHILL, features(0.20, 0.40, 72.0, 0.85)),
+            (CHILL, features(0.25, 0.35, 80.0, 0.90)),
+            (CHILL, features(0.15, 0.45, 68.0, 0.80)),
+            (HYPE, features(0.92, 0.80, 128.0, 0.05)),
+            (HYPE, features(0.88, 0.75, 140.0, 0.10)),
+        ])
+    }
+
+    #[test]
+    fn test_tempo_is_normalized() {
+        assert_eq!(features(0.5, 0.5, 20.0, 0.5).to_vector()[2], 0.0);
+        assert_eq!(features(0.5, 0.5, 125.0, 0.5).to_vector()[2], 0.5);
+        assert_eq!(features(0.5, 0.5, 300.0, 0.5).to_vector()[2], 1.0);
+    }
+
+    #[test]
+    fn test_suggests_nearest_tag_first() {
+        let profile = fixture_profile();
+
+        let calm = profile.suggest(&features(0.22, 0.40, 75.0, 0.88), &HashSet::new());
+        assert_eq!(calm[0].tag_id, CHILL);
+        assert!(calm[0].confidence > calm[1].confidence);
+
+        let loud = profile.suggest(&features(0.90, 0.78, 132.0, 0.07), &HashSet::new());
+        assert_eq!(loud[0].tag_id, HYPE);
+    }
+
+    #[test]
+    fn test_confidence_is_discounted_for_small_samples() {
+        let profile = TagProfile::learn(vec![(CHILL, features(0.2, 0.4, 72.0, 0.85))]);
+
+        let exact = profile.suggest(&features(0.2, 0.4, 72.0, 0.85), &HashSet::new());
+
+        assert!((exact[0].confidence - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_excluded_tags_are_not_suggested() {
+        let profile = fixture_profile();
+        let exclude = HashSet::from([CHILL]);
+
+        let suggestions = profile.suggest(&features(0.22, 0.40, 75.0, 0.88), &exclude);
+
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].tag_id, HYPE);
+    }
+
+    #[test]
+    fn test_empty_profile_suggests_nothing() {
+        let profile = TagProfile::learn(Vec::new());
+
+        assert!(profile.is_empty());
+        assert!(profile
+            .suggest(&features(0.5, 0.5, 120.0, 0.5), &HashSet::new())
+            .is_empty());
+    }
+}
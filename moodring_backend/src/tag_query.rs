@@ -0,0 +1,655 @@
+//! Boolean tag expressions such as `chill & !sad`, `(focus | study) & melancholy>=4`.
+//!
+//! Grammar, loosest binding first:
+//!
+//! ```text
+//! or      := and (("|" | "OR") and)*
+//! and     := unary (("&" | "AND")? unary)*     -- juxtaposition means AND
+//! unary   := ("!" | "NOT") unary | primary
+//! primary := "(" or ")" | tag [(">=" | "<=" | ">" | "<" | "=") integer]
+//! tag     := bare-name | "quoted name"
+//! ```
+
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+use crate::{matching, permissions, schema, DbPool, Song, Tag, TagSource};
+
+/// Weight assumed for tag links that were saved without one.
+pub const UNWEIGHTED_TAG_WEIGHT: i32 = 3;
+
+/// Deepest nesting of parentheses and negations a query may use. The
+/// parser and evaluator recurse once per level, so this bounds their stack.
+pub const MAX_QUERY_DEPTH: usize = 64;
+/// Longest query, in tokens, so long `&`/`|` chains stay cheap to evaluate.
+pub const MAX_QUERY_TOKENS: usize = 512;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+}
+
+impl Comparison {
+    fn holds(self, value: i32, threshold: i32) -> bool {
+        match self {
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Less => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Equal => value == threshold,
+        }
+    }
+}
+
+/// A parsed expression. `K` is the tag name after parsing and the tag ID
+/// once resolved against a user's tags.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TagExpr<K> {
+    Tag {
+        tag: K,
+        threshold: Option<(Comparison, i32)>,
+    },
+    Not(Box<TagExpr<K>>),
+    And(Box<TagExpr<K>>, Box<TagExpr<K>>),
+    Or(Box<TagExpr<K>>, Box<TagExpr<K>>),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Compare(Comparison),
+    Word(String),
+    Quoted(String),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '!' | '&' | '|' | '=' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    '!' => Token::Not,
+                    '&' => Token::And,
+                    '|' => Token::Or,
+                    _ => Token::Compare(Comparison::Equal),
+                });
+            }
+            '>' | '<' => {
+                chars.next();
+                let or_equal = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Compare(match (c, or_equal) {
+                    ('>', false) => Comparison::Greater,
+                    ('>', true) => Comparison::GreaterOrEqual,
+                    ('<', false) => Comparison::Less,
+                    _ => Comparison::LessOrEqual,
+                }));
+            }
+            '"' => {
+                chars.next();
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => name.push(c),
+                        None => return Err("Unterminated quoted tag name".to_string()),
+                    }
+                }
+                tokens.push(Token::Quoted(name));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| {
+                    !c.is_whitespace()
+                        && !matches!(c, '(' | ')' | '!' | '&' | '|' | '=' | '>' | '<' | '"')
+                }) {
+                    word.push(c);
+                }
+                tokens.push(match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn descend(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_QUERY_DEPTH {
+            return Err(format!(
+                "Tag query is nested more than {MAX_QUERY_DEPTH} levels deep"
+            ));
+        }
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<TagExpr<String>, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<TagExpr<String>, String> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                }
+                Some(Token::Not | Token::Open | Token::Word(_) | Token::Quoted(_)) => {}
+                _ => return Ok(expr),
+            }
+            expr = TagExpr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<TagExpr<String>, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            self.descend()?;
+            let inner = self.parse_unary()?;
+            self.depth -= 1;
+            return Ok(TagExpr::Not(Box::new(inner)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<TagExpr<String>, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                self.descend()?;
+                let expr = self.parse_or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("Missing closing parenthesis".to_string()),
+                }
+            }
+            Some(Token::Word(name) | Token::Quoted(name)) => {
+                let threshold = match self.peek() {
+                    Some(Token::Compare(comparison)) => {
+                        let comparison = *comparison;
+                        self.next();
+                        match self.next() {
+                            Some(Token::Word(number)) => {
+                                let weight = number
+                                    .parse::<i32>()
+                                    .map_err(|_| format!("Invalid weight for {name}: {number}"))?;
+                                Some((comparison, weight))
+                            }
+                            _ => return Err(format!("Missing weight after comparison on {name}")),
+                        }
+                    }
+                    _ => None,
+                };
+                Ok(TagExpr::Tag {
+                    tag: name,
+                    threshold,
+                })
+            }
+            Some(token) => Err(format!("Unexpected {token:?} in tag query")),
+            None => Err("Unexpected end of tag query".to_string()),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<TagExpr<String>, String> {
+    let tokens = tokenize(input)?;
+    if tokens.len() > MAX_QUERY_TOKENS {
+        return Err(format!("Tag query is longer than {MAX_QUERY_TOKENS} terms"));
+    }
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        depth: 0,
+    };
+    if parser.peek().is_none() {
+        return Err("Tag query is empty".to_string());
+    }
+    let expr = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {token:?} in tag query")),
+    }
+}
+
+impl TagExpr<String> {
+    /// Swaps tag names for IDs, failing on the first name `lookup` can't find.
+    pub fn resolve<F>(self, lookup: &F) -> Result<TagExpr<i32>, String>
+    where
+        F: Fn(&str) -> Option<i32>,
+    {
+        Ok(match self {
+            TagExpr::Tag { tag, threshold } => TagExpr::Tag {
+                tag: lookup(&tag).ok_or_else(|| format!("Unknown tag: {tag}"))?,
+                threshold,
+            },
+            TagExpr::Not(inner) => TagExpr::Not(Box::new(inner.resolve(lookup)?)),
+            TagExpr::And(a, b) => {
+                TagExpr::And(Box::new(a.resolve(lookup)?), Box::new(b.resolve(lookup)?))
+            }
+            TagExpr::Or(a, b) => {
+                TagExpr::Or(Box::new(a.resolve(lookup)?), Box::new(b.resolve(lookup)?))
+            }
+        })
+    }
+}
+
+impl TagExpr<i32> {
+    /// Whether a song with these tag links (tag ID to optional weight) matches.
+    pub fn matches(&self, links: &HashMap<i32, Option<i32>>) -> bool {
+        match self {
+            TagExpr::Tag { tag, threshold } => match (links.get(tag), threshold) {
+                (None, _) => false,
+                (Some(_), None) => true,
+                (Some(weight), Some((comparison, limit))) => {
+                    comparison.holds(weight.unwrap_or(UNWEIGHTED_TAG_WEIGHT), *limit)
+                }
+            },
+            TagExpr::Not(inner) => !inner.matches(links),
+            TagExpr::And(a, b) => a.matches(links) && b.matches(links),
+            TagExpr::Or(a, b) => a.matches(links) || b.matches(links),
+        }
+    }
+
+    fn collect_positive_tags(&self, negated: bool, out: &mut HashSet<i32>) {
+        match self {
+            TagExpr::Tag { tag, .. } if !negated => {
+                out.insert(*tag);
+            }
+            TagExpr::Tag { .. } => {}
+            TagExpr::Not(inner) => inner.collect_positive_tags(!negated, out),
+            TagExpr::And(a, b) | TagExpr::Or(a, b) => {
+                a.collect_positive_tags(negated, out);
+                b.collect_positive_tags(negated, out);
+            }
+        }
+    }
+
+    /// Ranking score: the summed weights of the non-negated tags in the
+    /// expression that the song carries.
+    pub fn score(&self, links: &HashMap<i32, Option<i32>>) -> i32 {
+        let mut positive = HashSet::new();
+        self.collect_positive_tags(false, &mut positive);
+        positive
+            .iter()
+            .filter_map(|tag| links.get(tag))
+            .map(|weight| weight.unwrap_or(UNWEIGHTED_TAG_WEIGHT))
+            .sum()
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TagQueryMatch {
+    pub song_id: String,
+    pub score: i32,
+    pub last_tagged_at: NaiveDateTime,
+    /// Catalogue metadata, when the song has been synced.
+    pub song: Option<Song>,
+    /// Other entries confirmed to be this song, whose tags were counted too.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub matched_song_ids: Vec<String>,
+}
+
+/// Evaluates `query` over the songs the user has tagged, highest score first
+/// and then most recently tagged. Names may refer to workspace tags too,
+/// though a personal tag wins over a shared one with the same name. With
+/// `source`, only links with that provenance are considered.
+pub fn evaluate(
+    conn: &mut PgConnection,
+    user_id: i32,
+    query: &str,
+    source: Option<TagSource>,
+) -> Result<Vec<TagQueryMatch>, String> {
+    let expr = parse(query)?;
+    let mut tag_ids: HashMap<String, i32> = HashMap::new();
+    for tag in permissions::visible_tags(conn, user_id)? {
+        tag_ids.entry(tag.name).or_insert(tag.id);
+    }
+    let expr = expr.resolve(&|name: &str| tag_ids.get(name).copied())?;
+
+    evaluate_expr(conn, user_id, &expr, source)
+}
+
+/// Like [`evaluate`], but names only resolve to the user's personal tags
+/// and only their own links count, for results shown outside any
+/// workspace.
+pub fn evaluate_personal(
+    conn: &mut PgConnection,
+    user_id: i32,
+    query: &str,
+) -> Result<Vec<TagQueryMatch>, String> {
+    let expr = parse(query)?;
+    let tag_ids: HashMap<String, i32> = personal_tags(conn, user_id)?
+        .into_iter()
+        .map(|tag| (tag.name, tag.id))
+        .collect();
+    let expr = expr.resolve(&|name: &str| tag_ids.get(name).copied())?;
+
+    evaluate_links(conn, user_id, &expr, None, Vec::new())
+}
+
+fn personal_tags(conn: &mut PgConnection, user_id: i32) -> Result<Vec<Tag>, String> {
+    use schema::tags::dsl;
+
+    dsl::tags
+        .filter(dsl::user_id.eq(user_id))
+        .filter(dsl::workspace_id.is_null())
+        .load::<Tag>(conn)
+        .map_err(|e| format!("Failed to load tags: {e}"))
+}
+
+/// The larger of two link weights, reading unweighted links as
+/// [`UNWEIGHTED_TAG_WEIGHT`].
+pub fn heavier_weight(a: Option<i32>, b: Option<i32>) -> Option<i32> {
+    if b.unwrap_or(UNWEIGHTED_TAG_WEIGHT) > a.unwrap_or(UNWEIGHTED_TAG_WEIGHT) {
+        b
+    } else {
+        a
+    }
+}
+
+/// Same as [`evaluate`], for an expression already resolved to tag IDs.
+/// Workspace tags count every member's links, not just the user's.
+pub fn evaluate_expr(
+    conn: &mut PgConnection,
+    user_id: i32,
+    expr: &TagExpr<i32>,
+    source: Option<TagSource>,
+) -> Result<Vec<TagQueryMatch>, String> {
+    let shared_tag_ids = permissions::workspace_tag_ids(conn, user_id)?;
+    evaluate_links(conn, user_id, expr, source, shared_tag_ids)
+}
+
+/// Evaluates over the user's own links plus every member's links on
+/// `shared_tag_ids`.
+fn evaluate_links(
+    conn: &mut PgConnection,
+    user_id: i32,
+    expr: &TagExpr<i32>,
+    source: Option<TagSource>,
+    shared_tag_ids: Vec<i32>,
+) -> Result<Vec<TagQueryMatch>, String> {
+    use schema::{song_tags, songs};
+
+    let mut links_query = song_tags::table
+        .filter(
+            song_tags::user_id
+                .eq(user_id)
+                .or(song_tags::tag_id.eq_any(shared_tag_ids)),
+        )
+        .select((
+            song_tags::song_id,
+            song_tags::tag_id,
+            song_tags::weight,
+            song_tags::created_at,
+        ))
+        .into_boxed();
+    if let Some(source) = source {
+        links_query = links_query.filter(song_tags::source.eq(source));
+    }
+    let links = links_query
+        .load::<(String, i32, Option<i32>, NaiveDateTime)>(conn)
+        .map_err(|e| format!("Failed to load song tags: {e}"))?;
+
+    // Entries confirmed to be the same song are evaluated as one, under the
+    // ID their group is known by. A tag on several of them counts once,
+    // with its highest weight.
+    let canonical = matching::canonical_song_ids(conn, user_id)?;
+    let mut by_song: HashMap<String, (HashMap<i32, Option<i32>>, NaiveDateTime)> = HashMap::new();
+    for (song_id, tag_id, weight, created_at) in links {
+        let song_id = canonical.get(&song_id).cloned().unwrap_or(song_id);
+        let entry = by_song
+            .entry(song_id)
+            .or_insert_with(|| (HashMap::new(), created_at));
+        let linked = entry.0.entry(tag_id).or_insert(weight);
+        *linked = heavier_weight(*linked, weight);
+        entry.1 = entry.1.max(created_at);
+    }
+    let mut members: HashMap<&str, Vec<String>> = HashMap::new();
+    for (song_id, representative) in &canonical {
+        if song_id != representative {
+            members
+                .entry(representative.as_str())
+                .or_default()
+                .push(song_id.clone());
+        }
+    }
+
+    let mut matches: Vec<TagQueryMatch> = by_song
+        .into_iter()
+        .filter(|(_, (song_links, _))| expr.matches(song_links))
+        .map(|(song_id, (song_links, last_tagged_at))| {
+            let mut matched_song_ids = members.remove(song_id.as_str()).unwrap_or_default();
+            matched_song_ids.sort();
+            TagQueryMatch {
+                score: expr.score(&song_links),
+                song_id,
+                last_tagged_at,
+                song: None,
+                matched_song_ids,
+            }
+        })
+        .collect();
+    matches.sort_by(|a, b| {
+        b.score
+            .cmp(&a.score)
+            .then(b.last_tagged_at.cmp(&a.last_tagged_at))
+            .then(a.song_id.cmp(&b.song_id))
+    });
+
+    let song_ids: Vec<&String> = matches.iter().map(|m| &m.song_id).collect();
+    let mut catalogue: HashMap<String, Song> = songs::table
+        .filter(songs::id.eq_any(song_ids))
+        .load::<Song>(conn)
+        .map_err(|e| format!("Failed to load songs: {e}"))?
+        .into_iter()
+        .map(|song| (song.id.clone(), song))
+        .collect();
+    for found in matches.iter_mut() {
+        found.song = catalogue.remove(&found.song_id);
+    }
+
+    Ok(matches)
+}
+
+pub async fn query_songs(
+    pool: &DbPool,
+    user_id: i32,
+    query: String,
+    source: Option<TagSource>,
+) -> Result<Vec<TagQueryMatch>, String> {
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        evaluate(&mut conn, user_id, &query, source)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> TagExpr<String> {
+        TagExpr::Tag {
+            tag: name.to_string(),
+            threshold: None,
+        }
+    }
+
+    fn resolve(query: &str) -> TagExpr<i32> {
+        let ids = HashMap::from([
+            ("chill".to_string(), 1),
+            ("sad".to_string(), 2),
+            ("melancholy".to_string(), 3),
+            ("rainy day".to_string(), 4),
+        ]);
+        parse(query)
+            .expect("query should parse")
+            .resolve(&|name: &str| ids.get(name).copied())
+            .expect("query should resolve")
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        assert_eq!(
+            parse("chill | sad & !melancholy").unwrap(),
+            TagExpr::Or(
+                Box::new(tag("chill")),
+                Box::new(TagExpr::And(
+                    Box::new(tag("sad")),
+                    Box::new(TagExpr::Not(Box::new(tag("melancholy"))))
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_keywords_quotes_and_juxtaposition() {
+        assert_eq!(
+            parse("chill AND NOT sad").unwrap(),
+            parse("chill & !sad").unwrap()
+        );
+        assert_eq!(parse("chill sad").unwrap(), parse("chill & sad").unwrap());
+        assert_eq!(parse("\"rainy day\"").unwrap(), tag("rainy day"));
+        assert_eq!(parse("rainy-day").unwrap(), tag("rainy-day"));
+    }
+
+    #[test]
+    fn test_parse_thresholds() {
+        assert_eq!(
+            parse("melancholy>=4").unwrap(),
+            TagExpr::Tag {
+                tag: "melancholy".to_string(),
+                threshold: Some((Comparison::GreaterOrEqual, 4))
+            }
+        );
+        assert_eq!(
+            parse("melancholy < 2").unwrap(),
+            TagExpr::Tag {
+                tag: "melancholy".to_string(),
+                threshold: Some((Comparison::Less, 2))
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("(chill").is_err());
+        assert!(parse("chill &").is_err());
+        assert!(parse("melancholy>=lots").is_err());
+        assert!(parse("\"rainy day").is_err());
+        assert!(parse("chill)").is_err());
+    }
+
+    #[test]
+    fn test_resolve_rejects_unknown_tags() {
+        let result = parse("chill & jazz")
+            .unwrap()
+            .resolve(&|name: &str| (name == "chill").then_some(1));
+
+        assert_eq!(result, Err("Unknown tag: jazz".to_string()));
+    }
+
+    #[test]
+    fn test_parse_limits_nesting() {
+        let nested = format!("{}chill{}", "(".repeat(64), ")".repeat(64));
+        assert!(parse(&nested).is_ok());
+
+        let too_deep = format!("{}chill{}", "(".repeat(65), ")".repeat(65));
+        assert!(parse(&too_deep).is_err());
+        // Deep enough to overflow the stack without the limit.
+        assert!(parse(&"NOT ".repeat(100_000)).is_err());
+        assert!(parse(&"(".repeat(100_000)).is_err());
+        assert!(parse(&["chill"; 600].join(" & ")).is_err());
+    }
+
+    #[test]
+    fn test_matches_thresholds() {
+        let expr = resolve("melancholy>=4");
+
+        assert!(expr.matches(&HashMap::from([(3, Some(5))])));
+        assert!(!expr.matches(&HashMap::from([(3, Some(2))])));
+        // Unweighted links count as the middle of the scale.
+        assert!(!expr.matches(&HashMap::from([(3, None)])));
+        assert!(resolve("melancholy=3").matches(&HashMap::from([(3, None)])));
+    }
+
+    #[test]
+    fn test_matches_boolean_logic() {
+        let expr = resolve("(chill | \"rainy day\") & !sad");
+
+        assert!(expr.matches(&HashMap::from([(1, None)])));
+        assert!(expr.matches(&HashMap::from([(4, Some(1))])));
+        assert!(!expr.matches(&HashMap::from([(1, None), (2, None)])));
+        assert!(!expr.matches(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_heavier_weight_reads_unweighted_as_default() {
+        assert_eq!(heavier_weight(None, Some(1)), None);
+        assert_eq!(heavier_weight(Some(1), None), None);
+        assert_eq!(heavier_weight(None, Some(5)), Some(5));
+        assert_eq!(heavier_weight(Some(4), Some(2)), Some(4));
+    }
+
+    #[test]
+    fn test_score_sums_positive_weights() {
+        let expr = resolve("chill & melancholy & !sad");
+
+        assert_eq!(expr.score(&HashMap::from([(1, Some(5)), (3, Some(2))])), 7);
+        assert_eq!(
+            expr.score(&HashMap::from([(1, None), (3, Some(1))])),
+            UNWEIGHTED_TAG_WEIGHT + 1
+        );
+    }
+}
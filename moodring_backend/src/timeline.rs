@@ -0,0 +1,307 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use rocket::serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::plays::StatsPeriod;
+use crate::tag_query::UNWEIGHTED_TAG_WEIGHT;
+use crate::{matching, permissions, schema, DbPool};
+
+/// Summed tag weight of the songs played in one period.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MoodPoint {
+    pub period_start: NaiveDate,
+    pub weight: i64,
+    pub plays: i64,
+}
+
+/// A tag's weight over time. Periods in which none of its songs were played
+/// are left out.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MoodSeries {
+    pub tag_id: i32,
+    pub tag_name: String,
+    pub points: Vec<MoodPoint>,
+}
+
+/// The tag with the most weight in a period.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DominantMood {
+    pub period_start: NaiveDate,
+    pub tag_id: i32,
+    pub tag_name: String,
+    pub weight: i64,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MoodTimeline {
+    /// The time zone periods were counted in.
+    pub timezone: String,
+    pub series: Vec<MoodSeries>,
+    pub dominant: Vec<DominantMood>,
+}
+
+/// The time zone a timeline is counted in: the requested one, else the
+/// user's setting, else UTC.
+pub fn resolve_timezone(requested: Option<&str>, setting: Option<&str>) -> Result<Tz, String> {
+    match requested.or(setting) {
+        Some(name) => name
+            .parse::<Tz>()
+            .map_err(|_| format!("Unknown time zone: {name}")),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// Sums, per period and tag, the weights of the tags on each played song.
+/// Plays are placed by their local date in `timezone` and only those on
+/// dates from `from` up to, but not including, `to` count. The second
+/// total is the number of plays.
+pub fn sum_mood_weights(
+    plays: &[(String, NaiveDateTime)],
+    weights_by_song: &HashMap<String, Vec<(i32, i32)>>,
+    timezone: Tz,
+    period: StatsPeriod,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> BTreeMap<NaiveDate, BTreeMap<i32, (i64, i64)>> {
+    let mut totals: BTreeMap<NaiveDate, BTreeMap<i32, (i64, i64)>> = BTreeMap::new();
+    for (song_id, played_at) in plays {
+        let local_date = played_at.and_utc().with_timezone(&timezone).date_naive();
+        if local_date < from || local_date >= to {
+            continue;
+        }
+        let period_start = period.start_of(local_date);
+        for (tag_id, weight) in weights_by_song.get(song_id).into_iter().flatten() {
+            let total = totals
+                .entry(period_start)
+                .or_default()
+                .entry(*tag_id)
+                .or_default();
+            total.0 += i64::from(*weight);
+            total.1 += 1;
+        }
+    }
+    totals
+}
+
+/// The heaviest tag of each period. Ties go to the lower tag ID so the
+/// answer doesn't change between requests.
+pub fn dominant_tags(
+    totals: &BTreeMap<NaiveDate, BTreeMap<i32, (i64, i64)>>,
+) -> Vec<(NaiveDate, i32, i64)> {
+    totals
+        .iter()
+        .filter_map(|(period_start, by_tag)| {
+            by_tag
+                .iter()
+                .max_by(|(a_id, (a, _)), (b_id, (b, _))| a.cmp(b).then(b_id.cmp(a_id)))
+                .map(|(tag_id, (weight, _))| (*period_start, *tag_id, *weight))
+        })
+        .collect()
+}
+
+/// The user's mood over time: for every period between `from` (inclusive)
+/// and `to` (exclusive), the summed weights of the tags on the songs they
+/// played, and which tag dominated.
+pub async fn mood_timeline(
+    pool: &DbPool,
+    user_id: i32,
+    from: NaiveDate,
+    to: NaiveDate,
+    period: StatsPeriod,
+    timezone: Option<String>,
+) -> Result<MoodTimeline, String> {
+    use schema::{plays, song_tags, users};
+
+    if from >= to {
+        return Err("from must be before to".to_string());
+    }
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+
+        let setting = users::table
+            .find(user_id)
+            .select(users::timezone)
+            .first::<Option<String>>(&mut conn)
+            .map_err(|e| format!("Failed to load user: {e}"))?;
+        let timezone = resolve_timezone(timezone.as_deref(), setting.as_deref())?;
+
+        let tag_names: HashMap<i32, String> = permissions::visible_tags(&mut conn, user_id)?
+            .into_iter()
+            .map(|tag| (tag.id, tag.name))
+            .collect();
+
+        // Entries confirmed to be the same song share their tags, each at
+        // its highest weight. Workspace tags count every member's links, as
+        // in tag queries.
+        let canonical = matching::canonical_song_ids(&mut conn, user_id)?;
+        let canonical_id = |song_id: String| canonical.get(&song_id).cloned().unwrap_or(song_id);
+        let shared_tag_ids = permissions::workspace_tag_ids(&mut conn, user_id)?;
+        let mut weights_by_song: HashMap<String, Vec<(i32, i32)>> = HashMap::new();
+        for (song_id, tag_id, weight) in song_tags::table
+            .filter(
+                song_tags::user_id
+                    .eq(user_id)
+                    .or(song_tags::tag_id.eq_any(shared_tag_ids)),
+            )
+            .select((song_tags::song_id, song_tags::tag_id, song_tags::weight))
+            .load::<(String, i32, Option<i32>)>(&mut conn)
+            .map_err(|e| format!("Failed to load song tags: {e}"))?
+        {
+            // Links to workspace tags the user has since lost access to.
+            if !tag_names.contains_key(&tag_id) {
+                continue;
+            }
+            let weight = weight.unwrap_or(UNWEIGHTED_TAG_WEIGHT);
+            let song_weights = weights_by_song.entry(canonical_id(song_id)).or_default();
+            match song_weights
+                .iter_mut()
+                .find(|(linked, _)| *linked == tag_id)
+            {
+                Some((_, linked_weight)) => *linked_weight = (*linked_weight).max(weight),
+                None => song_weights.push((tag_id, weight)),
+            }
+        }
+
+        // Local days start up to 14 hours either side of UTC midnight; plays
+        // outside the local range are dropped when summing.
+        let margin = chrono::Duration::days(1);
+        let start = (from - margin).and_hms_opt(0, 0, 0).unwrap_or_default();
+        let end = (to + margin).and_hms_opt(0, 0, 0).unwrap_or_default();
+        let user_plays: Vec<(String, NaiveDateTime)> = plays::table
+            .filter(plays::user_id.eq(user_id))
+            .filter(plays::played_at.ge(start))
+            .filter(plays::played_at.lt(end))
+            .select((plays::song_id, plays::played_at))
+            .load::<(String, NaiveDateTime)>(&mut conn)
+            .map_err(|e| format!("Failed to load plays: {e}"))?
+            .into_iter()
+            .map(|(song_id, played_at)| (canonical_id(song_id), played_at))
+            .collect();
+
+        let totals = sum_mood_weights(&user_plays, &weights_by_song, timezone, period, from, to);
+        let tag_name = |tag_id: i32| tag_names.get(&tag_id).cloned().unwrap_or_default();
+
+        let mut points_by_tag: BTreeMap<i32, Vec<MoodPoint>> = BTreeMap::new();
+        for (period_start, by_tag) in &totals {
+            for (tag_id, (weight, plays)) in by_tag {
+                points_by_tag.entry(*tag_id).or_default().push(MoodPoint {
+                    period_start: *period_start,
+                    weight: *weight,
+                    plays: *plays,
+                });
+            }
+        }
+
+        Ok(MoodTimeline {
+            timezone: timezone.name().to_string(),
+            dominant: dominant_tags(&totals)
+                .into_iter()
+                .map(|(period_start, tag_id, weight)| DominantMood {
+                    period_start,
+                    tag_id,
+                    tag_name: tag_name(tag_id),
+                    weight,
+                })
+                .collect(),
+            series: points_by_tag
+                .into_iter()
+                .map(|(tag_id, points)| MoodSeries {
+                    tag_id,
+                    tag_name: tag_name(tag_id),
+                    points,
+                })
+                .collect(),
+        })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    fn time(value: &str) -> NaiveDateTime {
+        crate::plays::parse_played_at(value).unwrap()
+    }
+
+    #[test]
+    fn test_resolve_timezone() {
+        assert_eq!(resolve_timezone(None, None).unwrap(), Tz::UTC);
+        assert_eq!(
+            resolve_timezone(None, Some("Europe/Berlin")).unwrap(),
+            Tz::Europe__Berlin
+        );
+        assert_eq!(
+            resolve_timezone(Some("Asia/Tokyo"), Some("Europe/Berlin")).unwrap(),
+            Tz::Asia__Tokyo
+        );
+        assert!(resolve_timezone(Some("Mars/Olympus"), None).is_err());
+    }
+
+    #[test]
+    fn test_sum_mood_weights_uses_local_dates() {
+        let plays = vec![
+            // 23:30 UTC on the 1st is already the 2nd in Berlin.
+            ("calm".to_string(), time("2026-10-01T23:30:00Z")),
+            ("calm".to_string(), time("2026-10-02T08:00:00Z")),
+            ("both".to_string(), time("2026-10-02T09:00:00Z")),
+            // Outside the range once moved to Berlin time.
+            ("calm".to_string(), time("2026-10-04T22:30:00Z")),
+        ];
+        let weights_by_song = HashMap::from([
+            ("calm".to_string(), vec![(1, 4)]),
+            ("both".to_string(), vec![(1, 2), (2, 5)]),
+        ]);
+
+        let totals = sum_mood_weights(
+            &plays,
+            &weights_by_song,
+            Tz::Europe__Berlin,
+            StatsPeriod::Day,
+            date(2026, 10, 1),
+            date(2026, 10, 5),
+        );
+
+        assert_eq!(
+            totals,
+            BTreeMap::from([(
+                date(2026, 10, 2),
+                BTreeMap::from([(1, (10, 3)), (2, (5, 1))])
+            )])
+        );
+    }
+
+    #[test]
+    fn test_dominant_tags_breaks_ties_by_tag_id() {
+        let totals = BTreeMap::from([
+            (
+                date(2026, 10, 5),
+                BTreeMap::from([(1, (4, 1)), (2, (9, 2))]),
+            ),
+            (
+                date(2026, 10, 12),
+                BTreeMap::from([(3, (6, 2)), (1, (6, 2))]),
+            ),
+        ]);
+
+        assert_eq!(
+            dominant_tags(&totals),
+            vec![(date(2026, 10, 5), 2, 9), (date(2026, 10, 12), 1, 6)]
+        );
+    }
+}
@@ -0,0 +1,878 @@
+use chrono::NaiveDateTime;
+use diesel::deserialize::{FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::prelude::*;
+use diesel::serialize::{Output, ToSql};
+use diesel::sql_types::Text;
+use hmac::{Hmac, Mac};
+use rocket::serde::{Deserialize, Serialize, Serializer};
+use sha2::Sha256;
+
+use crate::auth::generate_token;
+use crate::{schema, DbPool, SongTag, Tag, TransactionError};
+
+pub const SIGNATURE_HEADER: &str = "X-Moodring-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Moodring-Timestamp";
+pub const EVENT_HEADER: &str = "X-Moodring-Event";
+pub const DELIVERY_HEADER: &str = "X-Moodring-Delivery";
+
+/// Attempts before a delivery is moved to the dead letters.
+pub const MAX_ATTEMPTS: i32 = 8;
+const FIRST_RETRY_SECONDS: i64 = 30;
+const DEFAULT_DELIVERY_INTERVAL_SECONDS: u64 = 15;
+const DELIVERY_BATCH_SIZE: i64 = 50;
+const DELIVERY_TIMEOUT_SECONDS: u64 = 10;
+// A claimed delivery is retried after this long if its worker died before
+// recording the result.
+const CLAIM_LEASE_MINUTES: i64 = 5;
+const DELIVERY_LOG_LIMIT: i64 = 100;
+
+#[derive(
+    Serialize, Deserialize, AsExpression, FromSqlRow, Clone, Copy, PartialEq, Eq, Hash, Debug,
+)]
+#[serde(crate = "rocket::serde")]
+#[diesel(sql_type = Text)]
+pub enum WebhookEvent {
+    #[serde(rename = "tag.created")]
+    TagCreated,
+    #[serde(rename = "tag.deleted")]
+    TagDeleted,
+    #[serde(rename = "song_tag.added")]
+    SongTagAdded,
+    #[serde(rename = "song_tag.removed")]
+    SongTagRemoved,
+    #[serde(rename = "playlist.exported")]
+    PlaylistExported,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::TagCreated => "tag.created",
+            WebhookEvent::TagDeleted => "tag.deleted",
+            WebhookEvent::SongTagAdded => "song_tag.added",
+            WebhookEvent::SongTagRemoved => "song_tag.removed",
+            WebhookEvent::PlaylistExported => "playlist.exported",
+        }
+    }
+}
+
+impl std::str::FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tag.created" => Ok(WebhookEvent::TagCreated),
+            "tag.deleted" => Ok(WebhookEvent::TagDeleted),
+            "song_tag.added" => Ok(WebhookEvent::SongTagAdded),
+            "song_tag.removed" => Ok(WebhookEvent::SongTagRemoved),
+            "playlist.exported" => Ok(WebhookEvent::PlaylistExported),
+            other => Err(format!("Unknown webhook event: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for WebhookEvent {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for WebhookEvent {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+#[derive(
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    rocket::FromFormField,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+#[diesel(sql_type = Text)]
+pub enum DeliveryStatus {
+    /// Waiting for its first or next attempt.
+    Pending,
+    Delivered,
+    /// Out of attempts. Shown in the dead-letter view until redelivered.
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+}
+
+impl std::str::FromStr for DeliveryStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(DeliveryStatus::Pending),
+            "delivered" => Ok(DeliveryStatus::Delivered),
+            "dead" => Ok(DeliveryStatus::Dead),
+            other => Err(format!("Unknown delivery status: {other}")),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for DeliveryStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> diesel::serialize::Result {
+        <str as ToSql<Text, Pg>>::to_sql(self.as_str(), out)
+    }
+}
+
+impl FromSql<Text, Pg> for DeliveryStatus {
+    fn from_sql(bytes: PgValue<'_>) -> diesel::deserialize::Result<Self> {
+        let value = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(value.parse()?)
+    }
+}
+
+/// A registered receiver. The secret is only shown once, when the webhook
+/// is created.
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::webhooks)]
+pub struct Webhook {
+    pub id: i32,
+    pub user_id: i32,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Space-separated event names.
+    #[serde(serialize_with = "serialize_event_list")]
+    pub events: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Webhook {
+    pub fn subscribes_to(&self, event: WebhookEvent) -> bool {
+        self.events
+            .split_whitespace()
+            .any(|name| name == event.as_str())
+    }
+}
+
+fn serialize_event_list<S: Serializer>(events: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(events.split_whitespace())
+}
+
+#[derive(Queryable, Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: WebhookEvent,
+    /// The exact body that was signed and sent.
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_status_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NewWebhook {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+}
+
+impl NewWebhook {
+    pub fn validate(&self) -> Result<(), String> {
+        let url = reqwest::Url::parse(&self.url).map_err(|e| format!("Invalid URL: {e}"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Webhook URL must use http or https".to_string());
+        }
+        if self.events.is_empty() {
+            return Err("Subscribe to at least one event".to_string());
+        }
+        Ok(())
+    }
+
+    fn event_list(&self) -> String {
+        let mut names: Vec<&str> = self.events.iter().map(WebhookEvent::as_str).collect();
+        names.sort_unstable();
+        names.dedup();
+        names.join(" ")
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CreatedWebhook {
+    pub webhook: Webhook,
+    /// Verifies the signature on each delivery.
+    pub secret: String,
+}
+
+/// The `X-Moodring-Signature` value for a delivery: an HMAC-SHA256 of
+/// `"{timestamp}.{body}"` keyed with the webhook's secret. Including the
+/// timestamp lets receivers reject replayed deliveries.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    let digest: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={digest}")
+}
+
+/// The JSON body sent for an event.
+pub fn event_payload(
+    event: WebhookEvent,
+    user_id: i32,
+    occurred_at: NaiveDateTime,
+    data: serde_json::Value,
+) -> String {
+    serde_json::json!({
+        "event": event,
+        "user_id": user_id,
+        "occurred_at": occurred_at,
+        "data": data,
+    })
+    .to_string()
+}
+
+/// How long to wait after the given number of failed attempts: 30 seconds,
+/// doubling each time.
+pub fn retry_delay(attempts: i32) -> chrono::Duration {
+    let doublings = (attempts - 1).clamp(0, MAX_ATTEMPTS) as u32;
+    chrono::Duration::seconds(FIRST_RETRY_SECONDS * 2i64.pow(doublings))
+}
+
+/// Where a delivery goes after a failed attempt, given the attempts made so
+/// far including this one.
+pub fn after_failure(attempts: i32, now: NaiveDateTime) -> (DeliveryStatus, NaiveDateTime) {
+    if attempts >= MAX_ATTEMPTS {
+        (DeliveryStatus::Dead, now)
+    } else {
+        (DeliveryStatus::Pending, now + retry_delay(attempts))
+    }
+}
+
+/// How often the queue is worked, from `WEBHOOK_DELIVERY_INTERVAL_SECONDS`.
+pub fn webhook_delivery_interval() -> std::time::Duration {
+    let seconds = std::env::var("WEBHOOK_DELIVERY_INTERVAL_SECONDS")
+        .ok()
+        .and_then(|value| value.parse::<u64>().ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_DELIVERY_INTERVAL_SECONDS);
+    std::time::Duration::from_secs(seconds)
+}
+
+/// Queues a delivery of `event` to each of the user's webhooks subscribed
+/// to it. Returns how many were queued. Call it in the transaction making
+/// the change, so the event is queued if and only if the change commits.
+pub fn enqueue_event(
+    conn: &mut PgConnection,
+    user_id: i32,
+    event: WebhookEvent,
+    data: serde_json::Value,
+) -> Result<usize, String> {
+    enqueue_events(conn, user_id, event, vec![data])
+}
+
+/// [`enqueue_event`] for a batch of changes, one event each.
+pub fn enqueue_events(
+    conn: &mut PgConnection,
+    user_id: i32,
+    event: WebhookEvent,
+    data: Vec<serde_json::Value>,
+) -> Result<usize, String> {
+    use schema::{webhook_deliveries, webhooks};
+
+    if data.is_empty() {
+        return Ok(0);
+    }
+    let hook_ids: Vec<i32> = webhooks::table
+        .filter(webhooks::user_id.eq(user_id))
+        .load::<Webhook>(conn)
+        .map_err(|e| format!("Failed to load webhooks: {e}"))?
+        .iter()
+        .filter(|hook| hook.subscribes_to(event))
+        .map(|hook| hook.id)
+        .collect();
+    if hook_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let occurred_at = chrono::Utc::now().naive_utc();
+    let rows: Vec<_> = data
+        .into_iter()
+        .map(|data| event_payload(event, user_id, occurred_at, data))
+        .flat_map(|payload| {
+            hook_ids.iter().map(move |hook_id| {
+                (
+                    webhook_deliveries::webhook_id.eq(*hook_id),
+                    webhook_deliveries::event.eq(event),
+                    webhook_deliveries::payload.eq(payload.clone()),
+                )
+            })
+        })
+        .collect();
+    let mut queued = 0;
+    for chunk in rows.chunks(1000) {
+        queued += diesel::insert_into(webhook_deliveries::table)
+            .values(chunk)
+            .execute(conn)
+            .map_err(|e| format!("Failed to queue webhook deliveries: {e}"))?;
+    }
+    Ok(queued)
+}
+
+/// Queues `song_tag.added` for each of the user's new links.
+pub fn enqueue_links_added(
+    conn: &mut PgConnection,
+    user_id: i32,
+    links: &[SongTag],
+) -> Result<usize, String> {
+    let data = links
+        .iter()
+        .map(|song_tag| serde_json::json!({ "song_tag": song_tag }))
+        .collect();
+    enqueue_events(conn, user_id, WebhookEvent::SongTagAdded, data)
+}
+
+/// Queues `song_tag.removed` for `removed` links between a song and a tag.
+pub fn enqueue_links_removed(
+    conn: &mut PgConnection,
+    user_id: i32,
+    song_id: &str,
+    tag_id: i32,
+    removed: usize,
+) -> Result<usize, String> {
+    if removed == 0 {
+        return Ok(0);
+    }
+    enqueue_event(
+        conn,
+        user_id,
+        WebhookEvent::SongTagRemoved,
+        serde_json::json!({
+            "song_id": song_id,
+            "tag_id": tag_id,
+            "removed": removed,
+        }),
+    )
+}
+
+/// Queues `tag.created` for each of the user's new tags.
+pub fn enqueue_tags_created(
+    conn: &mut PgConnection,
+    user_id: i32,
+    tags: &[Tag],
+) -> Result<usize, String> {
+    let data = tags
+        .iter()
+        .map(|tag| serde_json::json!({ "tag": tag }))
+        .collect();
+    enqueue_events(conn, user_id, WebhookEvent::TagCreated, data)
+}
+
+/// Sends one delivery. Any 2xx response counts as delivered; returns the
+/// status code, or the code and reason the attempt failed.
+pub async fn send_delivery(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+    timestamp: i64,
+) -> Result<i32, (Option<i32>, String)> {
+    let response = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, delivery.event.as_str())
+        .header(DELIVERY_HEADER, delivery.id.to_string())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(
+            SIGNATURE_HEADER,
+            sign(&webhook.secret, timestamp, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .timeout(std::time::Duration::from_secs(DELIVERY_TIMEOUT_SECONDS))
+        .send()
+        .await
+        .map_err(|e| (None, format!("Request failed: {e}")))?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16() as i32)
+    } else {
+        Err((
+            Some(status.as_u16() as i32),
+            format!("Receiver responded with {status}"),
+        ))
+    }
+}
+
+/// Takes due deliveries off the queue. Claimed rows are pushed back by a
+/// lease so other workers skip them, and so they come round again if this
+/// worker never reports back.
+fn claim_due(
+    conn: &mut PgConnection,
+    now: NaiveDateTime,
+) -> Result<Vec<(WebhookDelivery, Webhook)>, String> {
+    use schema::{webhook_deliveries, webhooks};
+
+    conn.transaction::<_, TransactionError, _>(|conn| {
+        let ids = webhook_deliveries::table
+            .filter(webhook_deliveries::status.eq(DeliveryStatus::Pending))
+            .filter(webhook_deliveries::next_attempt_at.le(now))
+            .order(webhook_deliveries::next_attempt_at.asc())
+            .limit(DELIVERY_BATCH_SIZE)
+            .select(webhook_deliveries::id)
+            .for_update()
+            .skip_locked()
+            .load::<i32>(conn)?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+            .set(
+                webhook_deliveries::next_attempt_at
+                    .eq(now + chrono::Duration::minutes(CLAIM_LEASE_MINUTES)),
+            )
+            .execute(conn)?;
+        Ok(webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhook_deliveries::id.eq_any(&ids))
+            .order(webhook_deliveries::id.asc())
+            .select((webhook_deliveries::all_columns, webhooks::all_columns))
+            .load::<(WebhookDelivery, Webhook)>(conn)?)
+    })
+    .map_err(|TransactionError(e)| e)
+}
+
+fn record_attempt(
+    conn: &mut PgConnection,
+    delivery: &WebhookDelivery,
+    result: Result<i32, (Option<i32>, String)>,
+    now: NaiveDateTime,
+) -> Result<(), String> {
+    use schema::webhook_deliveries::dsl;
+
+    let attempts = delivery.attempts + 1;
+    let target = dsl::webhook_deliveries.find(delivery.id);
+    match result {
+        Ok(status_code) => diesel::update(target)
+            .set((
+                dsl::status.eq(DeliveryStatus::Delivered),
+                dsl::attempts.eq(attempts),
+                dsl::last_status_code.eq(status_code),
+                dsl::last_error.eq(None::<String>),
+                dsl::delivered_at.eq(now),
+            ))
+            .execute(conn),
+        Err((status_code, error)) => {
+            let (status, next_attempt_at) = after_failure(attempts, now);
+            diesel::update(target)
+                .set((
+                    dsl::status.eq(status),
+                    dsl::attempts.eq(attempts),
+                    dsl::next_attempt_at.eq(next_attempt_at),
+                    dsl::last_status_code.eq(status_code),
+                    dsl::last_error.eq(error),
+                ))
+                .execute(conn)
+        }
+    }
+    .map(|_| ())
+    .map_err(|e| format!("Failed to record webhook delivery: {e}"))
+}
+
+/// The client deliveries are sent with. It doesn't follow redirects, which
+/// would send the signed payload somewhere the user never registered.
+pub fn delivery_client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {e}"))
+}
+
+/// Attempts every due delivery once, recording each attempt as soon as it
+/// is made so a slow batch can't outlive the claims on its later
+/// deliveries. Returns how many were delivered.
+pub async fn deliver_due(pool: &DbPool) -> Result<usize, String> {
+    let claim_pool = pool.clone();
+    let due = tokio::task::spawn_blocking(move || {
+        let mut conn = claim_pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        claim_due(&mut conn, chrono::Utc::now().naive_utc())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))??;
+    if due.is_empty() {
+        return Ok(0);
+    }
+
+    let client = delivery_client()?;
+    let mut delivered = 0;
+    for (delivery, webhook) in due {
+        let timestamp = chrono::Utc::now().timestamp();
+        let result = send_delivery(&client, &webhook, &delivery, timestamp).await;
+        if result.is_ok() {
+            delivered += 1;
+        }
+
+        let pool = pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool
+                .get()
+                .map_err(|e| format!("Failed to get connection: {e}"))?;
+            record_attempt(&mut conn, &delivery, result, chrono::Utc::now().naive_utc())
+        })
+        .await
+        .map_err(|e| format!("Task join error: {e}"))??;
+    }
+    Ok(delivered)
+}
+
+pub async fn create_webhook(
+    pool: &DbPool,
+    user_id: i32,
+    new_webhook: NewWebhook,
+) -> Result<CreatedWebhook, String> {
+    use schema::webhooks::dsl;
+
+    new_webhook.validate()?;
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let secret = generate_token();
+        let webhook = diesel::insert_into(dsl::webhooks)
+            .values((
+                dsl::user_id.eq(user_id),
+                dsl::url.eq(&new_webhook.url),
+                dsl::secret.eq(&secret),
+                dsl::events.eq(new_webhook.event_list()),
+            ))
+            .get_result::<Webhook>(&mut conn)
+            .map_err(|e| format!("Failed to create webhook: {e}"))?;
+        Ok(CreatedWebhook { webhook, secret })
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn list_webhooks(pool: &DbPool, user_id: i32) -> Result<Vec<Webhook>, String> {
+    use schema::webhooks::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        dsl::webhooks
+            .filter(dsl::user_id.eq(user_id))
+            .order(dsl::created_at.asc())
+            .load::<Webhook>(&mut conn)
+            .map_err(|e| format!("Failed to load webhooks: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Deletes a webhook along with its queued deliveries and log.
+pub async fn delete_webhook(pool: &DbPool, user_id: i32, webhook_id: i32) -> Result<usize, String> {
+    use schema::webhooks::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        diesel::delete(
+            dsl::webhooks
+                .filter(dsl::id.eq(webhook_id))
+                .filter(dsl::user_id.eq(user_id)),
+        )
+        .execute(&mut conn)
+        .map_err(|e| format!("Failed to delete webhook: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// The delivery log for one webhook, newest first. Filtering on
+/// [`DeliveryStatus::Dead`] gives the dead letters.
+pub async fn list_deliveries(
+    pool: &DbPool,
+    user_id: i32,
+    webhook_id: i32,
+    status: Option<DeliveryStatus>,
+) -> Result<Vec<WebhookDelivery>, String> {
+    use schema::{webhook_deliveries, webhooks};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        webhooks::table
+            .filter(webhooks::id.eq(webhook_id))
+            .filter(webhooks::user_id.eq(user_id))
+            .select(webhooks::id)
+            .first::<i32>(&mut conn)
+            .map_err(|_| "Webhook not found or not owned by user".to_string())?;
+
+        let mut query = webhook_deliveries::table
+            .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+            .into_boxed();
+        if let Some(status) = status {
+            query = query.filter(webhook_deliveries::status.eq(status));
+        }
+        query
+            .order(webhook_deliveries::created_at.desc())
+            .limit(DELIVERY_LOG_LIMIT)
+            .load::<WebhookDelivery>(&mut conn)
+            .map_err(|e| format!("Failed to load webhook deliveries: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Puts a delivery back on the queue with a fresh set of attempts, usually
+/// to retry a dead letter once the receiver is fixed.
+pub async fn redeliver(
+    pool: &DbPool,
+    user_id: i32,
+    delivery_id: i32,
+) -> Result<WebhookDelivery, String> {
+    use schema::{webhook_deliveries, webhooks};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        let owned = webhooks::table
+            .filter(webhooks::user_id.eq(user_id))
+            .select(webhooks::id);
+        diesel::update(
+            webhook_deliveries::table
+                .filter(webhook_deliveries::id.eq(delivery_id))
+                .filter(webhook_deliveries::webhook_id.eq_any(owned)),
+        )
+        .set((
+            webhook_deliveries::status.eq(DeliveryStatus::Pending),
+            webhook_deliveries::attempts.eq(0),
+            webhook_deliveries::next_attempt_at.eq(chrono::Utc::now().naive_utc()),
+        ))
+        .get_result::<WebhookDelivery>(&mut conn)
+        .optional()
+        .map_err(|e| format!("Failed to requeue webhook delivery: {e}"))?
+        .ok_or_else(|| "Delivery not found or not owned by user".to_string())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(2026, 10, 18)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap()
+    }
+
+    fn webhook(url: &str, events: &str) -> Webhook {
+        Webhook {
+            id: 1,
+            user_id: 2,
+            url: url.to_string(),
+            secret: "shh".to_string(),
+            events: events.to_string(),
+            created_at: now(),
+            updated_at: now(),
+        }
+    }
+
+    fn delivery() -> WebhookDelivery {
+        WebhookDelivery {
+            id: 7,
+            webhook_id: 1,
+            event: WebhookEvent::TagCreated,
+            payload: event_payload(
+                WebhookEvent::TagCreated,
+                2,
+                now(),
+                serde_json::json!({ "tag": { "id": 3, "name": "rainy day" } }),
+            ),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now(),
+            last_status_code: None,
+            last_error: None,
+            delivered_at: None,
+            created_at: now(),
+        }
+    }
+
+    #[test]
+    fn test_sign_matches_known_hmac() {
+        // echo -n "1700000000.{}" | openssl dgst -sha256 -hmac key
+        assert_eq!(
+            sign("key", 1_700_000_000, "{}"),
+            "sha256=9d713ed406bb7076d4123f0dc2c39d2df5c654ed4b0cd56b52c8b4c940bd63ae"
+        );
+        assert_ne!(sign("key", 1, "{}"), sign("key", 2, "{}"));
+        assert_ne!(sign("key", 1, "{}"), sign("other", 1, "{}"));
+    }
+
+    #[test]
+    fn test_retries_back_off_then_die() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(retry_delay(3), chrono::Duration::minutes(2));
+        assert_eq!(
+            after_failure(1, now()),
+            (
+                DeliveryStatus::Pending,
+                now() + chrono::Duration::seconds(30)
+            )
+        );
+        assert_eq!(
+            after_failure(MAX_ATTEMPTS, now()),
+            (DeliveryStatus::Dead, now())
+        );
+    }
+
+    #[test]
+    fn test_new_webhook_validation() {
+        let hook = NewWebhook {
+            url: "http://localhost:8123/hooks".to_string(),
+            events: vec![WebhookEvent::SongTagAdded, WebhookEvent::TagCreated],
+        };
+        assert!(hook.validate().is_ok());
+        assert_eq!(hook.event_list(), "song_tag.added tag.created");
+
+        let ftp = NewWebhook {
+            url: "ftp://example.com".to_string(),
+            ..hook.clone()
+        };
+        assert!(ftp.validate().is_err());
+
+        let no_events = NewWebhook {
+            events: Vec::new(),
+            ..hook
+        };
+        assert!(no_events.validate().is_err());
+    }
+
+    #[test]
+    fn test_webhook_subscriptions_and_serialization() {
+        let hook = webhook("http://localhost", "song_tag.added tag.created");
+
+        assert!(hook.subscribes_to(WebhookEvent::TagCreated));
+        assert!(!hook.subscribes_to(WebhookEvent::TagDeleted));
+
+        let serialized = serde_json::to_value(&hook).unwrap();
+        assert!(serialized.get("secret").is_none());
+        assert_eq!(
+            serialized["events"],
+            serde_json::json!(["song_tag.added", "tag.created"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_delivery_signs_the_body() {
+        let mut server = mockito::Server::new_async().await;
+        let delivery = delivery();
+        let mock = server
+            .mock("POST", "/hooks")
+            .match_header(EVENT_HEADER, "tag.created")
+            .match_header(DELIVERY_HEADER, "7")
+            .match_header(TIMESTAMP_HEADER, "1700000000")
+            .match_header(
+                SIGNATURE_HEADER,
+                sign("shh", 1_700_000_000, &delivery.payload).as_str(),
+            )
+            .match_body(delivery.payload.as_str())
+            .with_status(204)
+            .create_async()
+            .await;
+
+        let hook = webhook(&format!("{}/hooks", server.url()), "tag.created");
+        let result = send_delivery(&reqwest::Client::new(), &hook, &delivery, 1_700_000_000).await;
+
+        mock.assert_async().await;
+        assert_eq!(result, Ok(204));
+    }
+
+    #[tokio::test]
+    async fn test_send_delivery_reports_receiver_errors() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/hooks")
+            .with_status(500)
+            .create_async()
+            .await;
+
+        let hook = webhook(&format!("{}/hooks", server.url()), "tag.created");
+        let result = send_delivery(&reqwest::Client::new(), &hook, &delivery(), 1).await;
+
+        mock.assert_async().await;
+        assert!(matches!(result, Err((Some(500), _))));
+    }
+
+    #[tokio::test]
+    async fn test_delivery_client_does_not_follow_redirects() {
+        let mut server = mockito::Server::new_async().await;
+        let redirect = server
+            .mock("POST", "/hooks")
+            .with_status(307)
+            .with_header("Location", "/elsewhere")
+            .create_async()
+            .await;
+        let elsewhere = server
+            .mock("POST", "/elsewhere")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let hook = webhook(&format!("{}/hooks", server.url()), "tag.created");
+        let client = delivery_client().unwrap();
+        let result = send_delivery(&client, &hook, &delivery(), 1).await;
+
+        redirect.assert_async().await;
+        elsewhere.assert_async().await;
+        assert!(matches!(result, Err((Some(307), _))));
+    }
+}
@@ -0,0 +1,353 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::permissions::{authorize_workspace, WorkspaceRole};
+use crate::{schema, DbPool, TransactionError};
+
+/// A shared tag set. Its tags are visible to every member.
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::workspaces)]
+pub struct Workspace {
+    pub id: i32,
+    pub name: String,
+    /// `None` once the creator's account is gone.
+    pub created_by: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+#[diesel(table_name = schema::workspace_members)]
+pub struct WorkspaceMember {
+    pub id: i32,
+    pub workspace_id: i32,
+    pub user_id: i32,
+    pub role: WorkspaceRole,
+    pub created_at: NaiveDateTime,
+}
+
+/// A workspace as seen by one of its members.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MemberWorkspace {
+    #[serde(flatten)]
+    pub workspace: Workspace,
+    pub role: WorkspaceRole,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NewWorkspace {
+    pub name: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MemberRole {
+    pub user_id: i32,
+    pub role: WorkspaceRole,
+}
+
+/// Whether a workspace with these members still has an admin after
+/// `member_id` changes to `new_role`, or leaves when it is `None`. A
+/// workspace nobody is left in needs no admin.
+pub fn keeps_an_admin(
+    members: &[(i32, WorkspaceRole)],
+    member_id: i32,
+    new_role: Option<WorkspaceRole>,
+) -> bool {
+    let remaining: Vec<WorkspaceRole> = members
+        .iter()
+        .filter(|(user_id, _)| *user_id != member_id)
+        .map(|(_, role)| *role)
+        .chain(new_role)
+        .collect();
+    remaining.is_empty() || remaining.contains(&WorkspaceRole::Admin)
+}
+
+fn load_member_roles(
+    conn: &mut PgConnection,
+    workspace_id: i32,
+) -> Result<Vec<(i32, WorkspaceRole)>, String> {
+    use schema::workspace_members::dsl;
+
+    dsl::workspace_members
+        .filter(dsl::workspace_id.eq(workspace_id))
+        .order(dsl::created_at.asc())
+        .select((dsl::user_id, dsl::role))
+        .load::<(i32, WorkspaceRole)>(conn)
+        .map_err(|e| format!("Failed to load workspace members: {e}"))
+}
+
+/// Creates a workspace with its creator as the first admin.
+pub async fn create_workspace(
+    pool: &DbPool,
+    user_id: i32,
+    new_workspace: NewWorkspace,
+) -> Result<MemberWorkspace, String> {
+    use schema::{workspace_members, workspaces};
+
+    let name = new_workspace.name.trim().to_string();
+    if name.is_empty() {
+        return Err("Workspace name must not be empty".to_string());
+    }
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let workspace = diesel::insert_into(workspaces::table)
+                .values((
+                    workspaces::name.eq(&name),
+                    workspaces::created_by.eq(user_id),
+                ))
+                .get_result::<Workspace>(conn)?;
+            diesel::insert_into(workspace_members::table)
+                .values((
+                    workspace_members::workspace_id.eq(workspace.id),
+                    workspace_members::user_id.eq(user_id),
+                    workspace_members::role.eq(WorkspaceRole::Admin),
+                ))
+                .execute(conn)?;
+            Ok(MemberWorkspace {
+                workspace,
+                role: WorkspaceRole::Admin,
+            })
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn list_workspaces(pool: &DbPool, user_id: i32) -> Result<Vec<MemberWorkspace>, String> {
+    use schema::{workspace_members, workspaces};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        Ok(workspaces::table
+            .inner_join(workspace_members::table)
+            .filter(workspace_members::user_id.eq(user_id))
+            .order(workspaces::name.asc())
+            .select((workspaces::all_columns, workspace_members::role))
+            .load::<(Workspace, WorkspaceRole)>(&mut conn)
+            .map_err(|e| format!("Failed to load workspaces: {e}"))?
+            .into_iter()
+            .map(|(workspace, role)| MemberWorkspace { workspace, role })
+            .collect())
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+pub async fn list_members(
+    pool: &DbPool,
+    user_id: i32,
+    workspace_id: i32,
+) -> Result<Vec<WorkspaceMember>, String> {
+    use schema::workspace_members::dsl;
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        authorize_workspace(&mut conn, user_id, workspace_id, WorkspaceRole::Viewer)?;
+        dsl::workspace_members
+            .filter(dsl::workspace_id.eq(workspace_id))
+            .order(dsl::created_at.asc())
+            .load::<WorkspaceMember>(&mut conn)
+            .map_err(|e| format!("Failed to load workspace members: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Adds a member or changes their role. Admins only; the last admin can't
+/// step down while others remain.
+pub async fn set_member_role(
+    pool: &DbPool,
+    user_id: i32,
+    workspace_id: i32,
+    member: MemberRole,
+) -> Result<WorkspaceMember, String> {
+    use schema::{users, workspace_members};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            authorize_workspace(conn, user_id, workspace_id, WorkspaceRole::Admin)?;
+            users::table
+                .find(member.user_id)
+                .select(users::id)
+                .first::<i32>(conn)
+                .map_err(|_| "User not found".to_string())?;
+            let members = load_member_roles(conn, workspace_id)?;
+            if !keeps_an_admin(&members, member.user_id, Some(member.role)) {
+                return Err("A workspace needs at least one admin".to_string().into());
+            }
+
+            Ok(diesel::insert_into(workspace_members::table)
+                .values((
+                    workspace_members::workspace_id.eq(workspace_id),
+                    workspace_members::user_id.eq(member.user_id),
+                    workspace_members::role.eq(member.role),
+                ))
+                .on_conflict((workspace_members::workspace_id, workspace_members::user_id))
+                .do_update()
+                .set(workspace_members::role.eq(member.role))
+                .get_result::<WorkspaceMember>(conn)?)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Removes a member; admins can remove anyone and members can leave. The
+/// workspace and its tags go when the last member does.
+pub async fn remove_member(
+    pool: &DbPool,
+    user_id: i32,
+    workspace_id: i32,
+    member_id: i32,
+) -> Result<usize, String> {
+    use schema::{workspace_members, workspaces};
+
+    let pool = pool.clone();
+
+    tokio::task::spawn_blocking(move || {
+        let mut conn = pool
+            .get()
+            .map_err(|e| format!("Failed to get connection: {e}"))?;
+        conn.transaction::<_, TransactionError, _>(|conn| {
+            let required = if member_id == user_id {
+                WorkspaceRole::Viewer
+            } else {
+                WorkspaceRole::Admin
+            };
+            authorize_workspace(conn, user_id, workspace_id, required)?;
+            let members = load_member_roles(conn, workspace_id)?;
+            if !keeps_an_admin(&members, member_id, None) {
+                return Err("Make another member an admin first".to_string().into());
+            }
+
+            let removed = diesel::delete(
+                workspace_members::table
+                    .filter(workspace_members::workspace_id.eq(workspace_id))
+                    .filter(workspace_members::user_id.eq(member_id)),
+            )
+            .execute(conn)?;
+            if removed > 0 && members.len() == 1 {
+                diesel::delete(workspaces::table.find(workspace_id)).execute(conn)?;
+            }
+            Ok(removed)
+        })
+        .map_err(|TransactionError(e)| e)
+    })
+    .await
+    .map_err(|e| format!("Task join error: {e}"))?
+}
+
+/// Prepares the user's workspaces for their account being erased: tags
+/// they created pass to another member and an admin is appointed where they
+/// were the only one. Workspaces they are alone in are left to go with the
+/// account; see [`delete_empty_workspaces`].
+pub fn hand_over_workspaces(conn: &mut PgConnection, user_id: i32) -> Result<(), String> {
+    use schema::{tags, workspace_members};
+
+    let workspace_ids = workspace_members::table
+        .filter(workspace_members::user_id.eq(user_id))
+        .select(workspace_members::workspace_id)
+        .load::<i32>(conn)
+        .map_err(|e| format!("Failed to load workspaces: {e}"))?;
+
+    for workspace_id in workspace_ids {
+        let others: Vec<(i32, WorkspaceRole)> = load_member_roles(conn, workspace_id)?
+            .into_iter()
+            .filter(|(member_id, _)| *member_id != user_id)
+            .collect();
+        // Longest-standing admin, else longest-standing member.
+        let Some(&(successor, successor_role)) = others
+            .iter()
+            .find(|(_, role)| *role == WorkspaceRole::Admin)
+            .or(others.first())
+        else {
+            continue;
+        };
+
+        if successor_role != WorkspaceRole::Admin {
+            diesel::update(
+                workspace_members::table
+                    .filter(workspace_members::workspace_id.eq(workspace_id))
+                    .filter(workspace_members::user_id.eq(successor)),
+            )
+            .set(workspace_members::role.eq(WorkspaceRole::Admin))
+            .execute(conn)
+            .map_err(|e| format!("Failed to appoint admin: {e}"))?;
+        }
+        diesel::update(
+            tags::table
+                .filter(tags::workspace_id.eq(workspace_id))
+                .filter(tags::user_id.eq(user_id)),
+        )
+        .set(tags::user_id.eq(successor))
+        .execute(conn)
+        .map_err(|e| format!("Failed to hand over tags: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Deletes workspaces nobody is a member of any more.
+pub fn delete_empty_workspaces(conn: &mut PgConnection) -> Result<usize, String> {
+    use schema::{workspace_members, workspaces};
+
+    diesel::delete(
+        workspaces::table.filter(diesel::dsl::not(diesel::dsl::exists(
+            workspace_members::table.filter(workspace_members::workspace_id.eq(workspaces::id)),
+        ))),
+    )
+    .execute(conn)
+    .map_err(|e| format!("Failed to delete empty workspaces: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_an_admin() {
+        let members = vec![
+            (1, WorkspaceRole::Admin),
+            (2, WorkspaceRole::Tagger),
+            (3, WorkspaceRole::Viewer),
+        ];
+
+        assert!(!keeps_an_admin(&members, 1, Some(WorkspaceRole::Tagger)));
+        assert!(!keeps_an_admin(&members, 1, None));
+        assert!(keeps_an_admin(&members, 2, None));
+        assert!(keeps_an_admin(&members, 4, Some(WorkspaceRole::Viewer)));
+
+        let two_admins = vec![(1, WorkspaceRole::Admin), (2, WorkspaceRole::Admin)];
+        assert!(keeps_an_admin(&two_admins, 1, None));
+    }
+
+    #[test]
+    fn test_last_member_can_leave() {
+        assert!(keeps_an_admin(&[(1, WorkspaceRole::Admin)], 1, None));
+    }
+}
@@ -85,7 +85,7 @@ export const ProfileMenu: React.FC<ProfileMenuProps> = ({
         ) : (
           <View style={styles.avatarContainer}>
             <Text style={styles.avatarPlaceholder}>
-              {user.display_name?.charAt(0).toUpperCase() || user.spotify_id.charAt(0).toUpperCase()}
+              {(user.display_name || user.spotify_id || '?').charAt(0).toUpperCase()}
             </Text>
           </View>
         )}
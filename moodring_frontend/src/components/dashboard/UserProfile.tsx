@@ -20,7 +20,7 @@ export const UserProfile: React.FC<UserProfileProps> = ({ user }) => {
         ) : (
           <View style={styles.avatarContainer}>
             <Text style={styles.avatarPlaceholder}>
-              {user.display_name?.charAt(0).toUpperCase() || user.spotify_id.charAt(0).toUpperCase()}
+              {(user.display_name || user.spotify_id || '?').charAt(0).toUpperCase()}
             </Text>
           </View>
         )}
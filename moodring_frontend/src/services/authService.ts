@@ -34,10 +34,12 @@ export class AuthService {
   async refreshSpotifyToken(userId: number): Promise<BackendAuthResponse> {
     try {
       const backendUrl = process.env.EXPO_PUBLIC_BACKEND_URL || 'http://localhost:8000';
+      const savedAuth = await this.loadSavedAuthData();
       const response = await fetch(`${backendUrl}/auth/refresh/${userId}`, {
         method: 'POST',
         headers: {
           'Content-Type': 'application/json',
+          ...(savedAuth && { Authorization: `Bearer ${savedAuth.access_token}` }),
         },
       });
 
@@ -1,7 +1,7 @@
 export interface BackendUser {
   id: number;
-  spotify_id: string;
-  email: string;
+  spotify_id: string | null;
+  email: string | null;
   display_name: string | null;
   spotify_access_token: string | null;
   spotify_refresh_token: string | null;
@@ -9,6 +9,9 @@ export interface BackendUser {
   profile_image_url: string | null;
   created_at: string;
   updated_at: string;
+  spotify_unlinked_at?: string | null;
+  spotify_scopes?: string | null;
+  email_verified?: boolean;
 }
 
 export interface BackendAuthResponse {